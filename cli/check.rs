@@ -0,0 +1,100 @@
+use std::env;
+
+use musso::config::Config;
+use musso::sorting::{find_misplaced, sort_files, validate_folder, Options};
+
+use crate::cli::CheckArgs;
+use crate::error::Error;
+use crate::sort::{library_options, log_report, resolve_format};
+use crate::AnyResult;
+
+pub fn run(config: &Config, args: CheckArgs) -> AnyResult<()> {
+    let path = args.path.unwrap_or(env::current_dir()?);
+    if !path.is_dir() {
+        let err = Error::InvalidRoot {
+            path: path.display().to_string(),
+        };
+
+        return Err(err.into());
+    }
+
+    let (format, _) = resolve_format(config, &path, args.format, None)?;
+    let defaults = library_options(config, &path, args.dryrun)?;
+    let options = Options {
+        format,
+        exfat_compat: args.exfat_compat || defaults.exfat_compat,
+        strip_empty_components: args.strip_empty_components || defaults.strip_empty_components,
+        keep_structure_depth: args.keep_structure_depth,
+        ..defaults
+    };
+
+    let misplaced = find_misplaced(&path, &path, &options)?;
+    for file in &misplaced {
+        log::warn!(
+            "Misplaced: \"{}\" (should be \"{}\")",
+            file.path.display(),
+            file.expected.display()
+        );
+    }
+
+    log::info!("Found {} misplaced files", misplaced.len());
+
+    if args.fix && !misplaced.is_empty() {
+        let files = misplaced.into_iter().map(|file| file.path);
+        log_report(&sort_files(&path, files, &options));
+    }
+
+    Ok(())
+}
+
+/// Checks that every file of the library `name` is where its format says,
+/// moving the misplaced ones with `fix`. Fails if misplaced files are left
+pub fn validate_library(config: &Config, name: &str, fix: bool) -> AnyResult<()> {
+    let library = config
+        .libraries
+        .get(name)
+        .ok_or_else(|| Error::UnknownLibrary {
+            name: name.to_owned(),
+        })?;
+    let options = config.options_of(name)?;
+
+    let (mut placed, mut misplaced, mut unknown) = (0, 0, 0);
+    for folder in &library.folders {
+        let validation = validate_folder(folder, folder, &options)?;
+
+        for file in &validation.misplaced {
+            log::warn!(
+                "Misplaced: \"{}\" (should be \"{}\")",
+                file.path.display(),
+                file.expected.display()
+            );
+        }
+        for (path, reason) in &validation.unknown {
+            log::error!("Couldn't check \"{}\" ({})", path.display(), reason);
+        }
+
+        placed += validation.placed;
+        unknown += validation.unknown.len();
+        misplaced += validation.misplaced.len();
+
+        if fix && !validation.misplaced.is_empty() {
+            let files = validation.misplaced.into_iter().map(|file| file.path);
+            let report = sort_files(folder, files, &options);
+            log_report(&report);
+            misplaced -= report.success;
+        }
+    }
+
+    log::info!(
+        "{} files placed correctly, {} misplaced, {} couldn't be checked",
+        placed,
+        misplaced,
+        unknown
+    );
+
+    if misplaced > 0 {
+        return Err(Error::MisplacedFiles { count: misplaced }.into());
+    }
+
+    Ok(())
+}
@@ -1,11 +1,33 @@
-use clap::Parser;
+use clap::{Args, Parser};
+use musso::format::{placeholder_value, BuildOptions, ParsedFormat};
+use musso::metadata::Metadata;
+use musso::sorting::{DedupPreference, DestExistsPolicy, LongPathPolicy};
+use musso::tag_rename::FrameMapping;
+use regex::Regex;
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Debug, Parser)]
 pub struct CliArgs {
-    /// Path to custom config file.
+    /// Path to custom config file, can be repeated to merge several files
+    /// (later ones win). A directory stands for the .toml files inside it,
+    /// `-` for stdin and an http(s):// URL for the fetched file (requires
+    /// the remote-config feature).
     #[clap(short, long)]
-    pub config: Option<PathBuf>,
+    pub config: Vec<PathBuf>,
+
+    /// Directory holding all musso state: the config file (unless --config
+    /// is given) and relative paths of state files like the event log.
+    #[clap(long, global = true)]
+    pub state_dir: Option<PathBuf>,
+
+    /// Also write the log to this file.
+    #[clap(long, global = true)]
+    pub log_file: Option<PathBuf>,
+
+    /// Rotate the log file once it reaches this size (in megabytes).
+    #[clap(long, global = true, requires = "log_file")]
+    pub log_file_max_mb: Option<u64>,
 
     #[clap(subcommand)]
     pub cmd: SubCommand,
@@ -15,38 +37,520 @@ pub struct CliArgs {
 pub enum SubCommand {
     /// Copy service file to systemd user config dir.
     #[clap(name = "copy-service")]
-    CopyService,
+    CopyService {
+        /// Install a system-wide unit in /etc/systemd/system instead.
+        #[clap(long)]
+        system: bool,
+    },
 
     /// Watch libraries and sort added files.
-    Watch,
+    Watch(WatchArgs),
+
+    /// Watch libraries in the background, detached from the terminal (unix
+    /// only). Logs only go to --log-file.
+    Daemon {
+        /// Write the PID of the daemon to this file, musso.pid in the state
+        /// dir or the runtime dir by default.
+        #[clap(long, value_name = "FILE")]
+        pid_file: Option<PathBuf>,
+
+        #[clap(flatten)]
+        watch: WatchArgs,
+    },
 
     /// Sort a music directory.
-    Sort {
+    Sort(Box<SortArgs>),
+
+    /// List the files of a music directory that aren't where the format says.
+    Check(CheckArgs),
+
+    /// Check that every file of a library is where its format says, exiting
+    /// with an error if some aren't.
+    ValidateLibrary {
+        /// Name of the library in the config file.
+        name: String,
+
+        /// Move the misplaced files to where they should be.
+        #[clap(long)]
+        fix: bool,
+    },
+
+    /// Remove empty directories under a music directory.
+    Clean {
         /// Path to music directory.
         path: Option<PathBuf>,
 
-        /// Custom format string.
+        /// Don't remove anything, only list what would be removed.
         #[clap(short, long)]
-        format: Option<String>,
+        dryrun: bool,
 
-        /// Don't sort anything (simulated run).
+        /// Remove folders containing only files with this name too (e.g.
+        /// .DS_Store), can be repeated. Taken from the config file if absent.
+        #[clap(long = "prune-junk")]
+        prune_junk: Vec<String>,
+    },
+
+    /// Move the files sorted in a run back to where they were.
+    Restore {
+        /// Summary written by sort --summary-file.
+        summary_file: PathBuf,
+
+        /// Don't move anything, only list what would be restored.
         #[clap(short, long)]
         dryrun: bool,
+    },
 
-        /// Sort files recursively.
+    /// Fill the missing tags of the files that failed in a run from their
+    /// file names, then sort them again.
+    Repair {
+        /// Summary written by sort --summary-file.
+        summary_file: PathBuf,
+
+        /// Format the file names follow, e.g. "{artist} - {track} - {title}.{ext}".
+        #[clap(short, long)]
+        filename_format: String,
+
+        /// Don't write or move anything, only list what would be repaired.
+        #[clap(short, long)]
+        dryrun: bool,
+    },
+
+    /// Embed the front cover of their release (from the Cover Art Archive) in
+    /// the files missing one.
+    #[cfg(feature = "fetch-art")]
+    #[clap(name = "fetch-art")]
+    FetchArt {
+        /// Path to music directory.
+        path: PathBuf,
+
+        /// Go through the subdirectories too.
         #[clap(short, long)]
         recursive: bool,
 
-        /// Remove empty directories found while and after sorting.
-        #[clap(name = "rm-empty", long)]
-        remove_empty: bool,
+        /// Don't change any file, only list the ones that would get a cover.
+        #[clap(short, long)]
+        dryrun: bool,
+    },
+
+    /// Write the tags of every file of a library to a SQLite database, to
+    /// query it with SQL.
+    #[cfg(feature = "catalogue")]
+    Catalogue {
+        /// Name of the library in the config file.
+        library: String,
+
+        /// Database file, created if missing.
+        db: PathBuf,
+
+        /// Only read the tags of the files modified since the last export.
+        #[clap(long)]
+        incremental: bool,
+    },
 
-        /// Mantain file names compatible with FAT32.
+    /// Copy ID3 frames into other frames that are missing, e.g. TPE2 into
+    /// the artist.
+    TagRename {
+        /// Path to music directory.
+        path: PathBuf,
+
+        /// Go through the subdirectories too.
+        #[clap(short, long)]
+        recursive: bool,
+
+        /// Frame to copy as FROM=TO, each side a frame id (e.g. TPE2) or one
+        /// of artist, album_artist, album, title, track, disc, genre and
+        /// year. Can be repeated.
+        #[clap(long = "map", required = true)]
+        mapping: Vec<FrameMapping>,
+
+        /// Remove the FROM frames after copying them.
+        #[clap(long)]
+        remove_source: bool,
+
+        /// Don't change any file, only list the frames that would be copied.
         #[clap(short, long)]
-        exfat_compat: bool,
+        dryrun: bool,
+    },
+
+    /// Print the tags musso reads from an audio file, as stored and as
+    /// sorting sees them, with its container and audio properties.
+    Tags {
+        /// Path to the audio file.
+        path: PathBuf,
     },
 
     /// Goodies related to sync mode.
     #[cfg(feature = "sync")]
     Sync,
 }
+
+#[derive(Debug, Args)]
+pub struct WatchArgs {
+    /// Append every received file-system event as JSON to this file.
+    #[clap(long)]
+    pub watch_event_log: Option<PathBuf>,
+
+    /// Rotate the event log once it reaches this size (in megabytes).
+    #[clap(long, requires = "watch_event_log")]
+    pub event_log_max_mb: Option<u64>,
+
+    /// Socket where the watcher accepts test events.
+    #[cfg(unix)]
+    #[clap(long)]
+    pub control_socket: Option<PathBuf>,
+
+    /// Don't watch, make the running watcher handle this path as if it
+    /// had just been created.
+    #[cfg(unix)]
+    #[clap(long)]
+    pub test_event: Option<PathBuf>,
+
+    /// Exit once no event is received for this long (e.g. 90, 30s, 5m
+    /// or 1h).
+    #[clap(long, visible_alias = "watch-timeout", value_parser = parse_duration)]
+    pub idle_timeout: Option<Duration>,
+
+    /// Sort the files already in the library folders before watching.
+    #[clap(long)]
+    pub scan_on_start: bool,
+
+    /// With --scan-on-start, only sort the files modified since the
+    /// previous run started.
+    #[clap(long, requires = "scan_on_start")]
+    pub since_last_run: bool,
+
+    /// With --since-last-run, sort every file when there's no previous
+    /// run (instead of none).
+    #[clap(long, requires = "since_last_run")]
+    pub first_run_all: bool,
+
+    /// Keep the folders emptied by sorting, overriding remove-empty of
+    /// the config file.
+    #[clap(long)]
+    pub no_remove_empty: bool,
+
+    /// Watch these folders instead of the libraries of the config file.
+    #[clap(long, num_args = 1.., value_name = "PATHS")]
+    pub watch_roots: Vec<PathBuf>,
+
+    /// With --watch-roots, format string of the files sorted in them.
+    #[clap(short, long, requires = "watch_roots")]
+    pub format: Option<ParsedFormat>,
+}
+
+#[derive(Debug, Args)]
+pub struct SortArgs {
+    /// Path to music directory.
+    pub path: Option<PathBuf>,
+
+    /// Custom format string.
+    #[clap(short, long)]
+    pub format: Option<String>,
+
+    /// Read the custom format string from a file.
+    #[clap(long, conflicts_with = "format")]
+    pub format_file: Option<PathBuf>,
+
+    /// Don't sort anything (simulated run).
+    #[clap(short, long)]
+    pub dryrun: bool,
+
+    /// Sort files recursively.
+    #[clap(short, long)]
+    pub recursive: bool,
+
+    /// Only walk this many levels of folders below the sorted one, 0 only
+    /// sorts its own files.
+    #[clap(long, value_name = "N")]
+    pub max_depth: Option<usize>,
+
+    /// Remove empty directories found while and after sorting.
+    #[clap(name = "rm-empty", long)]
+    pub remove_empty: bool,
+
+    /// Instead of removing them, write an empty file with this name (e.g.
+    /// .muso-sorted) in the folders emptied by sorting.
+    #[clap(long, value_name = "NAME", conflicts_with = "rm-empty")]
+    pub mark_empty: Option<String>,
+
+    /// Mantain file names compatible with FAT32.
+    #[clap(short, long)]
+    pub exfat_compat: bool,
+
+    /// Drop folders left empty by missing tags (e.g. `Artist/Title` instead
+    /// of failing without an album).
+    #[clap(long)]
+    pub strip_empty_components: bool,
+
+    /// Sort into existing (or already planned) folders whose name only
+    /// differs by diacritics (e.g. Motorhead for Motörhead). Taken from the
+    /// config file if absent.
+    #[clap(long)]
+    pub fold_diacritics: bool,
+
+    /// Also sort hidden files and look inside hidden directories.
+    #[clap(long)]
+    pub hidden: bool,
+
+    /// Permissions of the created directories, in octal (e.g. 755). Taken
+    /// from the config file if absent.
+    #[clap(long, value_parser = parse_octal)]
+    pub dir_mode: Option<u32>,
+
+    /// Explain which format was used and how each path was built.
+    #[clap(long)]
+    pub explain: bool,
+
+    /// Keep the access and modification times of moved files.
+    #[clap(long)]
+    pub preserve_timestamps: bool,
+
+    /// Extension of companion files moved along with their audio file
+    /// (e.g. lrc), can be repeated. Taken from the config file if absent.
+    #[clap(long = "companion")]
+    pub companions: Vec<String>,
+
+    /// Regular expression removed from titles to fill {title_clean}, can be
+    /// repeated. Taken from the config file if absent.
+    #[clap(long = "title-clean-pattern")]
+    pub title_clean_patterns: Vec<Regex>,
+
+    /// Leading article skipped by {initial} (e.g. the), can be repeated.
+    /// Taken from the config file if absent, the, a and an by default.
+    #[clap(long = "strip-article")]
+    pub strip_articles: Vec<String>,
+
+    /// Only sort the files listed in this M3U playlist.
+    #[clap(long)]
+    pub from_playlist: Option<PathBuf>,
+
+    /// With --rm-empty, remove folders containing only files with this name
+    /// too (e.g. .DS_Store), can be repeated. Taken from the config file if
+    /// absent.
+    #[clap(long = "prune-junk")]
+    pub prune_junk: Vec<String>,
+
+    /// Skip the files and folders whose full path matches this glob (e.g.
+    /// "*.part.mp3"), can be repeated. Adds to the ignore-patterns of the
+    /// library.
+    #[clap(long = "exclude", value_name = "PATTERN")]
+    pub exclude: Vec<String>,
+
+    /// If a file of an album can't be sorted, move back the other files of
+    /// the album that were already sorted.
+    #[clap(long)]
+    pub atomic_album: bool,
+
+    /// Keep this many folders of each file's path (below the sorted folder)
+    /// in front of its new path, e.g. 1 keeps Bootlegs/ of
+    /// Bootlegs/1994/Show/track.flac.
+    #[clap(long, default_value_t = 0)]
+    pub keep_structure_depth: usize,
+
+    /// Only rename the files inside their current folder, using the file
+    /// name part of the format.
+    #[clap(long, conflicts_with = "keep_structure_depth")]
+    pub rename_only: bool,
+
+    /// Fail files whose tags take longer than this many seconds to read.
+    /// Taken from the config file if absent.
+    #[clap(long)]
+    pub tag_read_timeout: Option<u64>,
+
+    /// Make read-only files writable when they have to be copied to another
+    /// file system, so they can be removed afterwards
+    #[clap(long)]
+    pub auto_chmod: bool,
+
+    /// Only sort files into folders that already exist, the files needing a
+    /// new one are skipped.
+    #[clap(long)]
+    pub no_create_dirs: bool,
+
+    /// What to do with an album whose destination folder already has files,
+    /// e.g. from another rip: merge (move the files in alongside them),
+    /// skip-album (leave its files in place) or error.
+    #[clap(long, value_name = "POLICY", default_value = "merge")]
+    pub dest_exists_policy: DestExistsPolicy,
+
+    /// Fail files whose destination only differs in case from an existing
+    /// file or folder (e.g. `album` and `Album`), which clash on
+    /// case-insensitive file systems. Implied by --exfat-compat.
+    #[clap(long)]
+    pub ignore_case_collision: bool,
+
+    /// Longest destination path allowed, in bytes.
+    #[clap(long, value_name = "BYTES")]
+    pub max_path_len: Option<usize>,
+
+    /// What to do with a file whose destination is longer than
+    /// --max-path-len: error (fail it) or truncate (shorten its name).
+    #[clap(
+        long,
+        value_name = "POLICY",
+        default_value = "error",
+        requires = "max_path_len"
+    )]
+    pub long_path_policy: LongPathPolicy,
+
+    /// Remove duplicate tracks: files whose tags give the same destination
+    /// as another audio file (ignoring the extension), keeping the copy
+    /// chosen by --prefer.
+    #[clap(
+        long,
+        value_name = "MODE",
+        value_parser = ["tag"]
+    )]
+    pub dedup_by: Option<String>,
+
+    /// Which copy --dedup-by keeps: lossless (a lossless one, then the
+    /// highest bitrate) or highest-bitrate. The existing file wins ties.
+    #[clap(
+        long,
+        value_name = "POLICY",
+        default_value = "lossless",
+        requires = "dedup_by"
+    )]
+    pub prefer: DedupPreference,
+
+    /// Move the copies removed by --dedup-by below this folder (keeping
+    /// their path) instead of deleting them.
+    #[clap(long, value_name = "DIR", requires = "dedup_by")]
+    pub quarantine: Option<PathBuf>,
+
+    /// Write the report of this run as JSON to this file, it can be undone
+    /// with the restore subcommand.
+    #[clap(long)]
+    pub summary_file: Option<PathBuf>,
+
+    /// Write one row per processed file (source, destination, status, error)
+    /// as CSV to this file.
+    #[clap(long)]
+    pub report_csv: Option<PathBuf>,
+
+    /// Confirm each move (y/n/a/q for yes/no/all/quit).
+    #[clap(short, long)]
+    pub interactive: bool,
+
+    /// Copy every file to a staging folder first and only move them into
+    /// place once all of them were copied, nothing is moved otherwise.
+    #[clap(long, conflicts_with_all = ["interactive", "from_playlist", "threads", "atomic_album"])]
+    pub atomic: bool,
+
+    /// Compare the SHA-256 sum of each file before and after moving it, a
+    /// file whose contents changed is put back and failed.
+    #[clap(long)]
+    pub verify_after: bool,
+
+    /// Sort with this many threads while the folder is walked.
+    #[clap(long, conflicts_with_all = ["interactive", "from_playlist"])]
+    pub threads: Option<usize>,
+
+    /// Sort the files listed in stdin (separated by newlines or NUL), each
+    /// one relative to the library folder containing it.
+    #[clap(long, conflicts_with_all = ["interactive", "from_playlist", "threads", "atomic"])]
+    pub from_stdin: bool,
+
+    /// With --from-stdin, root of the files outside every library folder.
+    #[clap(long, requires = "from_stdin")]
+    pub root: Option<PathBuf>,
+
+    /// Don't ask for confirmation before moving the files.
+    #[clap(short = 'y', long)]
+    pub assume_yes: bool,
+
+    /// With --dryrun, draw the folder tree the files would be sorted into.
+    #[clap(long, requires = "dryrun", conflicts_with = "from_stdin")]
+    pub preview_tree: bool,
+
+    /// Don't move anything, draw the folders the files would be sorted into
+    /// as a tree, with the amount of files below each one. Implies --dryrun.
+    #[clap(long, conflicts_with_all = ["from_stdin", "watch_after"])]
+    pub dry_run_tree: bool,
+
+    /// Once sorted, keep watching the folder and sort the new files (along
+    /// with the rest of its library, if it's the folder of one).
+    #[clap(long, conflicts_with_all = ["dryrun", "from_stdin", "from_playlist"])]
+    pub watch_after: bool,
+
+    /// After sorting, show how many files were sorted and failed for each
+    /// value of this placeholder (e.g. artist or album).
+    #[clap(long, value_parser = parse_placeholder, conflicts_with = "from_stdin")]
+    pub group_by: Option<String>,
+
+    /// Show a progress bar with the file being sorted and the remaining time.
+    #[cfg(feature = "progress")]
+    #[clap(long, conflicts_with = "interactive")]
+    pub progress: bool,
+
+    /// Print the time spent walking folders, reading tags and moving files.
+    #[cfg(feature = "profile")]
+    #[clap(long)]
+    pub profile: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct CheckArgs {
+    /// Path to music directory.
+    pub path: Option<PathBuf>,
+
+    /// Custom format string.
+    #[clap(short, long)]
+    pub format: Option<String>,
+
+    /// Mantain file names compatible with FAT32.
+    #[clap(short, long)]
+    pub exfat_compat: bool,
+
+    /// Drop folders left empty by missing tags (e.g. `Artist/Title` instead
+    /// of failing without an album).
+    #[clap(long)]
+    pub strip_empty_components: bool,
+
+    /// Move the misplaced files to where they should be.
+    #[clap(long)]
+    pub fix: bool,
+
+    /// With --fix, only show the moves (simulated run).
+    #[clap(short, long)]
+    pub dryrun: bool,
+
+    /// Like sort --keep-structure-depth, files are expected below this many
+    /// folders of their current path.
+    #[clap(long, default_value_t = 0)]
+    pub keep_structure_depth: usize,
+}
+
+fn parse_octal(s: &str) -> Result<u32, String> {
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    u32::from_str_radix(digits, 8).map_err(|_| format!("\"{}\" is not a valid octal mode", s))
+}
+
+fn parse_placeholder(s: &str) -> Result<String, String> {
+    let metadata = Metadata::default();
+
+    match placeholder_value(s, &metadata, &BuildOptions::default()) {
+        Err(_) => Err(format!("\"{}\" is not a placeholder", s)),
+        Ok(_) => Ok(s.to_owned()),
+    }
+}
+
+/// Seconds, optionally followed by an `s`, `m` or `h` unit
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (amount, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return Err(format!("\"{}\" is not a valid duration", s)),
+    };
+
+    amount
+        .parse::<u64>()
+        .map(|amount| Duration::from_secs(amount * multiplier))
+        .map_err(|_| format!("\"{}\" is not a valid duration", s))
+}
@@ -0,0 +1,61 @@
+//! Detaching the watcher from the terminal
+
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process;
+
+/// Turns the process into a daemon: forks twice (so it's adopted by init
+/// and can't get a controlling terminal again, with a new session in
+/// between), moves to `/`, writes its PID to `pid_file` and points stdin,
+/// stdout and stderr to `/dev/null`. Only the daemon returns
+pub fn detach(pid_file: &Path) -> io::Result<()> {
+    fork_and_exit_parent()?;
+    // SAFETY: the child isn't a process group leader, so this can't fail
+    // with EPERM and only changes the session of this process
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    fork_and_exit_parent()?;
+
+    std::env::set_current_dir("/")?;
+    // SAFETY: umask can't fail, it only sets the mask of this process
+    unsafe { libc::umask(0o022) };
+
+    if let Some(parent) = pid_file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(pid_file, format!("{}\n", process::id()))?;
+
+    redirect_std_streams()
+}
+
+/// Forks, returning in the child while the parent exits right away
+fn fork_and_exit_parent() -> io::Result<()> {
+    // SAFETY: nothing was spawned yet, the process only has one thread
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        // SAFETY: exits without running destructors or atexit handlers
+        // that belong to the child now
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+fn redirect_std_streams() -> io::Result<()> {
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        // SAFETY: both are valid file descriptors, `fd` is closed and
+        // replaced by a copy of `null`
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
@@ -1,7 +1,41 @@
+use musso::ExitCode;
 use thiserror::Error;
 
 #[derive(Debug, PartialEq, Error)]
 pub enum Error {
     #[error("Path {path} is not valid as root folder!")]
     InvalidRoot { path: String },
+
+    #[error("\"{path}\" isn't inside any library folder, use --root to sort it")]
+    OutsideRoots { path: String },
+
+    #[error("There is no library named \"{name}\" in the config file")]
+    UnknownLibrary { name: String },
+
+    #[error("{count} files are misplaced")]
+    MisplacedFiles { count: usize },
+
+    #[error("{count} files couldn't be sorted")]
+    FilesFailed { count: usize },
+
+    #[error("Can't ask for confirmation while reading paths from stdin, use --assume-yes")]
+    StdinConfirmation,
+
+    #[cfg(not(unix))]
+    #[error("Running as a daemon is only supported on unix")]
+    DaemonUnsupported,
+}
+
+impl Error {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            Error::InvalidRoot { .. }
+            | Error::OutsideRoots { .. }
+            | Error::UnknownLibrary { .. } => ExitCode::InvalidPath,
+            Error::MisplacedFiles { .. } | Error::FilesFailed { .. } => ExitCode::PartialFailure,
+            Error::StdinConfirmation => ExitCode::Usage,
+            #[cfg(not(unix))]
+            Error::DaemonUnsupported => ExitCode::Failure,
+        }
+    }
 }
@@ -1,12 +1,67 @@
 use ansi_term::Color::{Cyan, Red, Yellow};
 use log::{set_logger, set_max_level, Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
-pub struct MusoLogger;
+pub struct MusoLogger {
+    file: Option<Mutex<LogFile>>,
+}
+
+/// Plain text copy of the log, moved to `<path>.1` (replacing any previous
+/// one) once it reaches `max_bytes`
+pub struct LogFile {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    max_bytes: Option<u64>,
+}
+
+impl LogFile {
+    pub fn open(path: impl AsRef<Path>, max_mb: Option<u64>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = Self::append(&path)?;
+        let len = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            file,
+            len,
+            max_bytes: max_mb.map(|mb| mb * 1024 * 1024),
+        })
+    }
 
-static MUSO_LOGGER: MusoLogger = MusoLogger {};
+    fn append(path: &Path) -> io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let incoming = line.len() as u64 + 1;
+
+        if let Some(max_bytes) = self.max_bytes {
+            if self.len > 0 && self.len + incoming > max_bytes {
+                let mut rotated = self.path.clone().into_os_string();
+                rotated.push(".1");
+                fs::rename(&self.path, rotated)?;
+
+                self.file = Self::append(&self.path)?;
+                self.len = 0;
+            }
+        }
 
-pub fn init_logger() -> Result<(), SetLoggerError> {
-    set_logger(&MUSO_LOGGER).map(|_| set_max_level(LevelFilter::Info))
+        writeln!(self.file, "{}", line)?;
+        self.len += incoming;
+        Ok(())
+    }
+}
+
+pub fn init_logger(file: Option<LogFile>) -> Result<(), SetLoggerError> {
+    let logger = Box::leak(Box::new(MusoLogger {
+        file: file.map(Mutex::new),
+    }));
+
+    set_logger(logger).map(|_| set_max_level(LevelFilter::Info))
 }
 
 impl Log for MusoLogger {
@@ -15,13 +70,44 @@ impl Log for MusoLogger {
     }
 
     fn log(&self, record: &Record) {
+        let label = match record.level() {
+            Level::Info => "[info]",
+            Level::Warn => "[warn]",
+            Level::Error => "[err!]",
+            _ => return,
+        };
+
         match record.level() {
-            Level::Info => println!("{} {}", Cyan.bold().paint("[info]"), record.args()),
-            Level::Warn => eprintln!("{} {}", Yellow.bold().paint("[warn]"), record.args()),
-            Level::Error => eprintln!("{} {}", Red.bold().paint("[err!]"), record.args()),
-            _ => {}
+            Level::Info => println!("{} {}", Cyan.bold().paint(label), record.args()),
+            Level::Warn => eprintln!("{} {}", Yellow.bold().paint(label), record.args()),
+            _ => eprintln!("{} {}", Red.bold().paint(label), record.args()),
+        }
+
+        if let Some(file) = &self.file {
+            let line = format!(
+                "{} {} {}",
+                chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                label,
+                record.args()
+            );
+
+            if let Ok(mut file) = file.lock() {
+                if let Err(e) = file.write_line(&line) {
+                    eprintln!(
+                        "{} Couldn't write to log file ({})",
+                        Red.bold().paint("[err!]"),
+                        e
+                    );
+                }
+            }
         }
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                let _ = file.file.flush();
+            }
+        }
+    }
 }
@@ -1,103 +1,376 @@
+mod check;
 mod cli;
+#[cfg(unix)]
+mod daemon;
 mod error;
 mod logger;
+#[cfg(feature = "progress")]
+mod progress;
+mod sort;
+mod tags;
 
 use clap::Parser;
 use std::env;
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process;
-use std::str::FromStr;
 
 use human_panic::setup_panic;
-use musso::config::Config;
-use musso::format::ParsedFormat;
-use musso::sorting::{sort_folder, Options};
+use musso::config::{is_stream_source, Config};
+use musso::sorting::{remove_empty_dirs, restore, SortReport};
+use musso::tag_rename::rename_frames;
 use musso::utils;
-use musso::watcher::Watcher;
+#[cfg(unix)]
+use musso::watcher::send_test_event;
+use musso::watcher::{EventLog, StartupScan, Watcher};
+use musso::ExitCode;
 
-use crate::cli::{CliArgs, SubCommand};
+use crate::cli::{CliArgs, SubCommand, WatchArgs};
 use crate::error::Error;
-use crate::logger::init_logger;
+use crate::logger::{init_logger, LogFile};
 
 pub type AnyResult<T> = Result<T, anyhow::Error>;
 
-fn load_config(path: impl AsRef<Path>) -> AnyResult<Config> {
-    let path = path.as_ref();
-    let default_path = utils::default_config_path();
+/// Default config file, inside the state dir if there's one
+fn default_config_path(state_dir: Option<&Path>) -> PathBuf {
+    match state_dir {
+        Some(dir) => dir.join("config.toml"),
+        None => utils::default_config_path(),
+    }
+}
 
-    if path == default_path && !path.exists() {
-        cfg_if::cfg_if! {
-            if #[cfg(feature = "standalone")] {
-                utils::generate_resource(utils::Resource::Config, Some(include_str!("../share/config.toml")))?;
-            } else {
-                utils::generate_resource(utils::Resource::Config, None)?;
-            }
-        }
+/// Socket of the running watcher, inside the state dir if there's one
+#[cfg(unix)]
+fn default_control_socket(state_dir: Option<&Path>) -> PathBuf {
+    match state_dir {
+        Some(dir) => dir.join("watch.sock"),
+        None => dirs::runtime_dir()
+            .map(|dir| dir.join("musso.sock"))
+            .unwrap_or_else(|| utils::default_config_path().with_file_name("watch.sock")),
+    }
+}
+
+/// PID file of the daemon, inside the state dir if there's one
+#[cfg(unix)]
+fn default_pid_file(state_dir: Option<&Path>) -> PathBuf {
+    match state_dir {
+        Some(dir) => dir.join("musso.pid"),
+        None => dirs::runtime_dir()
+            .map(|dir| dir.join("musso.pid"))
+            .unwrap_or_else(|| utils::default_config_path().with_file_name("musso.pid")),
+    }
+}
+
+/// Time of the last watcher run, inside the state dir if there's one
+fn default_last_run_file(state_dir: Option<&Path>) -> PathBuf {
+    match state_dir {
+        Some(dir) => dir.join("last-run"),
+        None => dirs::state_dir()
+            .or_else(dirs::data_local_dir)
+            .map(|dir| dir.join("musso/last-run"))
+            .unwrap_or_else(|| utils::default_config_path().with_file_name("last-run")),
+    }
+}
+
+/// Relative state file paths are taken from the state dir if there's one
+fn state_path(state_dir: Option<&Path>, path: PathBuf) -> PathBuf {
+    match state_dir {
+        Some(dir) if path.is_relative() => dir.join(path),
+        _ => path,
+    }
+}
+
+fn load_config(paths: &[PathBuf], state_dir: Option<&Path>) -> AnyResult<Config> {
+    // Only the default config is created, a missing custom one is an error
+    let config = match paths {
+        [path] if *path == default_config_path(state_dir) => Config::load_or_init(path)?,
+        paths => Config::load_with_env_overrides(paths)?,
+    };
+
+    Ok(config)
+}
+
+/// Watches the libraries of `config` in the foreground
+fn watch(config: Config, state_dir: Option<&Path>, args: WatchArgs) -> AnyResult<()> {
+    let WatchArgs {
+        watch_event_log,
+        event_log_max_mb,
+        #[cfg(unix)]
+        control_socket,
+        #[cfg(unix)]
+        test_event,
+        idle_timeout,
+        scan_on_start,
+        since_last_run,
+        first_run_all,
+        no_remove_empty,
+        watch_roots,
+        format,
+    } = args;
+
+    #[cfg(unix)]
+    let control_socket = match control_socket {
+        Some(path) => state_path(state_dir, path),
+        None => default_control_socket(state_dir),
+    };
+
+    #[cfg(unix)]
+    if let Some(path) = test_event {
+        send_test_event(&control_socket, &path)?;
+        log::info!("Test event sent: \"{}\"", path.display());
+        return Ok(());
+    }
+
+    let mut config = config;
+    if !watch_roots.is_empty() {
+        let roots = watch_roots
+            .iter()
+            .map(fs::canonicalize)
+            .collect::<Result<_, _>>()?;
+        config = config.with_roots(roots, format.unwrap_or_default())?;
+    }
+    if no_remove_empty {
+        config.watch.remove_empty = Some(false);
     }
 
-    Ok(Config::from_path(path)?)
+    let mut watcher = Watcher::new(config);
+
+    #[cfg(unix)]
+    {
+        watcher = watcher.with_control_socket(control_socket);
+    }
+
+    if let Some(path) = watch_event_log {
+        let path = state_path(state_dir, path);
+        watcher = watcher.with_event_log(EventLog::new(path, event_log_max_mb));
+    }
+
+    if let Some(timeout) = idle_timeout {
+        watcher = watcher.with_idle_timeout(timeout);
+    }
+
+    if since_last_run {
+        watcher = watcher.with_startup_scan(StartupScan::SinceLastRun {
+            state_file: default_last_run_file(state_dir),
+            first_run_all,
+        });
+    } else if scan_on_start {
+        watcher = watcher.with_startup_scan(StartupScan::Everything);
+    }
+
+    watcher.watch()?;
+    Ok(())
+}
+
+/// Watches the libraries of `config` in a daemon, see [`daemon::detach`].
+/// Paths relative to the current folder are made absolute first, as the
+/// daemon runs in `/`
+#[cfg(unix)]
+fn daemonize(
+    config: Config,
+    state_dir: Option<&Path>,
+    pid_file: Option<PathBuf>,
+    args: WatchArgs,
+) -> AnyResult<()> {
+    // Sending a test event doesn't need a daemon
+    if args.test_event.is_some() {
+        return watch(config, state_dir, args);
+    }
+
+    let state_dir = state_dir.map(std::path::absolute).transpose()?;
+    let state_dir = state_dir.as_deref();
+    let absolute = |path: PathBuf| std::path::absolute(state_path(state_dir, path));
+
+    let pid_file = match pid_file {
+        Some(path) => absolute(path)?,
+        None => std::path::absolute(default_pid_file(state_dir))?,
+    };
+    let args = WatchArgs {
+        watch_event_log: args.watch_event_log.map(absolute).transpose()?,
+        control_socket: args.control_socket.map(absolute).transpose()?,
+        watch_roots: args
+            .watch_roots
+            .iter()
+            .map(fs::canonicalize)
+            .collect::<Result<_, _>>()?,
+        ..args
+    };
+
+    daemon::detach(&pid_file)?;
+    log::info!("Running as a daemon, PID {}", process::id());
+
+    let result = watch(config, state_dir, args);
+    if let Err(e) = fs::remove_file(&pid_file) {
+        log::error!("Couldn't remove \"{}\" ({})", pid_file.display(), e);
+    }
+
+    result
+}
+
+#[cfg(not(unix))]
+fn daemonize(
+    _config: Config,
+    _state_dir: Option<&Path>,
+    _pid_file: Option<PathBuf>,
+    _args: WatchArgs,
+) -> AnyResult<()> {
+    Err(Error::DaemonUnsupported.into())
 }
 
 fn run(opts: CliArgs) -> AnyResult<()> {
-    let config = opts.config.unwrap_or_else(utils::default_config_path);
-    let config = load_config(config)?;
+    let state_dir = opts.state_dir.as_deref();
+    let config_paths = match opts.config {
+        paths if paths.is_empty() => vec![default_config_path(state_dir)],
+        paths => paths,
+    };
+    let config = load_config(&config_paths, state_dir)?;
 
     match opts.cmd {
-        SubCommand::CopyService => {
+        SubCommand::CopyService { system } => {
+            let service = utils::Resource::Service {
+                exec: env::current_exe()?,
+                configs: config_paths
+                    .iter()
+                    .map(|path| {
+                        if is_stream_source(path) {
+                            Ok(path.clone())
+                        } else {
+                            std::path::absolute(path)
+                        }
+                    })
+                    .collect::<Result<_, _>>()?,
+                system,
+            };
+
             cfg_if::cfg_if! {
                 if #[cfg(feature = "standalone")] {
-                    utils::generate_resource(utils::Resource::Service, Some(include_str!("../share/musso.service")))?;
+                    utils::generate_resource(service, Some(include_str!("../share/musso.service")))?;
                 } else {
-                    utils::generate_resource(utils::Resource::Service, None)?;
+                    utils::generate_resource(service, None)?;
                 }
             }
         }
 
-        SubCommand::Watch => Watcher::new(config).watch()?,
+        SubCommand::Watch(args) => watch(config, state_dir, args)?,
+
+        SubCommand::Daemon { pid_file, watch } => daemonize(config, state_dir, pid_file, watch)?,
 
-        SubCommand::Sort {
+        SubCommand::Sort(args) => sort::run(&config, *args)?,
+
+        SubCommand::Check(args) => check::run(&config, args)?,
+
+        SubCommand::ValidateLibrary { name, fix } => check::validate_library(&config, &name, fix)?,
+
+        SubCommand::Clean {
             path,
-            format,
             dryrun,
-            recursive,
-            remove_empty,
-            exfat_compat,
+            mut prune_junk,
         } => {
             let path = path.unwrap_or(env::current_dir()?);
-            let format = format
-                .map_or(config.search_format(&path).cloned(), |s| {
-                    ParsedFormat::from_str(&s).ok()
-                })
-                .unwrap_or_default();
-
-            let options = Options {
-                format,
-                dryrun,
-                recursive,
-                exfat_compat,
-                remove_empty,
-            };
-
-            if path.is_dir() {
-                match sort_folder(&path, &path, &options) {
-                    Ok(report) => log::info!(
-                        "Done: {} successful out of {} ({} failed)",
-                        report.success,
-                        report.total,
-                        report.total - report.success
-                    ),
-
-                    Err(e) => return Err(e.into()),
-                }
-            } else {
+            if !path.is_dir() {
                 let err = Error::InvalidRoot {
                     path: path.display().to_string(),
                 };
 
                 return Err(err.into());
             }
+
+            // Library roots must survive even if they are empty
+            let keep: Vec<_> = config
+                .libraries
+                .values()
+                .flat_map(|library| library.folders.iter().cloned())
+                .collect();
+
+            if prune_junk.is_empty() {
+                if let Some(library) = config.search_library(&path) {
+                    prune_junk = config.prune_junk_of(library);
+                }
+            }
+
+            let removed = remove_empty_dirs(&path, &keep, &prune_junk, dryrun)?;
+            log::info!("Done: {} empty folders removed", removed.len());
+        }
+
+        SubCommand::Restore {
+            summary_file,
+            dryrun,
+        } => {
+            let file = std::fs::File::open(summary_file)?;
+            let report: SortReport = serde_json::from_reader(file)?;
+
+            let restored = restore(&report, dryrun);
+            log::info!(
+                "Done: {} restored out of {} ({} skipped)",
+                restored.success,
+                restored.total,
+                restored.total - restored.success
+            );
         }
 
+        SubCommand::Repair {
+            summary_file,
+            filename_format,
+            dryrun,
+        } => sort::repair(&config, &summary_file, &filename_format, dryrun)?,
+
+        #[cfg(feature = "fetch-art")]
+        SubCommand::FetchArt {
+            path,
+            recursive,
+            dryrun,
+        } => {
+            let report = musso::art::fetch_art(path, recursive, dryrun)?;
+            log::info!(
+                "Done: {} covers embedded out of {} ({} skipped, {} failed)",
+                report.embedded,
+                report.total,
+                report.skipped,
+                report.total - report.embedded - report.skipped
+            );
+        }
+
+        #[cfg(feature = "catalogue")]
+        SubCommand::Catalogue {
+            library,
+            db,
+            incremental,
+        } => {
+            let folders = &config
+                .libraries
+                .get(&library)
+                .ok_or(Error::UnknownLibrary { name: library })?
+                .folders;
+
+            let report = musso::catalogue::export(folders, &db, incremental)?;
+            log::info!(
+                "Done: {} files catalogued in \"{}\" ({} unchanged, {} removed)",
+                report.total,
+                db.display(),
+                report.unchanged,
+                report.removed
+            );
+        }
+
+        SubCommand::TagRename {
+            path,
+            recursive,
+            mapping,
+            remove_source,
+            dryrun,
+        } => {
+            let report = rename_frames(path, &mapping, remove_source, recursive, dryrun)?;
+            log::info!(
+                "Done: {} files changed out of {} ({} skipped, {} failed)",
+                report.changed,
+                report.total,
+                report.skipped,
+                report.total - report.changed - report.skipped
+            );
+        }
+
+        SubCommand::Tags { path } => tags::run(&path)?,
+
         #[cfg(feature = "sync")]
         SubCommand::Sync => {}
     }
@@ -106,16 +379,51 @@ fn run(opts: CliArgs) -> AnyResult<()> {
 }
 
 fn main() {
-    setup_panic!();
-    init_logger().unwrap();
+    // human-panic 1.x still names the panic hook argument PanicInfo
+    #[allow(deprecated)]
+    {
+        setup_panic!();
+    }
 
     let opts = CliArgs::parse();
+
+    let log_file = match &opts.log_file {
+        Some(path) => {
+            let path = state_path(opts.state_dir.as_deref(), path.to_owned());
+            // Kept absolute so rotating it still works from a daemon
+            let path = std::path::absolute(&path).unwrap_or(path);
+            match LogFile::open(&path, opts.log_file_max_mb) {
+                Ok(file) => Some(file),
+                Err(e) => {
+                    eprintln!("Couldn't open log file \"{}\" ({})", path.display(), e);
+                    process::exit(ExitCode::Io as i32);
+                }
+            }
+        }
+
+        None => None,
+    };
+    init_logger(log_file).unwrap();
+
     process::exit(match run(opts) {
         Err(e) => {
             log::error!("{}", e);
-            1
+            exit_code(&e) as i32
         }
 
         Ok(_) => 0,
     })
 }
+
+/// Exit code of the class of `e`, see [`ExitCode`]
+fn exit_code(e: &anyhow::Error) -> ExitCode {
+    if let Some(e) = e.downcast_ref::<musso::Error>() {
+        e.exit_code()
+    } else if let Some(e) = e.downcast_ref::<Error>() {
+        e.exit_code()
+    } else if e.downcast_ref::<std::io::Error>().is_some() {
+        ExitCode::Io
+    } else {
+        ExitCode::Failure
+    }
+}
@@ -0,0 +1,54 @@
+use indicatif::{ProgressBar, ProgressStyle};
+use log::LevelFilter;
+use musso::sorting::{OutcomeHook, SortReport, Status};
+
+/// Progress bar of a sort, info messages are hidden while it's shown so they
+/// don't break it
+pub struct Progress {
+    bar: ProgressBar,
+    /// Log level before the bar was shown
+    level: LevelFilter,
+}
+
+impl Progress {
+    pub fn start(total: usize) -> Self {
+        let bar = ProgressBar::new(total as u64);
+        bar.set_style(
+            ProgressStyle::with_template("{bar:30} [{pos}/{len}] ETA {eta} {wide_msg}")
+                .expect("progress template is valid"),
+        );
+
+        let level = log::max_level();
+        log::set_max_level(level.min(LevelFilter::Warn));
+        Self { bar, level }
+    }
+
+    /// Advances the bar with every processed file
+    pub fn hook(&self) -> OutcomeHook {
+        let bar = self.bar.clone();
+
+        OutcomeHook::new(move |outcome| {
+            let message = match (&outcome.destination, outcome.status) {
+                (Some(destination), _) => {
+                    format!("{} → {}", outcome.source.display(), destination.display())
+                }
+                (None, status) => format!("{} ({})", outcome.source.display(), status.as_str()),
+            };
+
+            bar.set_message(message);
+            bar.inc(1);
+        })
+    }
+
+    pub fn finish(self, report: &SortReport) {
+        let failed = report
+            .outcomes
+            .iter()
+            .filter(|outcome| outcome.status == Status::Failed)
+            .count();
+
+        self.bar
+            .finish_with_message(format!("{} sorted, {} failed", report.success, failed));
+        log::set_max_level(self.level);
+    }
+}
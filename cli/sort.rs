@@ -0,0 +1,521 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use musso::config::Config;
+use musso::format::ParsedFormat;
+use musso::library::Exclude;
+use musso::playlist;
+#[cfg(feature = "profile")]
+use musso::profile;
+use musso::prompt::InteractivePrompter;
+use musso::repair;
+use musso::sorting::{
+    plan_files, plan_folder, sort_files, sort_files_interactive, sort_folder, sort_folder_atomic,
+    sort_folder_interactive, sort_folder_parallel, sort_planned, sort_planned_atomic, Dedup,
+    DestExists, FoldDiacritics, Options, ReportGroup, SortReport,
+};
+use musso::tree;
+use musso::watcher::Watcher;
+
+use crate::cli::SortArgs;
+use crate::error::Error;
+#[cfg(feature = "progress")]
+use crate::progress::Progress;
+use crate::AnyResult;
+
+/// Format given in the command line, or the one of the library of `path`, along
+/// with where it comes from
+pub fn resolve_format(
+    config: &Config,
+    path: &Path,
+    format: Option<String>,
+    format_file: Option<PathBuf>,
+) -> AnyResult<(ParsedFormat, &'static str)> {
+    let (format, origin) = match (format, format_file) {
+        (Some(s), _) => (ParsedFormat::from_str(&s).ok(), "--format option"),
+        (None, Some(file)) => {
+            let s = fs::read_to_string(file)?;
+            let s = s.trim_end_matches(['\n', '\r']);
+            (ParsedFormat::from_str(s).ok(), "--format-file option")
+        }
+        (None, None) => (config.search_format(path).cloned(), "config file"),
+    };
+
+    Ok(match format {
+        Some(format) => (format, origin),
+        None => (ParsedFormat::default(), "default format"),
+    })
+}
+
+/// Options given in the command line, what's missing is taken from the
+/// library of `path`
+fn options_for(config: &Config, args: &SortArgs, path: &Path) -> AnyResult<Options<ParsedFormat>> {
+    let (format, origin) =
+        resolve_format(config, path, args.format.clone(), args.format_file.clone())?;
+    let library = config.search_library(path);
+    let defaults = match library {
+        Some(library) => config.options_of(library)?,
+        None => Options::default(),
+    };
+
+    let mut exclude = args.exclude.clone();
+    if let Some(library) = library {
+        exclude.extend(config.ignore_patterns_of(library));
+    }
+
+    if args.explain {
+        log::info!("Using format \"{}\" (from {})", format.as_str(), origin);
+    }
+
+    Ok(Options {
+        format,
+        dryrun: args.dryrun,
+        recursive: args.recursive,
+        exfat_compat: args.exfat_compat || defaults.exfat_compat,
+        remove_empty: args.remove_empty,
+        mark_empty: args.mark_empty.clone(),
+        max_depth: args.max_depth,
+        skip_hidden: !args.hidden,
+        dir_mode: args.dir_mode.or(defaults.dir_mode),
+        explain: args.explain,
+        preserve_timestamps: args.preserve_timestamps,
+        companions: given_or(&args.companions, defaults.companions),
+        title_clean_patterns: given_or(&args.title_clean_patterns, defaults.title_clean_patterns),
+        strip_articles: given_or(&args.strip_articles, defaults.strip_articles),
+        strip_empty_components: args.strip_empty_components || defaults.strip_empty_components,
+        prune_junk: given_or(&args.prune_junk, defaults.prune_junk),
+        exclude: Exclude::new(&exclude),
+        atomic_album: args.atomic_album,
+        keep_structure_depth: args.keep_structure_depth,
+        rename_only: args.rename_only,
+        tag_read_timeout: args
+            .tag_read_timeout
+            .map(Duration::from_secs)
+            .or(defaults.tag_read_timeout),
+        auto_chmod: args.auto_chmod,
+        dest_exists: DestExists::new(args.dest_exists_policy),
+        case_insensitive: args.ignore_case_collision,
+        max_path_len: args.max_path_len,
+        long_path_policy: args.long_path_policy,
+        verify_after: args.verify_after,
+        fold_diacritics: defaults
+            .fold_diacritics
+            .or_else(|| args.fold_diacritics.then(FoldDiacritics::default)),
+        create_dirs: !args.no_create_dirs,
+        dedup: args
+            .dedup_by
+            .is_some()
+            .then(|| Dedup::new(args.prefer, args.quarantine.clone())),
+        ..defaults
+    })
+}
+
+/// The values given in the command line, `library` ones if there are none
+fn given_or<T: Clone>(given: &[T], library: Vec<T>) -> Vec<T> {
+    if given.is_empty() {
+        library
+    } else {
+        given.to_vec()
+    }
+}
+
+pub fn run(config: &Config, mut args: SortArgs) -> AnyResult<()> {
+    args.dryrun |= args.dry_run_tree;
+    if args.from_stdin {
+        return run_from_stdin(config, args);
+    }
+
+    let path = args.path.clone().unwrap_or(env::current_dir()?);
+    let options = options_for(config, &args, &path)?;
+
+    if !path.is_dir() {
+        let err = Error::InvalidRoot {
+            path: path.display().to_string(),
+        };
+
+        return Err(err.into());
+    }
+
+    let playlist = match &args.from_playlist {
+        Some(playlist) => {
+            let (found, missing): (Vec<_>, Vec<_>) = playlist::read_m3u(playlist)?
+                .into_iter()
+                .partition(|entry| entry.is_file());
+
+            for entry in missing {
+                log::warn!("Playlist entry not found: \"{}\"", entry.display());
+            }
+
+            Some(found)
+        }
+
+        None => None,
+    };
+
+    let mut prompter = InteractivePrompter::stdio();
+
+    // Interactive runs already ask for each file. The files are planned up
+    // front to count the moves, so that plan is what gets sorted
+    let plan = if !options.dryrun && !args.interactive && !args.assume_yes {
+        let plan = match &playlist {
+            Some(found) => plan_files(&path, found.clone(), &options),
+            None => plan_folder(&path, &path, &options)?,
+        };
+
+        let question = format!(
+            "About to move {} files under {}, continue?",
+            plan.moves(),
+            path.display()
+        );
+
+        if plan.moves() > 0 && !prompter.ask_yes_no(&question)? {
+            log::info!("Nothing was moved");
+            return Ok(());
+        }
+
+        Some(plan)
+    } else {
+        None
+    };
+
+    #[cfg(feature = "progress")]
+    let progress = args.progress.then(|| {
+        let total = match &playlist {
+            Some(found) => found.len(),
+            None => options.walker().walk(&path).count(),
+        };
+
+        Progress::start(total)
+    });
+
+    #[cfg(feature = "progress")]
+    let options = Options {
+        on_outcome: progress.as_ref().map(Progress::hook),
+        ..options
+    };
+
+    #[cfg(feature = "profile")]
+    if args.profile {
+        profile::enable();
+    }
+
+    let report = match (plan, playlist) {
+        (Some(plan), Some(_)) => sort_planned(plan, &options),
+        (Some(plan), None) if args.atomic => sort_planned_atomic(plan, &options)?,
+        // Only the moves are left, the threads wouldn't make them faster
+        (Some(plan), None) => sort_planned(plan, &options),
+
+        (None, Some(found)) => {
+            if args.interactive {
+                sort_files_interactive(&path, found, &options, &mut prompter)
+            } else {
+                sort_files(&path, found, &options)
+            }
+        }
+
+        (None, None) if args.interactive => {
+            sort_folder_interactive(&path, &path, &options, &mut prompter)?
+        }
+        (None, None) if args.atomic => sort_folder_atomic(&path, &path, &options)?,
+        (None, None) => match args.threads {
+            Some(threads) => sort_folder_parallel(&path, &path, &options, threads)?,
+            None => sort_folder(&path, &path, &options)?,
+        },
+    };
+
+    #[cfg(feature = "progress")]
+    if let Some(progress) = progress {
+        progress.finish(&report);
+    }
+
+    if let Some(dedup) = &options.dedup {
+        log::info!("{} duplicates removed", dedup.removed().len());
+    }
+
+    if args.preview_tree {
+        print!("{}", tree::render(&path, &report.new_paths));
+    }
+
+    if args.dry_run_tree {
+        print!("{}", tree::render_folders(&path, &report.new_paths));
+    }
+
+    if let Some(placeholder) = &args.group_by {
+        print_groups(
+            placeholder,
+            &report.group_by(placeholder, &options.build_options())?,
+        );
+    }
+
+    write_reports(&args, &report)?;
+
+    if args.watch_after {
+        let overridden = args.format.is_some() || args.format_file.is_some();
+        watch_after(config, &path, options.format, overridden)?;
+    }
+
+    files_failed(&report)
+}
+
+/// Keeps `path` sorted after the sort is done. A folder of a library (whose
+/// format wasn't overridden) is watched along with the rest of its library,
+/// any other folder on its own with `format`
+fn watch_after(
+    config: &Config,
+    path: &Path,
+    format: ParsedFormat,
+    overridden: bool,
+) -> AnyResult<()> {
+    let path = fs::canonicalize(path)?;
+
+    let config = match config.search_library(&path) {
+        Some(library) if !overridden && config.root_for(&path) == Some(path.as_path()) => {
+            let mut config = config.clone();
+            config.watch.libraries = vec![library.to_owned()];
+            config
+        }
+        _ => config.clone().with_roots(vec![path], format)?,
+    };
+
+    log::info!("Sorting done, watching for new files");
+    Watcher::new(config).watch()?;
+
+    Ok(())
+}
+
+/// Sorts the files listed in stdin, each one relative to the library folder
+/// containing it or to --root
+fn run_from_stdin(config: &Config, args: SortArgs) -> AnyResult<()> {
+    // Answers would be read from the same stdin as the paths
+    if !args.dryrun && !args.assume_yes {
+        return Err(Error::StdinConfirmation.into());
+    }
+
+    #[cfg(feature = "profile")]
+    if args.profile {
+        profile::enable();
+    }
+
+    let mut input = Vec::new();
+    io::stdin().lock().read_to_end(&mut input)?;
+
+    let fallback = args.root.as_deref().map(std::path::absolute).transpose()?;
+
+    let mut roots: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for file in split_paths(&input) {
+        let file = std::path::absolute(&file)?;
+        let root = match (config.root_for(&file), &fallback) {
+            (Some(root), _) => root.to_path_buf(),
+            (None, Some(root)) if file.starts_with(root) => root.clone(),
+            _ => {
+                return Err(Error::OutsideRoots {
+                    path: file.display().to_string(),
+                }
+                .into())
+            }
+        };
+
+        roots.entry(root).or_default().push(file);
+    }
+
+    #[cfg(feature = "progress")]
+    let progress = args
+        .progress
+        .then(|| Progress::start(roots.values().map(Vec::len).sum()));
+
+    let mut report = SortReport::default();
+    for (root, files) in roots {
+        let options = options_for(config, &args, &root)?;
+
+        #[cfg(feature = "progress")]
+        let options = Options {
+            on_outcome: progress.as_ref().map(Progress::hook),
+            ..options
+        };
+
+        report = report.merge(sort_files(&root, files, &options));
+    }
+
+    #[cfg(feature = "progress")]
+    if let Some(progress) = progress {
+        progress.finish(&report);
+    }
+
+    write_reports(&args, &report)?;
+    files_failed(&report)
+}
+
+/// Sorts again the files that failed in the run of `summary_file`, filling
+/// their missing tags from their names. Each one is sorted with the options of
+/// its library
+pub fn repair(
+    config: &Config,
+    summary_file: &Path,
+    filename_format: &str,
+    dryrun: bool,
+) -> AnyResult<()> {
+    let report: SortReport = serde_json::from_reader(fs::File::open(summary_file)?)?;
+    let filename_format = ParsedFormat::from_str(filename_format)?;
+    let failed = repair::failed_files(&report);
+
+    let mut repaired = 0;
+    for file in &failed {
+        let Some(root) = config.root_for(file) else {
+            log::error!("\"{}\" isn't inside any library folder", file.display());
+            continue;
+        };
+
+        let result = library_options(config, root, dryrun).and_then(|options| {
+            repair::repair_file(root, file, &filename_format, &options).map_err(Into::into)
+        });
+        match result {
+            Ok(path) => {
+                repaired += 1;
+                log::info!("Repaired \"{}\" → \"{}\"", file.display(), path.display());
+            }
+            Err(e) => log::error!("Still can't sort \"{}\" ({})", file.display(), e),
+        }
+    }
+
+    log::info!(
+        "Done: {} repaired out of {} ({} still failed)",
+        repaired,
+        failed.len(),
+        failed.len() - repaired
+    );
+    match failed.len() - repaired {
+        0 => Ok(()),
+        count => Err(Error::FilesFailed { count }.into()),
+    }
+}
+
+/// Options of the library of `root`, the defaults outside of any library
+pub fn library_options(
+    config: &Config,
+    root: &Path,
+    dryrun: bool,
+) -> AnyResult<Options<ParsedFormat>> {
+    let options = match config.search_library(root) {
+        Some(library) => config.options_of(library)?,
+        None => Options::default(),
+    };
+
+    Ok(Options { dryrun, ..options })
+}
+
+/// Fails with [`Error::FilesFailed`] if some files of `report` couldn't be
+/// sorted, so the exit code tells them apart from a run that couldn't start
+fn files_failed(report: &SortReport) -> AnyResult<()> {
+    match report.total - report.success {
+        0 => Ok(()),
+        count => Err(Error::FilesFailed { count }.into()),
+    }
+}
+
+/// Paths separated by NUL if there is any in `input` (e.g. `find -print0`),
+/// otherwise by newlines
+fn split_paths(input: &[u8]) -> Vec<PathBuf> {
+    let separator = if input.contains(&0) { 0 } else { b'\n' };
+
+    input
+        .split(|byte| *byte == separator)
+        .map(|path| match separator {
+            b'\n' => path.strip_suffix(b"\r").unwrap_or(path),
+            _ => path,
+        })
+        .filter(|path| !path.is_empty())
+        .map(path_from_bytes)
+        .collect()
+}
+
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            use std::os::unix::ffi::OsStrExt;
+            PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+        } else {
+            PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+        }
+    }
+}
+
+fn print_groups(placeholder: &str, groups: &[ReportGroup]) {
+    let missing = format!("(no {})", placeholder);
+    let labels: Vec<&str> = groups
+        .iter()
+        .map(|group| group.label.as_deref().unwrap_or(&missing))
+        .collect();
+    let width = labels.iter().map(|label| label.chars().count()).max();
+    let width = width.unwrap_or(0).max(placeholder.len());
+
+    println!("{:<width$} {:>7} {:>7}", placeholder, "sorted", "failed");
+    for (label, group) in labels.iter().zip(groups) {
+        println!("{:<width$} {:>7} {:>7}", label, group.sorted, group.failed);
+    }
+}
+
+fn write_reports(args: &SortArgs, report: &SortReport) -> AnyResult<()> {
+    if args.dryrun {
+        warn_album_conflicts(report);
+    }
+
+    log_report(report);
+
+    if let Some(summary_file) = &args.summary_file {
+        let file = fs::File::create(summary_file)?;
+        serde_json::to_writer_pretty(file, report)?;
+        log::info!("Summary written to: \"{}\"", summary_file.display());
+    }
+
+    if let Some(report_csv) = &args.report_csv {
+        let file = fs::File::create(report_csv)?;
+        report.write_csv(io::BufWriter::new(file))?;
+        log::info!("CSV report written to: \"{}\"", report_csv.display());
+    }
+
+    #[cfg(feature = "profile")]
+    if args.profile {
+        println!("{}", profile::finish());
+    }
+
+    Ok(())
+}
+
+/// Warns about the albums that would be split because their files have
+/// different album artists
+fn warn_album_conflicts(report: &SortReport) {
+    for conflict in report.album_artist_conflicts() {
+        let artists: Vec<String> = conflict
+            .artists
+            .iter()
+            .map(|(artist, folders)| {
+                let folders: Vec<String> = folders
+                    .iter()
+                    .map(|folder| format!("\"{}\"", folder.display()))
+                    .collect();
+                format!("\"{}\" → {}", artist, folders.join(", "))
+            })
+            .collect();
+
+        log::warn!(
+            "Album \"{}\" of \"{}\" would be split, its files have different album artists: {}",
+            conflict.album,
+            conflict.folder.display(),
+            artists.join("; ")
+        );
+    }
+}
+
+pub fn log_report(report: &SortReport) {
+    log::info!(
+        "Done: {} successful out of {} ({} failed)",
+        report.success,
+        report.total,
+        report.total - report.success
+    );
+}
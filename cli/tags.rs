@@ -0,0 +1,66 @@
+use std::path::Path;
+
+use musso::metadata::{dump_tags, Tags};
+
+use crate::AnyResult;
+
+pub fn run(path: &Path) -> AnyResult<()> {
+    let dump = dump_tags(path)?;
+
+    print_table(
+        "File",
+        &[
+            ("path".to_owned(), path.display().to_string()),
+            ("container".to_owned(), dump.container.to_owned()),
+        ],
+    );
+    print_table("Tags as sorted", &normalized(&dump.tags));
+    print_table("Tags as stored", &dump.raw);
+    print_table("Audio", &dump.properties);
+
+    Ok(())
+}
+
+/// Every field of `tags`, `-` for the missing ones
+fn normalized(tags: &Tags) -> Vec<(String, String)> {
+    let text = |value: &Option<String>| value.clone();
+    let number = |value: Option<u32>| value.map(|value| value.to_string());
+
+    [
+        ("artist", text(&tags.artist)),
+        ("album", text(&tags.album)),
+        ("disc", number(tags.disc)),
+        ("track", number(tags.track)),
+        ("track_total", number(tags.track_total)),
+        ("title", text(&tags.title)),
+        ("year", tags.year.map(|year| year.to_string())),
+        ("genre", text(&tags.genre)),
+        ("label", text(&tags.label)),
+        ("catalog", text(&tags.catalog)),
+        ("mbid", text(&tags.mbid)),
+        (
+            "duration",
+            tags.duration
+                .map(|duration| format!("{:.1}s", duration.as_secs_f64())),
+        ),
+        ("ext", Some(tags.ext.clone())),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_owned(), value.unwrap_or_else(|| "-".to_owned())))
+    .collect()
+}
+
+/// Prints `rows` in two columns under `title`
+fn print_table(title: &str, rows: &[(String, String)]) {
+    println!("{}", title);
+
+    let width = rows.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+    for (key, value) in rows {
+        println!("  {:width$}  {}", key, value, width = width);
+    }
+
+    if rows.is_empty() {
+        println!("  (none)");
+    }
+    println!();
+}
@@ -0,0 +1,463 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use id3::TagLike;
+use lewton::header::HeaderReadError;
+use lewton::VorbisError;
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+
+use crate::metadata::sniff_container;
+use crate::utils;
+use crate::{Error, Result};
+
+const COVER_ART_ARCHIVE: &str = "https://coverartarchive.org/release";
+
+/// The Cover Art Archive asks clients to do at most one request per second
+const MIN_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Covers bigger than this are cut, which makes them fail to be recognized
+const MAX_COVER_BYTES: u64 = 32 * 1024 * 1024;
+
+/// Outcome of a [`fetch_art`] run
+#[derive(Debug, Clone, Default)]
+pub struct ArtReport {
+    /// Files that got a cover (or would have, in a dry run)
+    pub embedded: usize,
+    /// Files already having a cover, without a release id or whose release
+    /// has no front cover
+    pub skipped: usize,
+    pub total: usize,
+}
+
+/// Front cover image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cover {
+    pub mime_type: String,
+    pub data: Vec<u8>,
+}
+
+impl Cover {
+    fn from_data(data: Vec<u8>) -> Result<Self> {
+        let mime_type = infer::get(&data)
+            .map(|kind| kind.mime_type())
+            .filter(|mime| mime.starts_with("image/"))
+            .ok_or(Error::NotSupported)?;
+
+        Ok(Self {
+            mime_type: mime_type.to_owned(),
+            data,
+        })
+    }
+}
+
+/// Embeds the front cover of their release into the audio files under `dir`
+/// that have none, the release comes from their MusicBrainz album id tag
+pub fn fetch_art(dir: impl AsRef<Path>, recursive: bool, dryrun: bool) -> Result<ArtReport> {
+    let mut archive = CoverArtArchive::new();
+    let mut report = ArtReport::default();
+    let mut queue = VecDeque::from([dir.as_ref().to_path_buf()]);
+
+    while let Some(dir) = queue.pop_front() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+
+            if utils::is_hidden(&path) {
+                continue;
+            }
+
+            if path.is_dir() {
+                if recursive {
+                    queue.push_back(path);
+                }
+                continue;
+            }
+
+            if !utils::is_audio_file(&path) {
+                continue;
+            }
+
+            report.total += 1;
+            match fetch_file_art(&mut archive, &path, dryrun) {
+                Ok(true) => report.embedded += 1,
+                Ok(false) => report.skipped += 1,
+                Err(e) => log::error!("Couldn't embed cover in \"{}\" ({})", path.display(), e),
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Returns whether a cover was embedded
+fn fetch_file_art(archive: &mut CoverArtArchive, path: &Path, dryrun: bool) -> Result<bool> {
+    let container = sniff_container(path)?.ok_or(Error::NotSupported)?;
+    let info = ArtInfo::read(path, container)?;
+
+    if info.has_cover {
+        log::debug!("Already has a cover: \"{}\"", path.display());
+        return Ok(false);
+    }
+
+    let Some(release) = info.release else {
+        log::warn!(
+            "No MusicBrainz release id: \"{}\" (skipping)",
+            path.display()
+        );
+        return Ok(false);
+    };
+
+    let Some(cover) = archive.front_cover(&release)? else {
+        log::warn!("Release {} has no front cover (skipping)", release);
+        return Ok(false);
+    };
+
+    if dryrun {
+        log::info!("Embedding cover (dryrun): \"{}\"", path.display());
+        return Ok(true);
+    }
+
+    log::info!("Embedding cover: \"{}\"", path.display());
+    embed_cover(path, container, &cover)?;
+
+    Ok(true)
+}
+
+/// Cover Art Archive client, each release is downloaded only once
+struct CoverArtArchive {
+    agent: ureq::Agent,
+    last_request: Option<Instant>,
+    covers: HashMap<String, Option<Cover>>,
+}
+
+impl CoverArtArchive {
+    fn new() -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .user_agent(concat!("musso/", env!("CARGO_PKG_VERSION")))
+            .timeout(Duration::from_secs(30))
+            .build();
+
+        Self {
+            agent,
+            last_request: None,
+            covers: HashMap::new(),
+        }
+    }
+
+    fn front_cover(&mut self, release: &str) -> Result<Option<Cover>> {
+        if let Some(cover) = self.covers.get(release) {
+            return Ok(cover.clone());
+        }
+
+        // Release ids are UUIDs, anything else would change the URL
+        if release.is_empty() || !release.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+            return Err(Error::InvalidReleaseId {
+                id: release.to_owned(),
+            });
+        }
+
+        if let Some(last) = self.last_request {
+            if let Some(wait) = MIN_REQUEST_INTERVAL.checked_sub(last.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+        self.last_request = Some(Instant::now());
+
+        let url = format!("{}/{}/front", COVER_ART_ARCHIVE, release);
+        let cover = match self.agent.get(&url).call() {
+            Ok(response) => {
+                let mut data = Vec::new();
+                response
+                    .into_reader()
+                    .take(MAX_COVER_BYTES)
+                    .read_to_end(&mut data)?;
+
+                Some(Cover::from_data(data)?)
+            }
+
+            Err(ureq::Error::Status(404, _)) => None,
+
+            Err(e) => {
+                return Err(Error::HttpError {
+                    reason: e.to_string(),
+                })
+            }
+        };
+
+        self.covers.insert(release.to_owned(), cover.clone());
+        Ok(cover)
+    }
+}
+
+/// What the tags of a file say about its cover
+#[derive(Debug, Default)]
+struct ArtInfo {
+    has_cover: bool,
+    release: Option<String>,
+}
+
+impl ArtInfo {
+    fn read(path: &Path, container: &str) -> Result<Self> {
+        match container {
+            "mp3" => {
+                let tag = id3::no_tag_ok(id3::Tag::read_from_path(path))?.unwrap_or_default();
+                let release = tag
+                    .extended_texts()
+                    .find(|text| {
+                        text.description
+                            .eq_ignore_ascii_case("MusicBrainz Album Id")
+                    })
+                    .map(|text| text.value.clone());
+                let has_cover = tag.pictures().next().is_some();
+
+                Ok(Self { has_cover, release })
+            }
+
+            "flac" => {
+                let tag = metaflac::Tag::read_from_path(path)?;
+                let release = tag
+                    .get_vorbis("MUSICBRAINZ_ALBUMID")
+                    .and_then(|mut values| values.next().map(|s| s.to_owned()));
+                let has_cover = tag.pictures().next().is_some();
+
+                Ok(Self { has_cover, release })
+            }
+
+            "ogg" => {
+                let mut reader = ogg::reading::PacketReader::new(File::open(path)?);
+                let ((_, comments, _), _) = lewton::inside_ogg::read_headers(&mut reader)?;
+                let comment = |key: &str| {
+                    comments
+                        .comment_list
+                        .iter()
+                        .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                        .map(|(_, value)| value.clone())
+                };
+
+                Ok(Self {
+                    has_cover: comment("METADATA_BLOCK_PICTURE").is_some(),
+                    release: comment("MUSICBRAINZ_ALBUMID"),
+                })
+            }
+
+            "m4a" => {
+                let tag = mp4ameta::Tag::read_from_path(path)?;
+                let ident =
+                    mp4ameta::FreeformIdent::new("com.apple.iTunes", "MusicBrainz Album Id");
+                let release = tag.strings_of(&ident).next().map(|s| s.to_owned());
+
+                Ok(Self {
+                    has_cover: tag.artwork().is_some(),
+                    release,
+                })
+            }
+
+            _ => Err(Error::NotSupported),
+        }
+    }
+}
+
+/// Adds `cover` as the front cover of the file at `path`
+pub fn embed_cover(path: &Path, container: &str, cover: &Cover) -> Result<()> {
+    match container {
+        "mp3" => {
+            let mut tag = id3::no_tag_ok(id3::Tag::read_from_path(path))?.unwrap_or_default();
+            tag.add_frame(id3::frame::Picture {
+                mime_type: cover.mime_type.clone(),
+                picture_type: id3::frame::PictureType::CoverFront,
+                description: String::new(),
+                data: cover.data.clone(),
+            });
+            tag.write_to_path(path, id3::Version::Id3v24)?;
+        }
+
+        "flac" => {
+            let mut tag = metaflac::Tag::read_from_path(path)?;
+            tag.add_picture(
+                cover.mime_type.clone(),
+                metaflac::block::PictureType::CoverFront,
+                cover.data.clone(),
+            );
+            tag.save()?;
+        }
+
+        "ogg" => embed_ogg_cover(path, cover)?,
+
+        "m4a" => {
+            let fmt = match cover.mime_type.as_str() {
+                "image/jpeg" => mp4ameta::ImgFmt::Jpeg,
+                "image/png" => mp4ameta::ImgFmt::Png,
+                "image/bmp" => mp4ameta::ImgFmt::Bmp,
+                _ => return Err(Error::NotSupported),
+            };
+
+            let mut tag = mp4ameta::Tag::read_from_path(path)?;
+            tag.set_artwork(mp4ameta::Img::new(fmt, cover.data.clone()));
+            tag.write_to_path(path)?;
+        }
+
+        _ => return Err(Error::NotSupported),
+    }
+
+    Ok(())
+}
+
+/// Rewrites the ogg file at `path` adding a `METADATA_BLOCK_PICTURE` comment to
+/// its comment header, the audio packets are copied as they are
+fn embed_ogg_cover(path: &Path, cover: &Cover) -> Result<()> {
+    let picture = base64::engine::general_purpose::STANDARD.encode(flac_picture_block(cover));
+
+    let mut reader = ogg::reading::PacketReader::new(BufReader::new(File::open(path)?));
+    let mut writer = PacketWriter::new(Vec::new());
+    let mut first_stream = None;
+    let mut index = 0;
+
+    while let Some(packet) = reader.read_packet().map_err(VorbisError::from)? {
+        let serial = packet.stream_serial();
+        let info = if packet.last_in_stream() {
+            PacketWriteEndInfo::EndStream
+        } else if packet.last_in_page() {
+            PacketWriteEndInfo::EndPage
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+        let absgp = packet.absgp_page();
+
+        // Only the comment header of the first logical stream is changed
+        let is_comment_header = *first_stream.get_or_insert(serial) == serial && index == 1;
+        let data = if is_comment_header {
+            with_comment(&packet.data, "METADATA_BLOCK_PICTURE", &picture)?
+        } else {
+            packet.data
+        };
+
+        if first_stream == Some(serial) {
+            index += 1;
+        }
+
+        writer.write_packet(data.into_boxed_slice(), serial, info, absgp)?;
+    }
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".musso-tmp");
+    fs::write(&tmp, writer.into_inner())?;
+    fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+/// FLAC picture block used (base64 encoded) by `METADATA_BLOCK_PICTURE`
+fn flac_picture_block(cover: &Cover) -> Vec<u8> {
+    fn push_u32(block: &mut Vec<u8>, n: usize) {
+        block.extend_from_slice(&(n as u32).to_be_bytes());
+    }
+
+    let mut block = Vec::new();
+
+    // Front cover
+    push_u32(&mut block, 3);
+    push_u32(&mut block, cover.mime_type.len());
+    block.extend_from_slice(cover.mime_type.as_bytes());
+    // No description
+    push_u32(&mut block, 0);
+    // Width, height, depth and colors, unknown
+    block.extend_from_slice(&[0; 16]);
+    push_u32(&mut block, cover.data.len());
+    block.extend_from_slice(&cover.data);
+
+    block
+}
+
+/// Vorbis comment header `header` with a `key=value` comment added
+fn with_comment(header: &[u8], key: &str, value: &str) -> Result<Vec<u8>> {
+    let bad_header = || Error::from(VorbisError::BadHeader(HeaderReadError::HeaderBadFormat));
+
+    let read_u32 = |pos: usize| -> Result<u32> {
+        header
+            .get(pos..pos + 4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+            .ok_or_else(bad_header)
+    };
+
+    if header.get(..7) != Some(b"\x03vorbis".as_slice()) {
+        return Err(bad_header());
+    }
+
+    let vendor_len = read_u32(7)? as usize;
+    let count_pos = 11 + vendor_len;
+    let count = read_u32(count_pos)?;
+
+    let mut pos = count_pos + 4;
+    for _ in 0..count {
+        pos += 4 + read_u32(pos)? as usize;
+    }
+
+    // The framing bit must follow the comments
+    if header.get(pos) != Some(&1) {
+        return Err(bad_header());
+    }
+
+    let comment = format!("{}={}", key, value);
+    let mut rewritten = Vec::with_capacity(header.len() + comment.len() + 4);
+    rewritten.extend_from_slice(&header[..count_pos]);
+    rewritten.extend_from_slice(&(count + 1).to_le_bytes());
+    rewritten.extend_from_slice(&header[count_pos + 4..pos]);
+    rewritten.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+    rewritten.extend_from_slice(comment.as_bytes());
+    rewritten.push(1);
+
+    Ok(rewritten)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::Metadata;
+
+    // Smallest valid PNG signature and IHDR chunk start, enough for infer
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0\0\x01\0\0\0\x01";
+
+    fn cover() -> Cover {
+        Cover::from_data(PNG.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn ogg_cover_is_embedded() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("complete.ogg");
+        fs::copy("test_files/complete.ogg", &path)?;
+
+        assert!(!ArtInfo::read(&path, "ogg")?.has_cover);
+        embed_cover(&path, "ogg", &cover())?;
+        assert!(ArtInfo::read(&path, "ogg")?.has_cover);
+
+        // The other tags and the audio are still readable
+        let metadata = Metadata::from_path(&path)?;
+        assert_eq!("Title", metadata.get_title()?);
+
+        let mut stream = lewton::inside_ogg::OggStreamReader::new(File::open(&path)?)?;
+        while stream.read_dec_packet_itl()?.is_some() {}
+
+        Ok(())
+    }
+
+    #[test]
+    fn mp3_cover_is_embedded() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("complete.mp3");
+        fs::copy("test_files/complete.mp3", &path)?;
+
+        embed_cover(&path, "mp3", &cover())?;
+
+        let tag = id3::Tag::read_from_path(&path)?;
+        let picture = tag.pictures().next().unwrap();
+        assert_eq!("image/png", picture.mime_type);
+        assert_eq!(PNG, picture.data.as_slice());
+
+        Ok(())
+    }
+}
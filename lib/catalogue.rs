@@ -0,0 +1,187 @@
+//! Export of a library to a SQLite database, to query it with SQL
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::library::Library;
+use crate::metadata::Metadata;
+use crate::utils;
+use crate::{Error, Result};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS files (
+    path TEXT PRIMARY KEY NOT NULL,
+    artist TEXT,
+    album TEXT,
+    title TEXT,
+    track INTEGER,
+    year INTEGER,
+    genre TEXT,
+    duration_secs REAL,
+    file_size INTEGER NOT NULL,
+    last_modified INTEGER NOT NULL
+)";
+
+const UPSERT: &str = "
+INSERT INTO files (path, artist, album, title, track, year, genre, duration_secs, file_size, last_modified)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+ON CONFLICT (path) DO UPDATE SET
+    artist = excluded.artist,
+    album = excluded.album,
+    title = excluded.title,
+    track = excluded.track,
+    year = excluded.year,
+    genre = excluded.genre,
+    duration_secs = excluded.duration_secs,
+    file_size = excluded.file_size,
+    last_modified = excluded.last_modified";
+
+/// Outcome of a [`export`] run
+#[derive(Debug, Clone, Default)]
+pub struct CatalogueReport {
+    /// Files whose row was written
+    pub updated: usize,
+    /// Files left alone by an incremental run, their row is up to date
+    pub unchanged: usize,
+    /// Rows of files that don't exist anymore
+    pub removed: usize,
+    pub total: usize,
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::DatabaseError {
+            reason: e.to_string(),
+        }
+    }
+}
+
+/// Writes a row of the `files` table of `db` for every audio file under
+/// `folders`, creating the database if needed. With `incremental` the tags
+/// are only read again for files whose modification time changed. Rows of
+/// files that are gone are removed
+pub fn export(folders: &[PathBuf], db: &Path, incremental: bool) -> Result<CatalogueReport> {
+    let mut connection = Connection::open(db)?;
+    connection.execute(SCHEMA, [])?;
+
+    let mut report = CatalogueReport::default();
+    let mut seen = HashSet::new();
+    let transaction = connection.transaction()?;
+
+    let mut files = Vec::new();
+    for folder in folders {
+        files.extend(Library::scan(folder, utils::AUDIO_EXTENSIONS)?);
+    }
+
+    for file in files {
+        report.total += 1;
+
+        let path = file.to_string_lossy().into_owned();
+        let stat = fs::metadata(&file)?;
+        let last_modified = stat
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs() as i64);
+
+        seen.insert(path.clone());
+
+        if incremental {
+            let stored: Option<i64> = transaction
+                .query_row(
+                    "SELECT last_modified FROM files WHERE path = ?1",
+                    [&path],
+                    |row| row.get(0),
+                )
+                .optional()?;
+
+            if stored == Some(last_modified) {
+                report.unchanged += 1;
+                continue;
+            }
+        }
+
+        let metadata = match Metadata::from_path(&file) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                log::error!("Couldn't read tags of \"{}\" ({})", file.display(), e);
+                Metadata::default()
+            }
+        };
+
+        transaction.execute(
+            UPSERT,
+            params![
+                path,
+                metadata.artist,
+                metadata.album,
+                metadata.title,
+                metadata.track,
+                metadata.year,
+                metadata.genre,
+                metadata.duration.map(|duration| duration.as_secs_f64()),
+                stat.len() as i64,
+                last_modified,
+            ],
+        )?;
+        report.updated += 1;
+    }
+
+    let stored: Vec<String> = transaction
+        .prepare("SELECT path FROM files")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    for path in stored.iter().filter(|path| !seen.contains(*path)) {
+        transaction.execute("DELETE FROM files WHERE path = ?1", [path])?;
+        report.removed += 1;
+    }
+
+    transaction.commit()?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FixtureBuilder;
+
+    #[test]
+    fn files_are_exported_and_updated() -> Result<()> {
+        let fixture = FixtureBuilder::new()
+            .file("Artist/Album/1 - One.mp3")
+            .artist("Artist")
+            .album("Album")
+            .title("One")
+            .track(1u32)
+            .file("Artist/Album/2 - Two.flac")
+            .artist("Artist")
+            .title("Two")
+            .build()?;
+        let db = fixture.path().join(".catalogue.db");
+        let folders = [fixture.path().to_path_buf()];
+
+        let report = export(&folders, &db, false)?;
+        assert_eq!((2, 2), (report.total, report.updated));
+
+        let connection = Connection::open(&db)?;
+        let (album, track): (Option<String>, Option<u32>) = connection.query_row(
+            "SELECT album, track FROM files WHERE title = 'One'",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        assert_eq!((Some("Album".to_owned()), Some(1)), (album, track));
+
+        let report = export(&folders, &db, true)?;
+        assert_eq!((0, 2), (report.updated, report.unchanged));
+
+        fs::remove_file(fixture.path().join("Artist/Album/2 - Two.flac"))?;
+        let report = export(&folders, &db, true)?;
+        assert_eq!((1, 1), (report.total, report.removed));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+//! SHA-256 sums of files, used to check that moved files are intact
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use ring::digest::{Context, SHA256};
+
+use crate::utils;
+use crate::{Error, Result};
+
+/// Hex SHA-256 sum of the contents of `path`
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut context = Context::new(&SHA256);
+    let mut buf = vec![0; 64 * 1024];
+
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        context.update(&buf[..n]);
+    }
+
+    Ok(context
+        .finish()
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Like [`utils::move_file`], but fails with [`Error::ChecksumMismatch`] if
+/// `to` doesn't have the contents `from` had. `from` is left where it was
+/// then: a copy to another file system is removed before `from`, a renamed
+/// file is renamed back
+pub fn move_verified(from: &Path, to: &Path, auto_chmod: bool) -> Result<()> {
+    let expected = sha256_file(from)?;
+    let mismatch = || Error::ChecksumMismatch {
+        path: to.to_string_lossy().into_owned(),
+    };
+
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            std::fs::copy(from, to)?;
+
+            if sha256_file(to)? != expected {
+                std::fs::remove_file(to)?;
+                return Err(mismatch());
+            }
+            utils::remove_file(from, auto_chmod)?;
+        }
+
+        result => {
+            result?;
+
+            if sha256_file(to)? != expected {
+                std::fs::rename(to, from)?;
+                return Err(mismatch());
+            }
+        }
+    }
+
+    log::debug!("sha256 {}: \"{}\"", expected, to.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_digests() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sha256 = |data: &[u8]| -> Result<String> {
+            let path = dir.path().join("file");
+            std::fs::write(&path, data)?;
+            sha256_file(&path)
+        };
+
+        assert_eq!(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            sha256(b"")?
+        );
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            sha256(b"abc")?
+        );
+
+        Ok(())
+    }
+}
@@ -1,51 +1,593 @@
 use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
 
+use notify::event::{EventKind, ModifyKind};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use regex::Regex;
 use serde::Deserialize;
 
-use crate::format::ParsedFormat;
+use crate::format::{BuildOptions, ParsedFormat};
+use crate::library::Exclude;
+use crate::sorting::{Compilation, FoldDiacritics, Options, DEFAULT_VA_THRESHOLD};
+use crate::utils::{self, Resource};
 use crate::{Error, Result};
 
+/// Config file shipped with musso, used when there's no shared copy installed
+pub const DEFAULT_CONFIG: &str = include_str!("../share/config.toml");
+
+const ENV_PREFIX: &str = "MUSO_";
+
+/// Name of the only library of a config made by [`Config::with_roots`]
+pub const AD_HOC_LIBRARY: &str = "ad-hoc";
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct WatchConfig {
-    pub every: Option<u64>,
+    /// Seconds events are debounced for (1 by default), fractions allowed
+    pub every: Option<f64>,
     pub libraries: Vec<String>,
+
+    /// Maximum amount of files sorted per second, unlimited if not set
+    #[serde(rename = "max-files-per-sec")]
+    pub max_files_per_sec: Option<f64>,
+
+    /// Seconds to wait before trying to watch again a root that disappeared,
+    /// doubled after every failed attempt (5 by default)
+    #[serde(rename = "retry-delay-secs")]
+    pub retry_delay_secs: Option<u64>,
+
+    /// Upper bound for the retry delay (300 by default)
+    #[serde(rename = "max-retry-delay-secs")]
+    pub max_retry_delay_secs: Option<u64>,
+
+    /// Maximum amount of threads sorting files, one per library folder if
+    /// not set
+    #[serde(rename = "max-threads")]
+    pub max_threads: Option<usize>,
+
+    /// Skip events of hidden files and folders (e.g. `.DS_Store` or `._*`
+    /// AppleDouble files), true by default
+    #[serde(rename = "ignore-hidden")]
+    pub ignore_hidden: Option<bool>,
+
+    /// Don't sort files again when their contents change (e.g. tags edited
+    /// by a tagger), false by default
+    #[serde(rename = "ignore-data-events")]
+    pub ignore_data_events: Option<bool>,
+
+    /// Remove the folders emptied by sorting, true by default
+    #[serde(rename = "remove-empty")]
+    pub remove_empty: Option<bool>,
+
+    /// Name of the file written in the folders emptied by sorting instead of
+    /// removing them, which is then off by default
+    #[serde(rename = "mark-empty")]
+    pub mark_empty: Option<String>,
+
+    /// Kinds of events that get files sorted, new and renamed files (and
+    /// changed ones unless `ignore-data-events` is set) if not set
+    #[serde(rename = "event-filter")]
+    pub event_filter: Option<Vec<WatchEvent>>,
+
+    /// Seconds between the logs of the files sorted and failed since the
+    /// previous one, nothing is logged if not set
+    #[serde(rename = "heartbeat-secs")]
+    pub heartbeat_secs: Option<u64>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+impl WatchConfig {
+    /// Whether events of this kind are handled
+    pub fn is_handled(&self, event: WatchEvent) -> bool {
+        match &self.event_filter {
+            Some(filter) => filter.contains(&event),
+            None => event != WatchEvent::ModifyData || !self.ignore_data_events.unwrap_or(false),
+        }
+    }
+}
+
+/// Kind of filesystem event, see [`WatchConfig::event_filter`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WatchEvent {
+    /// A file was created (or moved in from outside the watched folders)
+    Create,
+    /// A file was renamed inside the watched folders
+    Rename,
+    /// The contents of a file changed, e.g. its tags were edited
+    #[serde(alias = "modify_data")]
+    ModifyData,
+}
+
+impl std::str::FromStr for WatchEvent {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "create" => Ok(WatchEvent::Create),
+            "rename" => Ok(WatchEvent::Rename),
+            "modify-data" | "modify_data" => Ok(WatchEvent::ModifyData),
+            _ => Err(Error::InvalidWatchEvent {
+                event: s.to_owned(),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct LibraryConfig {
     pub format: ParsedFormat,
     pub folders: Vec<PathBuf>,
 
     #[serde(rename = "exfat-compat")]
     pub exfat_compat: Option<bool>,
+
+    /// Whether subfolders are watched too (the default), disabling it saves
+    /// watch descriptors on big trees where files only arrive at the top
+    pub recursive: Option<bool>,
+
+    /// Extensions of files that follow the audio file with the same stem,
+    /// e.g. `{ lrc = true }`
+    pub companions: Option<HashMap<String, bool>>,
+
+    /// Regular expressions removed from titles to fill `{title_clean}`
+    #[serde(rename = "title-clean-patterns")]
+    pub title_clean_patterns: Option<Vec<String>>,
+
+    /// Leading articles skipped by `{initial}`, `the`, `a` and `an` by default
+    #[serde(rename = "strip-articles")]
+    pub strip_articles: Option<Vec<String>>,
+
+    /// Drop folders of the format left empty by missing tags instead of
+    /// failing the file
+    #[serde(rename = "strip-empty-components")]
+    pub strip_empty_components: Option<bool>,
+
+    /// Sort into existing folders whose name only differs by diacritics
+    #[serde(rename = "fold-diacritics")]
+    pub fold_diacritics: Option<bool>,
+
+    /// File names that don't keep a folder from being removed as empty (e.g.
+    /// `.DS_Store`), deleted along with the folder
+    #[serde(rename = "prune-junk")]
+    pub prune_junk: Option<Vec<String>>,
+
+    /// Globs of paths skipped when sorting a folder of the library, matched
+    /// against their full path (e.g. `'*/Incoming/partial/*'`)
+    #[serde(rename = "ignore-patterns")]
+    pub ignore_patterns: Option<Vec<String>>,
+
+    /// Format of the albums whose files have more than `va-threshold`
+    /// different artists, taken as compilations. Not inferred if not set
+    #[serde(rename = "compilation-format")]
+    pub compilation_format: Option<ParsedFormat>,
+
+    /// Artists an album can have before it's a compilation, 3 by default
+    #[serde(rename = "va-threshold")]
+    pub va_threshold: Option<usize>,
+
+    /// Milliseconds the watcher waits after the last event of a file before
+    /// sorting it (0 by default), so downloads in progress aren't sorted
+    #[serde(rename = "post-watch-sort-delay-ms")]
+    pub post_watch_sort_delay_ms: Option<u64>,
+
+    /// Seconds after which reading the tags of a file is given up, failing
+    /// the file (no limit by default)
+    #[serde(rename = "tag-read-timeout-secs")]
+    pub tag_read_timeout_secs: Option<u64>,
+
+    /// Permissions of the folders created while sorting, in octal (e.g.
+    /// `0o775` or `'775'`), unix only
+    #[serde(rename = "dir-mode")]
+    pub dir_mode: Option<DirMode>,
+}
+
+/// `dir-mode` of a library, a TOML integer (written in octal, `0o775`) or a
+/// string of octal digits (`'775'`)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum DirMode {
+    Mode(u32),
+    Octal(String),
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub watch: WatchConfig,
     pub libraries: HashMap<String, LibraryConfig>,
+    /// Format strings by name, a library whose format is `preset:<name>`
+    /// uses the one named `<name>`. A preset can name another one too
+    #[serde(default)]
+    pub presets: HashMap<String, String>,
 }
 
 impl Config {
     pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
-        let path = path.as_ref();
-        let contents = fs::read_to_string(path)?;
+        let mut config = Self::parse(path.as_ref())?;
 
-        let mut config: Self = toml::from_str(&contents).map_err(|e| Error::InvalidConfig {
-            reason: e.to_string(),
-        })?;
+        config.sanitize_folders()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Merges the configs at `paths` in order (see [`Config::parse_layered`])
+    pub fn from_paths(paths: &[PathBuf]) -> Result<Self> {
+        let mut config = Self::parse_layered(paths)?;
 
         config.sanitize_folders()?;
+        config.validate()?;
 
         Ok(config)
     }
 
-    fn sanitize_folders(&mut self) -> Result<()> {
-        let mut seen_folders = HashSet::new();
+    /// Loads the configs at `paths` (merged like [`Config::from_paths`]) and
+    /// then overrides their values with the `MUSO_` environment variables:
+    ///
+    /// - `MUSO_WATCH_EVERY`, `MUSO_WATCH_LIBRARIES` (comma separated),
+    ///   `MUSO_WATCH_MAX_FILES_PER_SEC`, `MUSO_WATCH_RETRY_DELAY_SECS`,
+    ///   `MUSO_WATCH_MAX_RETRY_DELAY_SECS` and `MUSO_WATCH_MAX_THREADS`
+    /// - `MUSO_DEFAULT_FORMAT`, format of every library (it can name a preset,
+    ///   like `MUSO_LIBRARY_<NAME>_FORMAT`)
+    /// - `MUSO_LIBRARY_<NAME>_FORMAT`, `MUSO_LIBRARY_<NAME>_FOLDER` (a list
+    ///   like `PATH`) and `MUSO_LIBRARY_<NAME>_EXFAT_COMPAT`, where `<NAME>` is
+    ///   the uppercased library name with `-` replaced by `_`
+    pub fn load_with_env_overrides(paths: &[PathBuf]) -> Result<Self> {
+        let mut config = Self::parse_layered(paths)?;
+
+        config.apply_overrides(std::env::vars())?;
+        config.sanitize_folders()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Like [`Config::load_with_env_overrides`], but generating the default
+    /// config at `path` first if there's no file there
+    pub fn load_or_init(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            utils::generate_resource_at(Resource::Config, path, Some(DEFAULT_CONFIG))?;
+        }
+
+        Self::load_with_env_overrides(&[path.to_path_buf()])
+    }
+
+    /// Sends the config at `path` (loaded like [`Config::from_path`]) to `tx`
+    /// every time the file changes, until the returned watcher is dropped.
+    /// Configs that can't be loaded are logged and skipped
+    pub fn watch_for_changes(path: &Path, tx: mpsc::Sender<Config>) -> Result<RecommendedWatcher> {
+        let path = path.to_path_buf();
+        let file_name = path.file_name().map(ToOwned::to_owned);
+        // Editors often replace the file instead of writing to it, so the
+        // folder containing it is watched
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(e) => {
+                        log::error!("Couldn't watch the config ({})", e);
+                        return;
+                    }
+                };
+
+                let changed = matches!(
+                    event.kind,
+                    EventKind::Create(_)
+                        | EventKind::Modify(
+                            ModifyKind::Data(_) | ModifyKind::Name(_) | ModifyKind::Any
+                        )
+                );
+                if !changed
+                    || !event
+                        .paths
+                        .iter()
+                        .any(|changed| changed.file_name() == file_name.as_deref())
+                {
+                    return;
+                }
+
+                match Config::from_path(&path) {
+                    Ok(config) => {
+                        log::info!("Reloaded the config \"{}\"", path.display());
+                        let _ = tx.send(config);
+                    }
+                    Err(e) => {
+                        log::error!("Couldn't reload the config \"{}\" ({})", path.display(), e)
+                    }
+                }
+            })?;
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+        Ok(watcher)
+    }
+
+    /// Replaces the libraries with a single one ([`AD_HOC_LIBRARY`]) made of
+    /// `folders` and `format`, keeping the `[watch]` settings. For one-off
+    /// watches of folders that aren't in the config file
+    pub fn with_roots(mut self, folders: Vec<PathBuf>, format: ParsedFormat) -> Result<Self> {
+        let library = LibraryConfig {
+            format,
+            folders,
+            ..Default::default()
+        };
+
+        self.libraries = HashMap::from([(AD_HOC_LIBRARY.to_owned(), library)]);
+        self.watch.libraries = vec![AD_HOC_LIBRARY.to_owned()];
+
+        self.sanitize_folders()?;
+        self.validate()?;
+
+        Ok(self)
+    }
+
+    fn parse(path: &Path) -> Result<Self> {
+        Self::parse_layered(&[path.to_path_buf()])
+    }
+
+    /// Merges the files at `paths` in order, a directory stands for the
+    /// `.toml` files inside it in name order (e.g. a `config.d` drop-in
+    /// directory). Tables are merged key by key, so libraries with the same
+    /// name are merged field by field, any other value of a later file
+    /// replaces the earlier one
+    fn parse_layered(paths: &[PathBuf]) -> Result<Self> {
+        let mut merged = toml::Table::new();
+        for path in paths {
+            for file in Self::layer_files(path)? {
+                merge_tables(&mut merged, Self::read_table(&file)?);
+            }
+        }
+
+        resolve_library_presets(&mut merged)?;
+        toml::Value::Table(merged)
+            .try_into()
+            .map_err(|e: toml::de::Error| Error::InvalidConfig {
+                reason: e.to_string(),
+            })
+    }
+
+    fn layer_files(path: &Path) -> Result<Vec<PathBuf>> {
+        if is_stream_source(path) || !path.is_dir() {
+            return Ok(vec![path.to_path_buf()]);
+        }
+
+        let mut files = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let file = entry?.path();
+            if file.is_file() && file.extension().is_some_and(|ext| ext == "toml") {
+                files.push(file);
+            }
+        }
+        files.sort();
+
+        Ok(files)
+    }
+
+    fn read_table(path: &Path) -> Result<toml::Table> {
+        let contents = if path == Path::new(STDIN_SOURCE) {
+            std::io::read_to_string(std::io::stdin())?
+        } else if is_url(path) {
+            fetch(&path.to_string_lossy())?
+        } else {
+            fs::read_to_string(path)?
+        };
+
+        toml::from_str(&contents).map_err(|e| Error::InvalidConfig {
+            reason: format!("{} ({})", e, path.display()),
+        })
+    }
+
+    fn apply_overrides(&mut self, vars: impl IntoIterator<Item = (String, String)>) -> Result<()> {
+        let vars: HashMap<String, String> = vars
+            .into_iter()
+            .filter(|(key, _)| key.starts_with(ENV_PREFIX))
+            .collect();
+
+        if let Some(every) = vars.get("MUSO_WATCH_EVERY") {
+            self.watch.every = Some(parse_env("MUSO_WATCH_EVERY", every)?);
+        }
+        if let Some(libraries) = vars.get("MUSO_WATCH_LIBRARIES") {
+            self.watch.libraries = libraries
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_owned)
+                .collect();
+        }
+        if let Some(rate) = vars.get("MUSO_WATCH_MAX_FILES_PER_SEC") {
+            self.watch.max_files_per_sec = Some(parse_env("MUSO_WATCH_MAX_FILES_PER_SEC", rate)?);
+        }
+        if let Some(delay) = vars.get("MUSO_WATCH_RETRY_DELAY_SECS") {
+            self.watch.retry_delay_secs = Some(parse_env("MUSO_WATCH_RETRY_DELAY_SECS", delay)?);
+        }
+        if let Some(delay) = vars.get("MUSO_WATCH_MAX_RETRY_DELAY_SECS") {
+            self.watch.max_retry_delay_secs =
+                Some(parse_env("MUSO_WATCH_MAX_RETRY_DELAY_SECS", delay)?);
+        }
+        if let Some(threads) = vars.get("MUSO_WATCH_MAX_THREADS") {
+            self.watch.max_threads = Some(parse_env("MUSO_WATCH_MAX_THREADS", threads)?);
+        }
+        if let Some(ignore) = vars.get("MUSO_WATCH_IGNORE_HIDDEN") {
+            self.watch.ignore_hidden = Some(parse_env("MUSO_WATCH_IGNORE_HIDDEN", ignore)?);
+        }
+        if let Some(ignore) = vars.get("MUSO_WATCH_IGNORE_DATA_EVENTS") {
+            self.watch.ignore_data_events =
+                Some(parse_env("MUSO_WATCH_IGNORE_DATA_EVENTS", ignore)?);
+        }
+        if let Some(remove) = vars.get("MUSO_WATCH_REMOVE_EMPTY") {
+            self.watch.remove_empty = Some(parse_env("MUSO_WATCH_REMOVE_EMPTY", remove)?);
+        }
+        if let Some(name) = vars.get("MUSO_WATCH_MARK_EMPTY") {
+            self.watch.mark_empty = Some(name.clone());
+        }
+        if let Some(secs) = vars.get("MUSO_WATCH_HEARTBEAT_SECS") {
+            self.watch.heartbeat_secs = Some(parse_env("MUSO_WATCH_HEARTBEAT_SECS", secs)?);
+        }
+        if let Some(filter) = vars.get("MUSO_WATCH_EVENT_FILTER") {
+            self.watch.event_filter = Some(
+                filter
+                    .split(',')
+                    .filter(|event| !event.trim().is_empty())
+                    .map(|event| parse_env("MUSO_WATCH_EVENT_FILTER", event))
+                    .collect::<Result<_>>()?,
+            );
+        }
+
+        let default_format = vars
+            .get("MUSO_DEFAULT_FORMAT")
+            .map(|format| {
+                let format = resolve_preset(&self.presets, format, "MUSO_DEFAULT_FORMAT")?;
+                parse_env::<ParsedFormat>("MUSO_DEFAULT_FORMAT", format)
+            })
+            .transpose()?;
 
         for (name, library) in &mut self.libraries {
+            let prefix = format!(
+                "{}LIBRARY_{}_",
+                ENV_PREFIX,
+                name.to_uppercase().replace('-', "_")
+            );
+            let var = |field: &str| {
+                let key = format!("{}{}", prefix, field);
+                vars.get(&key).map(|value| (key, value))
+            };
+
+            if let Some((key, format)) = var("FORMAT") {
+                library.format = parse_env(&key, resolve_preset(&self.presets, format, &key)?)?;
+            } else if let Some(format) = &default_format {
+                library.format = format.clone();
+            }
+            if let Some((_, folders)) = var("FOLDER") {
+                library.folders = std::env::split_paths(folders).collect();
+            }
+            if let Some((key, exfat_compat)) = var("EXFAT_COMPAT") {
+                library.exfat_compat = Some(parse_env(&key, exfat_compat)?);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the values that can't be verified while deserializing
+    pub fn validate(&self) -> Result<()> {
+        for name in self.libraries.keys() {
+            self.title_clean_patterns_of(name)?;
+            self.dir_mode_of(name)?;
+        }
+
+        if let Some(err) = self.duplicate_folder() {
+            return Err(err);
+        }
+
+        if let Some(every) = self.watch.every {
+            if every <= 0.0 || !every.is_finite() {
+                return Err(Error::InvalidConfig {
+                    reason: format!("every must be a positive amount of seconds, not {}", every),
+                });
+            }
+        }
+
+        if self.watch.max_threads == Some(0) {
+            return Err(Error::InvalidConfig {
+                reason: "max-threads must be greater than 0".into(),
+            });
+        }
+
+        if let Some(name) = &self.watch.mark_empty {
+            if self.watch.remove_empty == Some(true) {
+                return Err(Error::InvalidConfig {
+                    reason: "mark-empty can't be used along with remove-empty".into(),
+                });
+            }
+
+            if name.is_empty() || name.contains(['/', '\\']) || name == "." || name == ".." {
+                return Err(Error::InvalidConfig {
+                    reason: format!("mark-empty must be a file name, not \"{}\"", name),
+                });
+            }
+        }
+
+        if self.watch.heartbeat_secs == Some(0) {
+            return Err(Error::InvalidConfig {
+                reason: "heartbeat-secs must be greater than 0".into(),
+            });
+        }
+
+        for (outer, inner) in self.nested_folders() {
+            log::warn!(
+                "Library folder \"{}\" is inside \"{}\", files in it will be sorted by the innermost one",
+                inner.display(),
+                outer.display()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// First folder (in library name order) that belongs to two different
+    /// libraries, as an [`Error::DuplicateFolder`]
+    pub fn duplicate_folder(&self) -> Option<Error> {
+        let mut names: Vec<&String> = self.libraries.keys().collect();
+        names.sort();
+
+        let mut owners: HashMap<&Path, &str> = HashMap::new();
+        for name in names {
+            for folder in &self.libraries[name].folders {
+                match owners.get(folder.as_path()) {
+                    Some(owner) if owner != name => {
+                        return Some(Error::DuplicateFolder {
+                            folder: folder.display().to_string(),
+                            lib_a: owner.to_string(),
+                            lib_b: name.clone(),
+                        });
+                    }
+
+                    Some(_) => {}
+                    None => {
+                        owners.insert(folder, name);
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Pairs of library folders where the second one is inside the first one
+    pub fn nested_folders(&self) -> Vec<(&Path, &Path)> {
+        let folders: Vec<&Path> = self
+            .libraries
+            .values()
+            .flat_map(|library| library.folders.iter().map(PathBuf::as_path))
+            .collect();
+
+        let mut nested = Vec::new();
+        for outer in &folders {
+            for inner in &folders {
+                if outer != inner && inner.starts_with(outer) {
+                    nested.push((*outer, *inner));
+                }
+            }
+        }
+
+        nested.sort();
+        nested
+    }
+
+    fn sanitize_folders(&mut self) -> Result<()> {
+        let mut seen_folders: HashMap<PathBuf, String> = HashMap::new();
+
+        // Sorted so that errors always point to the same pair of libraries
+        let mut names: Vec<String> = self.libraries.keys().cloned().collect();
+        names.sort();
+
+        for name in names {
+            let library = self.libraries.get_mut(&name).unwrap();
             let mut sanitized: Vec<PathBuf> = Vec::new();
 
             for folder in library.folders.drain(..) {
@@ -72,19 +614,26 @@ impl Config {
                         name,
                         folder.display()
                     );
-                } else if seen_folders.contains(&folder) {
+                } else if let Some(owner) = seen_folders.get(&folder) {
                     log::error!(
                         "Library \"{}\" contains a repeated folder: {}",
                         name,
                         folder.display()
                     );
 
-                    return Err(Error::InvalidConfig {
-                        reason: "Repeated folder path in library".into(),
+                    if *owner == name {
+                        let reason = format!("Repeated folder path in library \"{}\"", name);
+                        return Err(Error::InvalidConfig { reason });
+                    }
+
+                    return Err(Error::DuplicateFolder {
+                        folder: folder.display().to_string(),
+                        lib_a: owner.clone(),
+                        lib_b: name,
                     });
                 } else {
                     sanitized.push(folder.clone());
-                    seen_folders.insert(folder);
+                    seen_folders.insert(folder, name.clone());
                 }
             }
 
@@ -94,25 +643,618 @@ impl Config {
         Ok(())
     }
 
-    pub fn search_format(&self, path: impl AsRef<Path>) -> Option<&ParsedFormat> {
+    /// Deepest library folder containing `path`
+    pub fn root_for(&self, path: impl AsRef<Path>) -> Option<&Path> {
+        let path = path.as_ref();
+
+        self.libraries
+            .values()
+            .flat_map(|library| library.folders.iter())
+            .filter(|folder| path.starts_with(folder))
+            .max_by_key(|folder| folder.components().count())
+            .map(|folder| folder.as_path())
+    }
+
+    /// Name of the library that contains `path` as one of its folders
+    pub fn search_library(&self, path: impl AsRef<Path>) -> Option<&str> {
         let path = path.as_ref().to_path_buf();
-        for library in self.libraries.values() {
+        for (name, library) in &self.libraries {
             if library.folders.contains(&path) {
-                return Some(&library.format);
+                return Some(name);
             }
         }
 
         None
     }
 
+    pub fn search_format(&self, path: impl AsRef<Path>) -> Option<&ParsedFormat> {
+        self.search_library(path)
+            .and_then(|name| self.format_of(name))
+    }
+
     pub fn format_of(&self, library: &str) -> Option<&ParsedFormat> {
         self.libraries.get(library).map(|library| &library.format)
     }
 
+    /// Sorting options set by the config of `library`, the defaults for the
+    /// rest. Every command sorting a library starts from these
+    pub fn options_of(&self, library: &str) -> Result<Options<ParsedFormat>> {
+        Ok(Options {
+            format: self.format_of(library).cloned().unwrap_or_default(),
+            recursive: self.is_recursive(library),
+            exfat_compat: self.is_exfat_compat(library),
+            dir_mode: self.dir_mode_of(library)?,
+            companions: self.companions_of(library),
+            title_clean_patterns: self.title_clean_patterns_of(library)?,
+            strip_articles: self.strip_articles_of(library),
+            strip_empty_components: self.is_strip_empty_components(library),
+            prune_junk: self.prune_junk_of(library),
+            exclude: Exclude::new(&self.ignore_patterns_of(library)),
+            tag_read_timeout: self.tag_read_timeout_of(library),
+            fold_diacritics: self
+                .is_fold_diacritics(library)
+                .then(FoldDiacritics::default),
+            compilation: self.compilation_of(library),
+            ..Default::default()
+        })
+    }
+
+    /// Enabled companion extensions of a library, sorted
+    pub fn companions_of(&self, library: &str) -> Vec<String> {
+        let mut companions: Vec<_> = self
+            .libraries
+            .get(library)
+            .and_then(|library| library.companions.as_ref())
+            .map(|companions| {
+                companions
+                    .iter()
+                    .filter(|(_, enabled)| **enabled)
+                    .map(|(ext, _)| ext.to_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        companions.sort();
+        companions
+    }
+
+    pub fn prune_junk_of(&self, library: &str) -> Vec<String> {
+        self.libraries
+            .get(library)
+            .and_then(|library| library.prune_junk.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn ignore_patterns_of(&self, library: &str) -> Vec<String> {
+        self.libraries
+            .get(library)
+            .and_then(|library| library.ignore_patterns.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn compilation_of(&self, library: &str) -> Option<Compilation> {
+        let library = self.libraries.get(library)?;
+
+        library.compilation_format.clone().map(|format| {
+            Compilation::new(format, library.va_threshold.unwrap_or(DEFAULT_VA_THRESHOLD))
+        })
+    }
+
+    pub fn strip_articles_of(&self, library: &str) -> Vec<String> {
+        self.libraries
+            .get(library)
+            .and_then(|library| library.strip_articles.clone())
+            .unwrap_or_else(|| BuildOptions::default().strip_articles)
+    }
+
+    pub fn title_clean_patterns_of(&self, library: &str) -> Result<Vec<Regex>> {
+        let patterns = self
+            .libraries
+            .get(library)
+            .and_then(|library| library.title_clean_patterns.as_ref());
+
+        patterns
+            .into_iter()
+            .flatten()
+            .map(|pattern| {
+                Regex::new(pattern).map_err(|e| Error::InvalidConfig {
+                    reason: format!("Invalid title-clean-patterns in \"{}\": {}", library, e),
+                })
+            })
+            .collect()
+    }
+
+    pub fn is_recursive(&self, library: &str) -> bool {
+        self.libraries
+            .get(library)
+            .and_then(|library| library.recursive)
+            .unwrap_or(true)
+    }
+
+    pub fn sort_delay_of(&self, library: &str) -> Duration {
+        let millis = self
+            .libraries
+            .get(library)
+            .and_then(|library| library.post_watch_sort_delay_ms)
+            .unwrap_or(0);
+
+        Duration::from_millis(millis)
+    }
+
+    pub fn tag_read_timeout_of(&self, library: &str) -> Option<Duration> {
+        self.libraries
+            .get(library)
+            .and_then(|library| library.tag_read_timeout_secs)
+            .map(Duration::from_secs)
+    }
+
+    pub fn dir_mode_of(&self, library: &str) -> Result<Option<u32>> {
+        let mode = match self
+            .libraries
+            .get(library)
+            .and_then(|library| library.dir_mode.as_ref())
+        {
+            None => return Ok(None),
+            Some(DirMode::Mode(mode)) => return Ok(Some(*mode)),
+            Some(DirMode::Octal(mode)) => mode,
+        };
+
+        let digits = mode.strip_prefix("0o").unwrap_or(mode);
+        u32::from_str_radix(digits, 8)
+            .map(Some)
+            .map_err(|_| Error::InvalidConfig {
+                reason: format!("Invalid dir-mode in \"{}\": {} isn't octal", library, mode),
+            })
+    }
+
+    pub fn is_strip_empty_components(&self, library: &str) -> bool {
+        self.libraries
+            .get(library)
+            .and_then(|library| library.strip_empty_components)
+            .unwrap_or(false)
+    }
+
+    pub fn is_fold_diacritics(&self, library: &str) -> bool {
+        self.libraries
+            .get(library)
+            .and_then(|library| library.fold_diacritics)
+            .unwrap_or(false)
+    }
+
     pub fn is_exfat_compat(&self, library: &str) -> bool {
         self.libraries
             .get(library)
             .and_then(|library| library.exfat_compat)
-            .unwrap_or_else(|| false)
+            .unwrap_or(false)
+    }
+}
+
+/// Config "path" standing for the config read from stdin
+pub const STDIN_SOURCE: &str = "-";
+
+/// Whether the config at `path` is read from stdin or fetched from an
+/// `http(s)://` URL instead of being a file
+pub fn is_stream_source(path: &Path) -> bool {
+    path == Path::new(STDIN_SOURCE) || is_url(path)
+}
+
+fn is_url(path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+#[cfg(feature = "remote-config")]
+fn fetch(url: &str) -> Result<String> {
+    let http_error = |e: &dyn std::fmt::Display| Error::HttpError {
+        reason: format!("{} ({})", e, url),
+    };
+
+    ureq::AgentBuilder::new()
+        .user_agent(concat!("musso/", env!("CARGO_PKG_VERSION")))
+        .timeout(Duration::from_secs(30))
+        .build()
+        .get(url)
+        .call()
+        .map_err(|e| http_error(&e))?
+        .into_string()
+        .map_err(|e| http_error(&e))
+}
+
+#[cfg(not(feature = "remote-config"))]
+fn fetch(url: &str) -> Result<String> {
+    Err(Error::InvalidConfig {
+        reason: format!(
+            "Can't fetch \"{}\", musso was built without the remote-config feature",
+            url
+        ),
+    })
+}
+
+/// Adds `layer` to `base`, merging the tables both have
+fn merge_tables(base: &mut toml::Table, layer: toml::Table) {
+    for (key, value) in layer {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(layer)) => {
+                merge_tables(base, layer)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Prefix of the library formats naming a preset
+const PRESET_PREFIX: &str = "preset:";
+
+/// Format string `format` stands for, following the presets it names. `whose`
+/// tells where it comes from in the errors
+fn resolve_preset<'a>(
+    presets: &'a HashMap<String, String>,
+    mut format: &'a str,
+    whose: &str,
+) -> Result<&'a str> {
+    let mut seen = HashSet::new();
+
+    while let Some(name) = format.strip_prefix(PRESET_PREFIX) {
+        if !seen.insert(name) {
+            return Err(Error::InvalidConfig {
+                reason: format!("Preset \"{}\" of {} refers to itself", name, whose),
+            });
+        }
+
+        format = presets.get(name).ok_or_else(|| Error::InvalidConfig {
+            reason: format!("Unknown preset \"{}\" in {}", name, whose),
+        })?;
+    }
+
+    Ok(format)
+}
+
+/// Replaces the formats (and compilation formats) of the libraries of
+/// `config` naming a preset with its format string, before they are parsed
+fn resolve_library_presets(config: &mut toml::Table) -> Result<()> {
+    let presets: HashMap<String, String> = match config.get("presets") {
+        Some(presets) => {
+            presets
+                .clone()
+                .try_into()
+                .map_err(|e: toml::de::Error| Error::InvalidConfig {
+                    reason: format!("Invalid presets: {}", e),
+                })?
+        }
+        None => HashMap::new(),
+    };
+
+    let Some(toml::Value::Table(libraries)) = config.get_mut("libraries") else {
+        return Ok(());
+    };
+    for (name, library) in libraries.iter_mut() {
+        for key in ["format", "compilation-format"] {
+            if let Some(toml::Value::String(format)) = library.get_mut(key) {
+                let whose = format!("library \"{}\"", name);
+                *format = resolve_preset(&presets, format, &whose)?.to_owned();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_env<T>(key: &str, value: &str) -> Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value.trim().parse().map_err(|e| Error::InvalidConfig {
+        reason: format!("Invalid value for {}: {}", key, e),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+[watch]
+every = 1
+libraries = ['default']
+
+[libraries.default]
+format = '{artist}/{title}.{ext}'
+folders = ['/music']
+
+[libraries.audio-books]
+format = '{artist}/{album}/{title}.{ext}'
+folders = ['/books']
+recursive = false
+"#,
+        )
+        .unwrap()
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn env_overrides_replace_values() -> Result<()> {
+        let mut config = config();
+        config.apply_overrides(vars(&[
+            ("MUSO_WATCH_EVERY", "5"),
+            ("MUSO_WATCH_LIBRARIES", "default, audio-books"),
+            (
+                "MUSO_DEFAULT_FORMAT",
+                "{artist}/{album}/{track} - {title}.{ext}",
+            ),
+            ("MUSO_LIBRARY_AUDIO_BOOKS_FORMAT", "{album}/{title}.{ext}"),
+            ("MUSO_LIBRARY_AUDIO_BOOKS_FOLDER", "/mnt/books:/srv/books"),
+            ("HOME", "/root"),
+        ]))?;
+
+        assert_eq!(Some(5.0), config.watch.every);
+        assert_eq!(vec!["default", "audio-books"], config.watch.libraries);
+        assert_eq!(
+            "{artist}/{album}/{track} - {title}.{ext}",
+            config.format_of("default").unwrap().as_str()
+        );
+        assert_eq!(
+            "{album}/{title}.{ext}",
+            config.format_of("audio-books").unwrap().as_str()
+        );
+        assert_eq!(
+            vec![PathBuf::from("/mnt/books"), PathBuf::from("/srv/books")],
+            config.libraries["audio-books"].folders
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn roots_replace_libraries() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let format = "{album}/{title}.{ext}".parse()?;
+
+        let config = config().with_roots(vec![dir.path().to_path_buf()], format)?;
+        assert_eq!(vec![AD_HOC_LIBRARY], config.watch.libraries);
+        assert_eq!(Some(AD_HOC_LIBRARY), config.search_library(dir.path()));
+        assert_eq!(
+            "{album}/{title}.{ext}",
+            config.format_of(AD_HOC_LIBRARY).unwrap().as_str()
+        );
+        assert_eq!(None, config.search_library("/music"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn dir_mode_is_octal() {
+        let mut config = config();
+        let library = config.libraries.get_mut("default").unwrap();
+        library.dir_mode = Some(DirMode::Octal("0o775".into()));
+        assert_eq!(Some(0o775), config.dir_mode_of("default").unwrap());
+        assert_eq!(None, config.dir_mode_of("audio-books").unwrap());
+
+        let library = config.libraries.get_mut("default").unwrap();
+        library.dir_mode = Some(DirMode::Octal("rwx".into()));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn dir_mode_is_integer_or_string() {
+        let library: LibraryConfig =
+            toml::from_str("format = '{title}'\nfolders = []\ndir-mode = 0o775").unwrap();
+        assert_eq!(Some(DirMode::Mode(0o775)), library.dir_mode);
+
+        let library: LibraryConfig =
+            toml::from_str("format = '{title}'\nfolders = []\ndir-mode = '775'").unwrap();
+        assert_eq!(Some(DirMode::Octal("775".into())), library.dir_mode);
+    }
+
+    #[test]
+    fn every_accepts_fractions() {
+        let mut config = config();
+        config
+            .apply_overrides(vars(&[("MUSO_WATCH_EVERY", "0.25")]))
+            .unwrap();
+        assert_eq!(Some(0.25), config.watch.every);
+        assert!(config.validate().is_ok());
+
+        let config: Config =
+            toml::from_str("[watch]\nevery = 0.25\nlibraries = []\n[libraries]\n").unwrap();
+        assert_eq!(Some(0.25), config.watch.every);
+
+        for every in [0.0, -1.0, f64::INFINITY, f64::NAN] {
+            let mut config = config.clone();
+            config.watch.every = Some(every);
+            assert!(config.validate().is_err(), "{} was accepted", every);
+        }
+    }
+
+    #[test]
+    fn libraries_are_recursive_by_default() {
+        let config = config();
+
+        assert!(config.is_recursive("default"));
+        assert!(!config.is_recursive("audio-books"));
+    }
+
+    #[test]
+    fn root_for_picks_deepest_folder() {
+        let mut config = config();
+        config.libraries.get_mut("audio-books").unwrap().folders =
+            vec![PathBuf::from("/music/books")];
+
+        assert_eq!(
+            Some(Path::new("/music/books")),
+            config.root_for("/music/books/Author/book.mp3")
+        );
+        assert_eq!(
+            Some(Path::new("/music")),
+            config.root_for("/music/Artist/song.mp3")
+        );
+        assert_eq!(None, config.root_for("/elsewhere/song.mp3"));
+    }
+
+    #[test]
+    fn event_filter_restricts_events() -> Result<()> {
+        let mut config = config();
+        assert!(config.watch.is_handled(WatchEvent::Create));
+        assert!(config.watch.is_handled(WatchEvent::ModifyData));
+
+        config.watch.ignore_data_events = Some(true);
+        assert!(!config.watch.is_handled(WatchEvent::ModifyData));
+
+        let watch: WatchConfig = toml::from_str(
+            "libraries = []\nevent-filter = ['create', 'modify-data']",
+        )
+        .map_err(|e| Error::InvalidConfig {
+            reason: e.to_string(),
+        })?;
+        assert!(watch.is_handled(WatchEvent::ModifyData));
+        assert!(!watch.is_handled(WatchEvent::Rename));
+
+        config.apply_overrides(vars(&[("MUSO_WATCH_EVENT_FILTER", "rename")]))?;
+        assert!(config.watch.is_handled(WatchEvent::Rename));
+        assert!(!config.watch.is_handled(WatchEvent::Create));
+
+        let result = config.apply_overrides(vars(&[("MUSO_WATCH_EVENT_FILTER", "delete")]));
+        assert!(matches!(result, Err(Error::InvalidConfig { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn stdin_and_urls_are_stream_sources() {
+        assert!(is_stream_source(Path::new("-")));
+        assert!(is_stream_source(Path::new(
+            "https://example.com/config.toml"
+        )));
+        assert!(!is_stream_source(Path::new("config.toml")));
+        assert!(!is_stream_source(Path::new("./-")));
+
+        let url = PathBuf::from("http://localhost:1/config.toml");
+        assert!(Config::from_paths(&[url]).is_err());
+    }
+
+    #[test]
+    fn mark_empty_excludes_remove_empty() {
+        let mut config = config();
+        config.watch.mark_empty = Some(".muso-sorted".into());
+        assert!(config.validate().is_ok());
+
+        config.watch.remove_empty = Some(true);
+        assert!(config.validate().is_err());
+
+        config.watch.remove_empty = None;
+        config.watch.mark_empty = Some("../sorted".into());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn invalid_env_override_is_an_error() {
+        let mut config = config();
+        let result = config.apply_overrides(vars(&[("MUSO_WATCH_EVERY", "often")]));
+
+        assert!(matches!(result, Err(Error::InvalidConfig { .. })));
+    }
+
+    #[test]
+    fn folder_shared_by_libraries_is_an_error() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let folder = dir.path().to_str().unwrap();
+
+        let mut config = config();
+        for library in config.libraries.values_mut() {
+            library.folders = vec![PathBuf::from(folder)];
+        }
+
+        match config.sanitize_folders() {
+            Err(err @ Error::DuplicateFolder { .. }) => assert_eq!(
+                format!(
+                    "Folder {} belongs to libraries \"audio-books\" and \"default\"",
+                    folder
+                ),
+                err.to_string()
+            ),
+            other => panic!("expected DuplicateFolder, got {:?}", other.map(|_| ())),
+        }
+
+        // Configs built by hand are checked by validate
+        for library in config.libraries.values_mut() {
+            library.folders = vec![PathBuf::from(folder)];
+        }
+        assert!(matches!(
+            config.validate(),
+            Err(Error::DuplicateFolder { lib_a, lib_b, .. }) if lib_a == "audio-books" && lib_b == "default"
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn later_configs_override_earlier_ones() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let base = dir.path().join("base.toml");
+        fs::write(
+            &base,
+            r#"
+[watch]
+every = 1
+libraries = ['default']
+
+[libraries.default]
+format = '{artist}/{title}.{ext}'
+folders = ['/music']
+exfat-compat = true
+"#,
+        )?;
+
+        let drop_in = dir.path().join("config.d");
+        fs::create_dir(&drop_in)?;
+        fs::write(
+            drop_in.join("10-folders.toml"),
+            "[libraries.default]\nfolders = ['/mnt/music']\n",
+        )?;
+        fs::write(
+            drop_in.join("20-books.toml"),
+            r#"
+[watch]
+libraries = ['default', 'books']
+
+[libraries.books]
+format = '{album}/{title}.{ext}'
+folders = ['/books']
+"#,
+        )?;
+        fs::write(drop_in.join("notes.txt"), "not a config")?;
+
+        let config = Config::parse_layered(&[base, drop_in])?;
+
+        let default = &config.libraries["default"];
+        assert_eq!(vec![PathBuf::from("/mnt/music")], default.folders);
+        assert_eq!("{artist}/{title}.{ext}", default.format.as_str());
+        assert_eq!(Some(true), default.exfat_compat);
+        assert_eq!(vec!["default", "books"], config.watch.libraries);
+        assert_eq!(Some(1.0), config.watch.every);
+        assert!(config.libraries.contains_key("books"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn missing_config_is_generated() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("musso/config.toml");
+
+        let config = Config::load_or_init(&path)?;
+        assert!(path.is_file());
+        assert!(config.libraries.contains_key("default"));
+
+        Ok(())
     }
 }
@@ -1,8 +1,11 @@
 mod parser;
 
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::result::Result as StdResult;
-use std::{path::PathBuf, str::FromStr};
+use std::str::FromStr;
 
+use regex::Regex;
 use serde::de::{self, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
@@ -19,6 +22,58 @@ pub struct ParsedFormat {
     orig_string: String,
 }
 
+/// Articles skipped by `{initial}` unless configured otherwise
+pub const DEFAULT_ARTICLES: &[&str] = &["the", "a", "an"];
+
+/// Settings that change how placeholders are filled
+#[derive(Debug, Clone)]
+pub struct BuildOptions {
+    /// Replace characters that aren't allowed on exFAT
+    pub exfat_compat: bool,
+    /// Matches of these patterns are removed from the title to fill
+    /// `{title_clean}`
+    pub title_clean_patterns: Vec<Regex>,
+    /// Leading words of the artist (ignoring case) skipped by `{initial}`
+    pub strip_articles: Vec<String>,
+    /// Drop folders left empty by missing (or empty) tags instead of failing,
+    /// e.g. `{artist}/{album}/{title}` without album gives `Artist/Title`
+    pub strip_empty_components: bool,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        Self {
+            exfat_compat: false,
+            title_clean_patterns: Vec::new(),
+            strip_articles: DEFAULT_ARTICLES.iter().map(|a| a.to_string()).collect(),
+            strip_empty_components: false,
+        }
+    }
+}
+
+impl BuildOptions {
+    pub fn exfat_compat(exfat_compat: bool) -> Self {
+        Self {
+            exfat_compat,
+            ..Default::default()
+        }
+    }
+}
+
+/// Describes how a path was built from a format string, see
+/// [`ParsedFormat::explain`]
+#[derive(Debug, Clone, Default)]
+pub struct Explanation {
+    /// The format string used to build the path
+    pub format: String,
+    /// Placeholders that were filled, along with the value that was used
+    pub resolved: Vec<(String, String)>,
+    /// Optional placeholders left empty because their tag is missing
+    pub dropped: Vec<String>,
+    /// The resulting path
+    pub path: PathBuf,
+}
+
 impl Default for ParsedFormat {
     fn default() -> Self {
         ParsedFormat::from_str("{artist}/{album}/{track} - {title}.{ext}").unwrap()
@@ -103,28 +158,201 @@ impl Serialize for ParsedFormat {
     }
 }
 
+/// Value of a single placeholder (e.g. `artist` or `track:2`, without the
+/// braces) for `metadata`, before it's made safe for paths. `None` if the tag
+/// is missing
+pub fn placeholder_value(
+    placeholder: &str,
+    metadata: &Metadata,
+    options: &BuildOptions,
+) -> Result<Option<String>> {
+    match parse_format_string(&format!("{{{}}}", placeholder))?.as_slice() {
+        [BasicComponent::Placeholder(p)] => ParsedFormat::get_from_metadata(
+            metadata,
+            Placeholder::Optional(p.clone().into_tag()),
+            options,
+        ),
+        _ => Err(Error::FailedToParse),
+    }
+}
+
 impl ParsedFormat {
+    pub fn as_str(&self) -> &str {
+        &self.orig_string
+    }
+
     pub fn build_path(&self, metadata: &Metadata, exfat_compat: bool) -> Result<PathBuf> {
+        self.build_path_with(metadata, &BuildOptions::exfat_compat(exfat_compat))
+    }
+
+    pub fn build_path_with(&self, metadata: &Metadata, options: &BuildOptions) -> Result<PathBuf> {
+        self.build(metadata, options, None)
+    }
+
+    /// Same as [`ParsedFormat::build_path_with`], but also reports which value
+    /// was used for each placeholder and which optionals were dropped
+    pub fn explain(&self, metadata: &Metadata, options: &BuildOptions) -> Result<Explanation> {
+        let mut explanation = Explanation {
+            format: self.orig_string.clone(),
+            ..Default::default()
+        };
+
+        explanation.path = self.build(metadata, options, Some(&mut explanation))?;
+        Ok(explanation)
+    }
+
+    /// The inverse of [`ParsedFormat::build_path`]: tags read back from the
+    /// last components of `path` (as many as the format has), e.g. `artist`
+    /// and `title` of `Artist - Title.mp3` with `{artist} - {title}.{ext}`.
+    /// Numbers only match digits, placeholders that can't be turned back
+    /// into a tag (like `{initial}`) match anything. `None` if the path
+    /// doesn't match, the extension is ignored when the format has no `{ext}`
+    pub fn parse_path(&self, path: &Path) -> Option<Metadata> {
+        let mut pattern = String::from("^");
+        let mut captured = HashSet::new();
+        let mut has_ext = false;
+
+        for (i, fs_component) in self.fs_components.iter().enumerate() {
+            if i > 0 {
+                pattern.push('/');
+            }
+
+            let (FsComponent::Dir(components) | FsComponent::File(components)) = fs_component;
+            for component in components {
+                let placeholder = match component {
+                    BasicComponent::String(s) => {
+                        pattern.push_str(&regex::escape(s));
+                        continue;
+                    }
+                    BasicComponent::Placeholder(placeholder) => placeholder,
+                };
+
+                let (name, class) = match placeholder.clone().into_tag() {
+                    Tag::Artist => (Some("artist"), r"[^/]+?"),
+                    Tag::Album => (Some("album"), r"[^/]+?"),
+                    Tag::Disc { .. } => (Some("disc"), r"\d+"),
+                    Tag::Track { .. } => (Some("track"), r"\d+"),
+                    Tag::TrackTotal { .. } => (Some("track_total"), r"\d+"),
+                    Tag::Title => (Some("title"), r"[^/]+?"),
+                    Tag::Label => (Some("label"), r"[^/]+?"),
+                    Tag::Catalog => (Some("catalog"), r"[^/]+?"),
+                    Tag::Mbid { short: false } => (Some("mbid"), r"[^/]+?"),
+                    Tag::Ext => {
+                        has_ext = true;
+                        (None, r"[^/.]+")
+                    }
+                    _ => (None, r"[^/]*?"),
+                };
+
+                // A repeated placeholder only captures its first value
+                match name {
+                    Some(name) if captured.insert(name) => {
+                        pattern.push_str(&format!("(?P<{}>{})", name, class))
+                    }
+                    _ => pattern.push_str(&format!("(?:{})", class)),
+                }
+                if placeholder.is_optional() {
+                    pattern.push('?');
+                }
+            }
+        }
+        pattern.push('$');
+
+        let regex = Regex::new(&pattern).ok()?;
+        let ext = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let subject = if has_ext {
+            path.to_path_buf()
+        } else {
+            path.with_extension("")
+        };
+        let components: Vec<_> = subject
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect();
+        let subject = components
+            .get(components.len().checked_sub(self.fs_components.len())?..)?
+            .join("/");
+
+        let captures = regex.captures(&subject)?;
+        let text = |name: &str| captures.name(name).map(|value| value.as_str().to_owned());
+        let number = |name: &str| {
+            captures
+                .name(name)
+                .and_then(|value| value.as_str().parse().ok())
+        };
+
+        Some(Metadata {
+            artist: text("artist"),
+            album: text("album"),
+            disc: number("disc"),
+            track: number("track"),
+            track_total: number("track_total"),
+            title: text("title"),
+            label: text("label"),
+            catalog: text("catalog"),
+            mbid: text("mbid"),
+            ext,
+            ..Default::default()
+        })
+    }
+
+    fn build(
+        &self,
+        metadata: &Metadata,
+        options: &BuildOptions,
+        mut explanation: Option<&mut Explanation>,
+    ) -> Result<PathBuf> {
         let mut path = String::with_capacity(128);
 
         for fs_component in &self.fs_components {
             match fs_component {
                 FsComponent::Dir(dir) => {
+                    let mut dir_name = String::new();
+                    let mut filled = false;
+
                     for component in dir {
                         match component {
                             BasicComponent::String(s) => {
-                                path.push_str(s);
+                                dir_name.push_str(s);
                             }
 
                             BasicComponent::Placeholder(p) => {
-                                let s = Self::get_from_metadata(metadata, *p)?
-                                    .ok_or(Error::OptionalInDir)?;
+                                let s = match Self::get_from_metadata(metadata, p.clone(), options)
+                                {
+                                    Err(Error::MissingTag { .. })
+                                        if options.strip_empty_components =>
+                                    {
+                                        if let Some(explanation) = explanation.as_mut() {
+                                            explanation.dropped.push(p.to_string());
+                                        }
+                                        continue;
+                                    }
+                                    s => s?.ok_or(Error::OptionalInDir)?,
+                                };
+                                filled |= !s.trim().is_empty();
+
+                                if let Some(explanation) = explanation.as_mut() {
+                                    explanation.resolved.push((p.to_string(), s.clone()));
+                                }
 
-                                path.push_str(&Self::replace(s, exfat_compat));
+                                dir_name.push_str(&Self::replace(s, options.exfat_compat));
                             }
                         }
                     }
 
+                    // Only folders made of placeholders alone are dropped,
+                    // literal text like `Singles` is kept
+                    let has_placeholder = dir
+                        .iter()
+                        .any(|component| matches!(component, BasicComponent::Placeholder(_)));
+                    if options.strip_empty_components && has_placeholder && !filled {
+                        continue;
+                    }
+
+                    path.push_str(&dir_name);
                     path.push('/');
                 }
 
@@ -141,8 +369,20 @@ impl ParsedFormat {
                                     required_founds += 1;
                                 }
 
-                                if let Some(s) = Self::get_from_metadata(metadata, *p)? {
-                                    path.push_str(&Self::replace(s, exfat_compat));
+                                match Self::get_from_metadata(metadata, p.clone(), options)? {
+                                    Some(s) => {
+                                        if let Some(explanation) = explanation.as_mut() {
+                                            explanation.resolved.push((p.to_string(), s.clone()));
+                                        }
+
+                                        path.push_str(&Self::replace(s, options.exfat_compat));
+                                    }
+
+                                    None => {
+                                        if let Some(explanation) = explanation.as_mut() {
+                                            explanation.dropped.push(p.to_string());
+                                        }
+                                    }
                                 }
                             }
                         }
@@ -176,7 +416,34 @@ impl ParsedFormat {
         }
     }
 
-    fn get_from_metadata(metadata: &Metadata, pholder: Placeholder) -> Result<Option<String>> {
+    fn clean_title(title: String, patterns: &[Regex]) -> String {
+        let cleaned = patterns.iter().fold(title, |title, pattern| {
+            pattern.replace_all(&title, "").into_owned()
+        });
+
+        cleaned.trim().to_string()
+    }
+
+    /// Uppercase first letter of `artist` after skipping a leading article,
+    /// `#` if it doesn't start with a letter
+    fn initial(artist: &str, articles: &[String]) -> String {
+        let artist = artist.trim();
+        let stripped = artist
+            .split_once(char::is_whitespace)
+            .filter(|(first, _)| articles.iter().any(|a| a.eq_ignore_ascii_case(first)))
+            .map_or(artist, |(_, rest)| rest.trim_start());
+
+        match stripped.chars().find(|c| c.is_alphanumeric()) {
+            Some(c) if c.is_alphabetic() => c.to_uppercase().collect(),
+            _ => "#".to_owned(),
+        }
+    }
+
+    fn get_from_metadata(
+        metadata: &Metadata,
+        pholder: Placeholder,
+        options: &BuildOptions,
+    ) -> Result<Option<String>> {
         let is_optional = pholder.is_optional();
         let tag = pholder.into_tag();
 
@@ -205,13 +472,288 @@ impl ParsedFormat {
                 Err(e) => Err(e),
             },
 
+            Tag::TrackTotal { leading } => match metadata.get_track_total() {
+                Ok(total) => Ok(Some(Self::add_leading_zeros(total, leading))),
+                Err(_) if is_optional => Ok(None),
+                Err(e) => Err(e),
+            },
+
             Tag::Title => match metadata.get_title() {
                 Ok(title) => Ok(Some(title)),
                 Err(_) if is_optional => Ok(None),
                 Err(e) => Err(e),
             },
 
+            Tag::TitleClean => match metadata.get_title() {
+                Ok(title) => Ok(Some(Self::clean_title(
+                    title,
+                    &options.title_clean_patterns,
+                ))),
+                Err(_) if is_optional => Ok(None),
+                Err(e) => Err(e),
+            },
+
+            Tag::Initial => match metadata.get_artist() {
+                Ok(artist) => Ok(Some(Self::initial(&artist, &options.strip_articles))),
+                Err(_) if is_optional => Ok(None),
+                Err(e) => Err(e),
+            },
+
+            Tag::Label => match metadata.get_label() {
+                Ok(label) => Ok(Some(label)),
+                Err(_) if is_optional => Ok(None),
+                Err(e) => Err(e),
+            },
+
+            Tag::Catalog => match metadata.get_catalog() {
+                Ok(catalog) => Ok(Some(catalog)),
+                Err(_) if is_optional => Ok(None),
+                Err(e) => Err(e),
+            },
+
+            Tag::Mbid { short } => match metadata.get_mbid() {
+                Ok(mbid) if short => Ok(Some(mbid.chars().take(8).collect())),
+                Ok(mbid) => Ok(Some(mbid)),
+                Err(_) if is_optional => Ok(None),
+                Err(e) => Err(e),
+            },
+
+            Tag::Codec => Ok(Some(metadata.get_codec())),
+
+            Tag::Mtime { format } => match metadata.get_mtime() {
+                Ok(mtime) => Ok(Some(mtime.format(&format).to_string())),
+                Err(_) if is_optional => Ok(None),
+                Err(e) => Err(e),
+            },
+
             Tag::Ext => Ok(Some(metadata.get_ext())),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn title_clean_strips_patterns() -> Result<()> {
+        let metadata = Metadata {
+            title: Some("Around the World (feat. Somebody) (Radio Edit)".into()),
+            ext: "flac".into(),
+            ..Default::default()
+        };
+
+        let options = BuildOptions {
+            title_clean_patterns: vec![
+                Regex::new(r"\s*\(feat\..*?\)").unwrap(),
+                Regex::new(r"\s*\(Radio Edit\)").unwrap(),
+            ],
+            ..Default::default()
+        };
+
+        let format = ParsedFormat::from_str("{title_clean} [{title}].{ext}")?;
+        let expected =
+            PathBuf::from("Around the World [Around the World (feat_ Somebody) (Radio Edit)].flac");
+
+        assert_eq!(expected, format.build_path_with(&metadata, &options)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn initial_skips_articles() -> Result<()> {
+        let format = ParsedFormat::from_str("{initial}/{artist}/{title}.{ext}")?;
+        let build = |artist: &str, options: &BuildOptions| {
+            let metadata = Metadata {
+                artist: Some(artist.into()),
+                title: Some("Song".into()),
+                ext: "mp3".into(),
+                ..Default::default()
+            };
+            format.build_path_with(&metadata, options)
+        };
+
+        let options = BuildOptions::default();
+        assert_eq!(
+            PathBuf::from("B/The Beatles/Song.mp3"),
+            build("The Beatles", &options)?
+        );
+        assert_eq!(
+            PathBuf::from("P/A Perfect Circle/Song.mp3"),
+            build("A Perfect Circle", &options)?
+        );
+        assert_eq!(PathBuf::from("#/2Pac/Song.mp3"), build("2Pac", &options)?);
+        // A lone article is the name itself
+        assert_eq!(PathBuf::from("T/The/Song.mp3"), build("The", &options)?);
+
+        let options = BuildOptions {
+            strip_articles: vec!["die".into()],
+            ..Default::default()
+        };
+        assert_eq!(
+            PathBuf::from("T/Die Toten Hosen/Song.mp3"),
+            build("Die Toten Hosen", &options)?
+        );
+        assert_eq!(
+            PathBuf::from("T/The Beatles/Song.mp3"),
+            build("The Beatles", &options)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn empty_components_are_stripped() -> Result<()> {
+        let format = ParsedFormat::from_str("{artist}/{album}/{title}.{ext}")?;
+        let options = BuildOptions {
+            strip_empty_components: true,
+            ..Default::default()
+        };
+
+        let metadata = Metadata {
+            artist: Some("Artist".into()),
+            title: Some("Title".into()),
+            ext: "mp3".into(),
+            ..Default::default()
+        };
+        assert!(format.build_path(&metadata, false).is_err());
+        assert_eq!(
+            PathBuf::from("Artist/Title.mp3"),
+            format.build_path_with(&metadata, &options)?
+        );
+
+        let metadata = Metadata {
+            album: Some(" ".into()),
+            ..metadata
+        };
+        assert_eq!(
+            PathBuf::from("Artist/Title.mp3"),
+            format.build_path_with(&metadata, &options)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn placeholder_values_are_raw() -> Result<()> {
+        let metadata = Metadata {
+            artist: Some("Mr. Big".into()),
+            track: Some(3),
+            ext: "mp3".into(),
+            ..Default::default()
+        };
+        let options = BuildOptions::default();
+
+        assert_eq!(
+            Some("Mr. Big".to_owned()),
+            placeholder_value("artist", &metadata, &options)?
+        );
+        assert_eq!(
+            Some("03".to_owned()),
+            placeholder_value("track:2", &metadata, &options)?
+        );
+        assert_eq!(None, placeholder_value("album", &metadata, &options)?);
+        assert!(placeholder_value("artist}/{album", &metadata, &options).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn label_and_catalog_are_filled() -> Result<()> {
+        let format = ParsedFormat::from_str("{label}/{album}/{title} {catalog?}.{ext}")?;
+        let metadata = Metadata {
+            album: Some("Album".into()),
+            title: Some("Title".into()),
+            label: Some("Warp".into()),
+            catalog: Some("WARPCD92".into()),
+            ext: "flac".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            PathBuf::from("Warp/Album/Title WARPCD92.flac"),
+            format.build_path(&metadata, false)?
+        );
+
+        let metadata = Metadata {
+            catalog: None,
+            ..metadata
+        };
+        assert_eq!(
+            PathBuf::from("Warp/Album/Title .flac"),
+            format.build_path(&metadata, false)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn mbid_can_be_shortened() -> Result<()> {
+        let format = ParsedFormat::from_str("{mbid:short}/{track:2} - {title}.{ext}")?;
+        let metadata = Metadata {
+            mbid: Some("3b5ecb9a-4a2e-4df0-b4c8-26d5bbcf9f7a".into()),
+            track: Some(4),
+            title: Some("Title".into()),
+            ext: "flac".into(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            PathBuf::from("3b5ecb9a/04 - Title.flac"),
+            format.build_path(&metadata, false)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn mtime_is_formatted() -> Result<()> {
+        // Mid June 2021, the same year and month in every time zone
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_623_758_400);
+        let metadata = Metadata {
+            title: Some("Episode".into()),
+            ext: "mp3".into(),
+            mtime: Some(mtime),
+            ..Default::default()
+        };
+
+        let format = ParsedFormat::from_str("{mtime:%Y}/{mtime:%m}/{title}.{ext}")?;
+        let expected = PathBuf::from("2021/06/Episode.mp3");
+
+        assert_eq!(expected, format.build_path(&metadata, false)?);
+
+        let untimed = Metadata {
+            mtime: None,
+            ..metadata
+        };
+        let format = ParsedFormat::from_str("{title}{mtime:%Y?}.{ext}")?;
+
+        assert_eq!(
+            PathBuf::from("Episode.mp3"),
+            format.build_path(&untimed, false)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn tags_are_parsed_back_from_paths() -> Result<()> {
+        let format = ParsedFormat::from_str("{artist}/{album}/{track:2} - {title}.{ext}")?;
+        let metadata = format
+            .parse_path(Path::new(
+                "/music/Some Artist/Album - Deluxe/03 - A - B.FLAC",
+            ))
+            .expect("path matches the format");
+
+        assert_eq!(Some("Some Artist"), metadata.artist.as_deref());
+        assert_eq!(Some("Album - Deluxe"), metadata.album.as_deref());
+        assert_eq!(Some(3), metadata.track);
+        assert_eq!(Some("A - B"), metadata.title.as_deref());
+        assert_eq!("flac", metadata.ext);
+
+        assert!(format
+            .parse_path(Path::new("Artist/Album/Title.mp3"))
+            .is_none());
+
+        Ok(())
+    }
+}
@@ -1,20 +1,46 @@
+use chrono::format::{Item, StrftimeItems};
 use nom::branch::alt;
 use nom::bytes::complete::{tag, take_till1};
 use nom::character::complete::{char, digit1};
-use nom::combinator::{map, opt};
+use nom::combinator::{map, opt, verify};
 use nom::multi::many1;
 use nom::sequence::{delimited, tuple};
 use nom::IResult;
+use std::fmt;
 
 use crate::{Error, Result};
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Tag {
     Artist,
     Album,
-    Disc { leading: u8 },
-    Track { leading: u8 },
+    Disc {
+        leading: u8,
+    },
+    Track {
+        leading: u8,
+    },
+    /// Amount of tracks, the `M` of `N/M` track numbers
+    TrackTotal {
+        leading: u8,
+    },
     Title,
+    TitleClean,
+    /// First letter of the artist, leading articles aside
+    Initial,
+    /// Record label
+    Label,
+    /// Catalog number of the release
+    Catalog,
+    /// MusicBrainz release id, only its first 8 characters with `short`
+    Mbid {
+        short: bool,
+    },
+    Codec,
+    /// Modification time of the file, formatted with a strftime string
+    Mtime {
+        format: String,
+    },
     Ext,
 }
 
@@ -25,14 +51,49 @@ impl From<&str> for Tag {
             "album" => Tag::Album,
             "disc" | "disk" => Tag::Disc { leading: 0 },
             "track" => Tag::Track { leading: 0 },
+            "track_total" => Tag::TrackTotal { leading: 0 },
             "title" => Tag::Title,
+            "title_clean" => Tag::TitleClean,
+            "initial" => Tag::Initial,
+            "label" => Tag::Label,
+            "catalog" => Tag::Catalog,
+            "mbid" => Tag::Mbid { short: false },
+            "codec" | "container" => Tag::Codec,
+            "mtime" => Tag::Mtime {
+                format: String::new(),
+            },
             "ext" => Tag::Ext,
             _ => unreachable!(),
         }
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Tag::Artist => write!(f, "artist"),
+            Tag::Album => write!(f, "album"),
+            Tag::Disc { leading: 0 } => write!(f, "disc"),
+            Tag::Disc { leading } => write!(f, "disc:{}", leading),
+            Tag::Track { leading: 0 } => write!(f, "track"),
+            Tag::Track { leading } => write!(f, "track:{}", leading),
+            Tag::TrackTotal { leading: 0 } => write!(f, "track_total"),
+            Tag::TrackTotal { leading } => write!(f, "track_total:{}", leading),
+            Tag::Title => write!(f, "title"),
+            Tag::TitleClean => write!(f, "title_clean"),
+            Tag::Initial => write!(f, "initial"),
+            Tag::Label => write!(f, "label"),
+            Tag::Catalog => write!(f, "catalog"),
+            Tag::Mbid { short: false } => write!(f, "mbid"),
+            Tag::Mbid { short: true } => write!(f, "mbid:short"),
+            Tag::Codec => write!(f, "codec"),
+            Tag::Mtime { format } => write!(f, "mtime:{}", format),
+            Tag::Ext => write!(f, "ext"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Placeholder {
     Required(Tag),
     Optional(Tag),
@@ -55,6 +116,16 @@ impl Placeholder {
         }
     }
 }
+
+impl fmt::Display for Placeholder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Placeholder::Required(tag) => write!(f, "{{{}}}", tag),
+            Placeholder::Optional(tag) => write!(f, "{{{}?}}", tag),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum BasicComponent {
     String(String),
@@ -70,9 +141,18 @@ pub enum FsComponent {
 fn tag_ident(input: &str) -> IResult<&str, &str> {
     alt((
         tag("ext"),
+        tag("codec"),
+        tag("container"),
+        tag("mtime"),
         tag("disc"),
         tag("disk"),
+        tag("track_total"),
         tag("track"),
+        tag("title_clean"),
+        tag("initial"),
+        tag("label"),
+        tag("catalog"),
+        tag("mbid"),
         tag("title"),
         tag("album"),
         tag("artist"),
@@ -88,6 +168,18 @@ fn tag_leading(input: &str) -> IResult<&str, u8> {
     ))
 }
 
+/// `:` followed by a valid strftime format (e.g. `:%Y-%m`)
+fn tag_strftime(input: &str) -> IResult<&str, String> {
+    let (input, (_, format)) = tuple((
+        char(':'),
+        verify(take_till1(|c: char| c == '}' || c == '?'), |s: &str| {
+            StrftimeItems::new(s).all(|item| item != Item::Error)
+        }),
+    ))(input)?;
+
+    Ok((input, format.to_owned()))
+}
+
 fn tag_complete(input: &str) -> IResult<&str, Tag> {
     let (input, output) = tag_ident(input)?;
 
@@ -102,6 +194,26 @@ fn tag_complete(input: &str) -> IResult<&str, Tag> {
             (input, Tag::Track { leading })
         }
 
+        Tag::TrackTotal { .. } => {
+            let (input, leading) = tag_leading(input)?;
+            (input, Tag::TrackTotal { leading })
+        }
+
+        Tag::Mtime { .. } => {
+            let (input, format) = tag_strftime(input)?;
+            (input, Tag::Mtime { format })
+        }
+
+        Tag::Mbid { .. } => {
+            let (input, short) = opt(tag(":short"))(input)?;
+            (
+                input,
+                Tag::Mbid {
+                    short: short.is_some(),
+                },
+            )
+        }
+
         placeholder => (input, placeholder),
     };
 
@@ -112,7 +224,7 @@ fn placeholder(input: &str) -> IResult<&str, Placeholder> {
     let (input, placeholder) = tag_complete(input)?;
 
     let (input, component) = match placeholder {
-        p @ Tag::Ext => (input, Placeholder::Required(p)),
+        p @ (Tag::Ext | Tag::Codec) => (input, Placeholder::Required(p)),
         p => {
             let (input, optional) = opt(char('?'))(input)?;
             let placeholder = if optional.is_some() {
@@ -174,6 +286,29 @@ mod tests {
             Ok(("?}", Tag::Track { leading: 3 }))
         );
         assert_eq!(tag_complete("disk"), Ok(("", Tag::Disc { leading: 0 })));
+        assert_eq!(
+            tag_complete("track_total:2"),
+            Ok(("", Tag::TrackTotal { leading: 2 }))
+        );
+        assert_eq!(tag_complete("title_clean"), Ok(("", Tag::TitleClean)));
+        assert_eq!(tag_complete("initial"), Ok(("", Tag::Initial)));
+        assert_eq!(tag_complete("catalog"), Ok(("", Tag::Catalog)));
+        assert_eq!(
+            tag_complete("mbid:short"),
+            Ok(("", Tag::Mbid { short: true }))
+        );
+        assert_eq!(tag_complete("container"), Ok(("", Tag::Codec)));
+        assert_eq!(
+            tag_complete("mtime:%Y/%m?}"),
+            Ok((
+                "?}",
+                Tag::Mtime {
+                    format: "%Y/%m".into()
+                }
+            ))
+        );
+        assert!(tag_complete("mtime:%Q}").is_err());
+        assert!(tag_complete("mtime}").is_err());
     }
 
     #[test]
@@ -242,6 +377,14 @@ mod tests {
         assert_eq!(parsed, Ok(("", expected)));
     }
 
+    #[test]
+    fn placeholder_display() {
+        for input in ["{artist}", "{album?}", "{disc:2}", "{track:3?}", "{ext}"] {
+            let (_, component) = component(input).unwrap();
+            assert!(matches!(component, BasicComponent::Placeholder(p) if p.to_string() == input));
+        }
+    }
+
     #[test]
     fn without_placeholders() {
         let expected = vec![BasicComponent::String("hello world".into())];
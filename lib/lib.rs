@@ -1,16 +1,49 @@
+#[cfg(feature = "fetch-art")]
+pub mod art;
+#[cfg(feature = "catalogue")]
+pub mod catalogue;
+pub mod checksum;
 pub mod config;
 pub mod format;
+pub mod library;
 pub mod metadata;
+pub mod playlist;
+pub mod profile;
+pub mod prompt;
+pub mod repair;
 pub mod sorting;
+pub mod tag_rename;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod testing;
+pub mod tree;
 pub mod utils;
 pub mod watcher;
 
 use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
 use thiserror::Error;
 
 /// Custom Result type used broadly used across this library
 pub type Result<T> = std::result::Result<T, self::Error>;
 
+/// Exit status of the command line tool for each class of error, they are
+/// part of its interface (see the README)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExitCode {
+    /// Any error without a class of its own
+    Failure = 1,
+    /// Invalid command line arguments
+    Usage = 2,
+    /// Invalid config file, format string or option value
+    Config = 3,
+    /// A folder or file to work on doesn't exist or isn't valid
+    InvalidPath = 4,
+    Io = 5,
+    /// The run was done, but some files failed (or are misplaced)
+    PartialFailure = 6,
+}
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("File type not supported!")]
@@ -34,6 +67,13 @@ pub enum Error {
     #[error("Invalid config file: {reason}")]
     InvalidConfig { reason: String },
 
+    #[error("Folder {folder} belongs to libraries \"{lib_a}\" and \"{lib_b}\"")]
+    DuplicateFolder {
+        folder: String,
+        lib_a: String,
+        lib_b: String,
+    },
+
     #[error("Failed to parse format string")]
     FailedToParse,
 
@@ -43,6 +83,66 @@ pub enum Error {
     #[error("File component must have one required placeholder (except from {{ext}})")]
     RequiredInFile,
 
+    #[error("Invalid MusicBrainz release id \"{id}\"")]
+    InvalidReleaseId { id: String },
+
+    #[error("HTTP error: {reason}")]
+    HttpError { reason: String },
+
+    #[error("Database error: {reason}")]
+    DatabaseError { reason: String },
+
+    #[error("Reading the tags of \"{path}\" took longer than {timeout:?}")]
+    TagReadTimeout { path: String, timeout: Duration },
+
+    #[error("Invalid frame mapping \"{mapping}\", expected FROM=TO (e.g. TPE2=artist)")]
+    InvalidFrameMapping { mapping: String },
+
+    #[error("Refusing to ignore the events of library folder \"{}\"", root.display())]
+    CircularIgnore { root: PathBuf },
+
+    #[error("Invalid destination policy \"{policy}\", expected merge, skip-album or error")]
+    InvalidDestExistsPolicy { policy: String },
+
+    #[error("Invalid long path policy \"{policy}\", expected error or truncate")]
+    InvalidLongPathPolicy { policy: String },
+
+    #[error("Destination \"{path}\" is longer than {max} bytes")]
+    PathTooLong { path: String, max: usize },
+
+    #[error("Invalid watch event \"{event}\", expected create, rename or modify-data")]
+    InvalidWatchEvent { event: String },
+
+    #[error("Destination folder \"{dir}\" already has files")]
+    DestinationPopulated { dir: String },
+
+    #[error("Skipping album, destination folder \"{dir}\" already has files")]
+    AlbumSkipped { dir: String },
+
+    #[error("Skipping, destination folder \"{dir}\" doesn't exist")]
+    NoDestinationDir { dir: String },
+
+    #[error("Invalid dedup preference \"{prefer}\", expected lossless or highest-bitrate")]
+    InvalidDedupPreference { prefer: String },
+
+    #[error("Removed \"{file}\", \"{kept}\" is a better copy of it")]
+    DuplicateRemoved { file: String, kept: String },
+
+    #[error("\"{file}\" doesn't match the format \"{format}\"")]
+    FilenameMismatch { file: String, format: String },
+
+    #[error("Nothing was sorted, \"{file}\" couldn't be ({reason})")]
+    AtomicSortAborted { file: String, reason: String },
+
+    #[error("\"{path}\" is the destination of \"{other}\" too")]
+    SameDestination { path: String, other: String },
+
+    #[error("\"{path}\" only differs in case from \"{existing}\"")]
+    CaseCollision { path: String, existing: String },
+
+    #[error("\"{path}\" doesn't have the contents of the moved file")]
+    ChecksumMismatch { path: String },
+
     #[error("Invalid sha256 sum found while parsing")]
     InvalidSha256,
 
@@ -82,3 +182,40 @@ pub enum Error {
         source: notify::Error,
     },
 }
+
+impl Error {
+    /// Whether a file failing with this was left alone on purpose, and is
+    /// reported as skipped instead of failed
+    pub fn is_skip(&self) -> bool {
+        matches!(
+            self,
+            Error::AlbumSkipped { .. }
+                | Error::NoDestinationDir { .. }
+                | Error::DuplicateRemoved { .. }
+        )
+    }
+
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            Error::InvalidConfig { .. }
+            | Error::DuplicateFolder { .. }
+            | Error::FailedToParse
+            | Error::OptionalInDir
+            | Error::RequiredInFile
+            | Error::InvalidFrameMapping { .. }
+            | Error::CircularIgnore { .. }
+            | Error::InvalidDestExistsPolicy { .. }
+            | Error::InvalidLongPathPolicy { .. }
+            | Error::InvalidWatchEvent { .. }
+            | Error::InvalidDedupPreference { .. } => ExitCode::Config,
+
+            Error::InvalidRoot { .. }
+            | Error::InvalidParent { .. }
+            | Error::ResourceNotFound { .. } => ExitCode::InvalidPath,
+
+            Error::IoError { .. } => ExitCode::Io,
+
+            _ => ExitCode::Failure,
+        }
+    }
+}
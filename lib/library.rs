@@ -0,0 +1,466 @@
+//! Listing the files of a library, honouring `.musignore` files
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+use crate::profile::{self, Phase};
+use crate::sorting::is_junk;
+use crate::utils;
+use crate::Result;
+
+/// Name of the files listing what musso leaves alone in their folder
+pub const IGNORE_FILE: &str = ".musignore";
+
+/// Patterns of the [`IGNORE_FILE`]s found while walking a folder. Each line
+/// of one is a glob (`*`, `**` and `?`), matched against the names of the
+/// entries below its folder, or against their path relative to it when it
+/// contains a `/`. A pattern ending with `/` only matches folders, lines
+/// starting with `#` are comments
+#[derive(Debug, Clone, Default)]
+pub struct Ignore {
+    patterns: Vec<Pattern>,
+}
+
+#[derive(Debug, Clone)]
+struct Pattern {
+    /// Folder of the ignore file
+    base: PathBuf,
+    regex: Regex,
+    /// Matched against the path relative to `base` instead of the name
+    anchored: bool,
+    dir_only: bool,
+}
+
+impl Ignore {
+    /// Adds the patterns of the ignore file of `dir`, if there's one
+    pub fn load(&mut self, dir: &Path) {
+        let file = dir.join(IGNORE_FILE);
+        let Ok(contents) = fs::read_to_string(&file) else {
+            return;
+        };
+
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (glob, dir_only) = match line.strip_suffix('/') {
+                Some(glob) => (glob, true),
+                None => (line, false),
+            };
+            let anchored = glob.contains('/');
+            let glob = glob.trim_start_matches('/');
+
+            match Regex::new(&glob_regex(glob, true)) {
+                Ok(regex) => self.patterns.push(Pattern {
+                    base: dir.to_path_buf(),
+                    regex,
+                    anchored,
+                    dir_only,
+                }),
+                Err(e) => log::error!(
+                    "Invalid pattern \"{}\" in \"{}\" ({})",
+                    line,
+                    file.display(),
+                    e
+                ),
+            }
+        }
+    }
+
+    /// Whether `path` matches a pattern of an ignore file above it
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        let is_dir = path.is_dir();
+
+        self.patterns.iter().any(|pattern| {
+            let Ok(relative) = path.strip_prefix(&pattern.base) else {
+                return false;
+            };
+            if pattern.dir_only && !is_dir {
+                return false;
+            }
+
+            let subject = if pattern.anchored {
+                relative.to_string_lossy()
+            } else {
+                path.file_name().unwrap_or_default().to_string_lossy()
+            };
+            pattern.regex.is_match(&subject)
+        })
+    }
+}
+
+/// Globs excluding files from sorting, matched against their full path. Unlike
+/// in an [`IGNORE_FILE`], `*` and `?` match `/` too, so `*.part.mp3` excludes
+/// files in any folder
+#[derive(Debug, Clone, Default)]
+pub struct Exclude {
+    patterns: Vec<Regex>,
+}
+
+impl Exclude {
+    pub fn new<S: AsRef<str>>(globs: &[S]) -> Self {
+        let patterns = globs
+            .iter()
+            .map(AsRef::as_ref)
+            .filter_map(|glob| match Regex::new(&glob_regex(glob, false)) {
+                Ok(regex) => Some(regex),
+                Err(e) => {
+                    log::error!("Invalid exclude pattern \"{}\" ({})", glob, e);
+                    None
+                }
+            })
+            .collect();
+
+        Self { patterns }
+    }
+
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        let path = path.to_string_lossy();
+        self.patterns.iter().any(|pattern| pattern.is_match(&path))
+    }
+}
+
+/// Anchored regular expression matching the same as `glob`, whose `*` and `?`
+/// don't match `/` when `separator` is set. Globs only have `*`, `**`, `?`
+/// and `[...]` classes (negated with `[!...]` or `[^...]`), anything else
+/// matches itself, an unclosed `[` too
+fn glob_regex(glob: &str, separator: bool) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' if separator => regex.push_str("[^/]*"),
+            '*' => regex.push_str(".*"),
+            '?' if separator => regex.push_str("[^/]"),
+            '?' => regex.push('.'),
+            '[' if glob_class(chars.clone()).is_some() => {
+                let (negated, class) = glob_class(&mut chars).unwrap_or_default();
+                regex.push('[');
+                if negated {
+                    regex.push('^');
+                    if separator {
+                        regex.push('/');
+                    }
+                }
+                for c in class {
+                    match c {
+                        '-' => regex.push('-'),
+                        c => regex.push_str(&regex::escape(&c.to_string())),
+                    }
+                }
+                regex.push(']');
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    regex.push('$');
+    regex
+}
+
+/// Whether the class after a `[` is negated and its characters, consuming
+/// them up to the closing `]`. A `]` right after the `[` is part of the class
+fn glob_class(chars: impl Iterator<Item = char>) -> Option<(bool, Vec<char>)> {
+    let mut class = Vec::new();
+    let mut negated = false;
+
+    for c in chars {
+        match c {
+            '!' | '^' if class.is_empty() && !negated => negated = true,
+            ']' if !class.is_empty() => return Some((negated, class)),
+            c => class.push(c),
+        }
+    }
+
+    None
+}
+
+/// What walking a folder leaves out: the entries matched by an
+/// [`IGNORE_FILE`] and the ones set here. Every walk of a library goes
+/// through it, so they all see the same files
+#[derive(Debug, Clone, Default)]
+pub struct Walker {
+    /// Leave out hidden files and folders
+    pub skip_hidden: bool,
+    /// Amount of folders below the walked one that are entered, `0` only
+    /// walks its own files. Unlimited if not set
+    pub max_depth: Option<usize>,
+    /// File names (e.g. `.DS_Store`) left out, they don't keep a folder from
+    /// being empty either
+    pub junk: Vec<String>,
+    pub exclude: Exclude,
+    /// Yield every file, not only the audio ones
+    pub any_file: bool,
+}
+
+impl Walker {
+    /// Audio files (or every file, see [`Walker::any_file`]) under `dir`,
+    /// breadth first so shallower files come before deeper ones. Folders are
+    /// read as the walk reaches them, so files moved meanwhile are seen where
+    /// they are then
+    pub fn walk(&self, dir: &Path) -> Walk<'_> {
+        Walk {
+            walker: self,
+            dir: dir.to_path_buf(),
+            ignore: Ignore::default(),
+            queue: VecDeque::from([dir.to_path_buf()]),
+            on_empty: None,
+        }
+    }
+
+    /// Like [`Walker::walk`], but failing if `dir` can't be read
+    pub fn files(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        fs::read_dir(dir)?;
+        Ok(self.walk(dir).collect())
+    }
+
+    /// Whether a walk of `root` leaves out `path`, or a folder between them
+    pub fn leaves_out(&self, root: &Path, path: &Path) -> bool {
+        let Ok(relative) = path.strip_prefix(root) else {
+            return false;
+        };
+
+        let mut ignore = Ignore::default();
+        let mut entry = root.to_path_buf();
+        for component in relative.components() {
+            ignore.load(&entry);
+            entry.push(component);
+
+            if is_junk(&entry, &self.junk) || self.skips(&entry, &ignore) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Whether `entry` is left out, junk aside
+    fn skips(&self, entry: &Path, ignore: &Ignore) -> bool {
+        if self.skip_hidden && utils::is_hidden(entry) {
+            log::debug!("Skipping hidden path: \"{}\"", entry.display());
+            return true;
+        }
+
+        if ignore.is_ignored(entry) {
+            log::debug!("Skipping ignored path: \"{}\"", entry.display());
+            return true;
+        }
+
+        if self.exclude.is_excluded(entry) {
+            log::debug!("Skipping excluded path: \"{}\"", entry.display());
+            return true;
+        }
+
+        false
+    }
+
+    /// Whether the folder `path` of a walk of `dir` is entered
+    fn enters(&self, dir: &Path, path: &Path) -> bool {
+        let Some(max_depth) = self.max_depth else {
+            return true;
+        };
+
+        let depth = path
+            .strip_prefix(dir)
+            .map_or(0, |below| below.components().count());
+        if depth > max_depth {
+            log::debug!("Not descending below max depth: \"{}\"", path.display());
+            return false;
+        }
+
+        true
+    }
+}
+
+type OnEmpty<'a> = Box<dyn FnMut(&Path) + 'a>;
+
+/// Iterator over the files of a folder, see [`Walker::walk`]
+pub struct Walk<'a> {
+    walker: &'a Walker,
+    dir: PathBuf,
+    ignore: Ignore,
+    queue: VecDeque<PathBuf>,
+    on_empty: Option<OnEmpty<'a>>,
+}
+
+impl<'a> Walk<'a> {
+    /// Calls `on_empty` with the folders found empty (junk aside), the walked
+    /// one included
+    pub fn on_empty(mut self, on_empty: impl FnMut(&Path) + 'a) -> Self {
+        self.on_empty = Some(Box::new(on_empty));
+        self
+    }
+
+    /// Queues the entries of `dir` that aren't left out
+    fn visit(&mut self, dir: &Path) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::error!("{}", e);
+                return;
+            }
+        };
+        self.ignore.load(dir);
+
+        let mut len = 0;
+        for entry in entries {
+            match entry {
+                Ok(entry) => {
+                    let entry = entry.path();
+                    if is_junk(&entry, &self.walker.junk) {
+                        continue;
+                    }
+
+                    len += 1;
+                    if !self.walker.skips(&entry, &self.ignore) {
+                        self.queue.push_back(entry);
+                    }
+                }
+
+                Err(e) => log::error!("{}", e),
+            }
+        }
+
+        if let (Some(on_empty), 0) = (&mut self.on_empty, len) {
+            on_empty(dir);
+        }
+    }
+}
+
+impl Iterator for Walk<'_> {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<PathBuf> {
+        while let Some(path) = self.queue.pop_front() {
+            let metadata = match profile::time(Phase::Walk, || fs::metadata(&path)) {
+                Ok(metadata) => metadata,
+                // Companion files may have been moved along with their audio file
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    log::error!(
+                        "Couldn't read metadata from: \"{}\" ({})",
+                        path.display(),
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            if metadata.is_file() {
+                if self.walker.any_file || utils::is_audio_file(&path) {
+                    return Some(path);
+                }
+                log::debug!("Skipping non audio file: \"{}\"", path.display());
+            } else if self.walker.enters(&self.dir, &path) {
+                profile::time(Phase::Walk, || self.visit(&path));
+            }
+        }
+
+        None
+    }
+}
+
+/// Files of a music library
+pub struct Library;
+
+impl Library {
+    /// Files under `root` and its subfolders with one of `extensions`
+    /// (compared ignoring case, audio ones or not), in path order. Files
+    /// without an extension aren't sniffed, so they're never part of it.
+    /// Hidden files and folders and the ones matched by an [`IGNORE_FILE`]
+    /// are left out, subfolders that can't be read are logged and skipped
+    pub fn scan(root: &Path, extensions: &[&str]) -> Result<Vec<PathBuf>> {
+        let walker = Walker {
+            skip_hidden: true,
+            any_file: true,
+            ..Default::default()
+        };
+
+        let mut files: Vec<_> = walker
+            .files(root)?
+            .into_iter()
+            .filter(|path| {
+                path.extension().is_some_and(|ext| {
+                    extensions
+                        .iter()
+                        .any(|extension| ext.eq_ignore_ascii_case(extension))
+                })
+            })
+            .collect();
+
+        files.sort();
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FixtureBuilder;
+
+    #[test]
+    fn ignored_files_are_not_scanned() -> Result<()> {
+        let fixture = FixtureBuilder::new()
+            .file("Artist/Album/1 - One.mp3")
+            .file("Artist/Album/2 - Two.flac")
+            .file("Artist/Demos/1 - Demo.mp3")
+            .file("Incoming/01.mp3")
+            .file("Incoming/partial/01.mp3")
+            .file(".hidden/01.mp3")
+            .build()?;
+        let root = fixture.path();
+        fs::write(
+            root.join(IGNORE_FILE),
+            "# Still downloading\nIncoming/partial/\nDemos/\n",
+        )?;
+        fs::write(root.join("Artist/Album").join(IGNORE_FILE), "2 - *\n")?;
+        fs::write(root.join("cover.jpg"), "")?;
+        fs::write(root.join("Incoming/02.wav"), "")?;
+
+        let files = Library::scan(root, &["mp3", "flac"])?;
+        let expected: Vec<_> = ["Artist/Album/1 - One.mp3", "Incoming/01.mp3"]
+            .iter()
+            .map(|path| root.join(path))
+            .collect();
+        assert_eq!(expected, files);
+
+        // Whatever the extensions are, not only audio ones
+        assert_eq!(
+            vec![root.join("Incoming/02.wav")],
+            Library::scan(root, &["WAV"])?
+        );
+        assert!(Library::scan(&root.join("missing"), &["mp3"]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn excluded_paths_match_anywhere() {
+        let exclude = Exclude::new(&["*.part.mp3", "/music/Incoming/**"]);
+
+        assert!(exclude.is_excluded(Path::new("/music/Artist/01.part.mp3")));
+        assert!(exclude.is_excluded(Path::new("/music/Incoming/new/01.mp3")));
+        assert!(!exclude.is_excluded(Path::new("/music/Artist/01.mp3")));
+        assert!(!Exclude::default().is_excluded(Path::new("/music/Artist/01.mp3")));
+    }
+
+    #[test]
+    fn globs_have_classes() {
+        let exclude = Exclude::new(&["*/CD[12]/*", "*/[!A-Z]*.mp3", "*[.mp3"]);
+
+        assert!(exclude.is_excluded(Path::new("/Music/Album/CD1/01.mp3")));
+        assert!(!exclude.is_excluded(Path::new("/Music/Album/CD3/Track.mp3")));
+        assert!(exclude.is_excluded(Path::new("/Music/Album/01.mp3")));
+        assert!(!exclude.is_excluded(Path::new("/Music/Album/Track.mp3")));
+        assert!(exclude.is_excluded(Path::new("/Music/Album/Track[.mp3")));
+    }
+}
@@ -1,19 +1,397 @@
 use id3::TagLike;
 use std::collections::HashMap;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Local};
 
 use crate::{Error, Result};
 
-#[derive(Debug)]
+/// Container of the file at `path` (`flac`, `mp3`, `ogg` or `m4a`) according to
+/// its first bytes, whatever its extension is. `None` if it isn't a supported one
+pub fn sniff_container(path: impl AsRef<Path>) -> Result<Option<&'static str>> {
+    let mut file = File::open(&path)?;
+    // NOTE(erichdongubler): This could be smaller if media types with larger magic bytes
+    // length requirements for `infer` get removed, so let's keep a table below of length
+    // required for each.
+    let mut magic_bytes = [0; 11];
+    if file.read_exact(&mut magic_bytes).is_err() {
+        return Ok(None);
+    }
+
+    let infer = infer::Infer::new();
+    let container = infer
+        .get(&magic_bytes)
+        .and_then(|ftype| match ftype.mime_type() {
+            // Minimum: 4 bytes
+            "audio/x-flac" => Some("flac"),
+            // Minimum: 4 bytes
+            "audio/mpeg" => Some("mp3"),
+            // Minimum: 4 bytes
+            "audio/ogg" => Some("ogg"),
+            // Minimum: 11 bytes (4 normally, 11 to include `m4p`)
+            "audio/m4a" => Some("m4a"),
+            // Unsupported file
+            _ => None,
+        });
+
+    Ok(container)
+}
+
+/// Tags of an audio file as format strings see them, every tag is `None`
+/// when the file doesn't have it (or it can't be parsed as a number)
+#[derive(Debug, Default)]
 pub struct Metadata {
+    /// The album artist, or the track artist if there is none
     pub artist: Option<String>,
     pub album: Option<String>,
     pub disc: Option<u32>,
+    /// Only the number of the track, even if written as `N/M`
     pub track: Option<u32>,
+    /// From the total tag or the `M` of an `N/M` track number
+    pub track_total: Option<u32>,
     pub title: Option<String>,
+    /// Year of the release date (just the year of dates like `2004-05-17`)
+    pub year: Option<i32>,
+    pub genre: Option<String>,
+    /// Record label (`LABEL` or `ORGANIZATION` comments, `TPUB` of mp3s)
+    pub label: Option<String>,
+    /// Catalog number of the release (`CATALOGNUMBER`)
+    pub catalog: Option<String>,
+    /// MusicBrainz release id (`MUSICBRAINZ_ALBUMID`, `MusicBrainz Album Id`
+    /// in mp3s and m4as)
+    pub mbid: Option<String>,
+    /// Length of the audio, only known when the file records it (the
+    /// `STREAMINFO` of flac files, `TLEN` of mp3s and the header of m4as)
+    pub duration: Option<Duration>,
+    /// Extension the file gets when sorted, always set
     pub ext: String,
+    /// Modification time of the file, not a tag
+    pub mtime: Option<SystemTime>,
+}
+
+/// Name of [`Metadata`] for users of [`tags_of`]
+pub type Tags = Metadata;
+
+/// Reads the tags of the audio file at `path` with the same rules used to
+/// sort it (e.g. the album artist taking the place of the artist), without
+/// moving anything
+pub fn tags_of(path: &Path) -> Result<Tags> {
+    Metadata::from_path(path)
+}
+
+/// Everything [`dump_tags`] reads from a file
+#[derive(Debug)]
+pub struct TagDump {
+    /// See [`sniff_container`]
+    pub container: &'static str,
+    /// The tags as format strings see them
+    pub tags: Tags,
+    /// Frames (mp3), comments (flac and ogg) or atoms (m4a) as stored in the
+    /// file, as key and value
+    pub raw: Vec<(String, String)>,
+    /// Properties of the audio stream (e.g. its sample rate)
+    pub properties: Vec<(String, String)>,
+}
+
+/// Reads the tags of the audio file at `path` both as stored and as sorting
+/// sees them, to find out why a file was sorted somewhere
+pub fn dump_tags(path: &Path) -> Result<TagDump> {
+    let container = sniff_container(path)?.ok_or(Error::NotSupported)?;
+    let tags = tags_of(path)?;
+
+    let mut raw = Vec::new();
+    let mut properties = Vec::new();
+    let mut property = |name: &str, value: String| properties.push((name.to_owned(), value));
+
+    match container {
+        "mp3" => {
+            let tag = id3::no_tag_ok(id3::Tag::read_from_path(path))?.unwrap_or_default();
+            for frame in tag.frames() {
+                raw.push(match frame.content().extended_text() {
+                    Some(text) => (
+                        format!("{}:{}", frame.id(), text.description),
+                        text.value.clone(),
+                    ),
+                    None => (frame.id().to_owned(), frame.content().to_string()),
+                });
+            }
+            property("id3 version", tag.version().to_string());
+        }
+
+        "flac" => {
+            let tag = metaflac::Tag::read_from_path(path)?;
+            if let Some(comments) = tag.vorbis_comments() {
+                for (key, values) in &comments.comments {
+                    raw.extend(values.iter().map(|value| (key.clone(), value.clone())));
+                }
+            }
+            raw.sort();
+
+            if let Some(info) = tag.get_streaminfo() {
+                property("sample rate", format!("{} Hz", info.sample_rate));
+                property("channels", info.num_channels.to_string());
+                property("bits per sample", info.bits_per_sample.to_string());
+            }
+        }
+
+        "ogg" => {
+            let mut reader = ogg::reading::PacketReader::new(File::open(path)?);
+            let ((ident, comments, _), _) = lewton::inside_ogg::read_headers(&mut reader)?;
+            raw = comments.comment_list;
+
+            property("sample rate", format!("{} Hz", ident.audio_sample_rate));
+            property("channels", ident.audio_channels.to_string());
+            if ident.bitrate_nominal > 0 {
+                property(
+                    "nominal bitrate",
+                    format!("{} kb/s", ident.bitrate_nominal / 1000),
+                );
+            }
+        }
+
+        _ => {
+            let tag = mp4ameta::Tag::read_from_path(path)?;
+            for (ident, data) in tag.data() {
+                let value = match data.string() {
+                    Some(value) => value.to_owned(),
+                    None => "(binary)".to_owned(),
+                };
+                raw.push((ident.to_string(), value));
+            }
+
+            if let Some(rate) = tag.sample_rate() {
+                property("sample rate", rate.to_string());
+            }
+            if let Some(channels) = tag.channel_config() {
+                property("channels", channels.to_string());
+            }
+            if let Some(bitrate) = tag.avg_bitrate() {
+                property("average bitrate", format!("{} kb/s", bitrate / 1000));
+            }
+        }
+    }
+
+    Ok(TagDump {
+        container,
+        tags,
+        raw,
+        properties,
+    })
+}
+
+/// Writes the tags of `tags` that are set (artist, album, disc, track,
+/// track total, title, year and genre) to the audio file at `path`, the
+/// others are left as they are. Ogg files aren't supported
+pub fn write_tags(path: &Path, tags: &Metadata) -> Result<()> {
+    match sniff_container(path)?.ok_or(Error::NotSupported)? {
+        "mp3" => {
+            let mut tag = id3::no_tag_ok(id3::Tag::read_from_path(path))?.unwrap_or_default();
+
+            if let Some(artist) = &tags.artist {
+                tag.set_artist(artist);
+            }
+            if let Some(album) = &tags.album {
+                tag.set_album(album);
+            }
+            if let Some(disc) = tags.disc {
+                tag.set_disc(disc);
+            }
+            if let Some(track) = tags.track {
+                tag.set_track(track);
+            }
+            if let Some(total) = tags.track_total {
+                tag.set_total_tracks(total);
+            }
+            if let Some(title) = &tags.title {
+                tag.set_title(title);
+            }
+            if let Some(year) = tags.year {
+                tag.set_year(year);
+            }
+            if let Some(genre) = &tags.genre {
+                tag.set_genre(genre);
+            }
+
+            tag.write_to_path(path, id3::Version::Id3v24)?;
+        }
+
+        "flac" => {
+            let mut tag = metaflac::Tag::read_from_path(path)?;
+            let comments = [
+                ("ARTIST", tags.artist.clone()),
+                ("ALBUM", tags.album.clone()),
+                ("DISCNUMBER", tags.disc.map(|disc| disc.to_string())),
+                ("TRACKNUMBER", tags.track.map(|track| track.to_string())),
+                (
+                    "TRACKTOTAL",
+                    tags.track_total.map(|total| total.to_string()),
+                ),
+                ("TITLE", tags.title.clone()),
+                ("DATE", tags.year.map(|year| year.to_string())),
+                ("GENRE", tags.genre.clone()),
+            ];
+
+            for (key, value) in comments {
+                if let Some(value) = value {
+                    tag.set_vorbis(key, vec![value]);
+                }
+            }
+
+            tag.save()?;
+        }
+
+        "m4a" => {
+            let mut tag = mp4ameta::Tag::read_from_path(path)?;
+            // m4a numbers are 16 bits
+            let number = |n: u32| u16::try_from(n).unwrap_or(u16::MAX);
+
+            if let Some(artist) = &tags.artist {
+                tag.set_artist(artist);
+            }
+            if let Some(album) = &tags.album {
+                tag.set_album(album);
+            }
+            if let Some(disc) = tags.disc {
+                tag.set_disc_number(number(disc));
+            }
+            if let Some(track) = tags.track {
+                tag.set_track_number(number(track));
+            }
+            if let Some(total) = tags.track_total {
+                tag.set_total_tracks(number(total));
+            }
+            if let Some(title) = &tags.title {
+                tag.set_title(title);
+            }
+            if let Some(year) = tags.year {
+                tag.set_year(year.to_string());
+            }
+            if let Some(genre) = &tags.genre {
+                tag.set_genre(genre);
+            }
+
+            tag.write_to_path(path)?;
+        }
+
+        _ => return Err(Error::NotSupported),
+    }
+
+    Ok(())
+}
+
+/// What decides which of two copies of a track is better, see
+/// [`audio_quality`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioQuality {
+    pub lossless: bool,
+    /// In bits per second, `None` if it can't be told
+    pub bitrate: Option<u32>,
+}
+
+/// Kilobits per second of the bitrate indexes 1 to 14 of MPEG audio frames
+const MPEG1_LAYER1_KBPS: [u32; 14] = [
+    32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+];
+const MPEG1_LAYER2_KBPS: [u32; 14] = [
+    32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+];
+const MPEG1_LAYER3_KBPS: [u32; 14] = [
+    32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+];
+const MPEG2_LAYER1_KBPS: [u32; 14] = [
+    32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+];
+const MPEG2_LAYER23_KBPS: [u32; 14] = [8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+
+/// Whether the audio file at `path` is lossless (flac) and its bitrate, the
+/// average one for flacs. ALAC m4as count as lossy, they can't be told apart
+/// from AAC ones
+pub fn audio_quality(path: &Path) -> Result<AudioQuality> {
+    let container = sniff_container(path)?.ok_or(Error::NotSupported)?;
+
+    let (lossless, bitrate) = match container {
+        "flac" => {
+            let size = fs::metadata(path)?.len();
+            let bitrate = metaflac::Tag::read_from_path(path)?
+                .get_streaminfo()
+                .filter(|info| info.sample_rate > 0 && info.total_samples > 0)
+                .map(|info| {
+                    let secs = info.total_samples as f64 / info.sample_rate as f64;
+                    (size as f64 * 8.0 / secs) as u32
+                });
+            (true, bitrate)
+        }
+
+        "mp3" => (false, mpeg_frame_bitrate(path)?),
+
+        "ogg" => {
+            let mut reader = ogg::reading::PacketReader::new(File::open(path)?);
+            let ((ident, _, _), _) = lewton::inside_ogg::read_headers(&mut reader)?;
+            (
+                false,
+                u32::try_from(ident.bitrate_nominal)
+                    .ok()
+                    .filter(|&bitrate| bitrate > 0),
+            )
+        }
+
+        _ => (false, mp4ameta::Tag::read_from_path(path)?.avg_bitrate()),
+    };
+
+    Ok(AudioQuality { lossless, bitrate })
+}
+
+/// Bitrate in the header of the first MPEG audio frame of an mp3, after its
+/// ID3v2 tag. Only right for constant bitrate files
+fn mpeg_frame_bitrate(path: &Path) -> Result<Option<u32>> {
+    use std::io::{Seek, SeekFrom};
+
+    let mut file = File::open(path)?;
+    let mut header = [0; 10];
+    file.read_exact(&mut header)?;
+
+    let mut offset = 0;
+    if header.starts_with(b"ID3") {
+        // Syncsafe integer, 7 bits per byte
+        let size = header[6..]
+            .iter()
+            .fold(0, |size, &byte| size << 7 | u64::from(byte & 0x7f));
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        offset = 10 + size + footer;
+    }
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut buf = vec![0; 4096];
+    let len = file.read(&mut buf)?;
+    for frame in buf[..len].windows(3) {
+        if frame[0] != 0xff || frame[1] & 0xe0 != 0xe0 {
+            continue;
+        }
+
+        let version = (frame[1] >> 3) & 0b11;
+        let layer = (frame[1] >> 1) & 0b11;
+        let index = usize::from(frame[2] >> 4);
+        // Reserved values, or the free and bad bitrate indexes
+        if version == 1 || layer == 0 || index == 0 || index == 15 {
+            continue;
+        }
+
+        let kbps = match (version == 3, layer) {
+            (true, 3) => MPEG1_LAYER1_KBPS,
+            (true, 2) => MPEG1_LAYER2_KBPS,
+            (true, _) => MPEG1_LAYER3_KBPS,
+            (false, 3) => MPEG2_LAYER1_KBPS,
+            (false, _) => MPEG2_LAYER23_KBPS,
+        };
+        return Ok(Some(kbps[index - 1] * 1000));
+    }
+
+    Ok(None)
 }
 
 macro_rules! impl_tag_getter {
@@ -30,28 +408,23 @@ macro_rules! impl_tag_getter {
 
 impl Metadata {
     pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
-        let mut file = File::open(&path)?;
-        // NOTE(erichdongubler): This could be smaller if media types with larger magic bytes
-        // length requirements for `infer` get removed, so let's keep a table below of length
-        // required for each.
-        let mut magic_bytes = [0; 11];
-        file.read_exact(&mut magic_bytes)
-            .map_err(|_| Error::NotSupported)?;
-
-        let infer = infer::Infer::new();
-        let ftype = infer.get(&magic_bytes).ok_or(Error::NotSupported)?;
-        match ftype.mime_type() {
-            // Minimum: 4 bytes
-            "audio/x-flac" => Metadata::from_flac_vorbis(&path),
-            // Minimum: 4 bytes
-            "audio/mpeg" => Metadata::from_id3(&path),
-            // Minimum: 4 bytes
-            "audio/ogg" => Metadata::from_ogg_vorbis(&path),
-            // Minimum: 11 bytes (4 normally, 11 to include `m4p`)
-            "audio/m4a" => Metadata::from_m4a(&path),
-            // Unsupported file
+        let mut metadata = match sniff_container(&path)? {
+            Some("flac") => Metadata::from_flac_vorbis(&path),
+            Some("mp3") => Metadata::from_id3(&path),
+            Some("ogg") => Metadata::from_ogg_vorbis(&path),
+            Some("m4a") => Metadata::from_m4a(&path),
             _ => Err(Error::NotSupported),
-        }
+        }?;
+
+        metadata.mtime = fs::metadata(&path).and_then(|m| m.modified()).ok();
+        Ok(metadata)
+    }
+
+    /// Like [`Metadata::from_path`], but failing with
+    /// [`Error::TagReadTimeout`] if reading takes longer than `timeout`. The
+    /// read runs in its own thread, which is left behind on timeout
+    pub fn from_path_with_timeout(path: impl AsRef<Path>, timeout: Duration) -> Result<Self> {
+        with_timeout(path.as_ref(), timeout, Self::from_path)
     }
 
     fn from_id3(path: impl AsRef<Path>) -> Result<Self> {
@@ -60,6 +433,10 @@ impl Metadata {
             Err(err) => err.partial_tag.clone().ok_or(err)?,
         };
 
+        Ok(Self::from_id3_tag(&tag))
+    }
+
+    fn from_id3_tag(tag: &id3::Tag) -> Self {
         let artist = if let Some(artist) = tag.album_artist() {
             Some(artist.to_owned())
         } else {
@@ -68,17 +445,40 @@ impl Metadata {
 
         let album = tag.album().map(|s| s.to_owned());
         let disc = tag.disc();
+        // `TRCK` may be `N/M`, these only keep their own part
         let track = tag.track();
+        let track_total = tag.total_tracks();
         let title = tag.title().map(|s| s.to_owned());
+        let year = tag
+            .year()
+            .or_else(|| tag.date_recorded().map(|date| date.year));
 
-        Ok(Metadata {
+        Metadata {
             artist,
             album,
             disc,
             track,
+            track_total,
             title,
+            year,
+            genre: tag.genre_parsed().map(|genre| genre.into_owned()),
+            label: tag
+                .get("TPUB")
+                .and_then(|frame| frame.content().text())
+                .map(str::to_owned),
+            catalog: Self::id3_extended_text(tag, "CATALOGNUMBER"),
+            mbid: Self::id3_extended_text(tag, "MusicBrainz Album Id"),
+            duration: tag.duration().map(|ms| Duration::from_millis(ms.into())),
             ext: "mp3".to_owned(),
-        })
+            mtime: None,
+        }
+    }
+
+    /// Value of the `TXXX` frame described as `description`
+    fn id3_extended_text(tag: &id3::Tag, description: &str) -> Option<String> {
+        tag.extended_texts()
+            .find(|text| text.description.eq_ignore_ascii_case(description))
+            .map(|text| text.value.clone())
     }
 
     fn from_flac_vorbis(path: impl AsRef<Path>) -> Result<Self> {
@@ -89,7 +489,15 @@ impl Metadata {
             .comments
             .to_owned();
 
-        Self::from_vorbis_comments(comments, "flac")
+        let mut metadata = Self::from_vorbis_comments(comments, "flac")?;
+        metadata.duration = tag
+            .get_streaminfo()
+            .filter(|info| info.sample_rate > 0 && info.total_samples > 0)
+            .map(|info| {
+                Duration::from_secs_f64(info.total_samples as f64 / info.sample_rate as f64)
+            });
+
+        Ok(metadata)
     }
 
     fn from_ogg_vorbis(path: impl AsRef<Path>) -> Result<Self> {
@@ -119,25 +527,80 @@ impl Metadata {
             .and_then(|d| d.first().map(|s| s.parse::<u32>().ok()))
             .flatten();
 
-        let track = comments
+        let (track, number_total) = comments
             .get("TRACKNUMBER")
-            .and_then(|t| t.first().map(|s| s.parse::<u32>().ok()))
-            .flatten();
+            .and_then(|t| t.first())
+            .map(|s| Self::split_track_number(s))
+            .unwrap_or_default();
+
+        let track_total = ["TRACKTOTAL", "TOTALTRACKS"]
+            .iter()
+            .find_map(|key| comments.get(*key).and_then(|t| t.first()))
+            .and_then(|s| s.trim().parse::<u32>().ok())
+            .or(number_total);
 
         let title = comments
             .get("TITLE")
             .and_then(|t| t.first().map(|s| s.to_owned()));
 
+        let year = comments
+            .get("DATE")
+            .and_then(|d| d.first())
+            .and_then(|date| Self::parse_year(date));
+
+        let genre = comments
+            .get("GENRE")
+            .and_then(|g| g.first().map(|s| s.to_owned()));
+
+        let label = ["LABEL", "ORGANIZATION"]
+            .iter()
+            .find_map(|key| comments.get(*key).and_then(|l| l.first()))
+            .map(|s| s.to_owned());
+
+        let catalog = comments
+            .get("CATALOGNUMBER")
+            .and_then(|c| c.first().map(|s| s.to_owned()));
+
+        let mbid = comments
+            .get("MUSICBRAINZ_ALBUMID")
+            .and_then(|m| m.first().map(|s| s.to_owned()));
+
         Ok(Metadata {
             artist,
             album,
             disc,
             track,
+            track_total,
             title,
+            year,
+            genre,
+            label,
+            catalog,
+            mbid,
+            duration: None,
             ext: ext.to_owned(),
+            mtime: None,
         })
     }
 
+    /// Splits a `N/M` track number into the track and the total
+    fn split_track_number(value: &str) -> (Option<u32>, Option<u32>) {
+        let (track, total) = match value.split_once('/') {
+            Some((track, total)) => (track, Some(total)),
+            None => (value, None),
+        };
+
+        (
+            track.trim().parse().ok(),
+            total.and_then(|total| total.trim().parse().ok()),
+        )
+    }
+
+    /// Year of a date like `2004` or `2004-05-17`
+    fn parse_year(date: &str) -> Option<i32> {
+        date.trim().get(..4).and_then(|year| year.parse().ok())
+    }
+
     fn ogg_comment_map(list: Vec<(String, String)>) -> HashMap<String, Vec<String>> {
         let mut map = HashMap::new();
 
@@ -157,10 +620,12 @@ impl Metadata {
             .or_else(|| tag.artist())
             .map(|a| a.to_string());
 
+        // Keep m4b/m4p, but don't trust extensions of other containers
         let ext = path
             .as_ref()
             .extension()
-            .map(|s| s.to_string_lossy().to_string())
+            .map(|s| s.to_string_lossy().to_lowercase())
+            .filter(|ext| ["m4a", "m4b", "m4p", "mp4"].contains(&ext.as_str()))
             .unwrap_or_else(|| "m4a".to_string());
 
         Ok(Metadata {
@@ -168,11 +633,26 @@ impl Metadata {
             album: tag.album().map(|a| a.to_owned()),
             disc: tag.disc_number().map(|this_disk| this_disk.into()),
             track: tag.track_number().map(|this_track| this_track.into()),
+            track_total: tag.total_tracks().map(|total| total.into()),
             title: tag.title().map(|a| a.to_owned()),
+            year: tag.year().and_then(Self::parse_year),
+            genre: tag.genre().map(|g| g.to_owned()),
+            label: Self::m4a_freeform(&tag, "LABEL"),
+            catalog: Self::m4a_freeform(&tag, "CATALOGNUMBER"),
+            mbid: Self::m4a_freeform(&tag, "MusicBrainz Album Id"),
+            duration: tag.duration(),
             ext,
+            mtime: None,
         })
     }
 
+    /// First value of the iTunes freeform atom `name`
+    fn m4a_freeform(tag: &mp4ameta::Tag, name: &str) -> Option<String> {
+        let ident = mp4ameta::FreeformIdent::new("com.apple.iTunes", name);
+        let value = tag.strings_of(&ident).next().map(|s| s.to_owned());
+        value
+    }
+
     pub fn get_artist(&self) -> Result<String> {
         impl_tag_getter!(self, artist)
     }
@@ -189,13 +669,72 @@ impl Metadata {
         impl_tag_getter!(self, track)
     }
 
+    pub fn get_track_total(&self) -> Result<String> {
+        impl_tag_getter!(self, track_total)
+    }
+
     pub fn get_title(&self) -> Result<String> {
         impl_tag_getter!(self, title)
     }
 
+    pub fn get_label(&self) -> Result<String> {
+        impl_tag_getter!(self, label)
+    }
+
+    pub fn get_catalog(&self) -> Result<String> {
+        impl_tag_getter!(self, catalog)
+    }
+
+    pub fn get_mbid(&self) -> Result<String> {
+        impl_tag_getter!(self, mbid)
+    }
+
+    pub fn get_mtime(&self) -> Result<DateTime<Local>> {
+        self.mtime
+            .map(DateTime::from)
+            .ok_or_else(|| Error::MissingTag {
+                tag: "mtime".into(),
+            })
+    }
+
     pub fn get_ext(&self) -> String {
         self.ext.clone()
     }
+
+    /// Uppercase name of the file container (e.g. `FLAC`, `MP3`, `OGG`,
+    /// `MP4`), the same for every extension of a container
+    pub fn get_codec(&self) -> String {
+        match self.ext.to_lowercase().as_str() {
+            "m4a" | "m4b" | "m4p" | "mp4" => "MP4".to_owned(),
+            "oga" => "OGG".to_owned(),
+            ext => ext.to_uppercase(),
+        }
+    }
+}
+
+fn with_timeout<T, F>(path: &Path, timeout: Duration, read: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(PathBuf) -> Result<T> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    let reader_path = path.to_path_buf();
+    thread::Builder::new()
+        .name("musso-tag-reader".into())
+        .spawn(move || {
+            let _ = tx.send(read(reader_path));
+        })?;
+
+    match rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => Err(Error::TagReadTimeout {
+            path: path.display().to_string(),
+            timeout,
+        }),
+        Err(RecvTimeoutError::Disconnected) => {
+            Err(std::io::Error::other("tag reader thread panicked").into())
+        }
+    }
 }
 
 #[cfg(test)]
@@ -246,6 +785,173 @@ mod tests {
         };
     }
 
+    mod tags_of {
+        use std::path::Path;
+
+        use crate::metadata::tags_of;
+        use crate::testing::FixtureBuilder;
+        use crate::Result;
+
+        #[test]
+        fn album_artist_comes_first() -> Result<()> {
+            let tags = tags_of(Path::new("test_files/complete.flac"))?;
+            assert_eq!(Some("Album Artist"), tags.artist.as_deref());
+            assert_eq!(Some(1), tags.track);
+
+            let tags = tags_of(Path::new("test_files/partial.mp3"))?;
+            assert_eq!(Some("Artist"), tags.artist.as_deref());
+            assert_eq!(None, tags.album);
+
+            Ok(())
+        }
+
+        #[test]
+        fn label_and_catalog_are_read() -> Result<()> {
+            let fixture = FixtureBuilder::new()
+                .file("song.mp3")
+                .label("Warp")
+                .catalog("WARPCD92")
+                .file("song.flac")
+                .label("Warp")
+                .catalog("WARPCD92")
+                .build()?;
+
+            for file in ["song.mp3", "song.flac"] {
+                let tags = tags_of(&fixture.path().join(file))?;
+                assert_eq!(Some("Warp"), tags.label.as_deref(), "{}", file);
+                assert_eq!(Some("WARPCD92"), tags.catalog.as_deref(), "{}", file);
+            }
+
+            Ok(())
+        }
+
+        #[test]
+        fn mbid_is_read() -> Result<()> {
+            let mbid = "3b5ecb9a-4a2e-4df0-b4c8-26d5bbcf9f7a";
+            let fixture = FixtureBuilder::new()
+                .file("song.mp3")
+                .mbid(mbid)
+                .file("song.flac")
+                .mbid(mbid)
+                .build()?;
+
+            for file in ["song.mp3", "song.flac"] {
+                let tags = tags_of(&fixture.path().join(file))?;
+                assert_eq!(Some(mbid), tags.mbid.as_deref(), "{}", file);
+            }
+
+            Ok(())
+        }
+    }
+
+    mod audio_quality {
+        use crate::metadata::audio_quality;
+        use crate::testing::FixtureBuilder;
+        use crate::Result;
+
+        #[test]
+        fn flac_is_lossless() -> Result<()> {
+            let fixture = FixtureBuilder::new()
+                .file("song.mp3")
+                .file("song.flac")
+                .build()?;
+
+            let mp3 = audio_quality(&fixture.path().join("song.mp3"))?;
+            assert!(!mp3.lossless);
+            assert!(mp3.bitrate.is_some());
+            assert!(audio_quality(&fixture.path().join("song.flac"))?.lossless);
+
+            Ok(())
+        }
+    }
+
+    mod dump_tags {
+        use crate::metadata::dump_tags;
+        use crate::testing::FixtureBuilder;
+        use crate::Result;
+
+        #[test]
+        fn raw_frames_are_listed() -> Result<()> {
+            let fixture = FixtureBuilder::new()
+                .file("song.mp3")
+                .artist("Artist")
+                .catalog("WARPCD92")
+                .file("song.flac")
+                .artist("Artist")
+                .build()?;
+
+            let dump = dump_tags(&fixture.path().join("song.mp3"))?;
+            assert_eq!("mp3", dump.container);
+            assert!(dump.raw.contains(&("TPE1".into(), "Artist".into())));
+            assert!(dump
+                .raw
+                .contains(&("TXXX:CATALOGNUMBER".into(), "WARPCD92".into())));
+
+            let dump = dump_tags(&fixture.path().join("song.flac"))?;
+            assert_eq!(Some("Artist"), dump.tags.artist.as_deref());
+            assert!(dump.raw.contains(&("ARTIST".into(), "Artist".into())));
+            assert!(dump
+                .properties
+                .contains(&("sample rate".into(), "44100 Hz".into())));
+
+            Ok(())
+        }
+    }
+
+    mod timeout {
+        use std::path::Path;
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        use crate::metadata::{with_timeout, Metadata};
+        use crate::Error;
+
+        #[test]
+        fn slow_reads_time_out() {
+            let start = Instant::now();
+            let result = with_timeout(Path::new("stuck.mp3"), Duration::from_millis(100), |_| {
+                thread::sleep(Duration::from_secs(5));
+                Ok(())
+            });
+
+            assert!(matches!(result, Err(Error::TagReadTimeout { .. })));
+            assert!(start.elapsed() < Duration::from_secs(5));
+        }
+
+        #[test]
+        fn fast_reads_are_returned() {
+            let metadata =
+                Metadata::from_path_with_timeout("test_files/complete.mp3", Duration::from_secs(5))
+                    .unwrap();
+            assert_eq!("Title", metadata.get_title().unwrap());
+        }
+    }
+
+    mod track_total {
+        use id3::TagLike;
+
+        use crate::metadata::Metadata;
+        use crate::Result;
+
+        #[test]
+        fn trck_with_total() -> Result<()> {
+            let mut tag = id3::Tag::new();
+            tag.set_text("TRCK", "7/14");
+
+            let metadata = Metadata::from_id3_tag(&tag);
+            assert_eq!("7", &metadata.get_track()?);
+            assert_eq!("14", &metadata.get_track_total()?);
+
+            Ok(())
+        }
+
+        #[test]
+        fn vorbis_track_number_with_total() {
+            assert_eq!((Some(7), Some(14)), Metadata::split_track_number("7/14"));
+            assert_eq!((Some(7), None), Metadata::split_track_number("7"));
+        }
+    }
+
     define_unit_test_for!(flac);
     define_unit_test_for!(mp3);
     define_unit_test_for!(ogg);
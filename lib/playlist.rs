@@ -0,0 +1,48 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::Result;
+
+/// Reads the entries of an M3U/M3U8 playlist, relative entries are resolved
+/// against the directory containing the playlist
+pub fn read_m3u(path: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)?;
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+
+    Ok(parse_m3u(&contents, base))
+}
+
+fn parse_m3u(contents: &str, base: &Path) -> Vec<PathBuf> {
+    contents
+        .trim_start_matches('\u{feff}')
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|entry| entry.strip_prefix("file://").unwrap_or(entry))
+        .map(|entry| base.join(entry))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_extended_m3u() {
+        let contents = "\u{feff}#EXTM3U\n\
+                        #EXTINF:123,Artist - Title\n\
+                        Artist/Title.flac\n\
+                        \n\
+                        /music/Other.mp3\r\n\
+                        file:///music/Uri.ogg\n";
+
+        let expected = vec![
+            PathBuf::from("/playlists/Artist/Title.flac"),
+            PathBuf::from("/music/Other.mp3"),
+            PathBuf::from("/music/Uri.ogg"),
+        ];
+
+        assert_eq!(expected, parse_m3u(contents, Path::new("/playlists")));
+    }
+}
@@ -0,0 +1,174 @@
+//! Time spent in each phase of sorting, only measured when built with the
+//! `profile` feature and enabled with [`enable`]
+
+#[cfg(feature = "profile")]
+use std::fmt;
+#[cfg(feature = "profile")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "profile")]
+use std::sync::Mutex;
+#[cfg(feature = "profile")]
+use std::time::{Duration, Instant};
+
+/// Part of sorting a folder
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Phase {
+    /// Reading folders and the file system metadata of their entries
+    Walk,
+    /// Reading the tags of a file
+    TagRead,
+    /// Creating the destination folder and moving a file
+    Move,
+}
+
+#[cfg(feature = "profile")]
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "profile")]
+static PROFILE: Mutex<Profile> = Mutex::new(Profile::new());
+
+/// Durations of every timed call, sorting in several threads adds up the time
+/// of each one
+#[cfg(feature = "profile")]
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    walk: Duration,
+    tag_reads: Vec<Duration>,
+    moves: Vec<Duration>,
+}
+
+#[cfg(feature = "profile")]
+impl Profile {
+    const fn new() -> Self {
+        Self {
+            walk: Duration::ZERO,
+            tag_reads: Vec::new(),
+            moves: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, phase: Phase, duration: Duration) {
+        match phase {
+            Phase::Walk => self.walk += duration,
+            Phase::TagRead => self.tag_reads.push(duration),
+            Phase::Move => self.moves.push(duration),
+        }
+    }
+}
+
+/// Nearest-rank percentile of `durations`, which have to be sorted
+#[cfg(feature = "profile")]
+fn percentile(durations: &[Duration], p: usize) -> Option<Duration> {
+    if durations.is_empty() {
+        return None;
+    }
+
+    let rank = (p * durations.len()).div_ceil(100).max(1);
+    Some(durations[rank - 1])
+}
+
+#[cfg(feature = "profile")]
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut tag_reads = self.tag_reads.clone();
+        tag_reads.sort();
+
+        let ms = |duration: Duration| format!("{:.3}ms", duration.as_secs_f64() * 1000.0);
+        let per_file = |durations: &[Duration], p| percentile(durations, p).map_or("-".into(), ms);
+
+        writeln!(
+            f,
+            "{:<10} {:>12} {:>7} {:>10} {:>10}",
+            "phase", "total", "files", "p50", "p99"
+        )?;
+        writeln!(
+            f,
+            "{:<10} {:>12} {:>7} {:>10} {:>10}",
+            "walk",
+            ms(self.walk),
+            "-",
+            "-",
+            "-"
+        )?;
+        writeln!(
+            f,
+            "{:<10} {:>12} {:>7} {:>10} {:>10}",
+            "tag read",
+            ms(tag_reads.iter().sum()),
+            tag_reads.len(),
+            per_file(&tag_reads, 50),
+            per_file(&tag_reads, 99)
+        )?;
+        write!(
+            f,
+            "{:<10} {:>12} {:>7} {:>10} {:>10}",
+            "move",
+            ms(self.moves.iter().sum()),
+            self.moves.len(),
+            "-",
+            "-"
+        )
+    }
+}
+
+/// Starts measuring, clearing what was measured before
+#[cfg(feature = "profile")]
+pub fn enable() {
+    if let Ok(mut profile) = PROFILE.lock() {
+        *profile = Profile::new();
+    }
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Stops measuring and returns what was measured since [`enable`]
+#[cfg(feature = "profile")]
+pub fn finish() -> Profile {
+    ENABLED.store(false, Ordering::SeqCst);
+    PROFILE
+        .lock()
+        .map(|mut profile| std::mem::take(&mut *profile))
+        .unwrap_or_default()
+}
+
+/// Runs `f`, adding the time it took to `phase`
+#[cfg(feature = "profile")]
+pub(crate) fn time<T>(phase: Phase, f: impl FnOnce() -> T) -> T {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return f();
+    }
+
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+
+    if let Ok(mut profile) = PROFILE.lock() {
+        profile.record(phase, elapsed);
+    }
+
+    result
+}
+
+/// Runs `f`, nothing is measured without the `profile` feature
+#[cfg(not(feature = "profile"))]
+#[inline(always)]
+pub(crate) fn time<T>(_phase: Phase, f: impl FnOnce() -> T) -> T {
+    f()
+}
+
+#[cfg(all(test, feature = "profile"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_use_nearest_rank() {
+        let durations: Vec<_> = (1..=200).map(Duration::from_millis).collect();
+
+        assert_eq!(Some(Duration::from_millis(100)), percentile(&durations, 50));
+        assert_eq!(Some(Duration::from_millis(198)), percentile(&durations, 99));
+        assert_eq!(
+            Some(Duration::from_millis(7)),
+            percentile(&durations[6..7], 99)
+        );
+        assert_eq!(None, percentile(&[], 50));
+    }
+}
@@ -0,0 +1,132 @@
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+/// Answer given to a proposed move
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Answer {
+    /// Move this file
+    Yes,
+    /// Leave this file where it is
+    No,
+    /// Move this file and every following one without asking
+    All,
+    /// Stop sorting
+    Quit,
+}
+
+/// Decides whether a file is moved, used by the interactive sorting functions
+pub trait Prompter {
+    fn confirm(&mut self, from: &Path, to: &Path) -> Answer;
+}
+
+/// Asks on `output` and reads the answers (`y/n/a/q`) from `input`, running
+/// out of input is taken as [`Answer::Quit`]
+#[derive(Debug)]
+pub struct InteractivePrompter<R, W> {
+    input: R,
+    output: W,
+}
+
+impl InteractivePrompter<io::StdinLock<'static>, io::Stderr> {
+    pub fn stdio() -> Self {
+        Self::new(io::stdin().lock(), io::stderr())
+    }
+}
+
+impl<R, W> InteractivePrompter<R, W>
+where
+    R: BufRead,
+    W: Write,
+{
+    pub fn new(input: R, output: W) -> Self {
+        Self { input, output }
+    }
+
+    fn ask(&mut self, from: &Path, to: &Path) -> io::Result<Answer> {
+        writeln!(self.output, "\"{}\" → \"{}\"", from.display(), to.display())?;
+
+        loop {
+            write!(self.output, "Move? [y]es/[n]o/[a]ll/[q]uit: ")?;
+            self.output.flush()?;
+
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                writeln!(self.output)?;
+                return Ok(Answer::Quit);
+            }
+
+            match line.trim().chars().next().map(|c| c.to_ascii_lowercase()) {
+                Some('y') => return Ok(Answer::Yes),
+                Some('n') => return Ok(Answer::No),
+                Some('a') => return Ok(Answer::All),
+                Some('q') => return Ok(Answer::Quit),
+                _ => continue,
+            }
+        }
+    }
+
+    /// Asks `question` once, anything but a yes (including running out of
+    /// input) is a no
+    pub fn ask_yes_no(&mut self, question: &str) -> io::Result<bool> {
+        write!(self.output, "{} [y/N] ", question)?;
+        self.output.flush()?;
+
+        let mut line = String::new();
+        if self.input.read_line(&mut line)? == 0 {
+            writeln!(self.output)?;
+            return Ok(false);
+        }
+
+        Ok(matches!(
+            line.trim().to_ascii_lowercase().as_str(),
+            "y" | "yes"
+        ))
+    }
+}
+
+impl<R, W> Prompter for InteractivePrompter<R, W>
+where
+    R: BufRead,
+    W: Write,
+{
+    fn confirm(&mut self, from: &Path, to: &Path) -> Answer {
+        self.ask(from, to).unwrap_or_else(|e| {
+            log::error!("Couldn't read answer ({})", e);
+            Answer::Quit
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_are_read_until_valid() {
+        let input = "\nwhat\nN\nyes\n";
+        let mut output = Vec::new();
+        let mut prompter = InteractivePrompter::new(input.as_bytes(), &mut output);
+
+        let (from, to) = (Path::new("a.mp3"), Path::new("b/a.mp3"));
+        assert_eq!(Answer::No, prompter.confirm(from, to));
+        assert_eq!(Answer::Yes, prompter.confirm(from, to));
+        assert_eq!(Answer::Quit, prompter.confirm(from, to));
+    }
+
+    #[test]
+    fn yes_no_defaults_to_no() -> io::Result<()> {
+        let input = "yes
+
+what
+";
+        let mut output = Vec::new();
+        let mut prompter = InteractivePrompter::new(input.as_bytes(), &mut output);
+
+        assert!(prompter.ask_yes_no("Continue?")?);
+        assert!(!prompter.ask_yes_no("Continue?")?);
+        assert!(!prompter.ask_yes_no("Continue?")?);
+        assert!(!prompter.ask_yes_no("Continue?")?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+//! Sorting again the files that failed because of missing tags, taking the
+//! tags from their file names
+
+use std::borrow::Borrow;
+use std::path::{Path, PathBuf};
+
+use crate::format::ParsedFormat;
+use crate::metadata::{self, Metadata};
+use crate::sorting::{sort_file, Options, SortReport, Status};
+use crate::{Error, Result};
+
+/// Files whose sort failed in `report`
+pub fn failed_files(report: &SortReport) -> Vec<PathBuf> {
+    report
+        .outcomes
+        .iter()
+        .filter(|outcome| outcome.status == Status::Failed)
+        .map(|outcome| outcome.source.clone())
+        .collect()
+}
+
+/// Writes the tags `file` is missing with the values its path gives
+/// according to `filename_format` (see [`ParsedFormat::parse_path`]), the
+/// tags it has are kept. Then sorts it, returning its new path. A dry run
+/// writes nothing and returns the path it would be sorted to
+pub fn repair_file<P>(
+    root: &Path,
+    file: &Path,
+    filename_format: &ParsedFormat,
+    options: &Options<P>,
+) -> Result<PathBuf>
+where
+    P: Borrow<ParsedFormat>,
+{
+    let parsed = filename_format
+        .parse_path(file)
+        .ok_or_else(|| Error::FilenameMismatch {
+            file: file.to_string_lossy().into_owned(),
+            format: filename_format.as_str().to_owned(),
+        })?;
+
+    // Files without any tag can't be read, everything is missing then
+    let mut tags = Metadata::from_path(file).unwrap_or_else(|_| Metadata {
+        ext: parsed.ext.clone(),
+        ..Default::default()
+    });
+    let missing = missing_tags(&tags, parsed);
+
+    for (name, value) in filled(&missing) {
+        if options.dryrun {
+            log::info!(
+                "Would set {} = \"{}\" (dryrun): \"{}\"",
+                name,
+                value,
+                file.display()
+            );
+        } else {
+            log::info!("Setting {} = \"{}\": \"{}\"", name, value, file.display());
+        }
+    }
+
+    if options.dryrun {
+        fill(&mut tags, missing);
+        return options
+            .format
+            .borrow()
+            .build_path_with(&tags, &options.build_options());
+    }
+
+    metadata::write_tags(file, &missing)?;
+    sort_file(root, file, options)
+}
+
+/// Tags of `parsed` that `tags` doesn't have
+fn missing_tags(tags: &Metadata, parsed: Metadata) -> Metadata {
+    Metadata {
+        artist: parsed.artist.filter(|_| tags.artist.is_none()),
+        album: parsed.album.filter(|_| tags.album.is_none()),
+        disc: parsed.disc.filter(|_| tags.disc.is_none()),
+        track: parsed.track.filter(|_| tags.track.is_none()),
+        track_total: parsed.track_total.filter(|_| tags.track_total.is_none()),
+        title: parsed.title.filter(|_| tags.title.is_none()),
+        ..Default::default()
+    }
+}
+
+fn fill(tags: &mut Metadata, missing: Metadata) {
+    tags.artist = tags.artist.take().or(missing.artist);
+    tags.album = tags.album.take().or(missing.album);
+    tags.disc = tags.disc.or(missing.disc);
+    tags.track = tags.track.or(missing.track);
+    tags.track_total = tags.track_total.or(missing.track_total);
+    tags.title = tags.title.take().or(missing.title);
+}
+
+/// Name and value of each tag set in `tags` by [`missing_tags`]
+fn filled(tags: &Metadata) -> Vec<(&'static str, String)> {
+    [
+        ("artist", tags.artist.clone()),
+        ("album", tags.album.clone()),
+        ("disc", tags.disc.map(|disc| disc.to_string())),
+        ("track", tags.track.map(|track| track.to_string())),
+        (
+            "track_total",
+            tags.track_total.map(|total| total.to_string()),
+        ),
+        ("title", tags.title.clone()),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| Some((name, value?)))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::sorting::sort_folder;
+    use crate::testing::FixtureBuilder;
+
+    #[test]
+    fn failed_files_are_sorted_with_tags_from_their_names() -> Result<()> {
+        let fixture = FixtureBuilder::new()
+            .file("Incoming/Artist - 03 - Title.mp3")
+            .file("Incoming/Artist - Notrack.flac")
+            .title("Kept")
+            .build()?;
+        let root = fixture.path();
+        let options = Options {
+            format: ParsedFormat::from_str("{artist}/{track:2} - {title}.{ext}")?,
+            recursive: true,
+            ..Default::default()
+        };
+
+        let report = sort_folder(root, root, &options)?;
+        let failed = failed_files(&report);
+        assert_eq!(2, failed.len());
+
+        let filename_format = ParsedFormat::from_str("{artist} - {track} - {title}.{ext}")?;
+        let mp3 = root.join("Incoming/Artist - 03 - Title.mp3");
+        let dryrun = Options {
+            dryrun: true,
+            ..options.clone()
+        };
+        assert_eq!(
+            PathBuf::from("Artist/03 - Title.mp3"),
+            repair_file(root, &mp3, &filename_format, &dryrun)?
+        );
+        assert!(mp3.exists());
+
+        assert_eq!(
+            PathBuf::from("Artist/03 - Title.mp3"),
+            repair_file(root, &mp3, &filename_format, &options)?
+        );
+        let tags = Metadata::from_path(root.join("Artist/03 - Title.mp3"))?;
+        assert_eq!(Some("Artist"), tags.artist.as_deref());
+        assert_eq!(Some(3), tags.track);
+
+        let flac = root.join("Incoming/Artist - Notrack.flac");
+        assert!(matches!(
+            repair_file(root, &flac, &filename_format, &options),
+            Err(Error::FilenameMismatch { .. })
+        ));
+
+        Ok(())
+    }
+}
@@ -1,9 +1,24 @@
-use std::borrow::Borrow;
+use std::borrow::{Borrow, Cow};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
 use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{fmt, thread};
 use std::{fs, path::PathBuf};
 
-use crate::format::ParsedFormat;
-use crate::metadata::Metadata;
+use serde::{Deserialize, Serialize};
+
+use regex::Regex;
+
+use crate::checksum;
+use crate::format::{self, BuildOptions, ParsedFormat};
+use crate::library::{Exclude, Walker};
+use crate::metadata::{self, AudioQuality, Metadata};
+use crate::profile::{self, Phase};
+use crate::prompt::{Answer, Prompter};
 use crate::utils;
 use crate::{Error, Result};
 
@@ -17,123 +32,2568 @@ where
     pub recursive: bool,
     pub exfat_compat: bool,
     pub remove_empty: bool,
+    /// Write a file with this name (e.g. `.muso-sorted`) in the folders
+    /// emptied by sorting instead of removing them, so they are kept but
+    /// marked as processed. Not used along with `remove_empty`
+    pub mark_empty: Option<String>,
+    /// Amount of folders below the sorted one that are walked, `0` only sorts
+    /// its own files. Unlimited if not set
+    pub max_depth: Option<usize>,
+    /// Don't descend into hidden directories nor sort hidden files
+    pub skip_hidden: bool,
+    /// Permissions given to the directories created while sorting (unix only)
+    pub dir_mode: Option<u32>,
+    /// Log how the destination of each file was built
+    pub explain: bool,
+    /// Restore access and modification times after moving a file, requires
+    /// the `preserve-timestamps` feature
+    pub preserve_timestamps: bool,
+    /// Extensions of companion files (e.g. `lrc`), a companion with the same
+    /// stem as a sorted file is moved next to it, using the same name
+    pub companions: Vec<String>,
+    /// Patterns removed from titles to fill `{title_clean}`
+    pub title_clean_patterns: Vec<Regex>,
+    /// Leading articles skipped by `{initial}`
+    pub strip_articles: Vec<String>,
+    /// Drop folders of the format that missing tags leave empty
+    pub strip_empty_components: bool,
+    /// File names (e.g. `.DS_Store`) that don't keep a directory from being
+    /// removed as empty, they are deleted along with it
+    pub prune_junk: Vec<String>,
+    /// Files and folders left alone while walking a folder
+    pub exclude: Exclude,
+    /// When a file of an album fails, move back the already sorted files of
+    /// the same album (by artist and album tags) so it isn't split
+    pub atomic_album: bool,
+    /// Amount of folders of a file's path below the root kept in front of
+    /// its destination (e.g. `1` keeps `Bootlegs/` of `Bootlegs/1994/x.flac`)
+    pub keep_structure_depth: usize,
+    /// Only rename files inside the folder they are in, using the file name
+    /// built from the format
+    pub rename_only: bool,
+    /// Reading the tags of a file taking longer than this fails it, so a
+    /// broken file can't stall the whole run
+    pub tag_read_timeout: Option<Duration>,
+    /// Make read-only files writable before removing them after copying
+    /// them to another file system
+    pub auto_chmod: bool,
+    /// What happens to the files of an album whose destination folder
+    /// already had files before this run
+    pub dest_exists: DestExists,
+    /// Fail files whose destination only differs in case from an existing
+    /// entry, which is the same path on case-insensitive file systems. Also
+    /// done with `exfat_compat`
+    pub case_insensitive: bool,
+    /// Longest destination allowed (in bytes, including the root), see
+    /// [`LongPathPolicy`]
+    pub max_path_len: Option<usize>,
+    pub long_path_policy: LongPathPolicy,
+    /// Compare the SHA-256 sums of each file before and after moving it,
+    /// putting it back if they differ
+    pub verify_after: bool,
+    /// Sort into a folder whose name only differs by diacritics (`Motorhead`
+    /// for `Motörhead`), so the first spelling seen names it, see
+    /// [`FoldDiacritics`]
+    pub fold_diacritics: Option<FoldDiacritics>,
+    /// Create the destination folders that don't exist yet, files needing
+    /// one are skipped otherwise
+    pub create_dirs: bool,
+    /// Remove the worse of two copies of a track sorted to the same
+    /// destination (ignoring the extension). Only done once the better copy
+    /// is in place, so declining the move or an aborted atomic sort keeps both
+    pub dedup: Option<Dedup>,
+    /// Sort albums with more artists than a threshold with another format,
+    /// for compilations without an album artist
+    pub compilation: Option<Compilation>,
+    /// Called with the outcome of each file as soon as it's processed
+    pub on_outcome: Option<OutcomeHook>,
 }
 
-#[derive(Debug, Clone)]
-pub struct SortReport {
-    pub success: usize,
-    pub total: usize,
-    pub new_paths: Vec<PathBuf>,
+/// Callback receiving the outcome of each processed file, e.g. to show
+/// progress
+#[derive(Clone)]
+pub struct OutcomeHook(Arc<dyn Fn(&FileOutcome) + Send + Sync>);
+
+impl OutcomeHook {
+    pub fn new(hook: impl Fn(&FileOutcome) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(hook))
+    }
 }
 
-pub fn sort_folder<R, D, P>(root: R, dir: D, options: &Options<P>) -> Result<SortReport>
-where
-    R: AsRef<Path>,
-    D: AsRef<Path>,
-    P: Borrow<ParsedFormat>,
-{
-    let mut report = SortReport {
-        success: 0,
-        total: 0,
-        new_paths: Vec::new(),
-    };
+impl fmt::Debug for OutcomeHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OutcomeHook")
+    }
+}
 
-    let dir = dir.as_ref().to_path_buf();
-    let mut stack = vec![dir];
+/// See [`DestExists`]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DestExistsPolicy {
+    /// Move the files in alongside the existing ones
+    #[default]
+    Merge,
+    /// Leave the files of the album where they are
+    SkipAlbum,
+    /// Fail the files of the album
+    Error,
+}
 
-    while let Some(path) = stack.pop() {
-        let metadata = match fs::metadata(&path) {
-            Ok(metadata) => metadata,
-            Err(e) => {
-                log::error!(
-                    "Couldn't read metadata from: \"{}\" ({})",
-                    path.display(),
-                    e
+impl FromStr for DestExistsPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "merge" => Ok(DestExistsPolicy::Merge),
+            "skip-album" => Ok(DestExistsPolicy::SkipAlbum),
+            "error" => Ok(DestExistsPolicy::Error),
+            _ => Err(Error::InvalidDestExistsPolicy {
+                policy: s.to_owned(),
+            }),
+        }
+    }
+}
+
+/// What happens to a file whose destination is longer than
+/// [`Options::max_path_len`]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LongPathPolicy {
+    /// Fail the file
+    #[default]
+    Error,
+    /// Shorten the file name (keeping its extension), components longer
+    /// than the file systems allow are shortened too
+    Truncate,
+}
+
+impl FromStr for LongPathPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "error" => Ok(LongPathPolicy::Error),
+            "truncate" => Ok(LongPathPolicy::Truncate),
+            _ => Err(Error::InvalidLongPathPolicy {
+                policy: s.to_owned(),
+            }),
+        }
+    }
+}
+
+/// Policy for destination folders that already have files, e.g. from a
+/// previous rip of the same album. Whether a folder has files is decided the
+/// first time a file is sorted into it, so the files moved in by the same run
+/// don't count
+#[derive(Debug, Clone, Default)]
+pub struct DestExists {
+    pub policy: DestExistsPolicy,
+    populated: Arc<Mutex<HashMap<PathBuf, bool>>>,
+}
+
+impl DestExists {
+    pub fn new(policy: DestExistsPolicy) -> Self {
+        Self {
+            policy,
+            populated: Arc::default(),
+        }
+    }
+
+    /// Fails with [`Error::AlbumSkipped`] or [`Error::DestinationPopulated`]
+    /// if `file` can't be moved into `dir`
+    fn check(&self, file: &Path, dir: &Path) -> Result<()> {
+        // Files already in their folder don't bring anything new to it
+        if self.policy == DestExistsPolicy::Merge || file.parent() == Some(dir) {
+            return Ok(());
+        }
+
+        let populated = *self
+            .populated
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| has_files(dir));
+
+        if !populated {
+            return Ok(());
+        }
+
+        let dir = dir.to_string_lossy().into_owned();
+        Err(match self.policy {
+            DestExistsPolicy::SkipAlbum => Error::AlbumSkipped { dir },
+            _ => Error::DestinationPopulated { dir },
+        })
+    }
+}
+
+/// Merging of folders whose names only differ by diacritics. A folder takes
+/// the name of an existing one or, if there's none, of the first spelling
+/// planned by the same run, so a dry run or an atomic sort (where nothing is
+/// created yet) gets the same folders as a plain run
+#[derive(Debug, Clone, Default)]
+pub struct FoldDiacritics {
+    /// Name of each folder by its path with the folded name
+    names: Arc<Mutex<HashMap<PathBuf, OsString>>>,
+}
+
+impl FoldDiacritics {
+    /// `new_path` (below `root`) with each folder replaced by the one whose
+    /// name is the same without diacritics
+    fn merged_folders(&self, root: &Path, new_path: PathBuf) -> PathBuf {
+        let (Some(dirs), Some(name)) = (new_path.parent(), new_path.file_name()) else {
+            return new_path;
+        };
+
+        let mut names = self.names.lock().unwrap_or_else(|e| e.into_inner());
+        let mut merged = PathBuf::new();
+        for component in dirs.components() {
+            let component = component.as_os_str();
+            let folded = utils::fold_diacritics(&component.to_string_lossy());
+
+            let parent = root.join(&merged);
+            let chosen = names
+                .entry(merged.join(&folded))
+                .or_insert_with(|| {
+                    existing_folder(&parent, component, &folded)
+                        .unwrap_or_else(|| component.to_owned())
+                })
+                .clone();
+
+            if chosen != component {
+                log::debug!(
+                    "Using \"{}\" for \"{}\"",
+                    chosen.to_string_lossy(),
+                    component.to_string_lossy()
                 );
-                continue;
             }
+            merged.push(chosen);
+        }
+
+        merged.join(name)
+    }
+}
+
+/// Folder of `parent` named `name`, or else one whose name without
+/// diacritics is `folded`
+fn existing_folder(parent: &Path, name: &OsStr, folded: &str) -> Option<OsString> {
+    if parent.join(name).is_dir() {
+        return Some(name.to_owned());
+    }
+
+    fs::read_dir(parent).ok().and_then(|entries| {
+        entries
+            .flatten()
+            .filter(|entry| entry.path().is_dir())
+            .map(|entry| entry.file_name())
+            .find(|entry| utils::fold_diacritics(&entry.to_string_lossy()) == folded)
+    })
+}
+
+/// Which of two copies of a track [`Dedup`] keeps
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DedupPreference {
+    /// A lossless copy, the one with the highest bitrate if both are
+    #[default]
+    Lossless,
+    /// The copy with the highest bitrate, even if it's lossy
+    HighestBitrate,
+}
+
+impl FromStr for DedupPreference {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "lossless" => Ok(DedupPreference::Lossless),
+            "highest-bitrate" => Ok(DedupPreference::HighestBitrate),
+            _ => Err(Error::InvalidDedupPreference {
+                prefer: s.to_owned(),
+            }),
+        }
+    }
+}
+
+impl DedupPreference {
+    /// Whether `a` is strictly better than `b`
+    fn prefers(self, a: AudioQuality, b: AudioQuality) -> bool {
+        match self {
+            DedupPreference::Lossless => (a.lossless, a.bitrate) > (b.lossless, b.bitrate),
+            DedupPreference::HighestBitrate => (a.bitrate, a.lossless) > (b.bitrate, b.lossless),
+        }
+    }
+}
+
+/// Removal of duplicate tracks: when a file is sorted to the destination of
+/// another audio file with the same name (but maybe another extension), the
+/// worse copy is deleted, or moved below `quarantine` keeping its path
+/// relative to the root. On ties the file already there is kept
+#[derive(Debug, Clone, Default)]
+pub struct Dedup {
+    pub prefer: DedupPreference,
+    pub quarantine: Option<PathBuf>,
+    removed: Arc<Mutex<Vec<PathBuf>>>,
+}
+
+impl Dedup {
+    pub fn new(prefer: DedupPreference, quarantine: Option<PathBuf>) -> Self {
+        Self {
+            prefer,
+            quarantine,
+            removed: Arc::default(),
+        }
+    }
+
+    /// Files removed (or that would have been in a dry run) so far
+    pub fn removed(&self) -> Vec<PathBuf> {
+        self.removed
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Compares `file` to the copies at its destination `new_path`, nothing
+    /// is removed until the moves, see [`Duplicates`]
+    fn resolve(&self, root: &Path, file: &Path, new_path: &Path) -> Result<Duplicates> {
+        let mut duplicates = Duplicates::default();
+
+        let dest = root.join(new_path);
+        let (Some(dir), Some(stem)) = (dest.parent(), dest.file_stem()) else {
+            return Ok(duplicates);
+        };
+        let Ok(entries) = fs::read_dir(dir) else {
+            return Ok(duplicates);
         };
 
-        if metadata.is_file() {
-            match sort_file(&root, path, options) {
-                Ok(new_path) => {
-                    report.success += 1;
-                    report.total += 1;
-                    report.new_paths.push(new_path);
-                }
+        let copies: Vec<_> = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path != file && path.file_stem() == Some(stem) && utils::is_audio_file(path)
+            })
+            .collect();
+        if copies.is_empty() {
+            return Ok(duplicates);
+        }
 
+        let quality = metadata::audio_quality(file)?;
+        for copy in copies {
+            let copy_quality = match metadata::audio_quality(&copy) {
+                Ok(copy_quality) => copy_quality,
                 Err(e) => {
-                    log::error!("{}", e);
-                    report.total += 1;
+                    log::warn!("Couldn't compare with \"{}\" ({})", copy.display(), e);
+                    continue;
                 }
+            };
+
+            if self.prefer.prefers(quality, copy_quality) {
+                duplicates.worse.push(copy);
+            } else if duplicates.better.is_none() {
+                duplicates.better = Some(copy);
             }
+        }
 
-            continue;
+        Ok(duplicates)
+    }
+
+    /// Removes `file` if [`Dedup::resolve`] found a better copy of it (along
+    /// with the worse ones), failing with [`Error::DuplicateRemoved`]
+    fn remove_if_worse<P>(
+        &self,
+        root: &Path,
+        file: &Path,
+        duplicates: &Duplicates,
+        options: &Options<P>,
+    ) -> Result<()>
+    where
+        P: Borrow<ParsedFormat>,
+    {
+        let Some(kept) = &duplicates.better else {
+            return Ok(());
+        };
+
+        self.remove_worse(root, kept, duplicates, options);
+        self.remove(root, file, options)?;
+        Err(Error::DuplicateRemoved {
+            file: file.to_string_lossy().into_owned(),
+            kept: kept.to_string_lossy().into_owned(),
+        })
+    }
+
+    /// Moves the worse copy at `destination` (which the move of the better
+    /// one replaces) to the quarantine ahead of the move, returning where
+    /// it went so [`Dedup::put_back`] can undo it if the move fails
+    fn set_aside<P>(
+        &self,
+        root: &Path,
+        destination: &Path,
+        duplicates: &Duplicates,
+        options: &Options<P>,
+    ) -> Result<Option<PathBuf>>
+    where
+        P: Borrow<ParsedFormat>,
+    {
+        let Some(quarantine) = &self.quarantine else {
+            return Ok(None);
+        };
+        if !duplicates.worse.iter().any(|copy| copy == destination) {
+            return Ok(None);
+        }
+
+        let to = quarantined(root, quarantine, destination);
+        if let Some(parent) = to.parent() {
+            utils::create_dir_with_mode(parent, options.dir_mode)?;
         }
+        utils::move_file(destination, &to, options.auto_chmod)?;
+        Ok(Some(to))
+    }
+
+    /// Undoes [`Dedup::set_aside`] after the move it made way for failed
+    fn put_back(set_aside: Option<PathBuf>, destination: &Path) {
+        let Some(set_aside) = set_aside else {
+            return;
+        };
 
-        match fs::read_dir(&path) {
-            Ok(entries) => {
-                let mut len = 0;
+        if let Err(e) = utils::move_file(&set_aside, destination, false) {
+            log::error!("Couldn't put back \"{}\" ({})", destination.display(), e);
+        }
+    }
 
-                for entry in entries {
-                    match entry {
-                        Ok(entry) => {
-                            len += 1;
-                            stack.push(entry.path());
-                        }
+    /// Removes the copies found worse than `kept`, now that it's in place. The
+    /// one at the path of `kept` was already replaced by it
+    fn remove_worse<P>(
+        &self,
+        root: &Path,
+        kept: &Path,
+        duplicates: &Duplicates,
+        options: &Options<P>,
+    ) where
+        P: Borrow<ParsedFormat>,
+    {
+        for copy in &duplicates.worse {
+            let result = if copy == kept {
+                self.record(copy);
+                Ok(())
+            } else {
+                self.remove(root, copy, options)
+            };
 
-                        Err(e) => {
-                            log::error!("{}", e);
-                        }
-                    }
+            match result {
+                Ok(()) => log::info!(
+                    "Removed \"{}\", \"{}\" is better",
+                    copy.display(),
+                    kept.display()
+                ),
+                // Another thread sorting a better copy got to it first
+                Err(Error::IoError { source }) if source.kind() == std::io::ErrorKind::NotFound => {
                 }
+                Err(e) => log::error!("Couldn't remove \"{}\" ({})", copy.display(), e),
+            }
+        }
+    }
 
-                if options.remove_empty && len == 0 {
-                    log::info!("Removing empty folder: \"{}\"", path.display());
-                    if let Err(e) = fs::remove_dir(path) {
-                        log::error!("Couldn't remove dir ({})", e);
+    fn remove<P>(&self, root: &Path, file: &Path, options: &Options<P>) -> Result<()>
+    where
+        P: Borrow<ParsedFormat>,
+    {
+        if !options.dryrun {
+            match &self.quarantine {
+                Some(quarantine) => {
+                    let to = quarantined(root, quarantine, file);
+                    if let Some(parent) = to.parent() {
+                        utils::create_dir_with_mode(parent, options.dir_mode)?;
                     }
+                    utils::move_file(file, &to, options.auto_chmod)?;
                 }
+                None => utils::remove_file(file, options.auto_chmod)?,
             }
+        }
 
-            Err(e) => {
-                log::error!("{}", e);
+        self.record(file);
+        Ok(())
+    }
+
+    fn record(&self, file: &Path) {
+        self.removed
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(file.to_path_buf());
+    }
+}
+
+/// Where `file` goes below `quarantine`, keeping its path relative to `root`
+fn quarantined(root: &Path, quarantine: &Path, file: &Path) -> PathBuf {
+    let relative = file
+        .strip_prefix(root)
+        .unwrap_or_else(|_| Path::new(file.file_name().unwrap_or_default()));
+    quarantine.join(relative)
+}
+
+/// Copies of a file at its destination, as found by [`Dedup::resolve`] while
+/// planning. They are only removed once the better copy is in place, so
+/// declining a move or an aborted atomic sort removes nothing
+#[derive(Debug, Default)]
+struct Duplicates {
+    /// Copies worse than the file, removed after it's moved
+    worse: Vec<PathBuf>,
+    /// A copy better than the file, which is removed instead of moved
+    better: Option<PathBuf>,
+}
+
+/// Fails with [`Error::CaseCollision`] if a component of `new_path` (below
+/// `root`) only differs in case from an entry that isn't `file`
+fn check_case_collision(root: &Path, file: &Path, new_path: &Path) -> Result<()> {
+    let mut dir = root.to_path_buf();
+
+    for component in new_path.components() {
+        let name = component.as_os_str().to_string_lossy();
+        // Nothing below a folder that doesn't exist yet can clash
+        let Ok(entries) = fs::read_dir(&dir) else {
+            break;
+        };
+
+        for entry in entries.flatten() {
+            let existing = entry.file_name();
+            let existing = existing.to_string_lossy();
+
+            if existing != name
+                && existing.to_lowercase() == name.to_lowercase()
+                && entry.path() != file
+            {
+                return Err(Error::CaseCollision {
+                    path: dir.join(&*name).to_string_lossy().into_owned(),
+                    existing: entry.path().to_string_lossy().into_owned(),
+                });
             }
         }
+
+        dir.push(&*name);
     }
 
-    Ok(report)
+    Ok(())
 }
 
-pub fn sort_file<R, F, P>(root: R, file: F, options: &Options<P>) -> Result<PathBuf>
+/// Whether `dir` has an entry that isn't hidden
+fn has_files(dir: &Path) -> bool {
+    fs::read_dir(dir).is_ok_and(|mut entries| {
+        entries.any(|entry| entry.is_ok_and(|entry| !utils::is_hidden(entry.path())))
+    })
+}
+
+impl<P> Default for Options<P>
+where
+    P: Borrow<ParsedFormat> + Default,
+{
+    fn default() -> Self {
+        Self {
+            format: P::default(),
+            dryrun: false,
+            recursive: false,
+            exfat_compat: false,
+            remove_empty: false,
+            mark_empty: None,
+            max_depth: None,
+            skip_hidden: true,
+            dir_mode: None,
+            explain: false,
+            preserve_timestamps: false,
+            companions: Vec::new(),
+            title_clean_patterns: Vec::new(),
+            strip_articles: BuildOptions::default().strip_articles,
+            strip_empty_components: false,
+            prune_junk: Vec::new(),
+            exclude: Exclude::default(),
+            atomic_album: false,
+            keep_structure_depth: 0,
+            rename_only: false,
+            tag_read_timeout: None,
+            auto_chmod: false,
+            dest_exists: DestExists::default(),
+            case_insensitive: false,
+            max_path_len: None,
+            long_path_policy: LongPathPolicy::default(),
+            verify_after: false,
+            fold_diacritics: None,
+            create_dirs: true,
+            dedup: None,
+            compilation: None,
+            on_outcome: None,
+        }
+    }
+}
+
+impl<P> Options<P>
+where
+    P: Borrow<ParsedFormat> + Default,
+{
+    /// Builder starting from the defaults, to only set the options that differ
+    pub fn builder() -> OptionsBuilder<P> {
+        OptionsBuilder::new()
+    }
+}
+
+/// Setters of `Options` fields taking their value
+macro_rules! setters {
+    ($($field:ident: $ty:ty),* $(,)?) => {
+        $(
+            #[doc = concat!("Sets [`Options::", stringify!($field), "`]")]
+            pub fn $field(&mut self, $field: $ty) -> &mut Self {
+                self.options.$field = $field;
+                self
+            }
+        )*
+    };
+}
+
+/// Setters of optional `Options` fields, taking the value they're set to
+macro_rules! optional_setters {
+    ($($field:ident: $ty:ty),* $(,)?) => {
+        $(
+            #[doc = concat!("Sets [`Options::", stringify!($field), "`]")]
+            pub fn $field(&mut self, $field: $ty) -> &mut Self {
+                self.options.$field = Some($field);
+                self
+            }
+        )*
+    };
+}
+
+/// Default of [`Compilation::va_threshold`]
+pub const DEFAULT_VA_THRESHOLD: usize = 3;
+
+/// Format of the albums inferred to be compilations: the ones whose files in
+/// a folder have more than `va_threshold` different artists
+#[derive(Debug, Clone)]
+pub struct Compilation {
+    pub format: ParsedFormat,
+    pub va_threshold: usize,
+    /// Amount of artists of each album, by folder
+    artists: Arc<Mutex<HashMap<PathBuf, HashMap<String, usize>>>>,
+}
+
+impl Compilation {
+    pub fn new(format: ParsedFormat, va_threshold: usize) -> Self {
+        Self {
+            format,
+            va_threshold,
+            artists: Arc::default(),
+        }
+    }
+
+    /// Whether the album of `file` is a compilation. The artists of a folder
+    /// are counted the first time one of its files is sorted, before any of
+    /// them is moved, giving up on tags taking longer than `timeout` to read
+    fn is_compilation(&self, file: &Path, metadata: &Metadata, timeout: Option<Duration>) -> bool {
+        let (Some(album), Some(dir)) = (&metadata.album, file.parent()) else {
+            return false;
+        };
+
+        let mut artists = self.artists.lock().unwrap_or_else(|e| e.into_inner());
+        let albums = artists
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| artists_by_album(dir, timeout));
+
+        albums
+            .get(album)
+            .is_some_and(|&count| count > self.va_threshold)
+    }
+}
+
+/// Amount of different artists of each album among the audio files of `dir`
+fn artists_by_album(dir: &Path, timeout: Option<Duration>) -> HashMap<String, usize> {
+    let mut albums: HashMap<String, HashSet<String>> = HashMap::new();
+
+    for file in fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| utils::is_audio_file(path))
+    {
+        if let Ok(Metadata {
+            album: Some(album),
+            artist: Some(artist),
+            ..
+        }) = read_tags(&file, timeout)
+        {
+            albums.entry(album).or_default().insert(artist);
+        }
+    }
+
+    albums
+        .into_iter()
+        .map(|(album, artists)| (album, artists.len()))
+        .collect()
+}
+
+/// Builds [`Options`] one field at a time, the ones that aren't set keep
+/// their default
+#[derive(Debug, Clone)]
+pub struct OptionsBuilder<P = ParsedFormat>
 where
-    R: AsRef<Path>,
-    F: AsRef<Path>,
     P: Borrow<ParsedFormat>,
 {
-    if options.dryrun {
-        log::info!("Working on (dryrun): \"{}\"", file.as_ref().display());
-    } else {
-        log::info!("Working on: \"{}\"", file.as_ref().display());
+    options: Options<P>,
+}
+
+impl<P> Default for OptionsBuilder<P>
+where
+    P: Borrow<ParsedFormat> + Default,
+{
+    fn default() -> Self {
+        Self {
+            options: Options::default(),
+        }
     }
+}
 
-    let metadata = Metadata::from_path(&file)?;
-    let new_path = options
-        .format
-        .borrow()
-        .build_path(&metadata, options.exfat_compat)?;
+impl<P> OptionsBuilder<P>
+where
+    P: Borrow<ParsedFormat> + Default,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
 
-    if !options.dryrun {
-        let new_path = root.as_ref().join(&new_path);
-        let new_path_parent = new_path.parent().ok_or(Error::InvalidParent {
-            child: new_path.to_string_lossy().into(),
-        })?;
+impl<P> OptionsBuilder<P>
+where
+    P: Borrow<ParsedFormat> + Clone,
+{
+    setters! {
+        format: P,
+        dryrun: bool,
+        recursive: bool,
+        exfat_compat: bool,
+        remove_empty: bool,
+        skip_hidden: bool,
+        explain: bool,
+        preserve_timestamps: bool,
+        companions: Vec<String>,
+        title_clean_patterns: Vec<Regex>,
+        strip_articles: Vec<String>,
+        strip_empty_components: bool,
+        prune_junk: Vec<String>,
+        exclude: Exclude,
+        atomic_album: bool,
+        keep_structure_depth: usize,
+        rename_only: bool,
+        auto_chmod: bool,
+        dest_exists: DestExists,
+        case_insensitive: bool,
+        long_path_policy: LongPathPolicy,
+        verify_after: bool,
+        create_dirs: bool,
+    }
 
-        utils::maybe_create_dir(new_path_parent)?;
-        fs::rename(&file, &new_path)?;
+    optional_setters! {
+        mark_empty: String,
+        max_depth: usize,
+        dir_mode: u32,
+        tag_read_timeout: Duration,
+        max_path_len: usize,
+        fold_diacritics: FoldDiacritics,
+        dedup: Dedup,
+        compilation: Compilation,
+        on_outcome: OutcomeHook,
     }
 
-    log::info!("Item created: \"{}\"", new_path.display());
+    pub fn build(&self) -> Options<P> {
+        self.options.clone()
+    }
+}
+
+impl<P> Options<P>
+where
+    P: Borrow<ParsedFormat>,
+{
+    pub fn build_options(&self) -> BuildOptions {
+        BuildOptions {
+            exfat_compat: self.exfat_compat,
+            title_clean_patterns: self.title_clean_patterns.clone(),
+            strip_articles: self.strip_articles.clone(),
+            strip_empty_components: self.strip_empty_components,
+        }
+    }
+
+    /// Walker leaving out what sorting leaves alone
+    pub fn walker(&self) -> Walker {
+        Walker {
+            skip_hidden: self.skip_hidden,
+            max_depth: self.max_depth,
+            junk: self.prune_junk.clone(),
+            exclude: self.exclude.clone(),
+            any_file: false,
+        }
+    }
+
+    /// Counts a file just sorted into `report` as verified if it was, see
+    /// [`Options::verify_after`]
+    fn count_verified(&self, report: &mut SortReport) {
+        if self.verify_after && !self.dryrun {
+            report.verified += 1;
+        }
+    }
+
+    /// Passes the last outcome of `report` to [`Options::on_outcome`]
+    fn notify(&self, report: &SortReport) {
+        if let (Some(hook), Some(outcome)) = (&self.on_outcome, report.outcomes.last()) {
+            (hook.0)(outcome);
+        }
+    }
+}
+
+/// Result of sorting a set of files, serializable so it can be stored by other
+/// tools, field names are part of the public API
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SortReport {
+    pub success: usize,
+    pub total: usize,
+    pub new_paths: Vec<PathBuf>,
+    /// What happened to each processed file, with absolute paths so moved
+    /// files can be moved back with [`restore`]
+    #[serde(default)]
+    pub outcomes: Vec<FileOutcome>,
+    /// Sorted files whose contents were checked, see [`Options::verify_after`]
+    #[serde(default)]
+    pub verified: usize,
+    /// Files left in place as their destination folder doesn't exist, see
+    /// [`Options::create_dirs`]
+    #[serde(default)]
+    pub skipped_no_dir: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileOutcome {
+    pub source: PathBuf,
+    pub destination: Option<PathBuf>,
+    pub status: Status,
+    pub error: Option<String>,
+    /// Extensions of the companions moved along, named like `source` before
+    /// and like `destination` after
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub companions: Vec<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    Moved,
+    /// Would have been moved, but it was a dry run
+    DryRun,
+    /// Left in place on purpose (e.g. declined in interactive mode)
+    Skipped,
+    Failed,
+    /// Moved, then moved back because another file of its album failed
+    RolledBack,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Moved => "moved",
+            Status::DryRun => "dry-run",
+            Status::Skipped => "skipped",
+            Status::Failed => "failed",
+            Status::RolledBack => "rolled-back",
+        }
+    }
+}
+
+/// Outcomes of a report sharing the value of a placeholder, see
+/// [`SortReport::group_by`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportGroup {
+    /// `None` for the files without the tag (or whose tags can't be read)
+    pub label: Option<String>,
+    /// Moved files, or files that would have been in a dry run
+    pub sorted: usize,
+    pub failed: usize,
+}
+
+/// Files of the same album and source folder whose album artists differ, so
+/// the album would be split, see [`SortReport::album_artist_conflicts`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlbumConflict {
+    pub album: String,
+    /// Folder the files were in before sorting
+    pub folder: PathBuf,
+    /// Each artist value along with the folders its files are sorted into
+    pub artists: Vec<(String, Vec<PathBuf>)>,
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or(path.to_path_buf())
+}
+
+impl SortReport {
+    /// Groups the sorted and failed files by the value of `placeholder` (e.g.
+    /// `artist`) in their tags, ordered by label with the files without it
+    /// first. Only the report is grouped, the tags are read again
+    pub fn group_by(&self, placeholder: &str, options: &BuildOptions) -> Result<Vec<ReportGroup>> {
+        let mut groups: BTreeMap<Option<String>, ReportGroup> = BTreeMap::new();
+
+        for outcome in &self.outcomes {
+            let (file, sorted) = match (outcome.status, &outcome.destination) {
+                (Status::Moved, Some(destination)) => (destination, true),
+                (Status::DryRun, _) => (&outcome.source, true),
+                (Status::Failed, _) => (&outcome.source, false),
+                _ => continue,
+            };
+
+            let label = match Metadata::from_path(file) {
+                Ok(metadata) => format::placeholder_value(placeholder, &metadata, options)?,
+                Err(_) => None,
+            };
+
+            let group = groups.entry(label.clone()).or_insert(ReportGroup {
+                label,
+                sorted: 0,
+                failed: 0,
+            });
+            if sorted {
+                group.sorted += 1;
+            } else {
+                group.failed += 1;
+            }
+        }
+
+        Ok(groups.into_values().collect())
+    }
+
+    /// Albums whose files (coming from the same folder) have different album
+    /// artists (or artists, when missing) and so end up in different
+    /// folders. The tags are read again, from the destination of moved files
+    pub fn album_artist_conflicts(&self) -> Vec<AlbumConflict> {
+        type Artists = BTreeMap<String, BTreeSet<PathBuf>>;
+        let mut albums: BTreeMap<(PathBuf, String), Artists> = BTreeMap::new();
+
+        for outcome in &self.outcomes {
+            let (Some(destination), Some(folder)) = (&outcome.destination, outcome.source.parent())
+            else {
+                continue;
+            };
+
+            let file = match outcome.status {
+                Status::Moved => destination,
+                Status::DryRun => &outcome.source,
+                _ => continue,
+            };
+
+            let Ok(Metadata {
+                album: Some(album),
+                artist: Some(artist),
+                ..
+            }) = Metadata::from_path(file)
+            else {
+                continue;
+            };
+
+            let destination_folder = destination.parent().unwrap_or(destination);
+            albums
+                .entry((folder.to_path_buf(), album))
+                .or_default()
+                .entry(artist)
+                .or_default()
+                .insert(destination_folder.to_path_buf());
+        }
+
+        albums
+            .into_iter()
+            .filter(|(_, artists)| {
+                let folders: BTreeSet<_> = artists.values().flatten().collect();
+                artists.len() > 1 && folders.len() > 1
+            })
+            .map(|((folder, album), artists)| AlbumConflict {
+                album,
+                folder,
+                artists: artists
+                    .into_iter()
+                    .map(|(artist, folders)| (artist, folders.into_iter().collect()))
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Combines two reports, adding up the counts and appending the paths
+    /// and outcomes of `other` after the ones of `self`
+    pub fn merge(mut self, other: SortReport) -> SortReport {
+        self.success += other.success;
+        self.total += other.total;
+        self.new_paths.extend(other.new_paths);
+        self.outcomes.extend(other.outcomes);
+        self.verified += other.verified;
+        self.skipped_no_dir.extend(other.skipped_no_dir);
+        self
+    }
+
+    /// Counts a sorted `file`, now at `new_path` (relative to `root`)
+    pub(crate) fn push_sorted(&mut self, root: &Path, file: &Path, sorted: Sorted, dryrun: bool) {
+        self.success += 1;
+        self.total += 1;
+
+        self.outcomes.push(FileOutcome {
+            source: absolute(file),
+            destination: Some(absolute(&root.join(&sorted.new_path))),
+            status: if dryrun {
+                Status::DryRun
+            } else {
+                Status::Moved
+            },
+            error: None,
+            companions: sorted.companions,
+        });
+
+        self.new_paths.push(sorted.new_path);
+    }
+
+    /// Counts a file that couldn't be sorted
+    pub(crate) fn push_failed(&mut self, file: &Path, error: &Error) {
+        self.total += 1;
+
+        self.outcomes.push(FileOutcome {
+            source: absolute(file),
+            destination: None,
+            status: Status::Failed,
+            error: Some(error.to_string()),
+            companions: Vec::new(),
+        });
+    }
+
+    /// Records a file left in place on purpose, it doesn't count in the total
+    pub(crate) fn push_skipped(&mut self, file: &Path) {
+        self.outcomes.push(FileOutcome {
+            source: absolute(file),
+            destination: None,
+            status: Status::Skipped,
+            error: None,
+            companions: Vec::new(),
+        });
+    }
+
+    /// Records a file left in place because of `error`, which doesn't make
+    /// it fail
+    pub(crate) fn push_skipped_by(&mut self, file: &Path, error: &Error) {
+        log::info!("{}", error);
+        if let Error::NoDestinationDir { .. } = error {
+            self.skipped_no_dir.push(absolute(file));
+        }
+
+        self.push_skipped(file);
+    }
+
+    /// Writes one row per outcome (`source,destination,status,error`) after
+    /// a header, quoting fields as described in RFC 4180
+    pub fn write_csv(&self, mut writer: impl Write) -> std::io::Result<()> {
+        writeln!(writer, "source,destination,status,error")?;
+
+        for outcome in &self.outcomes {
+            let destination = outcome
+                .destination
+                .as_ref()
+                .map(|path| path.to_string_lossy())
+                .unwrap_or_default();
+
+            writeln!(
+                writer,
+                "{},{},{},{}",
+                csv_field(&outcome.source.to_string_lossy()),
+                csv_field(&destination),
+                outcome.status.as_str(),
+                csv_field(outcome.error.as_deref().unwrap_or_default())
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+pub fn sort_folder<R, D, P>(root: R, dir: D, options: &Options<P>) -> Result<SortReport>
+where
+    R: AsRef<Path>,
+    D: AsRef<Path>,
+    P: Borrow<ParsedFormat>,
+{
+    walk_folder(root.as_ref(), dir.as_ref(), options, None)
+}
+
+/// Like [`sort_folder`], but every move has to be confirmed by `prompter`
+pub fn sort_folder_interactive<R, D, P>(
+    root: R,
+    dir: D,
+    options: &Options<P>,
+    prompter: &mut dyn Prompter,
+) -> Result<SortReport>
+where
+    R: AsRef<Path>,
+    D: AsRef<Path>,
+    P: Borrow<ParsedFormat>,
+{
+    walk_folder(root.as_ref(), dir.as_ref(), options, Some(prompter))
+}
+
+fn walk_folder<P>(
+    root: &Path,
+    dir: &Path,
+    options: &Options<P>,
+    prompter: Option<&mut dyn Prompter>,
+) -> Result<SortReport>
+where
+    P: Borrow<ParsedFormat>,
+{
+    let walker = options.walker();
+    let files = walker
+        .walk(dir)
+        .on_empty(|empty| remove_or_mark_empty(empty, options));
+
+    Ok(walk_files(root, files, options, prompter))
+}
+
+/// Removes the empty folder `dir` if `options.remove_empty` is set, or marks
+/// it with `options.mark_empty`
+fn remove_or_mark_empty<P>(dir: &Path, options: &Options<P>)
+where
+    P: Borrow<ParsedFormat>,
+{
+    if options.remove_empty {
+        log::info!("Removing empty folder: \"{}\"", dir.display());
+        if let Err(e) = remove_dir_with_junk(dir, &options.prune_junk) {
+            log::error!("Couldn't remove dir ({})", e);
+        }
+    } else if let Some(name) = &options.mark_empty {
+        if let Err(e) = mark_empty_dir(dir, name, &options.prune_junk) {
+            log::error!("Couldn't mark empty folder ({})", e);
+        }
+    }
+}
+
+/// Folder below the root where [`sort_folder_atomic`] stages its copies
+pub const STAGING_DIR: &str = ".muso_staging";
+
+/// Like [`sort_folder`], but no file is moved unless all of them can be: the
+/// files are copied to a folder inside [`STAGING_DIR`] first, and only once
+/// every copy is made they are renamed into place and the originals removed.
+/// If a file can't be planned or copied, or two files would be sorted to the
+/// same place, the copies are removed and nothing is changed
+pub fn sort_folder_atomic<R, D, P>(root: R, dir: D, options: &Options<P>) -> Result<SortReport>
+where
+    R: AsRef<Path>,
+    D: AsRef<Path>,
+    P: Borrow<ParsedFormat>,
+{
+    let plan = plan_folder(root, dir, options)?;
+    sort_planned_atomic(plan, options)
+}
+
+/// Like [`sort_planned`], with the guarantees of [`sort_folder_atomic`]
+pub fn sort_planned_atomic<P>(plan: Plan, options: &Options<P>) -> Result<SortReport>
+where
+    P: Borrow<ParsedFormat>,
+{
+    let root = plan.root.as_path();
+    let staging_root = root.join(STAGING_DIR);
+    let aborted = |file: &Path, e: Error| Error::AtomicSortAborted {
+        file: file.to_string_lossy().into_owned(),
+        reason: e.to_string(),
+    };
+
+    let mut report = SortReport::default();
+    let mut plans: Vec<(PathBuf, Planned)> = Vec::new();
+    // Every file is planned before any is moved, so two files sorted to the
+    // same place would both find it free
+    let mut destinations = HashMap::new();
+    for (file, planned) in plan.files {
+        match planned {
+            Ok(planned) => {
+                if let Some(other) = destinations.insert(planned.new_path.clone(), file.clone()) {
+                    let e = Error::SameDestination {
+                        path: planned.new_path.to_string_lossy().into_owned(),
+                        other: other.to_string_lossy().into_owned(),
+                    };
+                    return Err(aborted(&file, e));
+                }
+                plans.push((file, planned));
+            }
+
+            Err(e) if e.is_skip() => {
+                report.push_skipped_by(&file, &e);
+                options.notify(&report);
+            }
+
+            Err(e) => return Err(aborted(&file, e)),
+        }
+    }
+
+    if options.dryrun {
+        for (file, planned) in plans {
+            match apply_plan(root, &file, planned, options) {
+                Ok(sorted) => report.push_sorted(root, &file, sorted, true),
+                Err(e) if e.is_skip() => report.push_skipped_by(&file, &e),
+                Err(e) => return Err(e),
+            }
+            options.notify(&report);
+        }
+
+        return Ok(report);
+    }
+
+    let staging = staging_root.join(format!(
+        "{}-{}",
+        std::process::id(),
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    ));
+    for (file, planned) in &plans {
+        // Files with a better copy are removed rather than moved
+        if root.join(&planned.new_path) == *file || planned.duplicates.better.is_some() {
+            continue;
+        }
+
+        let staged = std::iter::once((file.clone(), planned.new_path.clone())).chain(
+            planned.companions.iter().map(|ext| {
+                (
+                    file.with_extension(ext),
+                    planned.new_path.with_extension(ext),
+                )
+            }),
+        );
+        for (file, new_path) in staged {
+            if let Err(e) = stage_file(&file, &staging.join(new_path)) {
+                discard_staging(&staging_root, &staging);
+                return Err(aborted(&file, e));
+            }
+        }
+    }
+
+    let mut emptied = Vec::new();
+    for (file, planned) in plans {
+        let new_path = planned.new_path.clone();
+        let companions = planned.companions.clone();
+
+        if let Some(dedup) = &options.dedup {
+            if let Err(e) = dedup.remove_if_worse(root, &file, &planned.duplicates, options) {
+                record_outcome(&mut report, root, &file, Err(e), options);
+                emptied.extend(file.parent().map(Path::to_path_buf));
+                options.notify(&report);
+                continue;
+            }
+        }
+
+        match commit_file(root, &staging, &file, planned, options) {
+            Ok(()) => {
+                log::info!("Item created: \"{}\"", new_path.display());
+                let companions =
+                    commit_companions(root, &staging, &file, &new_path, &companions, options);
+                emptied.extend(file.parent().map(Path::to_path_buf));
+                let sorted = Sorted {
+                    new_path,
+                    companions,
+                };
+                report.push_sorted(root, &file, sorted, false);
+                options.count_verified(&mut report);
+            }
+
+            Err(e) => {
+                log::error!("{}", e);
+                report.push_failed(&file, &e);
+            }
+        }
+        options.notify(&report);
+    }
+    discard_staging(&staging_root, &staging);
+
+    if let Some(dir) = &plan.dir {
+        tidy_planned(dir, &plan.empty, emptied, options);
+    }
+
+    Ok(report)
+}
+
+/// Copies `file` to `staged`, failing if the copy isn't as big as `file`
+fn stage_file(file: &Path, staged: &Path) -> Result<()> {
+    if let Some(parent) = staged.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let expected = fs::metadata(file)?.len();
+    let copied = fs::copy(file, staged)?;
+    if copied != expected || fs::metadata(staged)?.len() != expected {
+        return Err(std::io::Error::other(format!(
+            "copied {} of {} bytes to \"{}\"",
+            copied,
+            expected,
+            staged.display()
+        ))
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Moves the staged copy of `file` into place and removes `file`
+fn commit_file<P>(
+    root: &Path,
+    staging: &Path,
+    file: &Path,
+    planned: Planned,
+    options: &Options<P>,
+) -> Result<()>
+where
+    P: Borrow<ParsedFormat>,
+{
+    let destination = root.join(&planned.new_path);
+    if destination == file {
+        return Ok(());
+    }
+
+    let parent = destination.parent().ok_or(Error::InvalidParent {
+        child: destination.to_string_lossy().into(),
+    })?;
+    utils::create_dir_with_mode(parent, options.dir_mode)?;
+    let set_aside = match &options.dedup {
+        Some(dedup) => dedup.set_aside(root, &destination, &planned.duplicates, options)?,
+        None => None,
+    };
+    if let Err(e) = commit_staged(staging, file, &planned.new_path, &destination, options) {
+        Dedup::put_back(set_aside, &destination);
+        return Err(e);
+    }
+    utils::remove_file(file, options.auto_chmod)?;
+    if let Some(dedup) = &options.dedup {
+        dedup.remove_worse(root, &destination, &planned.duplicates, options);
+    }
+
+    #[cfg(feature = "preserve-timestamps")]
+    if let Some((atime, mtime)) = planned.times {
+        filetime::set_file_times(&destination, atime, mtime)?;
+    }
+
+    Ok(())
+}
+
+/// Moves the staged copy of `file` (at `new_path` below `staging`) to
+/// `destination`, checking its contents with [`Options::verify_after`]
+fn commit_staged<P>(
+    staging: &Path,
+    file: &Path,
+    new_path: &Path,
+    destination: &Path,
+    options: &Options<P>,
+) -> Result<()>
+where
+    P: Borrow<ParsedFormat>,
+{
+    utils::move_file(&staging.join(new_path), destination, false)?;
+    if options.verify_after {
+        let expected = checksum::sha256_file(file)?;
+        if checksum::sha256_file(destination)? != expected {
+            fs::remove_file(destination)?;
+            return Err(Error::ChecksumMismatch {
+                path: destination.to_string_lossy().into_owned(),
+            });
+        }
+        log::debug!("sha256 {}: \"{}\"", expected, destination.display());
+    }
+
+    Ok(())
+}
+
+/// Moves the staged copies of the `companions` of `file` into place next to
+/// it, removing the companions left behind. Returns the ones moved
+fn commit_companions<P>(
+    root: &Path,
+    staging: &Path,
+    file: &Path,
+    new_path: &Path,
+    companions: &[String],
+    options: &Options<P>,
+) -> Vec<String>
+where
+    P: Borrow<ParsedFormat>,
+{
+    let mut moved = Vec::new();
+    for ext in companions {
+        let companion = file.with_extension(ext);
+        let new_companion = new_path.with_extension(ext);
+
+        let result = utils::move_file(
+            &staging.join(&new_companion),
+            &root.join(&new_companion),
+            false,
+        )
+        .and_then(|()| utils::remove_file(&companion, options.auto_chmod));
+        if let Err(e) = result {
+            log::error!(
+                "Couldn't move companion \"{}\" ({})",
+                companion.display(),
+                e
+            );
+            continue;
+        }
+
+        log::info!("Companion created: \"{}\"", new_companion.display());
+        moved.push(ext.clone());
+    }
+
+    moved
+}
+
+/// Removes `staging`, and [`STAGING_DIR`] too if nothing else is left in it
+fn discard_staging(staging_root: &Path, staging: &Path) {
+    match fs::remove_dir_all(staging) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            log::error!(
+                "Couldn't remove staging folder \"{}\" ({})",
+                staging.display(),
+                e
+            );
+        }
+        _ => {}
+    }
+
+    let _ = fs::remove_dir(staging_root);
+}
+
+/// Like [`sort_folder`], but the files found while walking `dir` are sorted by
+/// `threads` worker threads. The order of the report isn't deterministic
+pub fn sort_folder_parallel<R, D, P>(
+    root: R,
+    dir: D,
+    options: &Options<P>,
+    threads: usize,
+) -> Result<SortReport>
+where
+    R: AsRef<Path>,
+    D: AsRef<Path>,
+    P: Borrow<ParsedFormat> + Sync,
+{
+    let root = root.as_ref();
+    let threads = threads.max(1);
+
+    // Bounded, so the walk doesn't get too far ahead of the workers
+    let (files_tx, files_rx) = crossbeam_channel::bounded::<PathBuf>(threads * 4);
+
+    let report = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                let files_rx = files_rx.clone();
+
+                scope.spawn(move || {
+                    let mut report = SortReport::default();
+                    for file in files_rx {
+                        let result = sort_one(root, &file, options);
+                        record_outcome(&mut report, root, &file, result, options);
+                        options.notify(&report);
+                    }
+
+                    report
+                })
+            })
+            .collect();
+
+        let walker = options.walker();
+        let files = walker
+            .walk(dir.as_ref())
+            .on_empty(|empty| remove_or_mark_empty(empty, options));
+        for file in files {
+            if files_tx.send(file).is_err() {
+                break;
+            }
+        }
+        drop(files_tx);
+
+        let mut report = workers
+            .into_iter()
+            .map(|worker| worker.join().expect("sorting thread panicked"))
+            .fold(SortReport::default(), SortReport::merge);
+
+        if options.atomic_album {
+            keep_albums_whole(root, &mut report, options);
+        }
+
+        report
+    });
+
+    Ok(report)
+}
+
+/// Sorts each one of `files`, failures are logged and counted in the report
+pub fn sort_files<R, I, P>(root: R, files: I, options: &Options<P>) -> SortReport
+where
+    R: AsRef<Path>,
+    I: IntoIterator<Item = PathBuf>,
+    P: Borrow<ParsedFormat>,
+{
+    walk_files(root.as_ref(), files, options, None)
+}
+
+/// Like [`sort_files`], but every move has to be confirmed by `prompter`
+pub fn sort_files_interactive<R, I, P>(
+    root: R,
+    files: I,
+    options: &Options<P>,
+    prompter: &mut dyn Prompter,
+) -> SortReport
+where
+    R: AsRef<Path>,
+    I: IntoIterator<Item = PathBuf>,
+    P: Borrow<ParsedFormat>,
+{
+    walk_files(root.as_ref(), files, options, Some(prompter))
+}
+
+fn walk_files<I, P>(
+    root: &Path,
+    files: I,
+    options: &Options<P>,
+    prompter: Option<&mut dyn Prompter>,
+) -> SortReport
+where
+    I: IntoIterator<Item = PathBuf>,
+    P: Borrow<ParsedFormat>,
+{
+    let mut confirm = Confirm::from(prompter);
+    let mut report = SortReport::default();
+
+    for file in files {
+        match confirm_and_sort(root, &file, options, &mut confirm) {
+            Ok(None) if matches!(confirm, Confirm::Stopped) => break,
+            Ok(None) => report.push_skipped(&file),
+            Ok(Some(new_path)) => record_outcome(&mut report, root, &file, Ok(new_path), options),
+            Err(e) => record_outcome(&mut report, root, &file, Err(e), options),
+        }
+        options.notify(&report);
+    }
+
+    if options.atomic_album {
+        keep_albums_whole(root, &mut report, options);
+    }
+
+    report
+}
+
+/// Destinations of files planned before any of them is moved, so the moves
+/// can be looked over (e.g. to confirm them) and then made as planned by
+/// [`sort_planned`]
+pub struct Plan {
+    root: PathBuf,
+    /// Folder the files were found in, tidied up once they are moved out
+    dir: Option<PathBuf>,
+    files: Vec<(PathBuf, Result<Planned>)>,
+    /// Folders found empty while walking `dir`
+    empty: Vec<PathBuf>,
+}
+
+impl Plan {
+    /// Amount of files that would be moved
+    pub fn moves(&self) -> usize {
+        self.files
+            .iter()
+            .filter(|(file, planned)| {
+                planned
+                    .as_ref()
+                    .is_ok_and(|planned| self.root.join(&planned.new_path) != *file)
+            })
+            .count()
+    }
+}
+
+/// Plans the files found while walking `dir` like [`sort_folder`] would sort
+/// them, without moving anything yet
+pub fn plan_folder<R, D, P>(root: R, dir: D, options: &Options<P>) -> Result<Plan>
+where
+    R: AsRef<Path>,
+    D: AsRef<Path>,
+    P: Borrow<ParsedFormat>,
+{
+    let (root, dir) = (root.as_ref(), dir.as_ref());
+    let staging_root = root.join(STAGING_DIR);
+
+    let mut empty = Vec::new();
+    let walker = options.walker();
+    let files: Vec<_> = walker
+        .walk(dir)
+        .on_empty(|dir| empty.push(dir.to_path_buf()))
+        .filter(|file| !file.starts_with(&staging_root))
+        .collect();
+
+    let files = files
+        .into_iter()
+        .map(|file| {
+            let planned = plan_file(root, &file, options);
+            (file, planned)
+        })
+        .collect();
+
+    Ok(Plan {
+        root: root.to_path_buf(),
+        dir: Some(dir.to_path_buf()),
+        files,
+        empty,
+    })
+}
+
+/// Plans `files` like [`sort_files`] would sort them, without moving
+/// anything yet
+pub fn plan_files<R, I, P>(root: R, files: I, options: &Options<P>) -> Plan
+where
+    R: AsRef<Path>,
+    I: IntoIterator<Item = PathBuf>,
+    P: Borrow<ParsedFormat>,
+{
+    let root = root.as_ref();
+    let files = files
+        .into_iter()
+        .map(|file| {
+            let planned = plan_file(root, &file, options);
+            (file, planned)
+        })
+        .collect();
+
+    Plan {
+        root: root.to_path_buf(),
+        dir: None,
+        files,
+        empty: Vec::new(),
+    }
+}
+
+/// Makes the moves of `plan`, failures are logged and counted in the report
+pub fn sort_planned<P>(plan: Plan, options: &Options<P>) -> SortReport
+where
+    P: Borrow<ParsedFormat>,
+{
+    let root = plan.root.as_path();
+    let mut report = SortReport::default();
+    let mut emptied = Vec::new();
+
+    for (file, planned) in plan.files {
+        let result = planned.and_then(|planned| apply_plan(root, &file, planned, options));
+        emptied.extend(file.parent().map(Path::to_path_buf));
+        record_outcome(&mut report, root, &file, result, options);
+        options.notify(&report);
+    }
+
+    if options.atomic_album {
+        keep_albums_whole(root, &mut report, options);
+    }
+
+    if let (Some(dir), false) = (&plan.dir, options.dryrun) {
+        tidy_planned(dir, &plan.empty, emptied, options);
+    }
+
+    report
+}
+
+/// Removes or marks the folders below `dir` that were `empty` before sorting
+/// and the ones `emptied` by it
+fn tidy_planned<P>(dir: &Path, empty: &[PathBuf], emptied: Vec<PathBuf>, options: &Options<P>)
+where
+    P: Borrow<ParsedFormat>,
+{
+    for empty in empty {
+        remove_or_mark_empty(empty, options);
+    }
+    for folder in emptied {
+        tidy_emptied(&folder, dir, options);
+    }
+}
+
+/// Moves back the sorted files of the albums having a file that failed, see
+/// [`Options::atomic_album`]
+fn keep_albums_whole<P>(root: &Path, report: &mut SortReport, options: &Options<P>)
+where
+    P: Borrow<ParsedFormat>,
+{
+    let album_of = |path: &Path| {
+        let metadata = Metadata::from_path(path).ok()?;
+        Some((metadata.get_artist().ok()?, metadata.get_album().ok()?))
+    };
+
+    let failed: HashSet<_> = report
+        .outcomes
+        .iter()
+        .filter(|outcome| outcome.status == Status::Failed)
+        .filter_map(|outcome| album_of(&outcome.source))
+        .collect();
+
+    for (artist, album) in &failed {
+        log::warn!(
+            "Album \"{}\" by \"{}\" has files that failed, moving back the rest",
+            album,
+            artist
+        );
+    }
+
+    for outcome in &mut report.outcomes {
+        let Some(destination) = &outcome.destination else {
+            continue;
+        };
+
+        // Dry runs didn't move anything, tags are still at the source
+        let current = match outcome.status {
+            Status::Moved => destination,
+            Status::DryRun => &outcome.source,
+            _ => continue,
+        };
+
+        if !album_of(current).is_some_and(|album| failed.contains(&album)) {
+            continue;
+        }
+
+        if outcome.status == Status::DryRun {
+            log::info!(
+                "Moving back (dryrun): \"{}\" -> \"{}\"",
+                destination.display(),
+                outcome.source.display()
+            );
+        } else {
+            log::info!(
+                "Moving back: \"{}\" -> \"{}\"",
+                destination.display(),
+                outcome.source.display()
+            );
+
+            if let Err(e) = restore_file(destination, &outcome.source) {
+                log::error!("Couldn't move back \"{}\" ({})", destination.display(), e);
+                continue;
+            }
+
+            restore_companions(destination, &outcome.source, &outcome.companions);
+
+            if let (true, Some(parent)) = (options.remove_empty, destination.parent()) {
+                if let Err(e) = remove_empty_ancestors(parent, absolute(root), &options.prune_junk)
+                {
+                    log::error!("Couldn't remove dir ({})", e);
+                }
+            }
+        }
+
+        report.success -= 1;
+        report
+            .new_paths
+            .retain(|new_path| absolute(&root.join(new_path)) != *destination);
+        outcome.status = Status::RolledBack;
+    }
+}
+
+/// Whether moves still have to be confirmed while walking files
+enum Confirm<'a> {
+    Ask(&'a mut dyn Prompter),
+    Always,
+    Stopped,
+}
+
+impl<'a> From<Option<&'a mut dyn Prompter>> for Confirm<'a> {
+    fn from(prompter: Option<&'a mut dyn Prompter>) -> Self {
+        prompter.map_or(Confirm::Always, Confirm::Ask)
+    }
+}
+
+/// Counts the outcome of sorting `file` into `report`, errors left alone on
+/// purpose (see [`Error::is_skip`]) as skipped
+fn record_outcome<P>(
+    report: &mut SortReport,
+    root: &Path,
+    file: &Path,
+    result: Result<Sorted>,
+    options: &Options<P>,
+) where
+    P: Borrow<ParsedFormat>,
+{
+    match result {
+        Ok(sorted) => {
+            report.push_sorted(root, file, sorted, options.dryrun);
+            options.count_verified(report);
+        }
+
+        Err(e) if e.is_skip() => report.push_skipped_by(file, &e),
+
+        Err(e) => {
+            log::error!("{}", e);
+            report.push_failed(file, &e);
+        }
+    }
+}
+
+/// Sorts `file` once it's confirmed, returns `None` for skipped files
+fn confirm_and_sort<P>(
+    root: &Path,
+    file: &Path,
+    options: &Options<P>,
+    confirm: &mut Confirm,
+) -> Result<Option<Sorted>>
+where
+    P: Borrow<ParsedFormat>,
+{
+    let prompter = match confirm {
+        Confirm::Ask(prompter) => prompter,
+        Confirm::Always => return sort_one(root, file, options).map(Some),
+        Confirm::Stopped => return Ok(None),
+    };
+
+    let planned = plan_file(root, file, options)?;
+    match prompter.confirm(file, &root.join(&planned.new_path)) {
+        Answer::Yes => {}
+
+        Answer::All => {
+            log::info!("Moving the remaining files without asking");
+            *confirm = Confirm::Always;
+        }
+
+        Answer::No => {
+            log::info!("Skipping: \"{}\"", file.display());
+            return Ok(None);
+        }
+
+        Answer::Quit => {
+            log::info!("Stopped sorting");
+            *confirm = Confirm::Stopped;
+            return Ok(None);
+        }
+    }
+
+    apply_plan(root, file, planned, options).map(Some)
+}
+
+pub fn sort_file<R, F, P>(root: R, file: F, options: &Options<P>) -> Result<PathBuf>
+where
+    R: AsRef<Path>,
+    F: AsRef<Path>,
+    P: Borrow<ParsedFormat>,
+{
+    sort_one(root.as_ref(), file.as_ref(), options).map(|sorted| sorted.new_path)
+}
+
+/// Like [`sort_file`], along with the companions moved
+pub(crate) fn sort_one<P>(root: &Path, file: &Path, options: &Options<P>) -> Result<Sorted>
+where
+    P: Borrow<ParsedFormat>,
+{
+    let planned = plan_file(root, file, options)?;
+    apply_plan(root, file, planned, options)
+}
+
+/// A file just sorted (or that would be in a dry run)
+pub(crate) struct Sorted {
+    /// Relative to the root
+    pub(crate) new_path: PathBuf,
+    /// Extensions of the companions moved along
+    pub(crate) companions: Vec<String>,
+}
+
+/// Destination of a file that's about to be moved
+struct Planned {
+    /// Relative to the root
+    new_path: PathBuf,
+    /// Extensions of the companions moved along, see [`companions_of`]
+    companions: Vec<String>,
+    duplicates: Duplicates,
+    #[cfg(feature = "preserve-timestamps")]
+    times: Option<(filetime::FileTime, filetime::FileTime)>,
+}
+
+/// Tags of `file`, failing if reading them takes longer than `timeout`
+fn read_tags(file: &Path, timeout: Option<Duration>) -> Result<Metadata> {
+    match timeout {
+        Some(timeout) => Metadata::from_path_with_timeout(file, timeout),
+        None => Metadata::from_path(file),
+    }
+}
+
+fn plan_file<P>(root: &Path, file: &Path, options: &Options<P>) -> Result<Planned>
+where
+    P: Borrow<ParsedFormat>,
+{
+    if options.dryrun {
+        log::info!("Working on (dryrun): \"{}\"", file.display());
+    } else {
+        log::info!("Working on: \"{}\"", file.display());
+    }
+
+    // Reading tags updates the access time, so this must be done first
+    #[cfg(feature = "preserve-timestamps")]
+    let times = if options.preserve_timestamps && !options.dryrun {
+        Some(utils::file_times(file)?)
+    } else {
+        None
+    };
+
+    #[cfg(not(feature = "preserve-timestamps"))]
+    if options.preserve_timestamps {
+        log::warn!(
+            "Can't preserve timestamps, musso was built without the preserve-timestamps feature"
+        );
+    }
+
+    let new_path = destination(root, file, options)?;
+
+    if let Some(dir) = root.join(&new_path).parent() {
+        if !options.create_dirs && !dir.is_dir() {
+            return Err(Error::NoDestinationDir {
+                dir: dir.to_string_lossy().into_owned(),
+            });
+        }
+
+        options.dest_exists.check(file, dir)?;
+    }
+
+    if options.case_insensitive || options.exfat_compat {
+        check_case_collision(root, file, &new_path)?;
+    }
+
+    let duplicates = match &options.dedup {
+        Some(dedup) => dedup.resolve(root, file, &new_path)?,
+        None => Duplicates::default(),
+    };
+
+    let companions = companions_of(root, file, &new_path, options);
+    Ok(Planned {
+        new_path,
+        companions,
+        duplicates,
+        #[cfg(feature = "preserve-timestamps")]
+        times,
+    })
+}
+
+/// Path relative to `root` where `file` belongs, built from its tags by the
+/// format (or the compilation one) and adjusted by the options
+fn destination<P>(root: &Path, file: &Path, options: &Options<P>) -> Result<PathBuf>
+where
+    P: Borrow<ParsedFormat>,
+{
+    let metadata = profile::time(Phase::TagRead, || read_tags(file, options.tag_read_timeout))?;
+    let format = match &options.compilation {
+        Some(compilation)
+            if compilation.is_compilation(file, &metadata, options.tag_read_timeout) =>
+        {
+            log::debug!("Sorting as a compilation: \"{}\"", file.display());
+            &compilation.format
+        }
+        _ => options.format.borrow(),
+    };
+
+    let build_options = options.build_options();
+
+    let new_path = if options.explain {
+        let explanation = format.explain(&metadata, &build_options)?;
+
+        log::info!("Format: \"{}\"", explanation.format);
+        for (placeholder, value) in &explanation.resolved {
+            log::info!("  {} = \"{}\"", placeholder, value);
+        }
+
+        for placeholder in &explanation.dropped {
+            log::info!("  {} dropped (tag is missing)", placeholder);
+        }
+
+        explanation.path
+    } else {
+        format.build_path_with(&metadata, &build_options)?
+    };
+    let new_path = placed(root, file, new_path, options);
+    let new_path = match &options.fold_diacritics {
+        Some(fold_diacritics) => fold_diacritics.merged_folders(root, new_path),
+        None => new_path,
+    };
+    match options.max_path_len {
+        Some(max) => fit_path_len(root, new_path, max, options.long_path_policy),
+        None => Ok(new_path),
+    }
+}
+
+/// Destination of `file` relative to `root`, given the path `built` from the
+/// format: only its file name with [`Options::rename_only`], otherwise after
+/// the folders kept by [`Options::keep_structure_depth`]
+fn placed<P>(root: &Path, file: &Path, built: PathBuf, options: &Options<P>) -> PathBuf
+where
+    P: Borrow<ParsedFormat>,
+{
+    if options.rename_only {
+        let dir = kept_structure(root, file, usize::MAX);
+        return match built.file_name() {
+            Some(name) => dir.join(name),
+            None => dir.join(built),
+        };
+    }
+
+    kept_structure(root, file, options.keep_structure_depth).join(built)
+}
+
+/// Longest file or folder name most file systems allow, in bytes
+const NAME_MAX: usize = 255;
+
+/// `new_path` (below `root`) shortened to `max` bytes according to `policy`
+fn fit_path_len(
+    root: &Path,
+    new_path: PathBuf,
+    max: usize,
+    policy: LongPathPolicy,
+) -> Result<PathBuf> {
+    let len = |path: &Path| root.join(path).as_os_str().len();
+    let too_long = |path: &Path| Error::PathTooLong {
+        path: root.join(path).to_string_lossy().into_owned(),
+        max,
+    };
+
+    if len(&new_path) <= max {
+        return Ok(new_path);
+    }
+    if policy == LongPathPolicy::Error {
+        return Err(too_long(&new_path));
+    }
+
+    let (Some(dir), Some(name)) = (new_path.parent(), new_path.file_name()) else {
+        return Err(too_long(&new_path));
+    };
+    let mut path: PathBuf = dir
+        .components()
+        .map(|component| truncated(&component.as_os_str().to_string_lossy(), NAME_MAX, false))
+        .collect();
+    let name = truncated(&name.to_string_lossy(), NAME_MAX, true);
+
+    let room = max.saturating_sub(len(&path.join("")));
+    let name = truncated(&name, room, true);
+    // Only the extension (or nothing) is left
+    if Path::new(&name).file_stem().is_none() || name.starts_with('.') {
+        return Err(too_long(&new_path));
+    }
+
+    path.push(name);
+    if len(&path) > max {
+        return Err(too_long(&new_path));
+    }
+
+    Ok(path)
+}
+
+/// `name` cut to `max` bytes, keeping the extension of file names
+fn truncated(name: &str, max: usize, keep_extension: bool) -> String {
+    if name.len() <= max {
+        return name.to_owned();
+    }
+
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if keep_extension && !stem.is_empty() => {
+            (stem, format!(".{}", extension))
+        }
+        _ => (name, String::new()),
+    };
+
+    let mut end = max.saturating_sub(extension.len()).min(stem.len());
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    format!("{}{}", stem[..end].trim_end(), extension)
+}
+
+/// First `depth` folders of `file` below `root`
+fn kept_structure(root: &Path, file: &Path, depth: usize) -> PathBuf {
+    file.parent()
+        .and_then(|parent| parent.strip_prefix(root).ok())
+        .map(|dirs| dirs.components().take(depth).collect())
+        .unwrap_or_default()
+}
+
+fn apply_plan<P>(root: &Path, file: &Path, planned: Planned, options: &Options<P>) -> Result<Sorted>
+where
+    P: Borrow<ParsedFormat>,
+{
+    let new_path = planned.new_path;
+    let destination = root.join(&new_path);
+    if let Some(dedup) = &options.dedup {
+        dedup.remove_if_worse(root, file, &planned.duplicates, options)?;
+    }
+
+    if !options.dryrun {
+        let new_path_parent = destination.parent().ok_or(Error::InvalidParent {
+            child: destination.to_string_lossy().into(),
+        })?;
+
+        let set_aside = match &options.dedup {
+            Some(dedup) => dedup.set_aside(root, &destination, &planned.duplicates, options)?,
+            None => None,
+        };
+        let moved = profile::time(Phase::Move, || -> Result<()> {
+            utils::create_dir_with_mode(new_path_parent, options.dir_mode)?;
+            if options.verify_after {
+                checksum::move_verified(file, &destination, options.auto_chmod)
+            } else {
+                Ok(utils::move_file(file, &destination, options.auto_chmod)?)
+            }
+        });
+        if let Err(e) = moved {
+            Dedup::put_back(set_aside, &destination);
+            return Err(e);
+        }
+
+        #[cfg(feature = "preserve-timestamps")]
+        if let Some((atime, mtime)) = planned.times {
+            filetime::set_file_times(&destination, atime, mtime)?;
+        }
+    }
+
+    log::info!("Item created: \"{}\"", new_path.display());
+    if let Some(dedup) = &options.dedup {
+        dedup.remove_worse(root, &destination, &planned.duplicates, options);
+    }
+    let companions = move_companions(root, file, &new_path, &planned.companions, options);
+
+    Ok(Sorted {
+        new_path,
+        companions,
+    })
+}
+
+/// Extensions of the companions of `file` (see [`Options::companions`]) that
+/// follow it to `new_path`. The ones already there are left alone, and the
+/// ones whose destination is taken are left where they are
+fn companions_of<P>(root: &Path, file: &Path, new_path: &Path, options: &Options<P>) -> Vec<String>
+where
+    P: Borrow<ParsedFormat>,
+{
+    let case_insensitive = options.case_insensitive || options.exfat_compat;
+
+    options
+        .companions
+        .iter()
+        .filter(|ext| {
+            let companion = file.with_extension(ext);
+            let new_companion = new_path.with_extension(ext);
+            let destination = root.join(&new_companion);
+            if companion == file || !companion.is_file() || destination == companion {
+                return false;
+            }
+
+            let taken = destination.exists()
+                || (case_insensitive
+                    && check_case_collision(root, &companion, &new_companion).is_err());
+            if taken {
+                log::warn!(
+                    "Companion destination is taken: \"{}\" (leaving \"{}\")",
+                    destination.display(),
+                    companion.display()
+                );
+            }
+
+            !taken
+        })
+        .cloned()
+        .collect()
+}
+
+/// Moves the `companions` of `file` next to it at `new_path`, returns the
+/// ones moved
+fn move_companions<P>(
+    root: &Path,
+    file: &Path,
+    new_path: &Path,
+    companions: &[String],
+    options: &Options<P>,
+) -> Vec<String>
+where
+    P: Borrow<ParsedFormat>,
+{
+    let mut moved = Vec::new();
+    for ext in companions {
+        let companion = file.with_extension(ext);
+        let new_companion = new_path.with_extension(ext);
+        if !options.dryrun {
+            let destination = root.join(&new_companion);
+            if let Err(e) = utils::move_file(&companion, &destination, options.auto_chmod) {
+                log::error!(
+                    "Couldn't move companion \"{}\" ({})",
+                    companion.display(),
+                    e
+                );
+                continue;
+            }
+        }
+
+        log::info!("Companion created: \"{}\"", new_companion.display());
+        moved.push(ext.clone());
+    }
+
+    moved
+}
+
+/// An audio file that isn't where the format says it should be
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Misplaced {
+    pub path: PathBuf,
+    /// Relative to the root
+    pub expected: PathBuf,
+}
+
+/// Audio files under `dir` that aren't at the path built for them relative to
+/// `root`, files whose path can't be built are logged and left out
+pub fn find_misplaced<R, D, P>(root: R, dir: D, options: &Options<P>) -> Result<Vec<Misplaced>>
+where
+    R: AsRef<Path>,
+    D: AsRef<Path>,
+    P: Borrow<ParsedFormat>,
+{
+    let validation = validate_folder(root, dir, options)?;
+    for (path, reason) in &validation.unknown {
+        log::error!("Couldn't check \"{}\" ({})", path.display(), reason);
+    }
+
+    Ok(validation.misplaced)
+}
+
+/// Placement of the audio files of a folder, see [`validate_folder`]
+#[derive(Debug, Clone, Default)]
+pub struct Validation {
+    /// Files already where the format says
+    pub placed: usize,
+    pub misplaced: Vec<Misplaced>,
+    /// Files whose path can't be built (e.g. a tag is missing), with the
+    /// reason
+    pub unknown: Vec<(PathBuf, String)>,
+}
+
+/// Compares every audio file under `dir` that sorting it would go through
+/// to the path built for it relative to `root`
+pub fn validate_folder<R, D, P>(root: R, dir: D, options: &Options<P>) -> Result<Validation>
+where
+    R: AsRef<Path>,
+    D: AsRef<Path>,
+    P: Borrow<ParsedFormat>,
+{
+    let root = root.as_ref();
+    let mut validation = Validation::default();
+    for path in options.walker().files(dir.as_ref())? {
+        match placement(root, path, options) {
+            Placement::Correct => validation.placed += 1,
+            Placement::Misplaced(misplaced) => validation.misplaced.push(misplaced),
+            Placement::Unknown(path, e) => validation.unknown.push((path, e.to_string())),
+        }
+    }
+
+    Ok(validation)
+}
+
+/// Same as [`find_misplaced`], but checking only `files`
+pub fn find_misplaced_files<R, I, P>(root: R, files: I, options: &Options<P>) -> Vec<Misplaced>
+where
+    R: AsRef<Path>,
+    I: IntoIterator<Item = PathBuf>,
+    P: Borrow<ParsedFormat>,
+{
+    files
+        .into_iter()
+        .filter(|path| utils::is_audio_file(path))
+        .filter_map(|path| match placement(root.as_ref(), path, options) {
+            Placement::Misplaced(misplaced) => Some(misplaced),
+            Placement::Unknown(path, e) => {
+                log::error!("Couldn't check \"{}\" ({})", path.display(), e);
+                None
+            }
+            Placement::Correct => None,
+        })
+        .collect()
+}
+
+enum Placement {
+    Correct,
+    Misplaced(Misplaced),
+    /// The path of the file can't be built
+    Unknown(PathBuf, Error),
+}
+
+fn placement<P>(root: &Path, path: PathBuf, options: &Options<P>) -> Placement
+where
+    P: Borrow<ParsedFormat>,
+{
+    let expected = match destination(root, &path, options) {
+        Ok(expected) => expected,
+        Err(e) => return Placement::Unknown(path, e),
+    };
+
+    if path != root.join(&expected) {
+        Placement::Misplaced(Misplaced { path, expected })
+    } else {
+        Placement::Correct
+    }
+}
+
+/// Moves the files of `report` back to where they were before sorting, newest
+/// first, along with their companions. Files that are gone or whose original
+/// path is taken are skipped with a warning, the returned report counts the
+/// restored files
+pub fn restore(report: &SortReport, dryrun: bool) -> SortReport {
+    let mut restored = SortReport::default();
+
+    let moved = report.outcomes.iter().rev().filter_map(|outcome| {
+        match (&outcome.status, &outcome.destination) {
+            (Status::Moved, Some(destination)) => Some((destination, outcome)),
+            _ => None,
+        }
+    });
+
+    for (from, outcome) in moved {
+        let to = &outcome.source;
+
+        if !from.is_file() {
+            log::warn!("Sorted file is gone: \"{}\" (skipping)", from.display());
+            restored.total += 1;
+            restored.push_skipped(from);
+            continue;
+        }
+
+        if to.exists() {
+            log::warn!("Original path is taken: \"{}\" (skipping)", to.display());
+            restored.total += 1;
+            restored.push_skipped(from);
+            continue;
+        }
+
+        if dryrun {
+            log::info!(
+                "Restoring (dryrun): \"{}\" -> \"{}\"",
+                from.display(),
+                to.display()
+            );
+        } else {
+            log::info!("Restoring: \"{}\" -> \"{}\"", from.display(), to.display());
+
+            if let Err(e) = restore_file(from, to) {
+                log::error!("Couldn't restore \"{}\" ({})", from.display(), e);
+                restored.push_failed(from, &e);
+                continue;
+            }
+            restore_companions(from, to, &outcome.companions);
+        }
+
+        restored.success += 1;
+        restored.total += 1;
+        restored.new_paths.push(to.clone());
+        restored.outcomes.push(FileOutcome {
+            source: from.clone(),
+            destination: Some(to.clone()),
+            status: if dryrun {
+                Status::DryRun
+            } else {
+                Status::Moved
+            },
+            error: None,
+            companions: outcome.companions.clone(),
+        });
+    }
+
+    restored
+}
+
+fn restore_file(from: &Path, to: &Path) -> Result<()> {
+    let parent = to.parent().ok_or(Error::InvalidParent {
+        child: to.to_string_lossy().into(),
+    })?;
+
+    utils::maybe_create_dir(parent)?;
+    utils::move_file(from, to, false)?;
+
+    Ok(())
+}
+
+/// Moves the `companions` of the file moved back from `from` to `to` next to
+/// it, the ones that are gone or whose original path is taken are left
+fn restore_companions(from: &Path, to: &Path, companions: &[String]) {
+    for ext in companions {
+        let (companion, original) = (from.with_extension(ext), to.with_extension(ext));
+        if !companion.is_file() || original.exists() {
+            log::warn!(
+                "Can't move back companion \"{}\" (skipping)",
+                companion.display()
+            );
+            continue;
+        }
+
+        if let Err(e) = utils::move_file(&companion, &original, false) {
+            log::error!(
+                "Couldn't move back companion \"{}\" ({})",
+                companion.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Whether `path` is a file named like one of `junk` (ignoring case)
+pub(crate) fn is_junk(path: &Path, junk: &[String]) -> bool {
+    if junk.is_empty() || !path.is_file() {
+        return false;
+    }
+
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| junk.iter().any(|junk| junk.eq_ignore_ascii_case(name)))
+        .unwrap_or(false)
+}
+
+/// Removes `dir`, deleting first the junk files that were left in it
+fn remove_dir_with_junk(dir: &Path, junk: &[String]) -> std::io::Result<()> {
+    if !junk.is_empty() {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if is_junk(&path, junk) {
+                log::debug!("Removing junk file: \"{}\"", path.display());
+                fs::remove_file(path)?;
+            }
+        }
+    }
+
+    fs::remove_dir(dir)
+}
+
+/// Removes the empty directories found under `dir` (and the ones that become
+/// empty because of it), `dir` itself and the paths in `keep` are never removed.
+/// Directories containing only files named like one of `junk` count as empty
+pub fn remove_empty_dirs(
+    dir: impl AsRef<Path>,
+    keep: &[PathBuf],
+    junk: &[String],
+    dryrun: bool,
+) -> Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    prune_dir(dir.as_ref(), keep, junk, dryrun, &mut removed)?;
+
+    Ok(removed)
+}
+
+/// Removes `dir` and its parents emptied by sorting (up to `root`) with
+/// [`Options::remove_empty`], or marks `dir` with [`Options::mark_empty`]
+pub(crate) fn tidy_emptied<P>(dir: &Path, root: &Path, options: &Options<P>)
+where
+    P: Borrow<ParsedFormat>,
+{
+    let result = if options.remove_empty {
+        remove_empty_ancestors(dir, root, &options.prune_junk).map(drop)
+    } else if let (Some(name), true) = (&options.mark_empty, dir != root) {
+        mark_empty_dir(dir, name, &options.prune_junk).map(drop)
+    } else {
+        Ok(())
+    };
+
+    if let Err(e) = result {
+        log::error!(
+            "Couldn't tidy up empty folder \"{}\" ({})",
+            dir.display(),
+            e
+        );
+    }
+}
+
+/// Writes the empty file `name` in `dir` if it's empty, or only has files
+/// named like one of `junk`. Returns whether it was written
+pub fn mark_empty_dir(dir: impl AsRef<Path>, name: &str, junk: &[String]) -> Result<bool> {
+    let dir = dir.as_ref();
+    let empty = fs::read_dir(dir)?.all(|entry| {
+        entry
+            .map(|entry| is_junk(&entry.path(), junk))
+            .unwrap_or(false)
+    });
+
+    if !empty {
+        return Ok(false);
+    }
+
+    log::info!("Marking empty folder: \"{}\"", dir.display());
+    fs::write(dir.join(name), "")?;
+    Ok(true)
+}
+
+/// Removes `dir` and then its parents while they are empty, stopping at `root`
+/// (which is never removed) or at the first directory that isn't empty.
+/// Directories containing only files named like one of `junk` count as empty
+pub fn remove_empty_ancestors(
+    dir: impl AsRef<Path>,
+    root: impl AsRef<Path>,
+    junk: &[String],
+) -> Result<Vec<PathBuf>> {
+    let root = root.as_ref();
+    let mut removed = Vec::new();
+
+    for dir in dir.as_ref().ancestors() {
+        if dir == root || !dir.starts_with(root) || !dir.is_dir() {
+            break;
+        }
+
+        let mut entries = fs::read_dir(dir)?;
+        let empty = entries.all(|entry| {
+            entry
+                .map(|entry| is_junk(&entry.path(), junk))
+                .unwrap_or(false)
+        });
+
+        if !empty {
+            break;
+        }
+
+        log::info!("Removing empty folder: \"{}\"", dir.display());
+        remove_dir_with_junk(dir, junk)?;
+        removed.push(dir.to_path_buf());
+    }
+
+    Ok(removed)
+}
+
+/// Returns whether `dir` is empty after pruning its subdirectories
+fn prune_dir(
+    dir: &Path,
+    keep: &[PathBuf],
+    junk: &[String],
+    dryrun: bool,
+    removed: &mut Vec<PathBuf>,
+) -> Result<bool> {
+    let mut empty = true;
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if is_junk(&path, junk) {
+            continue;
+        }
+
+        if path.is_dir() && !path.is_symlink() {
+            let prunable = match prune_dir(&path, keep, junk, dryrun, removed) {
+                Ok(prunable) => prunable && !keep.contains(&path),
+                Err(e) => {
+                    log::error!("Couldn't read \"{}\" ({})", path.display(), e);
+                    false
+                }
+            };
+
+            if prunable {
+                if dryrun {
+                    log::info!("Removing empty folder (dryrun): \"{}\"", path.display());
+                } else {
+                    log::info!("Removing empty folder: \"{}\"", path.display());
+                    if let Err(e) = remove_dir_with_junk(&path, junk) {
+                        log::error!("Couldn't remove dir ({})", e);
+                        empty = false;
+                        continue;
+                    }
+                }
+
+                removed.push(path);
+                continue;
+            }
+        }
+
+        empty = false;
+    }
 
-    Ok(new_path)
+    Ok(empty)
 }
@@ -0,0 +1,226 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use id3::{Frame, TagLike};
+
+use crate::metadata::sniff_container;
+use crate::utils;
+use crate::{Error, Result};
+
+/// Names accepted instead of the frame ids of the common tags
+const FRAME_ALIASES: &[(&str, &str)] = &[
+    ("artist", "TPE1"),
+    ("album_artist", "TPE2"),
+    ("album", "TALB"),
+    ("title", "TIT2"),
+    ("track", "TRCK"),
+    ("disc", "TPOS"),
+    ("genre", "TCON"),
+    ("year", "TDRC"),
+];
+
+/// Copies the text of the `from` frame into the `to` frame, parsed from
+/// `FROM=TO` where each side is a frame id (e.g. `TPE2`) or a tag name like
+/// `artist` (see [`FRAME_ALIASES`])
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameMapping {
+    pub from: String,
+    pub to: String,
+}
+
+impl FromStr for FrameMapping {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidFrameMapping {
+            mapping: s.to_owned(),
+        };
+
+        let (from, to) = s.split_once('=').ok_or_else(invalid)?;
+        let (from, to) = (
+            frame_id(from).ok_or_else(invalid)?,
+            frame_id(to).ok_or_else(invalid)?,
+        );
+
+        if from == to {
+            return Err(invalid());
+        }
+
+        Ok(Self { from, to })
+    }
+}
+
+/// Frame id of `name`, either an alias or four uppercase letters and digits
+fn frame_id(name: &str) -> Option<String> {
+    let name = name.trim();
+
+    if let Some((_, id)) = FRAME_ALIASES
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+    {
+        return Some((*id).to_owned());
+    }
+
+    let valid = name.len() == 4
+        && name
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+
+    valid.then(|| name.to_owned())
+}
+
+/// Outcome of a [`rename_frames`] run
+#[derive(Debug, Clone, Default)]
+pub struct TagRenameReport {
+    /// Files whose tag was changed (or would have been, in a dry run)
+    pub changed: usize,
+    /// Files without any frame to copy
+    pub skipped: usize,
+    pub total: usize,
+}
+
+/// Applies `mappings` to the mp3 files under `dir`, a frame is only copied if
+/// the destination is missing, the source is removed too with
+/// `remove_source`
+pub fn rename_frames(
+    dir: impl AsRef<Path>,
+    mappings: &[FrameMapping],
+    remove_source: bool,
+    recursive: bool,
+    dryrun: bool,
+) -> Result<TagRenameReport> {
+    let mut report = TagRenameReport::default();
+    let mut queue = VecDeque::from([dir.as_ref().to_path_buf()]);
+
+    while let Some(dir) = queue.pop_front() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+
+            if utils::is_hidden(&path) {
+                continue;
+            }
+
+            if path.is_dir() {
+                if recursive {
+                    queue.push_back(path);
+                }
+                continue;
+            }
+
+            if !matches!(sniff_container(&path), Ok(Some("mp3"))) {
+                continue;
+            }
+
+            report.total += 1;
+            match rename_file_frames(&path, mappings, remove_source, dryrun) {
+                Ok(true) => report.changed += 1,
+                Ok(false) => report.skipped += 1,
+                Err(e) => log::error!("Couldn't rename frames of \"{}\" ({})", path.display(), e),
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Returns whether the tag of `path` was changed
+fn rename_file_frames(
+    path: &Path,
+    mappings: &[FrameMapping],
+    remove_source: bool,
+    dryrun: bool,
+) -> Result<bool> {
+    let Some(mut tag) = id3::no_tag_ok(id3::Tag::read_from_path(path))? else {
+        return Ok(false);
+    };
+
+    let mut changed = false;
+    for mapping in mappings {
+        let Some(text) = tag
+            .get(&mapping.from)
+            .and_then(|frame| frame.content().text())
+            .map(str::to_owned)
+        else {
+            continue;
+        };
+
+        if tag.get(&mapping.to).is_none() {
+            log::info!(
+                "{} → {} (\"{}\"): \"{}\"",
+                mapping.from,
+                mapping.to,
+                text,
+                path.display()
+            );
+            tag.add_frame(Frame::text(&mapping.to, text));
+            changed = true;
+        }
+
+        if remove_source {
+            tag.remove(&mapping.from);
+            changed = true;
+        }
+    }
+
+    if changed && !dryrun {
+        tag.write_to_path(path, tag.version())?;
+    }
+
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FixtureBuilder;
+
+    #[test]
+    fn mappings_accept_ids_and_names() {
+        assert_eq!(
+            FrameMapping {
+                from: "TPE2".into(),
+                to: "TPE1".into()
+            },
+            "TPE2=artist".parse().unwrap()
+        );
+
+        for invalid in ["TPE2", "TPE2=", "tpe2=TPE1", "TPE1=artist", "TOOLONG=TPE1"] {
+            assert!(invalid.parse::<FrameMapping>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn missing_frames_are_copied() -> Result<()> {
+        let fixture = FixtureBuilder::new()
+            .file("only-album-artist.mp3")
+            .album_artist("Album Artist")
+            .title("Song")
+            .file("both.mp3")
+            .artist("Artist")
+            .album_artist("Album Artist")
+            .title("Song")
+            .build()?;
+
+        let mappings = ["album_artist=artist".parse()?];
+        let report = rename_frames(fixture.path(), &mappings, false, false, false)?;
+        assert_eq!(1, report.changed);
+        assert_eq!(2, report.total);
+
+        let tag = id3::Tag::read_from_path(fixture.path().join("only-album-artist.mp3"))?;
+        assert_eq!(Some("Album Artist"), tag.artist());
+        assert_eq!(Some("Album Artist"), tag.album_artist());
+
+        // An existing frame is never replaced
+        let tag = id3::Tag::read_from_path(fixture.path().join("both.mp3"))?;
+        assert_eq!(Some("Artist"), tag.artist());
+
+        rename_frames(fixture.path(), &mappings, true, false, false)?;
+        let tag = id3::Tag::read_from_path(fixture.path().join("both.mp3"))?;
+        assert_eq!(Some("Artist"), tag.artist());
+        assert_eq!(None, tag.album_artist());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+//! Helpers to build music folders for tests, only available in unit tests or
+//! with the `test-utils` feature.
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use id3::TagLike;
+use tempfile::TempDir;
+
+use crate::{Error, Result};
+
+/// Minimal MPEG audio frame header appended after the ID3 tag
+const MPEG_FRAME: [u8; 4] = [0xff, 0xfb, 0x90, 0x64];
+
+#[derive(Debug, Clone, Default)]
+struct FixtureFile {
+    path: PathBuf,
+    artist: Option<String>,
+    album_artist: Option<String>,
+    album: Option<String>,
+    disc: Option<u32>,
+    track: Option<u32>,
+    title: Option<String>,
+    label: Option<String>,
+    catalog: Option<String>,
+    mbid: Option<String>,
+}
+
+/// Creates a temporary directory with tagged MP3 and FLAC files, tag setters
+/// apply to the last file added, e.g.
+///
+/// ```
+/// # use musso::testing::FixtureBuilder;
+/// let fixture = FixtureBuilder::new()
+///     .file("01 - Song.mp3")
+///     .artist("Daft Punk")
+///     .album("Homework")
+///     .build()?;
+///
+/// assert!(fixture.path().join("01 - Song.mp3").is_file());
+/// # Ok::<(), musso::Error>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct FixtureBuilder {
+    files: Vec<FixtureFile>,
+}
+
+/// A temporary directory removed when dropped
+#[derive(Debug)]
+pub struct Fixture {
+    dir: TempDir,
+}
+
+impl Fixture {
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+}
+
+macro_rules! impl_tag_setter {
+    ($tag:ident, $ty:ty) => {
+        pub fn $tag(mut self, $tag: impl Into<$ty>) -> Self {
+            self.last().$tag = Some($tag.into());
+            self
+        }
+    };
+}
+
+impl FixtureBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file, `path` is relative to the fixture directory and its
+    /// extension (`mp3` or `flac`) decides the kind of tags written
+    pub fn file(mut self, path: impl AsRef<Path>) -> Self {
+        self.files.push(FixtureFile {
+            path: path.as_ref().to_path_buf(),
+            ..Default::default()
+        });
+
+        self
+    }
+
+    impl_tag_setter!(artist, String);
+    impl_tag_setter!(album_artist, String);
+    impl_tag_setter!(album, String);
+    impl_tag_setter!(disc, u32);
+    impl_tag_setter!(track, u32);
+    impl_tag_setter!(title, String);
+    impl_tag_setter!(label, String);
+    impl_tag_setter!(catalog, String);
+    impl_tag_setter!(mbid, String);
+
+    fn last(&mut self) -> &mut FixtureFile {
+        self.files
+            .last_mut()
+            .expect("FixtureBuilder::file must be called before setting tags")
+    }
+
+    pub fn build(self) -> Result<Fixture> {
+        let dir = tempfile::tempdir()?;
+
+        for file in &self.files {
+            let path = dir.path().join(&file.path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("mp3") => write_mp3(&path, file)?,
+                Some("flac") => write_flac(&path, file)?,
+                _ => return Err(Error::NotSupported),
+            }
+        }
+
+        Ok(Fixture { dir })
+    }
+}
+
+fn write_mp3(path: &Path, file: &FixtureFile) -> Result<()> {
+    let mut tag = id3::Tag::new();
+
+    if let Some(artist) = &file.artist {
+        tag.set_artist(artist);
+    }
+
+    if let Some(album_artist) = &file.album_artist {
+        tag.set_album_artist(album_artist);
+    }
+
+    if let Some(album) = &file.album {
+        tag.set_album(album);
+    }
+
+    if let Some(disc) = file.disc {
+        tag.set_disc(disc);
+    }
+
+    if let Some(track) = file.track {
+        tag.set_track(track);
+    }
+
+    if let Some(title) = &file.title {
+        tag.set_title(title);
+    }
+
+    if let Some(label) = &file.label {
+        tag.set_text("TPUB", label);
+    }
+
+    let extended_texts = [
+        ("CATALOGNUMBER", &file.catalog),
+        ("MusicBrainz Album Id", &file.mbid),
+    ];
+    for (description, value) in extended_texts {
+        if let Some(value) = value {
+            tag.add_frame(id3::frame::ExtendedText {
+                description: description.to_owned(),
+                value: value.clone(),
+            });
+        }
+    }
+
+    let mut out = File::create(path)?;
+    tag.write_to(&mut out, id3::Version::Id3v24)?;
+    out.write_all(&MPEG_FRAME)?;
+
+    Ok(())
+}
+
+fn write_flac(path: &Path, file: &FixtureFile) -> Result<()> {
+    let mut info = metaflac::block::StreamInfo::new();
+    info.min_block_size = 4096;
+    info.max_block_size = 4096;
+    info.sample_rate = 44100;
+    info.num_channels = 2;
+    info.bits_per_sample = 16;
+    info.md5 = vec![0; 16];
+
+    let mut tag = metaflac::Tag::new();
+    tag.push_block(metaflac::Block::StreamInfo(info));
+
+    let comments = [
+        ("ARTIST", file.artist.clone()),
+        ("ALBUMARTIST", file.album_artist.clone()),
+        ("ALBUM", file.album.clone()),
+        ("DISCNUMBER", file.disc.map(|disc| disc.to_string())),
+        ("TRACKNUMBER", file.track.map(|track| track.to_string())),
+        ("TITLE", file.title.clone()),
+        ("LABEL", file.label.clone()),
+        ("CATALOGNUMBER", file.catalog.clone()),
+        ("MUSICBRAINZ_ALBUMID", file.mbid.clone()),
+    ];
+
+    for (key, value) in comments {
+        if let Some(value) = value {
+            tag.set_vorbis(key, vec![value]);
+        }
+    }
+
+    let mut out = File::create(path)?;
+    tag.write_to(&mut out)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::Metadata;
+
+    #[test]
+    fn fixture_files_are_readable() -> Result<()> {
+        let fixture = FixtureBuilder::new()
+            .file("01 - Song.mp3")
+            .artist("Daft Punk")
+            .album("Homework")
+            .track(1u32)
+            .title("Revolution 909")
+            .file("nested/02 - Song.flac")
+            .artist("Daft Punk")
+            .album("Homework")
+            .disc(1u32)
+            .track(2u32)
+            .title("Da Funk")
+            .build()?;
+
+        let mp3 = Metadata::from_path(fixture.path().join("01 - Song.mp3"))?;
+        assert_eq!("Daft Punk", mp3.get_artist()?);
+        assert_eq!("Homework", mp3.get_album()?);
+        assert_eq!("1", mp3.get_track()?);
+        assert_eq!("Revolution 909", mp3.get_title()?);
+        assert_eq!("mp3", mp3.get_ext());
+
+        let flac = Metadata::from_path(fixture.path().join("nested/02 - Song.flac"))?;
+        assert_eq!("Daft Punk", flac.get_artist()?);
+        assert_eq!("1", flac.get_disc()?);
+        assert_eq!("2", flac.get_track()?);
+        assert_eq!("Da Funk", flac.get_title()?);
+        assert_eq!("flac", flac.get_ext());
+
+        Ok(())
+    }
+}
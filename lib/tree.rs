@@ -0,0 +1,156 @@
+//! `tree(1)` like rendering of a set of relative paths
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// Draws `paths` (relative to `root`) as a tree below `root`, the entries of
+/// each folder are sorted by name
+pub fn render(root: &Path, paths: &[PathBuf]) -> String {
+    let children = children_of(paths.iter().map(PathBuf::as_path));
+
+    let mut out = format!("{}\n", root.display());
+    render_children(&children, Path::new(""), "", &name_of, &mut out);
+    out
+}
+
+/// Draws only the folders of `paths` (relative to `root`) as a tree below
+/// `root`, each one with the amount of files below it
+pub fn render_folders(root: &Path, paths: &[PathBuf]) -> String {
+    let mut counts: HashMap<&Path, usize> = HashMap::new();
+    for path in paths {
+        for folder in path.ancestors().skip(1) {
+            *counts.entry(folder).or_default() += 1;
+        }
+    }
+
+    let children = children_of(
+        paths
+            .iter()
+            .filter_map(|path| path.parent())
+            .filter(|folder| !folder.as_os_str().is_empty()),
+    );
+    let count = |folder: &Path| files_label(counts.get(folder).copied().unwrap_or_default());
+
+    let mut out = format!("{} ({})\n", root.display(), count(Path::new("")));
+    let label = |folder: &Path| format!("{} ({})", name_of(folder), count(folder));
+    render_children(&children, Path::new(""), "", &label, &mut out);
+    out
+}
+
+fn files_label(count: usize) -> String {
+    match count {
+        1 => "1 file".to_owned(),
+        count => format!("{} files", count),
+    }
+}
+
+/// Entries of each folder leading to `paths`, sorted by name
+fn children_of<'a>(paths: impl IntoIterator<Item = &'a Path>) -> HashMap<PathBuf, Vec<PathBuf>> {
+    let mut children: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+
+    for path in paths {
+        let mut child = path;
+        while let Some(parent) = child.parent() {
+            let siblings = children.entry(parent.to_path_buf()).or_default();
+            if siblings.iter().any(|sibling| sibling == child) {
+                break;
+            }
+
+            siblings.push(child.to_path_buf());
+            child = parent;
+        }
+    }
+
+    for siblings in children.values_mut() {
+        siblings.sort();
+    }
+
+    children
+}
+
+fn name_of(entry: &Path) -> String {
+    entry
+        .file_name()
+        .unwrap_or(entry.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Draws the entries below `dir`, each one as given by `label`
+fn render_children(
+    children: &HashMap<PathBuf, Vec<PathBuf>>,
+    dir: &Path,
+    prefix: &str,
+    label: &dyn Fn(&Path) -> String,
+    out: &mut String,
+) {
+    let Some(entries) = children.get(dir) else {
+        return;
+    };
+
+    for (i, entry) in entries.iter().enumerate() {
+        let last = i + 1 == entries.len();
+        let (branch, indent) = if last {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+
+        let _ = writeln!(out, "{}{}{}", prefix, branch, label(entry));
+
+        render_children(
+            children,
+            entry,
+            &format!("{}{}", prefix, indent),
+            label,
+            out,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_are_drawn_as_tree() {
+        let paths = [
+            PathBuf::from("Artist/Album/2 - Two.mp3"),
+            PathBuf::from("Artist/Album/1 - One.mp3"),
+            PathBuf::from("Another/Single/1 - Song.flac"),
+        ];
+
+        let expected = "\
+music
+├── Another
+│   └── Single
+│       └── 1 - Song.flac
+└── Artist
+    └── Album
+        ├── 1 - One.mp3
+        └── 2 - Two.mp3
+";
+        assert_eq!(expected, render(Path::new("music"), &paths));
+    }
+
+    #[test]
+    fn folders_are_drawn_with_file_counts() {
+        let paths = [
+            PathBuf::from("Artist/Album/2 - Two.mp3"),
+            PathBuf::from("Artist/Album/1 - One.mp3"),
+            PathBuf::from("Artist/Single.mp3"),
+            PathBuf::from("Another/Single/1 - Song.flac"),
+            PathBuf::from("Loose.mp3"),
+        ];
+
+        let expected = "\
+music (5 files)
+├── Another (1 file)
+│   └── Single (1 file)
+└── Artist (3 files)
+    └── Album (2 files)
+";
+        assert_eq!(expected, render_folders(Path::new("music"), &paths));
+    }
+}
@@ -2,8 +2,19 @@ use std::fs::{self, File};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
+use icu_normalizer::DecomposingNormalizerBorrowed;
+use icu_properties::props::GeneralCategory;
+use icu_properties::CodePointMapData;
+
+use crate::metadata;
 use crate::{Error, Result};
 
+/// Extensions of the files that musso knows how to read tags from
+pub const AUDIO_EXTENSIONS: &[&str] = &["mp3", "flac", "ogg", "m4a", "m4p"];
+
+/// Extensions of archives, these are never moved even if they contain music
+pub const ARCHIVE_EXTENSIONS: &[&str] = &["zip", "rar", "7z", "tar", "gz", "bz2", "xz", "zst"];
+
 #[inline]
 pub fn default_config_path() -> PathBuf {
     dirs::config_dir().unwrap().join("musso/config.toml")
@@ -16,6 +27,76 @@ pub fn default_service_path() -> PathBuf {
         .join("systemd/user/musso.service")
 }
 
+/// Where a system-wide unit is installed, see [`Resource::Service::system`]
+#[inline]
+pub fn default_system_service_path() -> PathBuf {
+    PathBuf::from("/etc/systemd/system/musso.service")
+}
+
+fn has_extension_in(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)))
+        .unwrap_or(false)
+}
+
+#[inline]
+pub fn is_archive(path: impl AsRef<Path>) -> bool {
+    has_extension_in(path.as_ref(), ARCHIVE_EXTENSIONS)
+}
+
+/// Whether the file at `path` should be considered for sorting, only files with
+/// an extension from [`AUDIO_EXTENSIONS`] are, or files without extension whose
+/// first bytes belong to a supported container
+pub fn is_audio_file(path: impl AsRef<Path>) -> bool {
+    let path = path.as_ref();
+    if path.extension().is_none() {
+        return matches!(metadata::sniff_container(path), Ok(Some(_)));
+    }
+
+    !is_archive(path) && has_extension_in(path, AUDIO_EXTENSIONS)
+}
+
+/// `s` without the diacritics of its letters (`Motörhead` → `Motorhead`): the
+/// nonspacing marks left by NFD decomposing it. Letters that don't decompose
+/// (like `Æ`, `Ł` or `ß`) are kept
+pub fn fold_diacritics(s: &str) -> String {
+    let categories = CodePointMapData::<GeneralCategory>::new();
+
+    DecomposingNormalizerBorrowed::new_nfd()
+        .normalize(s)
+        .chars()
+        .filter(|&c| categories.get(c) != GeneralCategory::NonspacingMark)
+        .collect()
+}
+
+/// Whether the last component of `path` is a dotfile (e.g. `.DS_Store`), or
+/// on Windows has the hidden attribute
+pub fn is_hidden(path: impl AsRef<Path>) -> bool {
+    let path = path.as_ref();
+    let dotfile = path
+        .file_name()
+        .map(|name| name.to_string_lossy().starts_with('.'))
+        .unwrap_or(false);
+
+    dotfile || has_hidden_attribute(path)
+}
+
+#[cfg(windows)]
+fn has_hidden_attribute(path: &Path) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_HIDDEN;
+
+    fs::symlink_metadata(path)
+        .map(|metadata| metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(windows))]
+fn has_hidden_attribute(_path: &Path) -> bool {
+    false
+}
+
 pub fn maybe_create_dir(path: impl AsRef<Path>) -> std::io::Result<()> {
     match fs::create_dir_all(path) {
         Err(e) => match e.kind() {
@@ -26,52 +107,254 @@ pub fn maybe_create_dir(path: impl AsRef<Path>) -> std::io::Result<()> {
     }
 }
 
+/// Creates `path` and its missing parents, directories created by this call get
+/// their permissions set to `mode` (only supported on unix)
+pub fn create_dir_with_mode(path: impl AsRef<Path>, mode: Option<u32>) -> std::io::Result<()> {
+    let path = path.as_ref();
+
+    let mode = match mode {
+        Some(mode) => mode,
+        None => return maybe_create_dir(path),
+    };
+
+    let missing: Vec<_> = path
+        .ancestors()
+        .take_while(|ancestor| !ancestor.as_os_str().is_empty() && !ancestor.exists())
+        .map(Path::to_path_buf)
+        .collect();
+
+    maybe_create_dir(path)?;
+
+    for dir in missing.iter().rev() {
+        set_dir_mode(dir, mode)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_dir_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_dir_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    log::debug!(
+        "Ignoring directory mode {:o} for \"{}\" (not supported on this platform)",
+        mode,
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// Renames `from` to `to`, falling back to copying and removing `from` when
+/// they are on different file systems. With `auto_chmod` a read-only `from`
+/// is made writable before it's removed
+pub fn move_file(from: &Path, to: &Path, auto_chmod: bool) -> std::io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            copy_and_remove(from, to, auto_chmod)
+        }
+        result => result,
+    }
+}
+
+fn copy_and_remove(from: &Path, to: &Path, auto_chmod: bool) -> std::io::Result<()> {
+    fs::copy(from, to)?;
+    remove_file(from, auto_chmod)
+}
+
+/// Removes `path`, making it writable first if it's read-only and
+/// `auto_chmod` is set
+pub fn remove_file(path: &Path, auto_chmod: bool) -> std::io::Result<()> {
+    if auto_chmod {
+        let permissions = fs::metadata(path)?.permissions();
+        if permissions.readonly() {
+            log::debug!("Making \"{}\" writable to remove it", path.display());
+            fs::set_permissions(path, writable(permissions))?;
+        }
+    }
+
+    fs::remove_file(path)
+}
+
+#[cfg(unix)]
+fn writable(permissions: fs::Permissions) -> fs::Permissions {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::Permissions::from_mode(permissions.mode() | 0o200)
+}
+
+#[cfg(not(unix))]
+#[allow(clippy::permissions_set_readonly_false)]
+fn writable(mut permissions: fs::Permissions) -> fs::Permissions {
+    permissions.set_readonly(false);
+    permissions
+}
+
+/// Access and modification times of `path`, to be restored with
+/// [`filetime::set_file_times`] after moving it
+#[cfg(feature = "preserve-timestamps")]
+pub fn file_times(
+    path: impl AsRef<Path>,
+) -> std::io::Result<(filetime::FileTime, filetime::FileTime)> {
+    use filetime::FileTime;
+
+    let metadata = fs::metadata(path)?;
+    let atime = FileTime::from_system_time(metadata.accessed()?);
+    let mtime = FileTime::from_system_time(metadata.modified()?);
+
+    Ok((atime, mtime))
+}
+
 pub enum Resource {
     Config,
-    Service,
+    /// Systemd unit running `exec watch` with the config files at `configs`
+    Service {
+        exec: PathBuf,
+        configs: Vec<PathBuf>,
+        /// Installed as a system unit instead of a user one
+        system: bool,
+    },
 }
 
-pub fn generate_resource(res: Resource, default: Option<&str>) -> Result<()> {
-    let name = match res {
-        Resource::Config => "config",
-        Resource::Service => "service",
-    };
+impl Resource {
+    /// Replaces the `{{exec}}` placeholder of the service template with the
+    /// binary and `{{config}}` with a `--config` argument per config file,
+    /// other resources are kept as they are
+    fn render(&self, template: String) -> String {
+        match self {
+            Resource::Config => template,
+            Resource::Service { exec, configs, .. } => {
+                let config_args: Vec<String> = configs
+                    .iter()
+                    .map(|config| format!("--config \"{}\"", escape_unit_arg(config)))
+                    .collect();
+
+                template
+                    .replace("{{exec}}", &escape_unit_arg(exec))
+                    .replace("{{config}}", &config_args.join(" "))
+            }
+        }
+    }
+}
+
+/// Escapes `path` to be used inside a double quoted argument of a systemd
+/// unit, where `%` starts a specifier and `$` a variable
+fn escape_unit_arg(path: &Path) -> String {
+    path.to_string_lossy()
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$")
+}
 
+pub fn generate_resource(res: Resource, default: Option<&str>) -> Result<()> {
     let dest = match res {
         Resource::Config => default_config_path(),
-        Resource::Service => default_service_path(),
+        Resource::Service { system: false, .. } => default_service_path(),
+        Resource::Service { system: true, .. } => default_system_service_path(),
+    };
+
+    generate_resource_at(res, &dest, default)
+}
+
+/// Like [`generate_resource`], but writing the file to `dest`
+pub fn generate_resource_at(res: Resource, dest: &Path, default: Option<&str>) -> Result<()> {
+    let name = match res {
+        Resource::Config => "config",
+        Resource::Service { .. } => "service",
     };
 
     log::info!("Generating {} file", name);
 
     let shared = match res {
         Resource::Config => Path::new("/usr/share/musso/config.toml"),
-        Resource::Service => Path::new("/usr/share/musso/musso.service"),
+        Resource::Service { .. } => Path::new("/usr/share/musso/musso.service"),
     };
 
-    if !shared.exists() {
-        if let Some(default) = default {
-            let mut file = File::create(&dest)?;
-            write!(file, "{}", default)?;
-            log::info!("Successfully written to: \"{}\"", dest.to_string_lossy());
-        } else {
-            return Err(Error::ResourceNotFound {
-                path: shared.to_string_lossy().into(),
-            });
+    let parent = dest.parent().ok_or(Error::InvalidParent {
+        child: dest.to_string_lossy().into(),
+    })?;
+
+    maybe_create_dir(parent)?;
+
+    let template = if !shared.exists() {
+        match default {
+            Some(default) => default.to_owned(),
+            None => {
+                return Err(Error::ResourceNotFound {
+                    path: shared.to_string_lossy().into(),
+                })
+            }
         }
     } else {
         log::info!("Copying {} file from shared assets", name);
+        fs::read_to_string(shared)?
+    };
+
+    let mut file = File::create(dest)?;
+    write!(file, "{}", res.render(template))?;
+    log::info!("Successfully written to: \"{}\"", dest.to_string_lossy());
 
-        let parent = dest.parent().ok_or(Error::InvalidParent {
-            child: dest.to_string_lossy().into(),
-        })?;
+    log::info!("Successfully generated {} file", name);
+    Ok(())
+}
 
-        maybe_create_dir(parent)?;
-        fs::copy(shared, &dest)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        log::info!("Successfully copied to: \"{}\"", dest.to_string_lossy());
+    #[test]
+    fn diacritics_are_folded() {
+        assert_eq!("Motorhead", fold_diacritics("Motörhead"));
+        assert_eq!(
+            "Bjork Guðmundsdottir",
+            fold_diacritics("Björk Guðmundsdóttir")
+        );
+        assert_eq!("Dvorak", fold_diacritics("Dvořák"));
+        // Decomposed, o followed by a combining diaeresis
+        assert_eq!("Motorhead", fold_diacritics("Moto\u{308}rhead"));
+        assert_eq!("Æther ß", fold_diacritics("Æther ß"));
+        // Beyond Latin Extended-A
+        assert_eq!("Nguyen Van Ai", fold_diacritics("Nguyễn Văn Ái"));
+        assert_eq!("Ελενη", fold_diacritics("Ελένη"));
     }
 
-    log::info!("Successfully generated {} file", name);
-    Ok(())
+    #[test]
+    fn read_only_files_are_copied_and_removed() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (from, to) = (dir.path().join("ripped.mp3"), dir.path().join("sorted.mp3"));
+        fs::write(&from, "audio")?;
+
+        let mut permissions = fs::metadata(&from)?.permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&from, permissions)?;
+
+        copy_and_remove(&from, &to, true)?;
+        assert!(!from.exists());
+        assert_eq!("audio", fs::read_to_string(&to)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn service_template_gets_quoted_paths() {
+        let service = Resource::Service {
+            exec: PathBuf::from("/opt/musso 2/musso"),
+            configs: vec![
+                PathBuf::from("/etc/musso/config.toml"),
+                PathBuf::from("/home/me/100%/\"music\".toml"),
+            ],
+            system: false,
+        };
+
+        assert_eq!(
+            r#"ExecStart="/opt/musso 2/musso" --config "/etc/musso/config.toml" --config "/home/me/100%%/\"music\".toml" watch"#,
+            service.render(r#"ExecStart="{{exec}}" {{config}} watch"#.to_owned())
+        );
+    }
 }
@@ -1,157 +1,689 @@
+#[cfg(unix)]
+mod control;
+mod event_log;
+mod pool;
+mod scan;
+
 use log::debug;
-use std::borrow::Cow;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::{self, Receiver};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use notify::event::EventKind;
-use notify::RecursiveMode;
-use notify::Watcher as _;
-use notify_debouncer_full::{new_debouncer, DebounceEventResult};
+use notify::event::{EventKind, ModifyKind, RenameMode};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher as _};
+use notify_debouncer_full::{
+    new_debouncer, DebounceEventResult, DebouncedEvent, Debouncer, FileIdMap,
+};
 
-use crate::config::Config;
-use crate::sorting::{sort_file, sort_folder, Options};
+use crate::config::{Config, WatchEvent};
+use crate::format::ParsedFormat;
+use crate::sorting::{sort_folder, sort_one, tidy_emptied, Options, SortReport, Status};
+use crate::utils;
 use crate::{Error, Result};
 
+#[cfg(unix)]
+pub use self::control::send_test_event;
+pub use self::event_log::EventLog;
+use self::pool::ThreadPool;
+pub use self::scan::StartupScan;
+
+/// How often the watch loop wakes up to check whether it should stop
+const SHUTDOWN_POLL: Duration = Duration::from_millis(250);
+
+/// Ignored paths whose event never comes (e.g. folders that existed before
+/// sorting into them) are forgotten after this long
+const IGNORE_TTL: Duration = Duration::from_secs(60);
+
+type WatchSetup = (
+    Debouncer<RecommendedWatcher, FileIdMap>,
+    Receiver<DebounceEventResult>,
+    Option<JoinHandle<()>>,
+);
+
+/// Handle used to stop a watcher started with [`Watcher::watch_with_shutdown`]
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    running: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    /// Signals the watch loop to exit, it will stop after the event currently
+    /// being processed (if any)
+    pub fn shutdown(&self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+}
+
+/// Paths waiting for the sort delay of their library, any new event of a path
+/// (or of something inside it) restarts its wait
+#[derive(Debug, Default)]
+struct Pending {
+    /// Root, library, delay and when it's due for each path
+    paths: HashMap<PathBuf, (PathBuf, String, Duration, Instant)>,
+}
+
+impl Pending {
+    fn push(&mut self, root: PathBuf, library: String, path: PathBuf, delay: Duration) {
+        let due = Instant::now() + delay;
+        self.paths.insert(path, (root, library, delay, due));
+    }
+
+    fn touch(&mut self, path: &Path, now: Instant) {
+        for ancestor in path.ancestors() {
+            if let Some((_, _, delay, due)) = self.paths.get_mut(ancestor) {
+                *due = now + *delay;
+            }
+        }
+    }
+
+    /// Removes and returns the paths whose wait is over at `now`
+    fn take_due(&mut self, now: Instant) -> Vec<(PathBuf, String, PathBuf)> {
+        let due: Vec<PathBuf> = self
+            .paths
+            .iter()
+            .filter(|(_, (_, _, _, due))| *due <= now)
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        due.into_iter()
+            .filter_map(|path| {
+                let (root, library, _, _) = self.paths.remove(&path)?;
+                Some((root, library, path))
+            })
+            .collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.paths.len()
+    }
+}
+
+/// Token bucket used to limit how many files are sorted per second, allows
+/// bursts of up to one second worth of files
+#[derive(Debug, Clone)]
+struct Throttle {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Throttle {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: rate.max(1.0),
+            last: Instant::now(),
+        }
+    }
+
+    /// Blocks until a file can be sorted
+    fn acquire(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate.max(1.0));
+        self.last = now;
+
+        if self.tokens < 1.0 {
+            let wait = Duration::from_secs_f64((1.0 - self.tokens) / self.rate);
+            debug!("Throttling for {:?}", wait);
+            thread::sleep(wait);
+
+            self.tokens = 1.0;
+            self.last = Instant::now();
+        }
+
+        self.tokens -= 1.0;
+    }
+}
+
+/// Back-off state of a root that disappeared while being watched
+#[derive(Debug, Clone)]
+struct Retry {
+    delay: Duration,
+    next: Instant,
+}
+
+/// State shared between the watch loop and the threads sorting files
+#[derive(Debug, Clone)]
+struct Shared {
+    config: Arc<Config>,
+    /// Paths created by musso itself and when, their events are skipped once
+    ignore: Arc<Mutex<HashMap<PathBuf, Instant>>>,
+    throttle: Option<Arc<Mutex<Throttle>>>,
+    /// Files handled since the last heartbeat
+    stats: Arc<Mutex<Stats>>,
+}
+
+/// Counts logged by the heartbeat, see [`WatchConfig::heartbeat_secs`]
+///
+/// [`WatchConfig::heartbeat_secs`]: crate::config::WatchConfig::heartbeat_secs
+#[derive(Debug, Clone, Default)]
+struct Stats {
+    sorted: usize,
+    failed: usize,
+}
+
 #[derive(Debug, Clone)]
 pub struct Watcher {
-    config: Config,
+    config: Arc<Config>,
     roots: HashMap<PathBuf, String>,
-    ignore: HashSet<PathBuf>,
+    running: Arc<AtomicBool>,
+    event_log: Option<EventLog>,
+    control_socket: Option<PathBuf>,
+    idle_timeout: Option<Duration>,
+    startup_scan: Option<StartupScan>,
+    retries: HashMap<PathBuf, Retry>,
+    shared: Shared,
 }
 
 impl Watcher {
     pub fn new(config: Config) -> Self {
-        let mut roots = HashMap::new();
+        let mut roots: HashMap<PathBuf, String> = HashMap::new();
+
+        // Config::validate rejects folders shared by libraries, for configs
+        // built by hand the first library in name order keeps the folder
+        let mut libraries: Vec<_> = config.libraries.iter().collect();
+        libraries.sort_by_key(|(name, _)| *name);
 
-        for (name, library) in &config.libraries {
+        for (name, library) in libraries {
             for folder in &library.folders {
+                if let Some(owner) = roots.get(folder) {
+                    let err = Error::DuplicateFolder {
+                        folder: folder.display().to_string(),
+                        lib_a: owner.to_owned(),
+                        lib_b: name.to_owned(),
+                    };
+                    log::error!("{} (ignoring it for \"{}\")", err, name);
+                    continue;
+                }
+
                 roots.insert(folder.to_owned(), name.to_owned());
             }
         }
 
+        let throttle = match config.watch.max_files_per_sec {
+            Some(rate) if rate > 0.0 && rate.is_finite() => Some(Throttle::new(rate)),
+            Some(rate) => {
+                log::warn!("Invalid max-files-per-sec value: {} (ignoring)", rate);
+                None
+            }
+            None => None,
+        };
+
+        let config = Arc::new(config);
+        let shared = Shared {
+            config: Arc::clone(&config),
+            ignore: Arc::new(Mutex::new(HashMap::new())),
+            throttle: throttle.map(|throttle| Arc::new(Mutex::new(throttle))),
+            stats: Arc::default(),
+        };
+
         Self {
             config,
             roots,
-            ignore: HashSet::new(),
+            running: Arc::new(AtomicBool::new(false)),
+            event_log: None,
+            control_socket: None,
+            idle_timeout: None,
+            startup_scan: None,
+            retries: HashMap::new(),
+            shared,
         }
     }
 
+    /// Records every received event in `log` before processing it
+    pub fn with_event_log(mut self, log: EventLog) -> Self {
+        self.event_log = Some(log);
+        self
+    }
+
+    /// Accepts paths on the unix socket at `path` (see [`send_test_event`]),
+    /// handling each one as if it had just been created
+    #[cfg(unix)]
+    pub fn with_control_socket(mut self, path: impl AsRef<Path>) -> Self {
+        self.control_socket = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Stops watching once no event is received for `timeout`
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Sorts the files already in the library folders (all of them or the
+    /// ones changed since the last run, see [`StartupScan`]) once watching
+    /// starts
+    pub fn with_startup_scan(mut self, scan: StartupScan) -> Self {
+        self.startup_scan = Some(scan);
+        self
+    }
+
+    /// Watches libraries blocking the current thread
     pub fn watch(self) -> Result<()> {
+        match self.setup()? {
+            Some(setup) => self.watchloop(setup),
+            None => Ok(()),
+        }
+    }
+
+    /// Watches libraries in a new thread, the returned handle can be used to
+    /// stop it
+    pub fn watch_with_shutdown(self) -> Result<(JoinHandle<Result<()>>, ShutdownHandle)> {
+        let handle = ShutdownHandle {
+            running: Arc::clone(&self.running),
+        };
+
+        let setup = self.setup()?;
+        let thread = thread::spawn(move || match setup {
+            Some(setup) => self.watchloop(setup),
+            None => Ok(()),
+        });
+
+        Ok((thread, handle))
+    }
+
+    fn setup(&self) -> Result<Option<WatchSetup>> {
         if self.config.libraries.is_empty() {
             log::info!("No directories to watch!");
-            return Ok(());
+            return Ok(None);
         }
 
         let (tx, rx) = mpsc::channel();
-        let delay = Duration::from_secs(self.config.watch.every.unwrap_or_else(|| 1));
+        let delay = Duration::from_secs_f64(self.config.watch.every.unwrap_or(1.0));
+        #[cfg_attr(not(unix), allow(unused_variables))]
+        let test_tx = tx.clone();
         let mut debouncer = new_debouncer(delay, None, tx)?;
 
         for root in self.roots.keys() {
-            debouncer.watcher().watch(root, RecursiveMode::Recursive)?;
+            debouncer
+                .watcher()
+                .watch(root, Self::mode_of(&self.config, &self.roots[root]))?;
         }
 
+        self.running.store(true, Ordering::SeqCst);
         log::info!("Watching libraries");
-        self.watchloop(rx)
+
+        let listener = match &self.control_socket {
+            #[cfg(unix)]
+            Some(socket) => Some(control::listen(
+                socket.clone(),
+                test_tx,
+                Arc::clone(&self.running),
+            )?),
+            _ => None,
+        };
+
+        Ok(Some((debouncer, rx, listener)))
     }
 
-    fn watchloop(mut self, rx: Receiver<DebounceEventResult>) -> Result<()> {
-        loop {
-            for result in &rx {
-                match result {
-                    Err(err) => {
-                        log::error!("{:?}", err);
-                        continue;
-                    }
+    fn watchloop(mut self, (mut debouncer, rx, listener): WatchSetup) -> Result<()> {
+        // Every root gets its own thread unless max-threads is lower, roots
+        // are sorted so that they are always assigned to the same workers
+        let mut roots: Vec<PathBuf> = self.roots.keys().cloned().collect();
+        roots.sort();
+
+        let threads = match self.config.watch.max_threads {
+            Some(max) => roots.len().min(max),
+            None => roots.len(),
+        };
+        let pool = ThreadPool::new(threads);
+        let workers: HashMap<PathBuf, usize> = roots
+            .into_iter()
+            .enumerate()
+            .map(|(i, root)| (root, i % pool.len()))
+            .collect();
+        debug!("Sorting with {} thread(s)", pool.len());
+
+        let shared = self.shared.clone();
+        let sort = |root: PathBuf, library: String, path: PathBuf| {
+            let shared = shared.clone();
+            pool.execute(workers[&root], move || {
+                shared.throttled_move(&root, &library, &path);
+            });
+        };
 
-                    Ok(event) => {
-                        for ev in event {
-                            debug!("{:?}", ev);
-                            match ev.event.kind {
-                                EventKind::Other => {
-                                    continue;
-                                }
-
-                                EventKind::Create(_) => {
-                                    for path in &ev.paths {
-                                        if self.is_ignored(path) {
-                                            self.ignore.remove(path);
-                                            continue;
-                                        }
-                                        match self.move_files(path) {
-                                            Ok(_) => {}
-                                            Err(_) => continue,
-                                        };
-                                    }
-                                }
-                                EventKind::Modify(notify::event::ModifyKind::Name(
-                                    notify::event::RenameMode::Both,
-                                )) => {
-                                    for path in ev.paths.iter().skip(1).step_by(2) {
-                                        if self.is_ignored(path) {
-                                            self.ignore.remove(path);
-                                            continue;
-                                        }
-                                        match self.move_files(path) {
-                                            Ok(_) => {}
-                                            Err(_) => continue,
-                                        }
-                                    }
-                                }
-                                _ => {}
+        if let Some(scan) = &self.startup_scan {
+            match scan.begin() {
+                Ok(Some(since)) => {
+                    for (root, library) in &self.roots {
+                        let options = match self.shared.options_of(library) {
+                            Ok(options) => options,
+                            Err(e) => {
+                                log::error!("{}", e);
+                                continue;
                             }
+                        };
+                        let files: Vec<_> = options
+                            .walker()
+                            .walk(root)
+                            .filter(|file| scan::modified_after(file, since))
+                            .collect();
+                        log::info!(
+                            "Startup scan: {} file(s) to sort in \"{}\"",
+                            files.len(),
+                            root.display()
+                        );
+
+                        for file in files {
+                            sort(root.clone(), library.clone(), file);
                         }
                     }
                 }
+                Ok(None) => {}
+                Err(e) => log::error!("Couldn't record the start of this run ({})", e),
             }
         }
-    }
 
-    fn ignore_path<P, R>(&mut self, path: P, root: R) -> Result<()>
-    where
-        P: AsRef<Path>,
-        R: AsRef<Path>,
-    {
-        let root = root.as_ref();
-        let path = path.as_ref();
+        let mut pending = Pending::default();
+        let mut last_event = Instant::now();
+        let heartbeat = self.config.watch.heartbeat_secs.map(Duration::from_secs);
+        let mut last_heartbeat = Instant::now();
+
+        while self.running.load(Ordering::SeqCst) {
+            self.check_roots(&mut debouncer, Instant::now());
 
-        let parent = path.parent().ok_or(Error::InvalidParent {
-            child: path.to_string_lossy().into(),
-        })?;
+            if heartbeat.is_some_and(|every| last_heartbeat.elapsed() >= every) {
+                self.shared.heartbeat();
+                last_heartbeat = Instant::now();
+            }
+
+            for (root, library, path) in pending.take_due(Instant::now()) {
+                sort(root, library, path);
+            }
+
+            let result = match rx.recv_timeout(SHUTDOWN_POLL) {
+                Ok(result) => result,
+                Err(RecvTimeoutError::Timeout) => match self.idle_timeout {
+                    Some(timeout) if last_event.elapsed() >= timeout && pending.is_empty() => {
+                        log::info!("No events for {:?}, exiting", timeout);
+                        break;
+                    }
+                    _ => continue,
+                },
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            last_event = Instant::now();
+
+            match result {
+                Err(err) => {
+                    log::error!("{:?}", err);
+                    continue;
+                }
+
+                Ok(event) => {
+                    for ev in event {
+                        self.record_event(&ev);
+
+                        for path in &ev.event.paths {
+                            pending.touch(path, last_event);
+                        }
+
+                        for (root, library, dir) in self.moved_away(&ev) {
+                            let shared = self.shared.clone();
+                            pool.execute(workers[&root], move || {
+                                shared.prune_moved_away(&root, &library, &dir);
+                            });
+                        }
+
+                        for (root, library, path) in self.event_targets(ev) {
+                            let delay = self.config.sort_delay_of(&library);
+                            if delay.is_zero() {
+                                sort(root, library, path);
+                            } else {
+                                pending.push(root, library, path, delay);
+                            }
+                        }
+                    }
+                }
+            }
+        }
 
-        //why is this necessary?
-        if parent != root {
-            self.ignore.insert(parent.to_path_buf());
+        if !pending.is_empty() {
+            log::warn!(
+                "{} path(s) still waiting for their sort delay were not sorted",
+                pending.len()
+            );
         }
 
-        self.ignore.insert(root.to_path_buf().join(path));
+        // Waits for the files already queued
+        drop(pool);
+
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(listener) = listener {
+            let _ = listener.join();
+        }
+        log::info!("Stopped watching libraries");
 
         Ok(())
     }
 
-    fn is_ignored(&self, path: impl AsRef<Path>) -> bool {
-        let path = path.as_ref();
+    /// Notices roots that disappeared and tries to watch them again once they
+    /// re-appear, backing off exponentially between attempts
+    fn check_roots(
+        &mut self,
+        debouncer: &mut Debouncer<RecommendedWatcher, FileIdMap>,
+        now: Instant,
+    ) {
+        let base = Duration::from_secs(self.config.watch.retry_delay_secs.unwrap_or(5));
+        let max = Duration::from_secs(self.config.watch.max_retry_delay_secs.unwrap_or(300));
+
+        for root in self.roots.keys() {
+            let retry = match self.retries.get_mut(root) {
+                Some(retry) => retry,
+
+                None if root.exists() => continue,
+
+                None => {
+                    log::warn!("Library root \"{}\" disappeared", root.display());
+                    let _ = debouncer.watcher().unwatch(root);
+
+                    Self::log_next_retry(root, base);
+                    self.retries.insert(
+                        root.to_owned(),
+                        Retry {
+                            delay: base,
+                            next: now + base,
+                        },
+                    );
+
+                    continue;
+                }
+            };
+
+            if now < retry.next {
+                continue;
+            }
 
-        if path.is_file() {
-            self.ignore.contains(path)
+            if root.exists() {
+                let mode = Self::mode_of(&self.config, &self.roots[root]);
+                match debouncer.watcher().watch(root, mode) {
+                    Ok(_) => {
+                        log::info!("Watching again library root \"{}\"", root.display());
+                        self.retries.remove(root);
+                        continue;
+                    }
+
+                    Err(e) => {
+                        log::error!("Couldn't watch \"{}\" ({})", root.display(), e);
+                    }
+                }
+            }
+
+            retry.delay = (retry.delay * 2).min(max);
+            retry.next = now + retry.delay;
+            Self::log_next_retry(root, retry.delay);
+        }
+    }
+
+    fn mode_of(config: &Config, library: &str) -> RecursiveMode {
+        if config.is_recursive(library) {
+            RecursiveMode::Recursive
         } else {
-            for ignored in &self.ignore {
-                if !ignored.is_dir() {
+            RecursiveMode::NonRecursive
+        }
+    }
+
+    fn log_next_retry(root: &Path, delay: Duration) {
+        let at = chrono::Local::now() + delay;
+        log::info!(
+            "Next attempt to watch \"{}\" in {}s (at {})",
+            root.display(),
+            delay.as_secs(),
+            at.format("%H:%M:%S")
+        );
+    }
+
+    /// Processes the events already queued in `rx` in the current thread,
+    /// stopping once it's empty or `timeout` elapses. Meant for tests, which
+    /// can feed the channel themselves instead of waiting for the debouncer,
+    /// so the sort delays of the libraries don't apply
+    pub fn drain(
+        &mut self,
+        rx: &Receiver<DebounceEventResult>,
+        timeout: Duration,
+    ) -> Result<SortReport> {
+        let deadline = Instant::now() + timeout;
+        let mut report = SortReport::default();
+
+        while Instant::now() < deadline {
+            let events = match rx.try_recv() {
+                Ok(Ok(events)) => events,
+                Ok(Err(errors)) => {
+                    log::error!("{:?}", errors);
                     continue;
                 }
+                Err(_) => break,
+            };
+
+            for ev in events {
+                self.record_event(&ev);
 
-                if ignored.starts_with(path) {
-                    return true;
+                for (root, library, dir) in self.moved_away(&ev) {
+                    self.shared.prune_moved_away(&root, &library, &dir);
+                }
+
+                for (root, library, path) in self.event_targets(ev) {
+                    let moved = self.shared.throttled_move(&root, &library, &path);
+                    report = report.merge(moved);
                 }
             }
+        }
 
-            false
+        Ok(report)
+    }
+
+    fn record_event(&self, ev: &DebouncedEvent) {
+        if let Some(log) = &self.event_log {
+            if let Err(e) = log.record(ev) {
+                log::error!(
+                    "Couldn't write to event log \"{}\" ({})",
+                    log.path().display(),
+                    e
+                );
+            }
         }
     }
 
+    /// Paths of `ev` that have to be sorted, along with their root and library
+    fn event_targets(&self, ev: DebouncedEvent) -> Vec<(PathBuf, String, PathBuf)> {
+        debug!("{:?}", ev);
+        let watch = &self.config.watch;
+        let paths: Vec<PathBuf> = match ev.event.kind {
+            EventKind::Create(_) if watch.is_handled(WatchEvent::Create) => ev.event.paths,
+
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both))
+                if watch.is_handled(WatchEvent::Rename) =>
+            {
+                ev.event.paths.into_iter().skip(1).step_by(2).collect()
+            }
+
+            // Edited tags may give the file another destination
+            EventKind::Modify(ModifyKind::Data(_)) if watch.is_handled(WatchEvent::ModifyData) => {
+                ev.event
+                    .paths
+                    .into_iter()
+                    .filter(|path| path.is_file())
+                    .collect()
+            }
+
+            _ => return Vec::new(),
+        };
+
+        let mut targets = Vec::new();
+        for path in paths {
+            if self.shared.take_ignored(&path) {
+                continue;
+            }
+
+            let Some(root) = self.root_for(&path) else {
+                log::error!(
+                    "{}",
+                    Error::InvalidRoot {
+                        path: path.to_string_lossy().to_string(),
+                    }
+                );
+                continue;
+            };
+
+            let library = self.roots[&root].clone();
+            // Left out the same way as when sorting the whole root
+            match self.shared.options_of(&library) {
+                Ok(options) if options.walker().leaves_out(&root, &path) => {
+                    debug!("Ignoring \"{}\"", path.display());
+                    continue;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    log::error!("{}", e);
+                    continue;
+                }
+            }
+
+            targets.push((root, library, path));
+        }
+
+        targets
+    }
+
+    /// Folders left behind by files moved out of the watched roots, along
+    /// with their root and library
+    fn moved_away(&self, ev: &DebouncedEvent) -> Vec<(PathBuf, String, PathBuf)> {
+        if ev.event.kind != EventKind::Modify(ModifyKind::Name(RenameMode::From)) {
+            return Vec::new();
+        }
+
+        ev.event
+            .paths
+            .iter()
+            .filter_map(|path| {
+                let dir = path.parent()?;
+                let root = self.root_for(dir)?;
+                let library = self.roots[&root].clone();
+
+                Some((root, library, dir.to_path_buf()))
+            })
+            .collect()
+    }
+
+    /// Deepest library root containing `path`, so files inside nested
+    /// roots belong to the innermost library
     fn root_for(&self, path: impl AsRef<Path>) -> Option<PathBuf> {
         let path = path.as_ref();
+        // ancestors() starts from `path` itself and walks upwards
         for ancestor in path.ancestors() {
             if self.roots.contains_key(ancestor) {
                 return Some(ancestor.to_path_buf());
@@ -160,59 +692,397 @@ impl Watcher {
 
         None
     }
+}
 
-    fn move_files(&mut self, path: impl AsRef<Path>) -> Result<()> {
-        let path = path.as_ref();
-        if let Some(root) = self.root_for(path) {
-            let library = &self.roots[&root];
-
-            let options = Options {
-                format: Cow::Borrowed(self.config.format_of(library).unwrap()),
-                dryrun: false,
-                recursive: true,
-                exfat_compat: self.config.is_exfat_compat(library),
-                remove_empty: true,
-            };
+impl Shared {
+    fn throttled_move(&self, root: &Path, library: &str, path: &Path) -> SortReport {
+        if let Some(throttle) = &self.throttle {
+            throttle.lock().unwrap().acquire();
+        }
 
-            if path.is_dir() {
-                match sort_folder(&root, path, &options) {
-                    Ok(report) => {
-                        log::info!(
-                            "Done: {} successful out of {} ({} failed)",
-                            report.success,
-                            report.total,
-                            report.total - report.success
-                        );
+        let report = self.move_files(root, library, path);
+
+        let failed = report
+            .outcomes
+            .iter()
+            .filter(|outcome| outcome.status == Status::Failed)
+            .count();
+        let mut stats = self.stats.lock().unwrap();
+        stats.sorted += report.success;
+        stats.failed += failed;
+
+        report
+    }
+
+    /// Logs the files handled since the previous heartbeat and forgets them
+    fn heartbeat(&self) {
+        let stats = std::mem::take(&mut *self.stats.lock().unwrap());
+        let ignored = self.ignore.lock().unwrap().len();
+
+        log::info!(
+            "Heartbeat: {} file(s) sorted, {} failed, {} path(s) ignored",
+            stats.sorted,
+            stats.failed,
+            ignored
+        );
+    }
+
+    /// Skips the next event of `new_path` (relative to `root`) and of each
+    /// folder between it and `root`, as sorting may have created any of them.
+    /// Fails without ignoring anything if one of them is a library folder or
+    /// contains one, its events would be lost
+    fn ignore_path(&self, new_path: &Path, root: &Path) -> Result<()> {
+        let path = root.join(new_path);
+        let ancestors: Vec<_> = path
+            .ancestors()
+            .take_while(|ancestor| *ancestor != root)
+            .collect();
+
+        let folders = self
+            .config
+            .libraries
+            .values()
+            .flat_map(|library| &library.folders);
+        for folder in folders {
+            if ancestors
+                .iter()
+                .any(|ancestor| folder.starts_with(ancestor))
+            {
+                return Err(Error::CircularIgnore {
+                    root: folder.clone(),
+                });
+            }
+        }
+
+        let now = Instant::now();
+        let mut ignore = self.ignore.lock().unwrap();
+        for ancestor in ancestors {
+            ignore.insert(ancestor.to_path_buf(), now);
+        }
 
-                        for new_path in report.new_paths {
-                            self.ignore_path(new_path, &root)?;
+        Ok(())
+    }
+
+    /// Whether the event of `path` must be skipped, forgetting it if so
+    fn take_ignored(&self, path: &Path) -> bool {
+        let mut ignore = self.ignore.lock().unwrap();
+        ignore.retain(|_, since| since.elapsed() < IGNORE_TTL);
+
+        ignore.remove(path).is_some()
+    }
+
+    /// Options of the config of `library` along with the ones of the watcher
+    fn options_of(&self, library: &str) -> Result<Options<ParsedFormat>> {
+        let watch = &self.config.watch;
+
+        Ok(Options {
+            recursive: true,
+            remove_empty: watch.mark_empty.is_none() && watch.remove_empty.unwrap_or(true),
+            mark_empty: watch.mark_empty.clone(),
+            skip_hidden: watch.ignore_hidden.unwrap_or(true),
+            // Counting the artists of a folder for every event would read
+            // its tags again each time, files arriving one by one would be
+            // counted before the rest is there too
+            compilation: None,
+            ..self.config.options_of(library)?
+        })
+    }
+
+    /// Tidies up the folders emptied by a file moved out of `root`
+    fn prune_moved_away(&self, root: &Path, library: &str, dir: &Path) {
+        match self.options_of(library) {
+            Ok(options) => tidy_emptied(dir, root, &options),
+            Err(e) => log::error!("{}", e),
+        }
+    }
+
+    fn move_files(&self, root: &Path, library: &str, path: &Path) -> SortReport {
+        let options = match self.options_of(library) {
+            Ok(options) => options,
+            Err(e) => {
+                log::error!("{}", e);
+                return SortReport::default();
+            }
+        };
+
+        if path.is_dir() {
+            match sort_folder(root, path, &options) {
+                Ok(report) => {
+                    log::info!(
+                        "Done: {} successful out of {} ({} failed)",
+                        report.success,
+                        report.total,
+                        report.total - report.success
+                    );
+
+                    for new_path in &report.new_paths {
+                        if let Err(e) = self.ignore_path(new_path, root) {
+                            log::error!("{}", e);
                         }
-                        Ok(())
                     }
 
-                    Err(e) => {
-                        log::error!("{}", e);
-                        Err(e)
-                    }
+                    report
                 }
-            } else {
-                match sort_file(&root, path, &options) {
-                    Ok(new_path) => {
-                        log::info!("Done: 1 successful out of 1 (0 failed)");
-                        self.ignore_path(new_path, root)?;
-                        Ok(())
-                    }
 
-                    Err(e) => {
+                Err(e) => {
+                    log::error!("{}", e);
+                    SortReport::default()
+                }
+            }
+        } else if !utils::is_audio_file(path) {
+            log::debug!("Skipping non audio file: \"{}\"", path.display());
+            SortReport::default()
+        } else {
+            match sort_one(root, path, &options) {
+                Ok(sorted) => {
+                    log::info!("Done: 1 successful out of 1 (0 failed)");
+                    if let Err(e) = self.ignore_path(&sorted.new_path, root) {
                         log::error!("{}", e);
-                        Err(e)
                     }
+
+                    let mut report = SortReport::default();
+                    report.push_sorted(root, path, sorted, false);
+                    report
+                }
+
+                Err(e) if e.is_skip() => {
+                    let mut report = SortReport::default();
+                    report.push_skipped_by(path, &e);
+                    report
+                }
+
+                Err(e) => {
+                    log::error!("{}", e);
+
+                    let mut report = SortReport::default();
+                    report.push_failed(path, &e);
+                    report
                 }
             }
-        } else {
-            Err(Error::InvalidRoot {
-                path: path.to_string_lossy().to_string(),
-            })
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending_waits_since_last_event() {
+        let mut pending = Pending::default();
+        let start = Instant::now();
+        let dir = PathBuf::from("/music/Download");
+
+        pending.push(
+            PathBuf::from("/music"),
+            "default".into(),
+            dir.clone(),
+            Duration::from_secs(1),
+        );
+        assert!(pending.take_due(start).is_empty());
+
+        // A file still being written inside the folder restarts its wait
+        pending.touch(
+            &dir.join("song.mp3.part"),
+            start + Duration::from_millis(500),
+        );
+        assert!(pending
+            .take_due(start + Duration::from_millis(1200))
+            .is_empty());
+
+        let due = pending.take_due(start + Duration::from_millis(1600));
+        assert_eq!(
+            vec![(PathBuf::from("/music"), "default".to_owned(), dir)],
+            due
+        );
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn throttle_limits_rate() {
+        let mut throttle = Throttle::new(20.0);
+        let start = Instant::now();
+
+        // The first 20 are the allowed burst, the next 10 need half a second
+        for _ in 0..30 {
+            throttle.acquire();
+        }
+
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+
+    #[test]
+    fn retries_back_off_until_root_reappears() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path().join("music");
+        std::fs::create_dir(&root)?;
+
+        let config_path = dir.path().join("config.toml");
+        std::fs::write(
+            &config_path,
+            format!(
+                r#"
+[watch]
+libraries = ['music']
+retry-delay-secs = 1
+max-retry-delay-secs = 4
+
+[libraries.music]
+format = '{{artist}}/{{album}}/{{title}}.{{ext}}'
+folders = ['{}']
+"#,
+                root.display()
+            ),
+        )?;
+
+        let mut watcher = Watcher::new(Config::from_path(config_path)?);
+        let (tx, _rx) = std::sync::mpsc::channel();
+        let mut debouncer = new_debouncer(Duration::from_secs(1), None, tx)?;
+        debouncer.watcher().watch(&root, RecursiveMode::Recursive)?;
+
+        let start = Instant::now();
+        let mut delay_at = |secs: u64| {
+            watcher.check_roots(&mut debouncer, start + Duration::from_secs(secs));
+            watcher
+                .retries
+                .get(&root)
+                .map(|retry| retry.delay.as_secs())
+        };
+
+        std::fs::remove_dir(&root)?;
+        assert_eq!(Some(1), delay_at(0));
+        // Not due yet
+        assert_eq!(Some(1), delay_at(0));
+        assert_eq!(Some(2), delay_at(1));
+        assert_eq!(Some(4), delay_at(3));
+        // Capped by max-retry-delay-secs
+        assert_eq!(Some(4), delay_at(7));
+
+        std::fs::create_dir(&root)?;
+        assert_eq!(Some(4), delay_at(10));
+        assert_eq!(None, delay_at(11));
+
+        // Starts over from the base delay once it's gone again
+        std::fs::remove_dir(&root)?;
+        assert_eq!(Some(1), delay_at(12));
+
+        Ok(())
+    }
+
+    fn nested_config() -> Result<(tempfile::TempDir, Config)> {
+        let dir = tempfile::tempdir()?;
+        let outer = dir.path().join("music");
+        let inner = outer.join("podcasts");
+        std::fs::create_dir_all(&inner)?;
+
+        let config_path = dir.path().join("config.toml");
+        std::fs::write(
+            &config_path,
+            format!(
+                r#"
+[watch]
+libraries = ['music', 'podcasts']
+
+[libraries.music]
+format = '{{artist}}/{{album}}/{{title}}.{{ext}}'
+folders = ['{}']
+
+[libraries.podcasts]
+format = '{{artist}}/{{title}}.{{ext}}'
+folders = ['{}']
+"#,
+                outer.display(),
+                inner.display()
+            ),
+        )?;
+
+        let config = Config::from_path(config_path)?;
+        Ok((dir, config))
+    }
+
+    #[test]
+    fn nested_roots_use_deepest_library() -> Result<()> {
+        let (dir, config) = nested_config()?;
+        let outer = dir.path().join("music");
+        let inner = outer.join("podcasts");
+
+        assert_eq!(
+            vec![(outer.as_path(), inner.as_path())],
+            config.nested_folders()
+        );
+
+        let watcher = Watcher::new(config);
+        assert_eq!(Some(inner.clone()), watcher.root_for(inner.join("a/b.mp3")));
+        assert_eq!(Some(inner.clone()), watcher.root_for(&inner));
+        assert_eq!(Some(outer.clone()), watcher.root_for(outer.join("a/b.mp3")));
+        assert_eq!(None, watcher.root_for(dir.path().join("b.mp3")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn library_folders_are_never_ignored() -> Result<()> {
+        let (dir, config) = nested_config()?;
+        let outer = dir.path().join("music");
+        let inner = outer.join("podcasts");
+
+        let watcher = Watcher::new(config);
+        let ignore = |new_path: &str| watcher.shared.ignore_path(Path::new(new_path), &outer);
+
+        // Sorting a file of the outer library into the folder of the inner one
+        assert!(matches!(
+            ignore("podcasts/Artist/Song.mp3"),
+            Err(Error::CircularIgnore { root }) if root == inner
+        ));
+        assert!(!watcher.shared.take_ignored(&inner.join("Artist")));
+
+        ignore("Artist/Album/Song.mp3")?;
+        assert!(watcher.shared.take_ignored(&outer.join("Artist")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn heartbeat_resets_stats() -> Result<()> {
+        let (dir, config) = nested_config()?;
+        let outer = dir.path().join("music");
+        let fixture = crate::testing::FixtureBuilder::new()
+            .file("song.mp3")
+            .artist("Artist")
+            .album("Album")
+            .title("Song")
+            .build()?;
+        let song = outer.join("song.mp3");
+        std::fs::copy(fixture.path().join("song.mp3"), &song)?;
+
+        let watcher = Watcher::new(config);
+        watcher.shared.throttled_move(&outer, "music", &song);
+        watcher
+            .shared
+            .throttled_move(&outer, "music", &outer.join("gone.mp3"));
+        {
+            let stats = watcher.shared.stats.lock().unwrap();
+            assert_eq!((1, 1), (stats.sorted, stats.failed));
+        }
+
+        watcher.shared.heartbeat();
+        let stats = watcher.shared.stats.lock().unwrap();
+        assert_eq!((0, 0), (stats.sorted, stats.failed));
+
+        Ok(())
+    }
+
+    #[test]
+    fn shared_folder_keeps_first_library() -> Result<()> {
+        let (dir, mut config) = nested_config()?;
+        let outer = dir.path().join("music");
+
+        let podcasts = config.libraries.get_mut("podcasts").unwrap();
+        podcasts.folders.push(outer.clone());
+
+        let watcher = Watcher::new(config);
+        assert_eq!("music", watcher.roots[&outer]);
+
+        Ok(())
+    }
+}
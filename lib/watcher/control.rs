@@ -0,0 +1,107 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use notify::event::{CreateKind, Event, EventKind};
+use notify_debouncer_full::{DebounceEventResult, DebouncedEvent};
+
+use super::SHUTDOWN_POLL;
+use crate::Result;
+
+/// Asks the watcher listening on `socket` to handle `path` as if it had just
+/// been created
+pub fn send_test_event(socket: impl AsRef<Path>, path: impl AsRef<Path>) -> Result<()> {
+    let path = std::path::absolute(path)?;
+
+    let mut stream = UnixStream::connect(socket)?;
+    writeln!(stream, "{}", path.display())?;
+
+    Ok(())
+}
+
+/// Listens on `socket` until `running` is unset, every line received is sent
+/// as a create event of that path to `tx`
+pub(super) fn listen(
+    socket: PathBuf,
+    tx: Sender<DebounceEventResult>,
+    running: Arc<AtomicBool>,
+) -> Result<JoinHandle<()>> {
+    // A previous watcher that didn't exit cleanly leaves the socket behind
+    if socket.exists() && UnixStream::connect(&socket).is_err() {
+        fs::remove_file(&socket)?;
+    }
+
+    let listener = UnixListener::bind(&socket)?;
+    listener.set_nonblocking(true)?;
+    log::info!("Accepting test events on \"{}\"", socket.display());
+
+    let thread = thread::spawn(move || {
+        while running.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = forward_events(stream, &tx, &running) {
+                        log::error!("Couldn't read test event ({})", e);
+                    }
+                }
+
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(SHUTDOWN_POLL),
+                Err(e) => log::error!("Couldn't accept test event ({})", e),
+            }
+        }
+
+        let _ = fs::remove_file(&socket);
+    });
+
+    Ok(thread)
+}
+
+/// Sends an event for every line of `stream` until it's closed or `running`
+/// is unset, a client that keeps the connection open doesn't hold up shutdown
+fn forward_events(
+    stream: UnixStream,
+    tx: &Sender<DebounceEventResult>,
+    running: &AtomicBool,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(SHUTDOWN_POLL))?;
+
+    let mut stream = BufReader::new(stream);
+    let mut line = Vec::new();
+    while running.load(Ordering::SeqCst) {
+        // Timing out keeps what was read of the line so far
+        match stream.read_until(b'\n', &mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e),
+        }
+
+        let read = String::from_utf8_lossy(&line);
+        let path = PathBuf::from(read.trim_end_matches(['\n', '\r']));
+        line.clear();
+        log::info!("Received test event: \"{}\"", path.display());
+
+        let event = Event::new(EventKind::Create(CreateKind::Any)).add_path(path);
+        if tx
+            .send(Ok(vec![DebouncedEvent::new(event, Instant::now())]))
+            .is_err()
+        {
+            break;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,133 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+use notify_debouncer_full::DebouncedEvent;
+use serde::Serialize;
+
+use crate::Result;
+
+/// Appends every received file-system event as a JSON line to a file, useful
+/// to get a trace of what the watcher saw
+#[derive(Debug, Clone)]
+pub struct EventLog {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct LoggedEvent<'e> {
+    timestamp: DateTime<Local>,
+    kind: String,
+    paths: &'e [PathBuf],
+}
+
+impl EventLog {
+    /// Once the log reaches `max_mb` megabytes it's moved to `<path>.1`
+    /// (replacing any previous one) and a new log is started
+    pub fn new(path: impl AsRef<Path>, max_mb: Option<u64>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            max_bytes: max_mb.map(|mb| mb * 1024 * 1024),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&self, event: &DebouncedEvent) -> Result<()> {
+        let logged = LoggedEvent {
+            timestamp: Local::now(),
+            kind: format!("{:?}", event.kind),
+            paths: &event.paths,
+        };
+
+        let mut line = serde_json::to_vec(&logged).map_err(std::io::Error::from)?;
+        line.push(b'\n');
+
+        self.maybe_rotate(line.len() as u64)?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        file.write_all(&line)?;
+        Ok(())
+    }
+
+    fn maybe_rotate(&self, incoming: u64) -> Result<()> {
+        let max_bytes = match self.max_bytes {
+            Some(max_bytes) => max_bytes,
+            None => return Ok(()),
+        };
+
+        let len = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return Ok(()),
+        };
+
+        if len > 0 && len + incoming > max_bytes {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            fs::rename(&self.path, rotated)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use notify::event::{CreateKind, EventKind};
+    use notify::Event;
+
+    use super::*;
+
+    fn create_event(path: &str) -> DebouncedEvent {
+        let event = Event::new(EventKind::Create(CreateKind::File)).add_path(path.into());
+        DebouncedEvent::new(event, Instant::now())
+    }
+
+    #[test]
+    fn records_json_lines() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let log = EventLog::new(dir.path().join("events.log"), None);
+
+        log.record(&create_event("/music/a.flac"))?;
+        log.record(&create_event("/music/b.flac"))?;
+
+        let contents = fs::read_to_string(log.path())?;
+        let lines: Vec<_> = contents.lines().collect();
+
+        assert_eq!(2, lines.len());
+        assert!(lines[0].contains("\"paths\":[\"/music/a.flac\"]"));
+        assert!(lines[1].contains("\"kind\":\"Create(File)\""));
+
+        Ok(())
+    }
+
+    #[test]
+    fn rotates_when_full() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let log = EventLog {
+            path: dir.path().join("events.log"),
+            max_bytes: Some(1),
+        };
+
+        log.record(&create_event("/music/a.flac"))?;
+        log.record(&create_event("/music/b.flac"))?;
+
+        let current = fs::read_to_string(log.path())?;
+        let rotated = fs::read_to_string(dir.path().join("events.log.1"))?;
+
+        assert!(current.contains("b.flac"));
+        assert!(rotated.contains("a.flac"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fixed amount of threads, each one runs the jobs sent to it in order
+#[derive(Debug)]
+pub(super) struct ThreadPool {
+    workers: Vec<Worker>,
+}
+
+#[derive(Debug)]
+struct Worker {
+    sender: Option<Sender<Job>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    /// Spawns `size` threads (at least one)
+    pub(super) fn new(size: usize) -> Self {
+        let workers = (0..size.max(1))
+            .map(|id| {
+                let (sender, receiver) = mpsc::channel::<Job>();
+                let thread = thread::Builder::new()
+                    .name(format!("musso-worker-{}", id))
+                    .spawn(move || {
+                        for job in receiver {
+                            // A panicking job shouldn't take down the other
+                            // jobs of this worker
+                            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                                log::error!("Worker {} panicked while sorting", id);
+                            }
+                        }
+                    })
+                    .expect("failed to spawn worker thread");
+
+                Worker {
+                    sender: Some(sender),
+                    thread: Some(thread),
+                }
+            })
+            .collect();
+
+        Self { workers }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Runs `job` in the worker that `index` maps to, jobs sent with the same
+    /// index run one after the other
+    pub(super) fn execute<F>(&self, index: usize, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let worker = &self.workers[index % self.workers.len()];
+        if let Some(sender) = &worker.sender {
+            if sender.send(Box::new(job)).is_err() {
+                log::error!(
+                    "Worker {} is gone, dropping job",
+                    index % self.workers.len()
+                );
+            }
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    /// Waits for the queued jobs to finish
+    fn drop(&mut self) {
+        for worker in &mut self.workers {
+            worker.sender.take();
+        }
+
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn jobs_of_a_worker_run_in_order() {
+        let pool = ThreadPool::new(2);
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        for i in 0..10 {
+            let order = Arc::clone(&order);
+            pool.execute(0, move || order.lock().unwrap().push(i));
+        }
+
+        // Worker 1 keeps running jobs after one of them panics
+        pool.execute(1, || panic!("panicking job"));
+        let after_panic = Arc::clone(&order);
+        pool.execute(1, move || after_panic.lock().unwrap().push(10));
+        drop(pool);
+
+        let order = order.lock().unwrap();
+        assert_eq!(11, order.len());
+        assert_eq!(
+            (0..10).collect::<Vec<_>>(),
+            order
+                .iter()
+                .copied()
+                .filter(|i| *i < 10)
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,65 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::utils;
+use crate::Result;
+
+/// Files of the library folders sorted before watching starts, see
+/// [`super::Watcher::with_startup_scan`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StartupScan {
+    /// Every file
+    Everything,
+    /// Only files modified after the previous run, whose start time is kept
+    /// in `state_file`. The first run (without a time yet) sorts everything
+    /// with `first_run_all` and nothing otherwise
+    SinceLastRun {
+        state_file: PathBuf,
+        first_run_all: bool,
+    },
+}
+
+impl StartupScan {
+    /// Files modified after the returned time have to be sorted, `None` if
+    /// none has. The start of this run is recorded for the next one
+    pub(super) fn begin(&self) -> Result<Option<SystemTime>> {
+        let (state_file, first_run_all) = match self {
+            StartupScan::Everything => return Ok(Some(UNIX_EPOCH)),
+            StartupScan::SinceLastRun {
+                state_file,
+                first_run_all,
+            } => (state_file, *first_run_all),
+        };
+
+        let last_run = fs::read_to_string(state_file)
+            .ok()
+            .and_then(|secs| secs.trim().parse().ok())
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if let Some(parent) = state_file.parent() {
+            utils::maybe_create_dir(parent)?;
+        }
+        fs::write(state_file, format!("{}\n", now))?;
+
+        Ok(match last_run {
+            Some(last_run) => Some(last_run),
+            None if first_run_all => Some(UNIX_EPOCH),
+            None => {
+                log::info!("First run, recording the time without sorting existing files");
+                None
+            }
+        })
+    }
+}
+
+/// Whether `path` was modified after `since`
+pub(super) fn modified_after(path: &Path, since: SystemTime) -> bool {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|mtime| mtime > since)
+}
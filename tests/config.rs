@@ -0,0 +1,99 @@
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use musso::config::Config;
+use musso::Result;
+
+fn write_config(path: &Path, folder: &Path, format: &str) -> Result<()> {
+    let contents = format!(
+        r#"
+[watch]
+every = 1
+libraries = ['default']
+
+[libraries.default]
+format = '{}'
+folders = ['{}']
+"#,
+        format,
+        folder.display()
+    );
+
+    fs::write(path, contents)?;
+    Ok(())
+}
+
+fn format_of(config: &Config) -> &str {
+    config.format_of("default").unwrap().as_str()
+}
+
+#[test]
+fn config_is_read_again_when_loaded() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("config.toml");
+
+    write_config(&path, dir.path(), "{artist}/{title}.{ext}")?;
+    let config = Config::from_path(&path)?;
+    assert_eq!("{artist}/{title}.{ext}", format_of(&config));
+
+    write_config(&path, dir.path(), "{album}/{title}.{ext}")?;
+    let config = Config::from_path(&path)?;
+    assert_eq!("{album}/{title}.{ext}", format_of(&config));
+
+    Ok(())
+}
+
+#[test]
+fn changed_configs_are_sent() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("config.toml");
+    write_config(&path, dir.path(), "{artist}/{title}.{ext}")?;
+
+    let (tx, rx) = mpsc::channel();
+    let _watcher = Config::watch_for_changes(&path, tx)?;
+
+    write_config(&path, dir.path(), "{album}/{title}.{ext}")?;
+
+    // A write can be seen halfway, so wait for the complete config
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let config = rx
+            .recv_timeout(remaining)
+            .expect("the changed config wasn't sent");
+
+        if format_of(&config) == "{album}/{title}.{ext}" {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn formats_can_name_presets() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("config.toml");
+
+    write_config(&path, dir.path(), "preset:standard")?;
+    let mut contents = fs::read_to_string(&path)?;
+    contents.push_str(
+        "\n[presets]\nstandard = 'preset:albums'\nalbums = '{artist}/{album}/{title}.{ext}'\n",
+    );
+    fs::write(&path, &contents)?;
+    let config = Config::from_path(&path)?;
+    assert_eq!("{artist}/{album}/{title}.{ext}", format_of(&config));
+
+    write_config(&path, dir.path(), "preset:missing")?;
+    assert!(Config::from_path(&path).is_err());
+
+    fs::write(
+        &path,
+        contents.replace("'preset:albums'", "'preset:standard'"),
+    )?;
+    assert!(Config::from_path(&path).is_err());
+
+    Ok(())
+}
@@ -5,7 +5,7 @@ macro_rules! define_tests_for {
             use std::path::PathBuf;
             use std::str::FromStr;
 
-            use musso::format::ParsedFormat;
+            use musso::format::{BuildOptions, ParsedFormat};
             use musso::metadata::Metadata;
             use musso::{Error, Result};
 
@@ -64,6 +64,49 @@ macro_rules! define_tests_for {
                 Ok(())
             }
 
+            #[test]
+            fn explain_dropped_optional() -> Result<()> {
+                let ext = stringify!($ext);
+                let metadata = Metadata::from_path(format!("test_files/partial.{}", ext))?;
+
+                let format = "{artist}/{album?} - {title}.{ext}";
+                let format = ParsedFormat::from_str(format)?;
+                let explanation = format.explain(&metadata, &BuildOptions::default())?;
+
+                assert_eq!(format.build_path(&metadata, false)?, explanation.path);
+                assert_eq!(vec!["{album?}".to_string()], explanation.dropped);
+                assert_eq!(
+                    vec![
+                        ("{artist}".to_string(), "Artist".to_string()),
+                        ("{title}".to_string(), "Title".to_string()),
+                        ("{ext}".to_string(), ext.to_string()),
+                    ],
+                    explanation.resolved
+                );
+
+                Ok(())
+            }
+
+            #[test]
+            fn codec_directory() -> Result<()> {
+                let ext = stringify!($ext);
+                let metadata = Metadata::from_path(format!("test_files/complete.{}", ext))?;
+
+                let format = "{codec}/{artist}/{title}.{ext}";
+                let format = ParsedFormat::from_str(format)?;
+
+                let codec = match ext {
+                    "m4a" | "m4p" => "MP4".to_owned(),
+                    ext => ext.to_uppercase(),
+                };
+                let expected = format!("{}/Album Artist/Title.{}", codec, ext);
+                let expected = PathBuf::from(expected);
+
+                assert_eq!(expected, format.build_path(&metadata, false)?);
+
+                Ok(())
+            }
+
             #[test]
             fn bad_optional_formats() -> Result<()> {
                 let ext = stringify!($ext);
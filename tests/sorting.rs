@@ -0,0 +1,1499 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use musso::format::ParsedFormat;
+use musso::library::Exclude;
+use musso::prompt::{Answer, Prompter};
+use musso::sorting::{
+    find_misplaced, plan_folder, remove_empty_dirs, restore, sort_folder, sort_folder_atomic,
+    sort_folder_interactive, sort_folder_parallel, sort_planned, validate_folder, Compilation,
+    Dedup, DedupPreference, DestExists, DestExistsPolicy, FoldDiacritics, LongPathPolicy,
+    Misplaced, Options, SortReport, Status, STAGING_DIR,
+};
+use musso::testing::FixtureBuilder;
+use musso::{Error, Result};
+
+fn options() -> Options<ParsedFormat> {
+    Options {
+        recursive: true,
+        ..Default::default()
+    }
+}
+
+fn copy_test_file(name: &str, to: impl AsRef<Path>) -> Result<()> {
+    fs::copy(Path::new("test_files").join(name), to)?;
+    Ok(())
+}
+
+#[test]
+fn only_audio_files_are_moved() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("01.mp3")
+        .artist("Daft Punk")
+        .album("Homework")
+        .track(1u32)
+        .title("Revolution 909")
+        .file("02.flac")
+        .artist("Daft Punk")
+        .album("Homework")
+        .track(2u32)
+        .title("Da Funk")
+        .build()?;
+
+    let root = fixture.path();
+    fs::write(root.join("album.zip"), b"PK\x03\x04 not really an archive")?;
+    fs::write(
+        root.join("cover.jpg"),
+        b"\xff\xd8\xff\xe0 not really an image",
+    )?;
+    fs::write(root.join("notes.txt"), "some notes")?;
+
+    let report = sort_folder(root, root, &options())?;
+
+    assert_eq!(2, report.total);
+    assert_eq!(2, report.success);
+
+    assert!(root
+        .join("Daft Punk/Homework/1 - Revolution 909.mp3")
+        .is_file());
+    assert!(root.join("Daft Punk/Homework/2 - Da Funk.flac").is_file());
+    assert!(root.join("album.zip").is_file());
+    assert!(root.join("cover.jpg").is_file());
+    assert!(root.join("notes.txt").is_file());
+
+    Ok(())
+}
+
+#[test]
+fn container_is_sniffed_from_magic_bytes() -> Result<()> {
+    for name in ["complete", "complete.mp3"] {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+
+        copy_test_file("complete.flac", root.join(name))?;
+
+        let report = sort_folder(root, root, &options())?;
+        assert_eq!(1, report.success, "{} wasn't sorted", name);
+        assert!(root.join("Album Artist/Album/1 - Title.flac").is_file());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn hidden_files_are_skipped_by_default() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let root = dir.path();
+
+    fs::create_dir(root.join(".Trash-1000"))?;
+    copy_test_file("complete.mp3", root.join(".Trash-1000/complete.mp3"))?;
+    copy_test_file("complete.flac", root.join(".complete.flac"))?;
+
+    let report = sort_folder(root, root, &options())?;
+
+    assert_eq!(0, report.total);
+    assert!(root.join(".Trash-1000/complete.mp3").is_file());
+    assert!(root.join(".complete.flac").is_file());
+
+    let options = Options {
+        skip_hidden: false,
+        ..options()
+    };
+
+    let report = sort_folder(root, root, &options)?;
+
+    assert_eq!(2, report.total);
+    assert_eq!(2, report.success);
+    assert!(root.join("Album Artist/Album/1 - Title.mp3").is_file());
+    assert!(root.join("Album Artist/Album/1 - Title.flac").is_file());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn created_dirs_get_dir_mode() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir()?;
+    let root = dir.path();
+
+    copy_test_file("complete.mp3", root.join("complete.mp3"))?;
+
+    let options = Options {
+        dir_mode: Some(0o750),
+        ..options()
+    };
+
+    sort_folder(root, root, &options)?;
+
+    for created in ["Album Artist", "Album Artist/Album"] {
+        let mode = fs::metadata(root.join(created))?.permissions().mode();
+        assert_eq!(0o750, mode & 0o777);
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "preserve-timestamps")]
+#[test]
+fn timestamps_are_preserved() -> Result<()> {
+    use filetime::FileTime;
+
+    let dir = tempfile::tempdir()?;
+    let root = dir.path();
+    let file = root.join("complete.mp3");
+
+    copy_test_file("complete.mp3", &file)?;
+
+    let atime = FileTime::from_unix_time(1_000_000_000, 0);
+    let mtime = FileTime::from_unix_time(1_200_000_000, 0);
+    filetime::set_file_times(&file, atime, mtime)?;
+
+    let options = Options {
+        preserve_timestamps: true,
+        ..options()
+    };
+
+    sort_folder(root, root, &options)?;
+
+    let metadata = fs::metadata(root.join("Album Artist/Album/1 - Title.mp3"))?;
+    assert_eq!(mtime, FileTime::from_last_modification_time(&metadata));
+    assert_eq!(atime, FileTime::from_last_access_time(&metadata));
+
+    Ok(())
+}
+
+#[test]
+fn companions_follow_their_audio_file() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let root = dir.path();
+
+    copy_test_file("complete.flac", root.join("track.flac"))?;
+    fs::write(root.join("track.lrc"), "[00:00.00] lyrics")?;
+    fs::write(root.join("other.lrc"), "[00:00.00] orphan")?;
+
+    let options = Options {
+        companions: vec!["lrc".into()],
+        ..options()
+    };
+
+    let report = sort_folder(root, root, &options)?;
+
+    assert_eq!(1, report.total);
+    assert!(root.join("Album Artist/Album/1 - Title.flac").is_file());
+    assert!(root.join("Album Artist/Album/1 - Title.lrc").is_file());
+    assert!(!root.join("track.lrc").exists());
+    assert!(root.join("other.lrc").is_file());
+
+    Ok(())
+}
+
+#[test]
+fn companions_dont_overwrite_existing_files() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let root = dir.path();
+
+    copy_test_file("complete.flac", root.join("track.flac"))?;
+    fs::write(root.join("track.lrc"), "[00:00.00] new lyrics")?;
+    fs::create_dir_all(root.join("Album Artist/Album"))?;
+    fs::write(
+        root.join("Album Artist/Album/1 - Title.lrc"),
+        "[00:00.00] lyrics",
+    )?;
+
+    let options = Options {
+        companions: vec!["lrc".into()],
+        ..options()
+    };
+
+    sort_folder(root, root, &options)?;
+
+    assert!(root.join("Album Artist/Album/1 - Title.flac").is_file());
+    assert_eq!(
+        "[00:00.00] lyrics",
+        fs::read_to_string(root.join("Album Artist/Album/1 - Title.lrc"))?
+    );
+    assert!(root.join("track.lrc").is_file());
+
+    Ok(())
+}
+
+#[test]
+fn atomic_sorts_stage_companions() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let root = dir.path();
+
+    copy_test_file("complete.flac", root.join("track.flac"))?;
+    fs::write(root.join("track.lrc"), "[00:00.00] lyrics")?;
+
+    let options = Options {
+        companions: vec!["lrc".into()],
+        ..options()
+    };
+
+    sort_folder_atomic(root, root, &options)?;
+
+    assert!(root.join("Album Artist/Album/1 - Title.flac").is_file());
+    assert!(root.join("Album Artist/Album/1 - Title.lrc").is_file());
+    assert!(!root.join("track.lrc").exists());
+    assert!(!root.join(STAGING_DIR).exists());
+
+    Ok(())
+}
+
+#[test]
+fn clean_removes_nested_empty_dirs() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let root = dir.path();
+
+    fs::create_dir_all(root.join("a/b/c"))?;
+    fs::create_dir_all(root.join("d/e"))?;
+    fs::create_dir_all(root.join("library"))?;
+    fs::write(root.join("d/file.txt"), "keep me")?;
+
+    let keep = vec![root.join("library")];
+
+    let removed = remove_empty_dirs(root, &keep, &[], true)?;
+    assert_eq!(4, removed.len());
+    assert!(root.join("a/b/c").is_dir());
+
+    remove_empty_dirs(root, &keep, &[], false)?;
+    assert!(!root.join("a").exists());
+    assert!(!root.join("d/e").exists());
+    assert!(root.join("d/file.txt").is_file());
+    assert!(root.join("library").is_dir());
+    assert!(root.is_dir());
+
+    Ok(())
+}
+
+#[test]
+fn clean_removes_dirs_with_only_junk() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let root = dir.path();
+
+    fs::create_dir_all(root.join("a/b"))?;
+    fs::create_dir_all(root.join("c"))?;
+    fs::write(root.join("a/.DS_Store"), "junk")?;
+    fs::write(root.join("a/b/thumbs.db"), "junk")?;
+    fs::write(root.join("c/Thumbs.db"), "junk")?;
+    fs::write(root.join("c/cover.jpg"), "not junk")?;
+
+    let junk = vec![".DS_Store".to_owned(), "Thumbs.db".to_owned()];
+
+    // Junk alone is not enough without opting in
+    assert!(remove_empty_dirs(root, &[], &[], false)?.is_empty());
+
+    remove_empty_dirs(root, &[], &junk, false)?;
+    assert!(!root.join("a").exists());
+    assert!(root.join("c/Thumbs.db").is_file());
+    assert!(root.join("c/cover.jpg").is_file());
+
+    Ok(())
+}
+
+#[test]
+fn report_roundtrips_through_json() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let root = dir.path();
+
+    copy_test_file("complete.ogg", root.join("complete.ogg"))?;
+
+    let report = sort_folder(root, root, &options())?;
+    let json = serde_json::to_value(&report).unwrap();
+
+    assert_eq!(1, json["success"]);
+    assert_eq!(1, json["total"]);
+    assert_eq!("Album Artist/Album/1 - Title.ogg", json["new_paths"][0]);
+
+    let parsed: SortReport = serde_json::from_value(json).unwrap();
+    assert_eq!(report.new_paths, parsed.new_paths);
+    assert_eq!(report.outcomes, parsed.outcomes);
+
+    Ok(())
+}
+
+#[test]
+fn merged_reports_add_up() {
+    let report = |success, total, path: &str| SortReport {
+        success,
+        total,
+        new_paths: vec![PathBuf::from(path)],
+        ..Default::default()
+    };
+    let (a, b, c) = (report(1, 2, "a"), report(0, 1, "b"), report(3, 3, "c"));
+
+    let left = a.clone().merge(b.clone()).merge(c.clone());
+    let right = c.merge(b.merge(a));
+
+    assert_eq!((4, 6), (left.success, left.total));
+    assert_eq!((left.success, left.total), (right.success, right.total));
+    assert_eq!(
+        vec![PathBuf::from("a"), PathBuf::from("b"), PathBuf::from("c")],
+        left.new_paths
+    );
+}
+
+#[test]
+fn report_is_grouped_by_placeholder() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("a.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("One")
+        .file("b.mp3")
+        .artist("Artist")
+        .title("No Album")
+        .file("c.flac")
+        .artist("Other")
+        .album("Album")
+        .track(1u32)
+        .title("Two")
+        .build()?;
+    let root = fixture.path();
+
+    let report = sort_folder(root, root, &options())?;
+    let groups = report.group_by("artist", &Default::default())?;
+
+    let counts: Vec<_> = groups
+        .iter()
+        .map(|group| (group.label.as_deref(), group.sorted, group.failed))
+        .collect();
+    assert_eq!(vec![(Some("Artist"), 1, 1), (Some("Other"), 1, 0)], counts);
+
+    Ok(())
+}
+
+#[test]
+fn albums_with_different_album_artists_conflict() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("Album/1.mp3")
+        .artist("Artist")
+        .album_artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("One")
+        .file("Album/2.mp3")
+        .artist("Artist feat. Guest")
+        .album("Album")
+        .track(2u32)
+        .title("Two")
+        .file("Other/1.mp3")
+        .artist("Artist")
+        .album("Other")
+        .track(1u32)
+        .title("One")
+        .build()?;
+    let root = fixture.path();
+
+    let options = Options {
+        dryrun: true,
+        recursive: true,
+        ..options()
+    };
+    let report = sort_folder(root, root, &options)?;
+    let conflicts = report.album_artist_conflicts();
+
+    assert_eq!(1, conflicts.len());
+    assert_eq!("Album", conflicts[0].album);
+    let artists: Vec<_> = conflicts[0]
+        .artists
+        .iter()
+        .map(|(artist, _)| artist.as_str())
+        .collect();
+    assert_eq!(vec!["Artist", "Artist feat. Guest"], artists);
+
+    Ok(())
+}
+
+#[test]
+fn csv_report_quotes_fields() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let root = dir.path().join("a, \"quoted\" dir");
+    fs::create_dir(&root)?;
+
+    copy_test_file("complete.ogg", root.join("complete.ogg"))?;
+
+    let report = sort_folder(&root, &root, &options())?;
+    let mut csv = Vec::new();
+    report.write_csv(&mut csv)?;
+    let csv = String::from_utf8(csv).unwrap();
+
+    let quoted = |path: PathBuf| {
+        format!(
+            "\"{}\"",
+            std::path::absolute(path)
+                .unwrap()
+                .to_string_lossy()
+                .replace('"', "\"\"")
+        )
+    };
+
+    assert_eq!(
+        format!(
+            "source,destination,status,error\n{},{},moved,\n",
+            quoted(root.join("complete.ogg")),
+            quoted(root.join("Album Artist/Album/1 - Title.ogg"))
+        ),
+        csv
+    );
+
+    Ok(())
+}
+
+#[test]
+fn restore_moves_files_back() -> Result<()> {
+    let fixture = three_tracks()?;
+    let root = fixture.path();
+
+    let report = sort_folder(root, root, &options())?;
+    assert_eq!(3, report.outcomes.len());
+
+    // A new file took the place of one of the originals
+    fs::write(root.join("two.mp3"), "taken")?;
+
+    let restored = restore(&report, false);
+    assert_eq!(3, restored.total);
+    assert_eq!(2, restored.success);
+    // The skipped one is part of the report too
+    assert_eq!(3, restored.outcomes.len());
+    assert_eq!(
+        1,
+        restored
+            .outcomes
+            .iter()
+            .filter(|outcome| outcome.status == Status::Skipped)
+            .count()
+    );
+
+    assert!(root.join("one.mp3").is_file());
+    assert!(root.join("three.mp3").is_file());
+    assert!(root.join("Artist/Album/2 - two.mp3").is_file());
+    assert!(!root.join("Artist/Album/1 - one.mp3").exists());
+
+    Ok(())
+}
+
+#[test]
+fn restore_moves_companions_back() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let root = dir.path();
+
+    copy_test_file("complete.flac", root.join("track.flac"))?;
+    fs::write(root.join("track.lrc"), "[00:00.00] lyrics")?;
+
+    let options = Options {
+        companions: vec!["lrc".into()],
+        ..options()
+    };
+    let report = sort_folder(root, root, &options)?;
+    assert!(root.join("Album Artist/Album/1 - Title.lrc").is_file());
+
+    // Through the JSON report, like the restore subcommand
+    let json = serde_json::to_string(&report).unwrap();
+    let report: SortReport = serde_json::from_str(&json).unwrap();
+    let restored = restore(&report, false);
+    assert_eq!(1, restored.success);
+    assert!(root.join("track.flac").is_file());
+    assert!(root.join("track.lrc").is_file());
+    assert!(!root.join("Album Artist/Album/1 - Title.lrc").exists());
+
+    Ok(())
+}
+
+#[test]
+fn shallow_files_are_sorted_first() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("a/b/deep.flac")
+        .artist("Artist")
+        .album("Album")
+        .track(3u32)
+        .title("Deep")
+        .file("shallow.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("Shallow")
+        .file("a/middle.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(2u32)
+        .title("Middle")
+        .build()?;
+
+    let root = fixture.path();
+    let report = sort_folder(root, root, &options())?;
+
+    let expected: Vec<PathBuf> = vec![
+        "Artist/Album/1 - Shallow.mp3".into(),
+        "Artist/Album/2 - Middle.mp3".into(),
+        "Artist/Album/3 - Deep.flac".into(),
+    ];
+
+    assert_eq!(expected, report.new_paths);
+
+    Ok(())
+}
+
+/// Answers depending on the title (file stem) of the proposed file
+struct ByTitle {
+    answers: Vec<(&'static str, Answer)>,
+    asked: usize,
+}
+
+impl Prompter for ByTitle {
+    fn confirm(&mut self, from: &Path, _to: &Path) -> Answer {
+        self.asked += 1;
+        let stem = from.file_stem().unwrap().to_str().unwrap();
+        self.answers
+            .iter()
+            .find(|(title, _)| *title == stem)
+            .map(|(_, answer)| *answer)
+            .unwrap_or(Answer::Quit)
+    }
+}
+
+fn three_tracks() -> Result<musso::testing::Fixture> {
+    let mut builder = FixtureBuilder::new();
+    for (track, title) in ["one", "two", "three"].into_iter().enumerate() {
+        builder = builder
+            .file(format!("{}.mp3", title))
+            .artist("Artist")
+            .album("Album")
+            .track(track as u32 + 1)
+            .title(title);
+    }
+
+    builder.build()
+}
+
+#[test]
+fn parallel_sort_moves_every_file() -> Result<()> {
+    let fixture = three_tracks()?;
+    let root = fixture.path();
+
+    let report = sort_folder_parallel(root, root, &options(), 2)?;
+    assert_eq!(3, report.success);
+    assert_eq!(3, report.total);
+
+    for (track, title) in ["one", "two", "three"].into_iter().enumerate() {
+        let expected = format!("Artist/Album/{} - {}.mp3", track + 1, title);
+        assert!(root.join(&expected).is_file());
+        assert!(report.new_paths.contains(&PathBuf::from(expected)));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn atomic_album_moves_back_sorted_siblings() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("one.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("one")
+        .file("two.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(2u32)
+        .file("other.mp3")
+        .artist("Artist")
+        .album("Other")
+        .track(1u32)
+        .title("other")
+        .build()?;
+    let root = fixture.path();
+
+    let options = Options {
+        atomic_album: true,
+        remove_empty: true,
+        ..options()
+    };
+
+    // two.mp3 has no title, so the rest of its album is moved back
+    let report = sort_folder(root, root, &options)?;
+    assert_eq!(1, report.success);
+    assert_eq!(3, report.total);
+    assert_eq!(
+        vec![PathBuf::from("Artist/Other/1 - other.mp3")],
+        report.new_paths
+    );
+
+    assert!(root.join("one.mp3").is_file());
+    assert!(root.join("two.mp3").is_file());
+    assert!(!root.join("Artist/Album").exists());
+    assert!(root.join("Artist/Other/1 - other.mp3").is_file());
+
+    let rolled_back: Vec<_> = report
+        .outcomes
+        .iter()
+        .filter(|outcome| outcome.status == Status::RolledBack)
+        .collect();
+    assert_eq!(1, rolled_back.len());
+    assert!(rolled_back[0].source.ends_with("one.mp3"));
+
+    Ok(())
+}
+
+#[test]
+fn interactive_skips_declined_files() -> Result<()> {
+    let fixture = three_tracks()?;
+    let root = fixture.path();
+
+    let mut prompter = ByTitle {
+        answers: vec![
+            ("one", Answer::Yes),
+            ("two", Answer::No),
+            ("three", Answer::Yes),
+        ],
+        asked: 0,
+    };
+    let report = sort_folder_interactive(root, root, &options(), &mut prompter)?;
+
+    assert_eq!(3, prompter.asked);
+    assert_eq!(2, report.success);
+    assert!(root.join("Artist/Album/1 - one.mp3").is_file());
+    assert!(root.join("two.mp3").is_file());
+    assert!(root.join("Artist/Album/3 - three.mp3").is_file());
+
+    Ok(())
+}
+
+#[test]
+fn interactive_all_stops_asking() -> Result<()> {
+    let fixture = three_tracks()?;
+    let root = fixture.path();
+
+    let mut prompter = ByTitle {
+        answers: vec![
+            ("one", Answer::All),
+            ("two", Answer::All),
+            ("three", Answer::All),
+        ],
+        asked: 0,
+    };
+    let report = sort_folder_interactive(root, root, &options(), &mut prompter)?;
+
+    assert_eq!(1, prompter.asked);
+    assert_eq!(3, report.success);
+
+    Ok(())
+}
+
+#[test]
+fn interactive_quit_moves_nothing_else() -> Result<()> {
+    let fixture = three_tracks()?;
+    let root = fixture.path();
+
+    let mut prompter = ByTitle {
+        answers: Vec::new(),
+        asked: 0,
+    };
+    let report = sort_folder_interactive(root, root, &options(), &mut prompter)?;
+
+    assert_eq!(1, prompter.asked);
+    assert_eq!(0, report.total);
+    assert!(!root.join("Artist").exists());
+
+    Ok(())
+}
+
+#[test]
+fn misplaced_files_are_found() -> Result<()> {
+    let fixture = three_tracks()?;
+    let root = fixture.path();
+
+    sort_folder(root, root, &options())?;
+    fs::rename(
+        root.join("Artist/Album/2 - two.mp3"),
+        root.join("Artist/two.mp3"),
+    )?;
+
+    let misplaced = find_misplaced(root, root, &options())?;
+    assert_eq!(
+        vec![Misplaced {
+            path: root.join("Artist/two.mp3"),
+            expected: "Artist/Album/2 - two.mp3".into(),
+        }],
+        misplaced
+    );
+
+    Ok(())
+}
+
+#[test]
+fn planned_moves_are_the_ones_made() -> Result<()> {
+    let fixture = three_tracks()?;
+    let root = fixture.path();
+
+    let plan = plan_folder(root, root, &options())?;
+    assert_eq!(3, plan.moves());
+    assert!(root.join("one.mp3").is_file());
+
+    // Files showing up after planning wait for the next run
+    fs::copy(root.join("one.mp3"), root.join("late.mp3"))?;
+
+    let report = sort_planned(plan, &options());
+    assert_eq!((3, 3), (report.success, report.total));
+    assert!(root.join("late.mp3").is_file());
+    let misplaced = find_misplaced(root, root, &options())?;
+    assert_eq!(
+        vec![root.join("late.mp3")],
+        misplaced
+            .into_iter()
+            .map(|misplaced| misplaced.path)
+            .collect::<Vec<_>>()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn top_folders_are_kept_with_structure_depth() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("Bootlegs/1994/Show/track.mp3")
+        .artist("Artist")
+        .album("Live")
+        .track(1u32)
+        .title("Song")
+        .build()?;
+    let root = fixture.path();
+
+    let options = Options {
+        keep_structure_depth: 1,
+        ..options()
+    };
+
+    let report = sort_folder(root, root, &options)?;
+    assert_eq!(1, report.success);
+    assert!(root.join("Bootlegs/Artist/Live/1 - Song.mp3").is_file());
+
+    // Sorting again keeps the same folder
+    assert!(find_misplaced(root, root, &options)?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn rename_only_keeps_the_folder() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("Downloads/Some Album/track01.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("Song")
+        .build()?;
+    let root = fixture.path();
+
+    let options = Options {
+        rename_only: true,
+        ..options()
+    };
+
+    let report = sort_folder(root, root, &options)?;
+    assert_eq!(
+        vec![PathBuf::from("Downloads/Some Album/1 - Song.mp3")],
+        report.new_paths
+    );
+    assert!(root.join("Downloads/Some Album/1 - Song.mp3").is_file());
+    assert!(find_misplaced(root, root, &options)?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn populated_album_folders_are_skipped() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("Artist/Album/1 - Old.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("Old")
+        .file("Rip/01.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("New")
+        .file("Rip/02.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(2u32)
+        .title("Newer")
+        .file("Other/01.mp3")
+        .artist("Artist")
+        .album("Other Album")
+        .track(1u32)
+        .title("Song")
+        .file("Other/02.mp3")
+        .artist("Artist")
+        .album("Other Album")
+        .track(2u32)
+        .title("Another Song")
+        .build()?;
+    let root = fixture.path();
+
+    let failing = Options {
+        dest_exists: DestExists::new(DestExistsPolicy::Error),
+        dryrun: true,
+        ..options()
+    };
+    let report = sort_folder(root, root, &failing)?;
+    let failed: Vec<_> = report
+        .outcomes
+        .iter()
+        .filter(|outcome| outcome.status == Status::Failed)
+        .collect();
+    assert_eq!(2, failed.len());
+
+    let skipping = Options {
+        dest_exists: DestExists::new(DestExistsPolicy::SkipAlbum),
+        ..options()
+    };
+    let report = sort_folder(root, root, &skipping)?;
+    assert_eq!((3, 3), (report.success, report.total));
+
+    // The folder of the other album was empty when its first file got there
+    assert!(root
+        .join("Artist/Other Album/2 - Another Song.mp3")
+        .is_file());
+    assert!(root.join("Rip/01.mp3").is_file());
+    assert!(root.join("Rip/02.mp3").is_file());
+    assert_eq!(
+        2,
+        report
+            .outcomes
+            .iter()
+            .filter(|outcome| outcome.status == Status::Skipped)
+            .count()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn case_only_collisions_are_failed() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("Rip/01.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("Song")
+        .file("Other Rip/01.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("song")
+        .build()?;
+    let root = fixture.path();
+
+    let options = Options {
+        case_insensitive: true,
+        ..options()
+    };
+    let report = sort_folder(root, root, &options)?;
+    assert_eq!((1, 2), (report.success, report.total));
+
+    let failed = report
+        .outcomes
+        .iter()
+        .filter(|outcome| outcome.status == Status::Failed)
+        .count();
+    assert_eq!(1, failed);
+    assert_eq!(1, fs::read_dir(root.join("Artist/Album"))?.count());
+
+    Ok(())
+}
+
+#[test]
+fn long_paths_are_failed_or_truncated() -> Result<()> {
+    let title = "Très long titre ".repeat(20);
+    let fixture = FixtureBuilder::new()
+        .file("01.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title(title.trim())
+        .build()?;
+    let root = fixture.path();
+    let max = root.join("Artist/Album/1 - ").as_os_str().len() + 100;
+
+    let failing = Options {
+        max_path_len: Some(max),
+        ..options()
+    };
+    let report = sort_folder(root, root, &failing)?;
+    assert_eq!((0, 1), (report.success, report.total));
+    assert!(root.join("01.mp3").exists());
+
+    let truncating = Options {
+        max_path_len: Some(max),
+        long_path_policy: LongPathPolicy::Truncate,
+        ..options()
+    };
+    let report = sort_folder(root, root, &truncating)?;
+    assert_eq!((1, 1), (report.success, report.total));
+
+    let new_path = root.join(&report.new_paths[0]);
+    assert!(new_path.as_os_str().len() <= max);
+    assert!(new_path.exists());
+    assert_eq!(Some("mp3".as_ref()), new_path.extension());
+
+    Ok(())
+}
+
+#[test]
+fn atomic_sorts_all_files_or_none() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("Rip/01.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("One")
+        .file("Rip/02.flac")
+        .artist("Artist")
+        .album("Album")
+        .track(2u32)
+        .title("Two")
+        .build()?;
+    let root = fixture.path();
+    fs::write(root.join("Rip/03.mp3"), "not audio")?;
+
+    assert!(matches!(
+        sort_folder_atomic(root, root, &options()),
+        Err(Error::AtomicSortAborted { .. })
+    ));
+    assert!(root.join("Rip/01.mp3").exists());
+    assert!(root.join("Rip/02.flac").exists());
+    assert!(!root.join("Artist").exists());
+    assert!(!root.join(STAGING_DIR).exists());
+
+    fs::remove_file(root.join("Rip/03.mp3"))?;
+    let options = Options {
+        remove_empty: true,
+        ..options()
+    };
+    let report = sort_folder_atomic(root, root, &options)?;
+    assert_eq!((2, 2), (report.success, report.total));
+    assert!(root.join("Artist/Album/1 - One.mp3").exists());
+    assert!(root.join("Artist/Album/2 - Two.flac").exists());
+    assert!(!root.join("Rip").exists());
+    assert!(!root.join(STAGING_DIR).exists());
+
+    Ok(())
+}
+
+#[test]
+fn atomic_aborts_on_shared_destinations() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("a.mp3")
+        .artist("Artist")
+        .title("Song")
+        .file("b.mp3")
+        .artist("Artist")
+        .title("Song")
+        .build()?;
+    let root = fixture.path();
+    fs::write(
+        root.join("b.mp3"),
+        [fs::read(root.join("b.mp3"))?, vec![0; 1024]].concat(),
+    )?;
+    let sizes = [
+        fs::metadata(root.join("a.mp3"))?.len(),
+        fs::metadata(root.join("b.mp3"))?.len(),
+    ];
+
+    let options = Options {
+        format: "{artist}/{title}.{ext}".parse()?,
+        ..options()
+    };
+    assert!(matches!(
+        sort_folder_atomic(root, root, &options),
+        Err(Error::AtomicSortAborted { .. })
+    ));
+    assert_eq!(sizes[0], fs::metadata(root.join("a.mp3"))?.len());
+    assert_eq!(sizes[1], fs::metadata(root.join("b.mp3"))?.len());
+    assert!(!root.join("Artist").exists());
+
+    Ok(())
+}
+
+#[test]
+fn atomic_skips_excluded_files() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("Rip/01.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("One")
+        .file("Rip/02.part.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(2u32)
+        .title("Two")
+        .build()?;
+    let root = fixture.path();
+
+    let options = Options {
+        exclude: Exclude::new(&["*.part.mp3"]),
+        ..options()
+    };
+    let report = sort_folder_atomic(root, root, &options)?;
+    assert_eq!((1, 1), (report.success, report.total));
+    assert!(root.join("Rip/02.part.mp3").exists());
+
+    Ok(())
+}
+
+#[test]
+fn verified_files_are_counted() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("01.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("Song")
+        .build()?;
+    let root = fixture.path();
+    let contents = fs::read(root.join("01.mp3"))?;
+
+    let options = Options {
+        verify_after: true,
+        ..options()
+    };
+    let report = sort_folder(root, root, &options)?;
+    assert_eq!((1, 1), (report.success, report.verified));
+    assert_eq!(contents, fs::read(root.join("Artist/Album/1 - Song.mp3"))?);
+
+    Ok(())
+}
+
+#[test]
+fn folders_differing_by_diacritics_are_merged() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("Artist/Album/1 - Old.mp3")
+        .artist("Motörhead")
+        .album("Ace of Spades")
+        .track(1u32)
+        .title("Old")
+        .file("Rip/02.mp3")
+        .artist("Motorhead")
+        .album("Ace of Spades")
+        .track(2u32)
+        .title("New")
+        .build()?;
+    let root = fixture.path();
+    fs::rename(root.join("Artist"), root.join("Motörhead"))?;
+    fs::rename(
+        root.join("Motörhead/Album"),
+        root.join("Motörhead/Ace of Spades"),
+    )?;
+
+    let options = Options {
+        fold_diacritics: Some(FoldDiacritics::default()),
+        ..options()
+    };
+    let report = sort_folder(root, root, &options)?;
+    assert_eq!(report.total, report.success);
+    assert!(root.join("Motörhead/Ace of Spades/2 - New.mp3").exists());
+    assert!(!root.join("Motorhead").exists());
+
+    // Checking expects the merged folders too
+    assert!(find_misplaced(root, root, &options)?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn spellings_planned_in_the_same_run_are_merged() -> Result<()> {
+    let fixture = || {
+        FixtureBuilder::new()
+            .file("Rip/01.mp3")
+            .artist("Motörhead")
+            .album("Ace of Spades")
+            .track(1u32)
+            .title("One")
+            .file("Rip/02.mp3")
+            .artist("Motorhead")
+            .album("Ace of Spades")
+            .track(2u32)
+            .title("Two")
+            .build()
+    };
+    let options = || Options {
+        fold_diacritics: Some(FoldDiacritics::default()),
+        ..options()
+    };
+    let folders = |report: &SortReport| -> Vec<PathBuf> {
+        let mut folders: Vec<_> = report
+            .new_paths
+            .iter()
+            .filter_map(|path| path.parent().map(Path::to_path_buf))
+            .collect();
+        folders.dedup();
+        folders
+    };
+
+    let dry = fixture()?;
+    let dryrun = Options {
+        dryrun: true,
+        ..options()
+    };
+    let report = sort_folder(dry.path(), dry.path(), &dryrun)?;
+    assert_eq!(2, report.success);
+    assert_eq!(1, folders(&report).len());
+
+    let atomic = fixture()?;
+    let report = sort_folder_atomic(atomic.path(), atomic.path(), &options())?;
+    assert_eq!(2, report.success);
+    assert_eq!(1, folders(&report).len());
+    assert!(atomic.path().join(&folders(&report)[0]).is_dir());
+
+    Ok(())
+}
+
+#[test]
+fn musignore_keeps_files_in_place() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("01.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("Song")
+        .file("Incoming/01.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(2u32)
+        .title("Partial")
+        .build()?;
+    let root = fixture.path();
+    fs::write(root.join(".musignore"), "Incoming/\n")?;
+
+    let report = sort_folder(root, root, &options())?;
+    assert_eq!((1, 1), (report.success, report.total));
+    assert!(root.join("Incoming/01.mp3").exists());
+
+    Ok(())
+}
+
+#[test]
+fn missing_folders_are_not_created() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("Artist/Album/1 - Old.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("Old")
+        .file("02.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(2u32)
+        .title("New")
+        .file("03.mp3")
+        .artist("Artist")
+        .album("Stub")
+        .track(1u32)
+        .title("Badly Tagged")
+        .build()?;
+    let root = fixture.path();
+
+    let options = Options {
+        create_dirs: false,
+        ..options()
+    };
+    let report = sort_folder(root, root, &options)?;
+    assert!(root.join("Artist/Album/2 - New.mp3").exists());
+    assert!(!root.join("Artist/Stub").exists());
+    assert_eq!(vec![root.join("03.mp3")], report.skipped_no_dir);
+
+    Ok(())
+}
+
+#[test]
+fn worse_duplicates_are_removed() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("Artist/Album/1 - One.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("One")
+        .file("Rip/01.flac")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("One")
+        .file("Rip/02.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(2u32)
+        .title("Two")
+        .file("Artist/Album/2 - Two.flac")
+        .artist("Artist")
+        .album("Album")
+        .track(2u32)
+        .title("Two")
+        .build()?;
+    let root = fixture.path();
+    let quarantine = root.join(".quarantine");
+
+    let dedup = Dedup::new(DedupPreference::Lossless, Some(quarantine.clone()));
+    let options = Options {
+        dedup: Some(dedup.clone()),
+        ..options()
+    };
+    let report = sort_folder(root, root, &options)?;
+
+    assert!(root.join("Artist/Album/1 - One.flac").exists());
+    assert!(!root.join("Artist/Album/1 - One.mp3").exists());
+    assert!(quarantine.join("Artist/Album/1 - One.mp3").exists());
+    assert!(root.join("Artist/Album/2 - Two.flac").exists());
+    assert!(!root.join("Artist/Album/2 - Two.mp3").exists());
+    assert!(quarantine.join("Rip/02.mp3").exists());
+    assert_eq!(2, dedup.removed().len());
+    assert!(report
+        .outcomes
+        .iter()
+        .any(|outcome| outcome.source == root.join("Rip/02.mp3")
+            && outcome.status == Status::Skipped));
+
+    Ok(())
+}
+
+#[test]
+fn duplicates_are_kept_until_the_better_copy_moves() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("Artist/Album/1 - One.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("One")
+        .file("Rip/one.flac")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("One")
+        .build()?;
+    let root = fixture.path();
+
+    let dedup = Dedup::new(DedupPreference::Lossless, None);
+    let options = Options {
+        dedup: Some(dedup.clone()),
+        ..options()
+    };
+
+    let mut prompter = ByTitle {
+        answers: vec![("one", Answer::No)],
+        asked: 0,
+    };
+    sort_folder_interactive(root, root, &options, &mut prompter)?;
+    assert!(root.join("Artist/Album/1 - One.mp3").exists());
+    assert!(root.join("Rip/one.flac").exists());
+    assert!(dedup.removed().is_empty());
+
+    let report = sort_folder_atomic(root, root, &options)?;
+    assert_eq!(report.total, report.success);
+    assert!(root.join("Artist/Album/1 - One.flac").exists());
+    assert!(!root.join("Artist/Album/1 - One.mp3").exists());
+    assert_eq!(vec![root.join("Artist/Album/1 - One.mp3")], dedup.removed());
+
+    Ok(())
+}
+
+#[test]
+fn builder_sets_only_the_given_options() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("a/01.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("One")
+        .build()?;
+    let root = fixture.path();
+
+    let options = Options::<ParsedFormat>::builder()
+        .recursive(true)
+        .dryrun(true)
+        .max_depth(1)
+        .build();
+    assert_eq!(Some(1), options.max_depth);
+    assert!(options.skip_hidden && options.create_dirs);
+
+    let report = sort_folder(root, root, &options)?;
+    assert_eq!(1, report.success);
+    assert!(root.join("a/01.mp3").exists());
+
+    Ok(())
+}
+
+#[test]
+fn max_depth_limits_the_walk() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("top.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("Top")
+        .file("a/middle.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(2u32)
+        .title("Middle")
+        .file("a/b/deep.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(3u32)
+        .title("Deep")
+        .build()?;
+    let root = fixture.path();
+
+    let options = Options {
+        max_depth: Some(1),
+        ..options()
+    };
+
+    let report = sort_folder(root, root, &options)?;
+    assert_eq!(2, report.total);
+    assert!(root.join("Artist/Album/1 - Top.mp3").is_file());
+    assert!(root.join("Artist/Album/2 - Middle.mp3").is_file());
+    assert!(root.join("a/b/deep.mp3").is_file());
+
+    Ok(())
+}
+
+#[test]
+fn folders_are_validated() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("Artist/Album/1 - Placed.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("Placed")
+        .file("misplaced.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(2u32)
+        .title("Misplaced")
+        .file("untagged.mp3")
+        .build()?;
+    let root = fixture.path();
+
+    let validation = validate_folder(root, root, &options())?;
+    assert_eq!(1, validation.placed);
+    assert_eq!(
+        vec![Misplaced {
+            path: root.join("misplaced.mp3"),
+            expected: PathBuf::from("Artist/Album/2 - Misplaced.mp3"),
+        }],
+        validation.misplaced
+    );
+    assert_eq!(root.join("untagged.mp3"), validation.unknown[0].0);
+
+    Ok(())
+}
+
+#[test]
+fn excluded_files_are_not_sorted() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("Incoming/01.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("One")
+        .file("Incoming/02.part.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(2u32)
+        .title("Two")
+        .build()?;
+    let root = fixture.path();
+
+    let options = Options {
+        exclude: Exclude::new(&["*.part.mp3"]),
+        ..options()
+    };
+    let report = sort_folder(root, root, &options)?;
+
+    assert_eq!((1, 1), (report.total, report.success));
+    assert!(root.join("Artist/Album/1 - One.mp3").exists());
+    assert!(root.join("Incoming/02.part.mp3").exists());
+
+    Ok(())
+}
+
+#[test]
+fn albums_with_many_artists_are_compilations() -> Result<()> {
+    let mut builder = FixtureBuilder::new();
+    for (i, artist) in ["A", "B", "C", "D"].iter().enumerate() {
+        builder = builder
+            .file(format!("Rip/{}.mp3", i + 1))
+            .artist(*artist)
+            .album("Hits")
+            .track(i as u32 + 1)
+            .title(format!("Song {}", i + 1));
+    }
+    let fixture = builder
+        .file("Rip/solo.mp3")
+        .artist("A")
+        .album("Solo")
+        .track(1u32)
+        .title("Alone")
+        .build()?;
+    let root = fixture.path();
+
+    let options = Options {
+        compilation: Some(Compilation::new(
+            "Compilations/{album}/{track} - {title}.{ext}".parse()?,
+            3,
+        )),
+        ..options()
+    };
+    sort_folder(root, root, &options)?;
+
+    assert!(root.join("Compilations/Hits/1 - Song 1.mp3").exists());
+    assert!(root.join("Compilations/Hits/4 - Song 4.mp3").exists());
+    assert!(root.join("A/Solo/1 - Alone.mp3").exists());
+
+    Ok(())
+}
+
+#[test]
+fn validation_skips_what_sorting_leaves_alone() -> Result<()> {
+    let fixture = FixtureBuilder::new()
+        .file("misplaced.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("One")
+        .file("Incoming/ignored.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(2u32)
+        .title("Two")
+        .file("excluded.part.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(3u32)
+        .title("Three")
+        .file("Deep/Below/deep.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(4u32)
+        .title("Four")
+        .build()?;
+    let root = fixture.path();
+    fs::write(root.join(".musignore"), "Incoming/\n")?;
+
+    let options = Options {
+        exclude: Exclude::new(&["*.part.mp3"]),
+        max_depth: Some(1),
+        ..options()
+    };
+    let validation = validate_folder(root, root, &options)?;
+
+    let misplaced: Vec<_> = validation.misplaced.iter().map(|m| &m.path).collect();
+    assert_eq!(vec![&root.join("misplaced.mp3")], misplaced);
+
+    Ok(())
+}
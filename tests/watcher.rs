@@ -0,0 +1,531 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use notify::event::{CreateKind, DataChange, Event, EventKind, ModifyKind, RenameMode};
+use notify_debouncer_full::{DebounceEventResult, DebouncedEvent};
+
+use musso::config::Config;
+use musso::testing::FixtureBuilder;
+use musso::watcher::{StartupScan, Watcher};
+use musso::Result;
+
+fn config_for(dir: &tempfile::TempDir) -> Result<Config> {
+    config_with_format(dir, "{artist}/{album}/{track} - {title}.{ext}")
+}
+
+fn config_with_format(dir: &tempfile::TempDir, format: &str) -> Result<Config> {
+    let path = dir.path().join("config.toml");
+    let contents = format!(
+        r#"
+[watch]
+every = 1
+libraries = ['default']
+
+[libraries.default]
+format = '{}'
+folders = ['{}']
+"#,
+        format,
+        dir.path().display()
+    );
+
+    fs::write(&path, contents)?;
+    Config::from_path(path)
+}
+
+#[test]
+fn shutdown_stops_watch_loop() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let config = config_for(&dir)?;
+
+    let (thread, handle) = Watcher::new(config).watch_with_shutdown()?;
+    assert!(handle.is_running());
+
+    handle.shutdown();
+    thread.join().expect("watch thread panicked")?;
+    assert!(!handle.is_running());
+
+    Ok(())
+}
+
+#[test]
+fn idle_watcher_exits() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let config = config_for(&dir)?;
+
+    let start = Instant::now();
+    let (thread, handle) = Watcher::new(config)
+        .with_idle_timeout(Duration::from_millis(500))
+        .watch_with_shutdown()?;
+
+    thread.join().expect("watch thread panicked")?;
+    assert!(start.elapsed() >= Duration::from_millis(500));
+    assert!(!handle.is_running());
+
+    Ok(())
+}
+
+#[test]
+fn startup_scan_only_sorts_files_since_last_run() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let config = config_for(&dir)?;
+    let state = tempfile::tempdir()?;
+    let state_file = state.path().join("last-run");
+
+    let fixture = FixtureBuilder::new()
+        .file("old.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("Old")
+        .file("new.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(2u32)
+        .title("New")
+        .build()?;
+    for name in ["old.mp3", "new.mp3"] {
+        fs::copy(fixture.path().join(name), dir.path().join(name))?;
+    }
+
+    let old = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    fs::File::options()
+        .write(true)
+        .open(dir.path().join("old.mp3"))?
+        .set_modified(old)?;
+
+    let scan = |first_run_all| {
+        let scan = StartupScan::SinceLastRun {
+            state_file: state_file.clone(),
+            first_run_all,
+        };
+
+        let (thread, _) = Watcher::new(config.clone())
+            .with_startup_scan(scan)
+            .with_idle_timeout(Duration::from_millis(500))
+            .watch_with_shutdown()?;
+        thread.join().expect("watch thread panicked")
+    };
+
+    // The first run only records its time
+    scan(false)?;
+    assert!(state_file.is_file());
+    assert!(dir.path().join("new.mp3").is_file());
+
+    fs::write(&state_file, "1500000000\n")?;
+    scan(false)?;
+    assert!(dir.path().join("Artist/Album/2 - New.mp3").is_file());
+    assert!(dir.path().join("old.mp3").is_file());
+
+    Ok(())
+}
+
+#[test]
+fn created_files_are_sorted() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let config = config_for(&dir)?;
+
+    let fixture = FixtureBuilder::new()
+        .file("song.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("Song")
+        .build()?;
+
+    let (thread, handle) = Watcher::new(config).watch_with_shutdown()?;
+    fs::copy(fixture.path().join("song.mp3"), dir.path().join("song.mp3"))?;
+
+    let expected = dir.path().join("Artist/Album/1 - Song.mp3");
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !expected.is_file() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    handle.shutdown();
+    thread.join().expect("watch thread panicked")?;
+
+    assert!(expected.is_file());
+    assert!(!dir.path().join("song.mp3").exists());
+
+    Ok(())
+}
+
+fn created(path: PathBuf) -> DebounceEventResult {
+    let event = Event::new(EventKind::Create(CreateKind::File)).add_path(path);
+    Ok(vec![DebouncedEvent::new(event, Instant::now())])
+}
+
+fn debounced(kind: EventKind, paths: &[PathBuf]) -> DebouncedEvent {
+    let event = paths
+        .iter()
+        .fold(Event::new(kind), |event, path| event.add_path(path.clone()));
+
+    DebouncedEvent::new(event, Instant::now())
+}
+
+#[test]
+fn drain_sorts_queued_events() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let config = config_for(&dir)?;
+
+    let fixture = FixtureBuilder::new()
+        .file("song.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("Song")
+        .build()?;
+    fs::copy(fixture.path().join("song.mp3"), dir.path().join("song.mp3"))?;
+
+    let (tx, rx) = mpsc::channel();
+    tx.send(created(dir.path().join("song.mp3"))).unwrap();
+
+    let mut watcher = Watcher::new(config);
+    let report = watcher.drain(&rx, Duration::from_secs(5))?;
+
+    let expected = dir.path().join("Artist/Album/1 - Song.mp3");
+    assert_eq!(1, report.success);
+    assert_eq!(
+        vec![PathBuf::from("Artist/Album/1 - Song.mp3")],
+        report.new_paths
+    );
+    assert!(expected.is_file());
+
+    // The event caused by musso itself is skipped
+    tx.send(created(expected)).unwrap();
+    let report = watcher.drain(&rx, Duration::from_secs(5))?;
+    assert_eq!(0, report.total);
+
+    Ok(())
+}
+
+#[test]
+fn hidden_files_are_ignored() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let config = config_for(&dir)?;
+
+    let fixture = FixtureBuilder::new()
+        .file("song.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("Song")
+        .build()?;
+    let hidden = [
+        dir.path().join("._song.mp3"),
+        dir.path().join(".downloads/song.mp3"),
+    ];
+    for path in &hidden {
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::copy(fixture.path().join("song.mp3"), path)?;
+    }
+
+    let (tx, rx) = mpsc::channel();
+    for path in &hidden {
+        tx.send(created(path.clone())).unwrap();
+    }
+
+    let report = Watcher::new(config).drain(&rx, Duration::from_secs(5))?;
+    assert_eq!(0, report.total);
+    assert!(hidden.iter().all(|path| path.is_file()));
+
+    Ok(())
+}
+
+#[test]
+fn musignored_files_are_ignored() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let config = config_for(&dir)?;
+
+    let fixture = FixtureBuilder::new()
+        .file("song.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("Song")
+        .build()?;
+    let ignored = dir.path().join("Incoming/partial/song.mp3");
+    fs::create_dir_all(ignored.parent().unwrap())?;
+    fs::copy(fixture.path().join("song.mp3"), &ignored)?;
+    fs::write(dir.path().join("Incoming/.musignore"), "partial/\n")?;
+
+    let (tx, rx) = mpsc::channel();
+    tx.send(created(ignored.clone())).unwrap();
+
+    let report = Watcher::new(config).drain(&rx, Duration::from_secs(5))?;
+    assert_eq!(0, report.total);
+    assert!(ignored.is_file());
+
+    Ok(())
+}
+
+#[test]
+fn excluded_files_are_ignored() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("config.toml");
+    fs::write(
+        &path,
+        format!(
+            r#"
+[watch]
+every = 1
+libraries = ['default']
+
+[libraries.default]
+format = '{{artist}}/{{album}}/{{track}} - {{title}}.{{ext}}'
+folders = ['{}']
+ignore-patterns = ['*.part.mp3']
+"#,
+            dir.path().display()
+        ),
+    )?;
+    let config = Config::from_path(path)?;
+
+    let fixture = FixtureBuilder::new()
+        .file("song.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("Song")
+        .build()?;
+    let excluded = dir.path().join("Incoming/song.part.mp3");
+    fs::create_dir_all(excluded.parent().unwrap())?;
+    fs::copy(fixture.path().join("song.mp3"), &excluded)?;
+
+    let (tx, rx) = mpsc::channel();
+    tx.send(created(excluded.clone())).unwrap();
+
+    let report = Watcher::new(config).drain(&rx, Duration::from_secs(5))?;
+    assert_eq!(0, report.total);
+    assert!(excluded.is_file());
+
+    Ok(())
+}
+
+#[test]
+fn edited_files_are_sorted_again() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let fixture = FixtureBuilder::new()
+        .file("Artist/Album/1 - Song.mp3")
+        .artist("Artist")
+        .album("Retagged")
+        .track(1u32)
+        .title("Song")
+        .build()?;
+    let edited = dir.path().join("Artist/Album/1 - Song.mp3");
+    fs::create_dir_all(edited.parent().unwrap())?;
+    fs::copy(fixture.path().join("Artist/Album/1 - Song.mp3"), &edited)?;
+
+    let modified = || {
+        let kind = EventKind::Modify(ModifyKind::Data(DataChange::Content));
+        Ok(vec![debounced(kind, std::slice::from_ref(&edited))])
+    };
+
+    let mut config = config_for(&dir)?;
+    config.watch.ignore_data_events = Some(true);
+    let (tx, rx) = mpsc::channel();
+    tx.send(modified()).unwrap();
+    let report = Watcher::new(config).drain(&rx, Duration::from_secs(5))?;
+    assert_eq!(0, report.total);
+    assert!(edited.is_file());
+
+    tx.send(modified()).unwrap();
+    let report = Watcher::new(config_for(&dir)?).drain(&rx, Duration::from_secs(5))?;
+    assert_eq!(1, report.success);
+    assert!(dir.path().join("Artist/Retagged/1 - Song.mp3").is_file());
+
+    Ok(())
+}
+
+#[test]
+fn own_folders_and_files_are_not_sorted_again() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let config = config_with_format(&dir, "{artist}/{album}/Disc {disc}/{track} - {title}.{ext}")?;
+
+    let fixture = FixtureBuilder::new()
+        .file("song.mp3")
+        .artist("Artist")
+        .album("Album")
+        .disc(2u32)
+        .track(1u32)
+        .title("Song")
+        .build()?;
+    let source = dir.path().join("song.mp3");
+    fs::copy(fixture.path().join("song.mp3"), &source)?;
+
+    let (tx, rx) = mpsc::channel();
+    tx.send(created(source.clone())).unwrap();
+
+    let mut watcher = Watcher::new(config);
+    let report = watcher.drain(&rx, Duration::from_secs(5))?;
+    assert_eq!(1, report.success);
+
+    // What the file system reports for the move: the three new folders,
+    // then the rename into the deepest one
+    let artist = dir.path().join("Artist");
+    let album = artist.join("Album");
+    let disc = album.join("Disc 2");
+    let expected = disc.join("1 - Song.mp3");
+    tx.send(Ok(vec![
+        debounced(EventKind::Create(CreateKind::Folder), &[artist]),
+        debounced(EventKind::Create(CreateKind::Folder), &[album]),
+        debounced(EventKind::Create(CreateKind::Folder), &[disc]),
+        debounced(
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
+            &[source, expected.clone()],
+        ),
+    ]))
+    .unwrap();
+
+    let report = watcher.drain(&rx, Duration::from_secs(5))?;
+    assert_eq!(0, report.total);
+    assert!(expected.is_file());
+
+    // Each path is only skipped once
+    tx.send(created(expected)).unwrap();
+    let report = watcher.drain(&rx, Duration::from_secs(5))?;
+    assert_eq!(1, report.total);
+
+    Ok(())
+}
+
+#[test]
+fn moving_files_away_prunes_their_folder() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let config = config_for(&dir)?;
+
+    let album = dir.path().join("Artist/Album");
+    fs::create_dir_all(&album)?;
+    fs::write(dir.path().join("Artist/cover.jpg"), "")?;
+
+    // The file is already gone, `mv`-ed to a folder outside of the library
+    let event = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::From)))
+        .add_path(album.join("1 - Song.mp3"));
+
+    let (tx, rx) = mpsc::channel();
+    tx.send(Ok(vec![DebouncedEvent::new(event, Instant::now())]))
+        .unwrap();
+
+    let mut watcher = Watcher::new(config);
+    let report = watcher.drain(&rx, Duration::from_secs(5))?;
+
+    assert_eq!(0, report.total);
+    assert!(!album.exists());
+    // Its parent still has a file
+    assert!(dir.path().join("Artist/cover.jpg").is_file());
+
+    Ok(())
+}
+
+#[test]
+fn emptied_folders_can_be_kept() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut config = config_for(&dir)?;
+    config.watch.remove_empty = Some(false);
+
+    let album = dir.path().join("Artist/Album");
+    fs::create_dir_all(&album)?;
+
+    let event = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::From)))
+        .add_path(album.join("1 - Song.mp3"));
+
+    let (tx, rx) = mpsc::channel();
+    tx.send(Ok(vec![DebouncedEvent::new(event, Instant::now())]))
+        .unwrap();
+
+    Watcher::new(config).drain(&rx, Duration::from_secs(5))?;
+    assert!(album.is_dir());
+
+    Ok(())
+}
+
+#[test]
+fn emptied_folders_can_be_marked() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut config = config_for(&dir)?;
+    config.watch.mark_empty = Some(".muso-sorted".into());
+
+    let album = dir.path().join("Incoming/Album");
+    fs::create_dir_all(&album)?;
+
+    let event = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::From)))
+        .add_path(album.join("1 - Song.mp3"));
+
+    let (tx, rx) = mpsc::channel();
+    tx.send(Ok(vec![DebouncedEvent::new(event, Instant::now())]))
+        .unwrap();
+
+    Watcher::new(config).drain(&rx, Duration::from_secs(5))?;
+    assert!(album.join(".muso-sorted").is_file());
+    // Only the emptied folder is marked
+    assert!(!dir.path().join("Incoming/.muso-sorted").exists());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_events_are_handled_like_created_files() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let config = config_for(&dir)?;
+    let state = tempfile::tempdir()?;
+    let socket = state.path().join("watch.sock");
+
+    let fixture = FixtureBuilder::new()
+        .file("song.mp3")
+        .artist("Artist")
+        .album("Album")
+        .track(1u32)
+        .title("Song")
+        .build()?;
+    // Already there before watching, so no real event is fired for it
+    fs::copy(fixture.path().join("song.mp3"), dir.path().join("song.mp3"))?;
+
+    let (thread, handle) = Watcher::new(config)
+        .with_control_socket(&socket)
+        .watch_with_shutdown()?;
+    musso::watcher::send_test_event(&socket, dir.path().join("song.mp3"))?;
+
+    let expected = dir.path().join("Artist/Album/1 - Song.mp3");
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !expected.is_file() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    handle.shutdown();
+    thread.join().expect("watch thread panicked")?;
+
+    assert!(expected.is_file());
+    assert!(!socket.exists());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn open_control_connections_dont_hold_up_shutdown() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let config = config_for(&dir)?;
+    let state = tempfile::tempdir()?;
+    let socket = state.path().join("watch.sock");
+
+    let (thread, handle) = Watcher::new(config)
+        .with_control_socket(&socket)
+        .watch_with_shutdown()?;
+    // Never sends a whole line nor closes the connection
+    let mut client = std::os::unix::net::UnixStream::connect(&socket)?;
+    std::io::Write::write_all(&mut client, b"partial")?;
+    thread::sleep(Duration::from_millis(500));
+
+    handle.shutdown();
+    thread.join().expect("watch thread panicked")?;
+
+    assert!(!socket.exists());
+    drop(client);
+
+    Ok(())
+}